@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use pseudokafka::de;
+
+// Any input must either parse or fail with an error, never panic
+fuzz_target!(|data: &[u8]| {
+    let _ = de::from_stream(data);
+});
@@ -1,43 +1,236 @@
-use pseudokafka::{ser::Serialize, de, broker};
-use std::{io::Write, net::TcpListener, net::TcpStream, thread};
-
-const KAFKA_HOST: &str = "0.0.0.0:9092";
-
-fn handle_client(mut stream: TcpStream) {
-    loop {
-        match de::from_stream(&stream) {
-            Ok(req) => {
-                dbg!(&req);
-                match broker::process(&req) {
-                    Some(resp) => {
-                        stream
-                            .write_all(resp.to_bytes().unwrap().as_slice())
-                            .unwrap();
-                    }
-                    None => break,
-                }
-            }
-            Err(e) => {
-                println!("Error reading message: {:?}", e);
-                break;
-            }
-        }
-    }
+use pseudokafka::state::State;
+use pseudokafka::{config::BrokerConfig, fixture::Fixture, logging, recorder, server::Server};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::{env, process};
+
+const USAGE: &str = "Usage: pseudokafka [OPTIONS]
+
+  --config FILE                 read broker properties from a server.properties file
+  --listen LISTENERS            addresses to listen on, as NAME://HOST:PORT,...
+                                (listeners, default PLAINTEXT://0.0.0.0:9092)
+  --advertised-listener LISTENERS
+                                addresses given to clients, by listener name
+                                (advertised.listeners)
+  --node-id ID                  broker id (node.id, default 1003)
+  --cluster-id ID               cluster id (cluster.id)
+  --brokers N                   brokers to simulate, on consecutive ports (cluster.brokers)
+  --num-partitions N            partitions of auto-created topics (num.partitions, default 1)
+  --auto-create-topics BOOL     create unknown topics on use (auto.create.topics.enable)
+  --admin HOST:PORT             serve the HTTP admin endpoint, e.g. to inject faults
+                                (admin.listener)
+  --log-level LEVEL             level of the logs, or filtering directives as in RUST_LOG
+                                (logging.level, default info)
+  --log-format FORMAT           text or json (logging.format, default text)
+  --override KEY=VALUE          set any other broker property
+  --load-snapshot FILE          replace the broker state with a snapshot before starting
+  --fixture FILE                seed topics, records and offsets from a YAML, JSON or TOML file
+  --save-snapshot FILE          write a snapshot of the starting state and exit (GET
+                                /snapshot on the admin endpoint for the live one)
+  --metrics HOST:PORT           serve Prometheus metrics on /metrics (metrics.listener)
+  --rest HOST:PORT              serve a Confluent REST Proxy compatible API (rest.listener)
+  --schema-registry HOST:PORT   serve a Schema Registry compatible API, with schemas kept
+                                in the _schemas topic (schema.registry.listener)
+  --record FILE                 record every request and response to a JSONL file
+                                (record.file)
+  --replay FILE                 send a recording to the broker, print the responses that
+                                differ and exit
+  --replay-to HOST:PORT         with --replay, replay to another broker instead of
+                                starting one
+
+Properties are read from the file, then from PSEUDOKAFKA_* environment variables
+(e.g. PSEUDOKAFKA_ADVERTISED_LISTENERS), then from the command line.";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
 }
 
 fn main() {
-    let listener = TcpListener::bind(KAFKA_HOST).unwrap();
-    // Accept connections and process them, spawning a new thread for each one
-    println!("Server listening on {}", KAFKA_HOST);
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // println!("New connection: {}", stream.peer_addr().unwrap());
-                thread::spawn(move || handle_client(stream));
+    let mut config_file = None;
+    let mut properties = Vec::new();
+    let mut load_snapshot = None;
+    let mut fixture = None;
+    let mut save_snapshot = None;
+    let mut replay = None;
+    let mut replay_to = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        let key = match arg.as_str() {
+            "--config" => {
+                config_file = Some(value);
+                continue;
+            }
+            "--load-snapshot" => {
+                load_snapshot = Some(value);
+                continue;
             }
-            Err(e) => {
-                println!("Error: {}", e);
+            "--fixture" => {
+                fixture = Some(value);
+                continue;
             }
+            "--save-snapshot" => {
+                save_snapshot = Some(value);
+                continue;
+            }
+            "--replay" => {
+                replay = Some(value);
+                continue;
+            }
+            "--replay-to" => {
+                replay_to = Some(value);
+                continue;
+            }
+            "--override" => match value.split_once('=') {
+                Some((key, value)) => {
+                    properties.push((key.to_string(), value.to_string()));
+                    continue;
+                }
+                None => usage(),
+            },
+            "--listen" => "listeners",
+            "--advertised-listener" => "advertised.listeners",
+            "--node-id" => "node.id",
+            "--cluster-id" => "cluster.id",
+            "--brokers" => "cluster.brokers",
+            "--num-partitions" => "num.partitions",
+            "--auto-create-topics" => "auto.create.topics.enable",
+            "--admin" => "admin.listener",
+            "--record" => "record.file",
+            "--metrics" => "metrics.listener",
+            "--rest" => "rest.listener",
+            "--schema-registry" => "schema.registry.listener",
+            "--log-level" => "logging.level",
+            "--log-format" => "logging.format",
+            _ => usage(),
+        };
+        properties.push((key.to_string(), value));
+    }
+    if replay_to.is_some() && replay.is_none() {
+        usage();
+    }
+
+    // Defaults, then the file, the environment and the command line
+    let (mut config, ignored) = match &config_file {
+        Some(file) => BrokerConfig::from_file(file).unwrap_or_else(|e| {
+            eprintln!("Error reading config {}: {}", file, e);
+            process::exit(2);
+        }),
+        None => (BrokerConfig::default(), vec![]),
+    };
+    if let Err(e) = config.set_from_env(env::vars()) {
+        eprintln!("Error: {}", e);
+        process::exit(2);
+    }
+    for (key, value) in &properties {
+        if !config.set(key, value) {
+            eprintln!("Error: invalid {}={}", key, value);
+            process::exit(2);
         }
     }
+
+    if let Err(e) = logging::init(&config.log_level, config.log_format) {
+        eprintln!("Error: {}", e);
+        process::exit(2);
+    }
+    for (key, value) in ignored {
+        let file = config_file.as_deref().unwrap_or_default();
+        tracing::warn!("{}: ignoring property {}={}", file, key, value);
+    }
+
+    let recording = replay.map(|file| {
+        recorder::read(&file).unwrap_or_else(|e| {
+            eprintln!("Error reading {}: {}", file, e);
+            process::exit(2);
+        })
+    });
+    if let (Some(recording), Some(addr)) = (&recording, &replay_to) {
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .unwrap_or_else(|| usage());
+        replay_and_exit(recording, |_| addr);
+    }
+
+    let mut state = State::open(config).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(2);
+    });
+    if let Some(file) = load_snapshot {
+        state.load_snapshot(&file).unwrap_or_else(|e| {
+            eprintln!("Error loading snapshot {}: {}", file, e);
+            process::exit(2);
+        });
+        tracing::info!("Snapshot loaded from {}", file);
+    }
+    if let Some(file) = fixture {
+        Fixture::from_file(&file)
+            .and_then(|f| f.apply(&mut state))
+            .unwrap_or_else(|e| {
+                eprintln!("Error loading fixture {}: {}", file, e);
+                process::exit(2);
+            });
+        tracing::info!("Fixture loaded from {}", file);
+    }
+    if let Some(file) = save_snapshot {
+        state.save_snapshot(&file).unwrap_or_else(|e| {
+            eprintln!("Error saving snapshot {}: {}", file, e);
+            process::exit(2);
+        });
+        tracing::info!("Snapshot saved to {}", file);
+        return;
+    }
+    let listeners = state.config.listeners.clone();
+    let server = Server::start_listeners(state).unwrap_or_else(|e| {
+        eprintln!("Error starting the listeners: {}", e);
+        process::exit(2);
+    });
+    if let Some(recording) = &recording {
+        replay_and_exit(recording, |e| {
+            server
+                .broker_addr(e.node_id, &e.listener)
+                .unwrap_or_else(|| server.local_addr())
+        });
+    }
+    let nodes = server.state().lock().unwrap().cluster.nodes.clone();
+    for node in nodes {
+        for l in &listeners {
+            let addr = server.broker_addr(node.id, &l.name).unwrap();
+            tracing::info!("Broker {} listening on {}://{}", node.id, l.name, addr);
+        }
+    }
+    if let Some(addr) = server.admin_addr() {
+        tracing::info!("Admin endpoint listening on http://{}", addr);
+    }
+    if let Some(addr) = server.metrics_addr() {
+        tracing::info!("Metrics served on http://{}/metrics", addr);
+    }
+    if let Some(addr) = server.rest_addr() {
+        tracing::info!("REST Proxy listening on http://{}", addr);
+    }
+    if let Some(addr) = server.schema_registry_addr() {
+        tracing::info!("Schema Registry listening on http://{}", addr);
+    }
+    server.wait();
+}
+
+// Replay a recording, exiting with an error if any response differs
+fn replay_and_exit(
+    recording: &[recorder::Exchange],
+    target: impl Fn(&recorder::Exchange) -> SocketAddr,
+) -> ! {
+    let differences = recorder::replay(recording, target).unwrap_or_else(|e| {
+        eprintln!("Error replaying: {}", e);
+        process::exit(1);
+    });
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    println!(
+        "{} requests replayed, {} responses differ",
+        recording.len(),
+        differences.len()
+    );
+    process::exit(if differences.is_empty() { 0 } else { 1 });
 }
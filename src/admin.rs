@@ -0,0 +1,778 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::faults::Fault;
+use crate::fixture::FixtureRecord;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::log::now_ms;
+use crate::state::{SharedState, State, Topic};
+
+/// Records returned by default when reading a partition
+const DEFAULT_LIMIT: usize = 100;
+/// Most records returned when reading a partition
+const MAX_LIMIT: usize = 10_000;
+
+/// Answer a request to the HTTP admin endpoint
+///
+/// * `GET /topics` - topics, with the offsets and replicas of their partitions
+/// * `POST /topics` - create a topic, given its name, partitions and configs
+/// * `GET /topics/{topic}` - a topic
+/// * `DELETE /topics/{topic}` - delete a topic
+/// * `GET /topics/{topic}/partitions/{partition}/records` - read records from
+///   `offset` (the log start by default), at most `limit` of them
+/// * `POST /topics/{topic}/records` - produce records, given as in fixtures
+/// * `GET /groups` - consumer groups, with their committed offsets and lag
+/// * `GET /groups/{group}` - a consumer group
+/// * `POST /reset` - remove every topic, committed offset and fault
+/// * `GET /snapshot` - a snapshot of the whole state, as saved to a file
+/// * `GET /faults` - faults being injected, with their ids
+/// * `POST /faults` - add a fault, given as JSON
+/// * `DELETE /faults` - remove every fault
+/// * `DELETE /faults/{id}` - remove a fault
+/// * `GET /brokers` - brokers, with their racks and whether they are online
+/// * `PUT /brokers/{id}` - take a broker offline or bring it back, given
+///   `online`
+/// * `POST /topics/{topic}/partitions/{partition}/election` - elect a
+///   `leader`, the preferred one without, or any replica online with `unclean`
+/// * `PUT /topics/{topic}/partitions/{partition}/isr` - change the replicas
+///   in sync, given `isr`
+/// * `PUT /topics/{topic}/partitions/{partition}/replicas` - reassign a
+///   partition, given `replicas`
+/// * `DELETE /topics/{topic}/partitions/{partition}/reassignment` - cancel a
+///   reassignment
+///
+/// * `state` - broker state
+/// * `req` - HTTP request
+pub fn handle(state: &SharedState, req: &HttpRequest) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    let segments = req.segments();
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    let result = match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["topics"]) => Ok(list_topics(&state)),
+        ("POST", ["topics"]) => create_topic(&mut state, req),
+        ("GET", ["topics", topic]) => Ok(describe_topic(&state, topic)),
+        ("DELETE", ["topics", topic]) => delete_topic(&mut state, topic),
+        ("GET", ["topics", topic, "partitions", partition, "records"]) => {
+            Ok(read_records(&state, topic, partition, req))
+        }
+        ("POST", ["topics", topic, "records"]) => produce(&mut state, topic, req),
+        (method, ["topics", topic, "partitions", partition, action]) => Ok(control_partition(
+            &mut state, method, topic, partition, action, req,
+        )),
+        ("GET", ["brokers"]) => Ok(list_brokers(&state)),
+        ("PUT", ["brokers", id]) => Ok(set_broker_online(&mut state, id, req)),
+        ("GET", ["groups"]) => Ok(HttpResponse::json(200, &groups(&state, None))),
+        ("GET", ["groups", group]) => match groups(&state, Some(group)).pop() {
+            Some(group) => Ok(HttpResponse::json(200, &group)),
+            None => Ok(HttpResponse::not_found()),
+        },
+        ("POST", ["reset"]) => state.reset().map(|()| {
+            info!("State reset");
+            HttpResponse::no_content()
+        }),
+        ("GET", ["snapshot"]) => state.snapshot().map(|body| HttpResponse {
+            status: 200,
+            content_type: "application/octet-stream".to_string(),
+            body,
+        }),
+        (_, ["faults"]) | (_, ["faults", _]) => Ok(faults(&mut state, req)),
+        (_, ["topics", ..])
+        | (_, ["groups", ..])
+        | (_, ["reset"])
+        | (_, ["snapshot"])
+        | (_, ["brokers", ..]) => Ok(HttpResponse::error(405, "method not allowed")),
+        _ => Ok(HttpResponse::not_found()),
+    };
+    result.unwrap_or_else(|e| match e.kind() {
+        // Invalid topic names, from topics created or auto-created
+        io::ErrorKind::InvalidInput => HttpResponse::error(422, e.to_string()),
+        _ => HttpResponse::error(500, e.to_string()),
+    })
+}
+
+#[derive(Serialize)]
+struct PartitionInfo {
+    partition: u32,
+    log_start_offset: i64,
+    log_end_offset: i64,
+    /// Size of the log, in bytes
+    size: usize,
+    leader: Option<u32>,
+    leader_epoch: u32,
+    replicas: Vec<u32>,
+    isr: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct TopicInfo {
+    name: String,
+    configs: BTreeMap<String, String>,
+    partitions: Vec<PartitionInfo>,
+}
+
+fn partition_info(state: &State, topic: &str, partition: u32) -> Option<PartitionInfo> {
+    let log = state
+        .topics
+        .get(topic)?
+        .partitions
+        .get(partition as usize)?;
+    let assignment = state.cluster.assignment(topic, partition);
+    Some(PartitionInfo {
+        partition,
+        log_start_offset: log.log_start_offset(),
+        log_end_offset: log.log_end_offset(),
+        size: log.size(),
+        leader: assignment.leader,
+        leader_epoch: assignment.leader_epoch,
+        replicas: assignment.replicas,
+        isr: assignment.isr,
+    })
+}
+
+fn topic_info(state: &State, name: &str) -> Option<TopicInfo> {
+    let topic = state.topics.get(name)?;
+    let partitions = (0..topic.partitions.len() as u32)
+        .filter_map(|p| partition_info(state, name, p))
+        .collect();
+    Some(TopicInfo {
+        name: name.to_string(),
+        configs: topic.config.to_properties().into_iter().collect(),
+        partitions,
+    })
+}
+
+fn list_topics(state: &State) -> HttpResponse {
+    let topics = state
+        .topics
+        .keys()
+        .filter_map(|name| topic_info(state, name))
+        .collect::<Vec<_>>();
+    HttpResponse::json(200, &topics)
+}
+
+fn describe_topic(state: &State, name: &str) -> HttpResponse {
+    match topic_info(state, name) {
+        Some(topic) => HttpResponse::json(200, &topic),
+        None => HttpResponse::not_found(),
+    }
+}
+
+fn create_topic(state: &mut State, req: &HttpRequest) -> io::Result<HttpResponse> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct NewTopic {
+        name: String,
+        /// The broker's default when missing
+        partitions: Option<usize>,
+        #[serde(default)]
+        configs: HashMap<String, String>,
+    }
+
+    let topic = match serde_json::from_slice::<NewTopic>(&req.body) {
+        Ok(topic) => topic,
+        Err(e) => return Ok(HttpResponse::error(422, format!("invalid topic: {}", e))),
+    };
+    let partitions = topic.partitions.unwrap_or(state.config.num_partitions);
+    if partitions == 0 {
+        return Ok(HttpResponse::error(422, "invalid number of partitions"));
+    }
+    if let Err(e) = Topic::validate_name(&topic.name) {
+        return Ok(HttpResponse::error(422, e.to_string()));
+    }
+    let mut config = state.config.default_topic_config.clone();
+    for (key, value) in &topic.configs {
+        if !config.set(key, value) {
+            let message = format!("invalid config {}={}", key, value);
+            return Ok(HttpResponse::error(422, message));
+        }
+    }
+    if !state.create_topic(&topic.name, partitions, config)? {
+        return Ok(HttpResponse::error(409, "topic already exists"));
+    }
+    info!(topic = %topic.name, partitions, "Topic created");
+    let mut resp = describe_topic(state, &topic.name);
+    resp.status = 201;
+    Ok(resp)
+}
+
+fn delete_topic(state: &mut State, topic: &str) -> io::Result<HttpResponse> {
+    if !state.delete_topic(topic)? {
+        return Ok(HttpResponse::not_found());
+    }
+    info!(topic, "Topic deleted");
+    Ok(HttpResponse::no_content())
+}
+
+fn read_records(state: &State, topic: &str, partition: &str, req: &HttpRequest) -> HttpResponse {
+    #[derive(Serialize)]
+    struct RecordInfo {
+        offset: i64,
+        timestamp: i64,
+        key: Option<String>,
+        value: Option<String>,
+        headers: BTreeMap<String, Option<String>>,
+    }
+    #[derive(Serialize)]
+    struct Page {
+        records: Vec<RecordInfo>,
+        /// Offset to read the next page from
+        next_offset: i64,
+        log_end_offset: i64,
+    }
+
+    let log = match partition
+        .parse::<usize>()
+        .ok()
+        .and_then(|p| state.topics.get(topic).and_then(|t| t.partitions.get(p)))
+    {
+        Some(log) => log,
+        None => return HttpResponse::not_found(),
+    };
+    let offset = match req.param("offset").map(str::parse) {
+        None => log.log_start_offset(),
+        Some(Ok(offset)) => offset,
+        Some(Err(_)) => return HttpResponse::error(400, "invalid offset"),
+    };
+    let limit = match req.param("limit").map(str::parse::<usize>) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) => limit.clamp(1, MAX_LIMIT),
+        Some(Err(_)) => return HttpResponse::error(400, "invalid limit"),
+    };
+    // Keys, values and headers as UTF-8 (lossy) by default
+    let encode: fn(&[u8]) -> String = match req.param("encoding") {
+        None | Some("utf8") => |bytes| String::from_utf8_lossy(bytes).into_owned(),
+        Some("base64") => |bytes| BASE64.encode(bytes),
+        Some(_) => return HttpResponse::error(400, "encoding must be utf8 or base64"),
+    };
+
+    let records = log
+        .batches()
+        .filter(|b| b.last_offset >= offset)
+        .flat_map(|b| &b.records)
+        .filter(|r| r.offset >= offset)
+        .take(limit)
+        .map(|r| RecordInfo {
+            offset: r.offset,
+            timestamp: r.timestamp,
+            key: r.key.as_deref().map(encode),
+            value: r.value.as_deref().map(encode),
+            headers: r
+                .headers
+                .iter()
+                .map(|h| (h.key.clone(), h.value.as_deref().map(encode)))
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    let next_offset = match records.last() {
+        Some(last) if records.len() == limit => last.offset + 1,
+        // Read up to the end, past compacted offsets
+        _ => log.log_end_offset().max(offset),
+    };
+    HttpResponse::json(
+        200,
+        &Page {
+            records,
+            next_offset,
+            log_end_offset: log.log_end_offset(),
+        },
+    )
+}
+
+fn produce(state: &mut State, topic: &str, req: &HttpRequest) -> io::Result<HttpResponse> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Records {
+        records: Vec<FixtureRecord>,
+    }
+    #[derive(Serialize)]
+    struct Offset {
+        partition: u32,
+        offset: i64,
+    }
+    #[derive(Serialize)]
+    struct Produced {
+        offsets: Vec<Offset>,
+    }
+
+    let records = match serde_json::from_slice::<Records>(&req.body) {
+        Ok(records) => records.records,
+        Err(e) => return Ok(HttpResponse::error(422, format!("invalid records: {}", e))),
+    };
+    if !state.topics.contains_key(topic) && !state.config.auto_create_topics {
+        return Ok(HttpResponse::not_found());
+    }
+    let num_partitions = state.get_or_create_topic(topic)?.partitions.len();
+    if let Some(r) = records
+        .iter()
+        .find(|r| r.partition as usize >= num_partitions)
+    {
+        return Ok(HttpResponse::error(
+            422,
+            format!("no partition {}", r.partition),
+        ));
+    }
+    // Every record is checked before any is appended
+    let now = now_ms();
+    let batches = records
+        .iter()
+        .map(|r| (r.partition, r.to_batch(now)))
+        .collect::<Vec<_>>();
+    for (partition, batch) in &batches {
+        if let Err(e) = state.check_append(topic, *partition, batch, None) {
+            let status = if e.is_retriable() { 503 } else { 422 };
+            let message = format!("partition {}: {}", partition, e);
+            return Ok(HttpResponse::error(status, message));
+        }
+    }
+    let mut offsets = vec![];
+    for (partition, batch) in &batches {
+        offsets.push(Offset {
+            partition: *partition,
+            offset: state.append(topic, *partition, batch)?,
+        });
+    }
+    Ok(HttpResponse::json(200, &Produced { offsets }))
+}
+
+fn list_brokers(state: &State) -> HttpResponse {
+    #[derive(Serialize)]
+    struct BrokerInfo<'a> {
+        id: u32,
+        rack: Option<&'a str>,
+        online: bool,
+        endpoints: Vec<String>,
+    }
+
+    let brokers = state
+        .cluster
+        .nodes
+        .iter()
+        .map(|n| BrokerInfo {
+            id: n.id,
+            rack: n.rack.as_deref(),
+            online: n.online,
+            endpoints: n.endpoints.iter().map(|l| l.to_string()).collect(),
+        })
+        .collect::<Vec<_>>();
+    HttpResponse::json(200, &brokers)
+}
+
+fn set_broker_online(state: &mut State, id: &str, req: &HttpRequest) -> HttpResponse {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Broker {
+        online: bool,
+    }
+
+    let id = match id.parse() {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::not_found(),
+    };
+    let online = match serde_json::from_slice::<Broker>(&req.body) {
+        Ok(broker) => broker.online,
+        Err(e) => return HttpResponse::error(422, format!("invalid broker: {}", e)),
+    };
+    match state.set_broker_online(id, online) {
+        Ok(()) => {
+            info!(node_id = id, online, "Broker state changed");
+            list_brokers(state)
+        }
+        Err(_) => HttpResponse::not_found(),
+    }
+}
+
+// Change the leader, ISR or replicas of a partition, answering with the
+// partition as it is after the change
+fn control_partition(
+    state: &mut State,
+    method: &str,
+    topic: &str,
+    partition: &str,
+    action: &str,
+    req: &HttpRequest,
+) -> HttpResponse {
+    #[derive(Deserialize, Default)]
+    #[serde(default, deny_unknown_fields)]
+    struct Change {
+        leader: Option<u32>,
+        unclean: bool,
+        isr: Option<Vec<u32>>,
+        replicas: Option<Vec<u32>>,
+    }
+
+    let partition = match partition.parse::<u32>() {
+        Ok(p) if partition_info(state, topic, p).is_some() => p,
+        _ => return HttpResponse::not_found(),
+    };
+    let change = match req.body.as_slice() {
+        [] => Change::default(),
+        body => match serde_json::from_slice::<Change>(body) {
+            Ok(change) => change,
+            Err(e) => return HttpResponse::error(422, format!("invalid change: {}", e)),
+        },
+    };
+    let result = match (method, action, change) {
+        (
+            "POST",
+            "election",
+            Change {
+                leader: Some(leader),
+                ..
+            },
+        ) => state.elect_leader(topic, partition, leader),
+        ("POST", "election", Change { unclean: true, .. }) => {
+            state.elect_unclean_leader(topic, partition)
+        }
+        ("POST", "election", _) => state.elect_preferred_leader(topic, partition),
+        ("PUT", "isr", Change { isr: Some(isr), .. }) => state.set_isr(topic, partition, isr),
+        (
+            "PUT",
+            "replicas",
+            Change {
+                replicas: Some(replicas),
+                ..
+            },
+        ) => state.reassign_partition(topic, partition, replicas),
+        ("PUT", "isr", _) => return HttpResponse::error(422, "missing isr"),
+        ("PUT", "replicas", _) => return HttpResponse::error(422, "missing replicas"),
+        ("DELETE", "reassignment", _) => state.cancel_reassignment(topic, partition),
+        (_, "election", _) | (_, "isr", _) | (_, "replicas", _) | (_, "reassignment", _) => {
+            return HttpResponse::error(405, "method not allowed")
+        }
+        _ => return HttpResponse::not_found(),
+    };
+    match result {
+        Ok(assignment) => {
+            info!(topic, partition, ?assignment, "Partition changed");
+            let info = partition_info(state, topic, partition);
+            HttpResponse::json(200, &info)
+        }
+        Err(e) => HttpResponse::error(409, e.to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct GroupOffset {
+    topic: String,
+    partition: u32,
+    offset: i64,
+    /// None if the partition doesn't exist anymore
+    log_end_offset: Option<i64>,
+    lag: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct GroupInfo {
+    group: String,
+    /// Always empty, as groups are not coordinated by the broker
+    members: Vec<String>,
+    offsets: Vec<GroupOffset>,
+    /// Lag over every partition
+    lag: i64,
+}
+
+fn groups(state: &State, only: Option<&str>) -> Vec<GroupInfo> {
+    state
+        .offsets
+        .iter()
+        .filter(|(group, _)| only.is_none_or(|g| g == group.as_str()))
+        .map(|(group, offsets)| {
+            let offsets = offsets
+                .iter()
+                .map(|((topic, p), &offset)| {
+                    let end = state
+                        .topics
+                        .get(topic)
+                        .and_then(|t| t.partitions.get(*p as usize))
+                        .map(|log| log.log_end_offset());
+                    GroupOffset {
+                        topic: topic.clone(),
+                        partition: *p,
+                        offset,
+                        log_end_offset: end,
+                        lag: end.map(|end| (end - offset).max(0)),
+                    }
+                })
+                .collect::<Vec<_>>();
+            GroupInfo {
+                group: group.clone(),
+                members: vec![],
+                lag: offsets.iter().filter_map(|o| o.lag).sum(),
+                offsets,
+            }
+        })
+        .collect()
+}
+
+fn faults(state: &mut State, req: &HttpRequest) -> HttpResponse {
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        id: u64,
+        #[serde(flatten)]
+        fault: &'a Fault,
+    }
+    #[derive(Serialize)]
+    struct Created {
+        id: u64,
+    }
+
+    let segments = req.segments();
+    let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", [_]) => {
+            let faults = state
+                .faults
+                .list()
+                .map(|(id, fault)| Entry { id, fault })
+                .collect::<Vec<_>>();
+            HttpResponse::json(200, &faults)
+        }
+        ("POST", [_]) => match serde_json::from_slice::<Fault>(&req.body) {
+            Ok(fault) => {
+                let id = state.faults.add(fault);
+                info!(id, "Fault added");
+                HttpResponse::json(201, &Created { id })
+            }
+            Err(e) => HttpResponse::error(422, format!("invalid fault: {}", e)),
+        },
+        ("DELETE", [_]) => {
+            state.faults.clear();
+            HttpResponse::no_content()
+        }
+        ("DELETE", [_, id]) => match id.parse() {
+            Ok(id) if state.faults.remove(id) => HttpResponse::no_content(),
+            _ => HttpResponse::not_found(),
+        },
+        _ => HttpResponse::error(405, "method not allowed"),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrokerConfig;
+    use crate::snapshot;
+    use serde_json::{json, Value};
+
+    fn call(state: &SharedState, method: &str, target: &str, body: Value) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let req = HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|p| p.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: serde_json::to_vec(&body).unwrap(),
+            ..HttpRequest::default()
+        };
+        let resp = handle(state, &req);
+        let body = serde_json::from_slice(&resp.body).unwrap_or(Value::Null);
+        (resp.status, body)
+    }
+
+    #[test]
+    fn topics_and_records() {
+        let state = State::default().into_shared();
+        let topic = json!({"name": "orders", "partitions": 2, "configs": {"retention.ms": "1000"}});
+        let (status, created) = call(&state, "POST", "/topics", topic.clone());
+        assert_eq!(status, 201);
+        assert_eq!(created["configs"]["retention.ms"], "1000");
+        assert_eq!(call(&state, "POST", "/topics", topic).0, 409);
+        let topic = json!({"name": "../orders", "partitions": 1});
+        assert_eq!(call(&state, "POST", "/topics", topic).0, 422);
+
+        let records = json!({"records": [
+            {"key": "a", "value": "1"},
+            {"partition": 1, "value": "2", "headers": {"h": "v"}},
+            {"key": "a"},
+        ]});
+        let (status, produced) = call(&state, "POST", "/topics/orders/records", records);
+        assert_eq!(status, 200);
+        assert_eq!(
+            produced["offsets"],
+            json!([
+                {"partition": 0, "offset": 0},
+                {"partition": 1, "offset": 0},
+                {"partition": 0, "offset": 1},
+            ])
+        );
+        let records = json!({"records": [{"partition": 2, "value": "x"}]});
+        assert_eq!(
+            call(&state, "POST", "/topics/orders/records", records).0,
+            422
+        );
+
+        // Records are checked as by the broker, and none is appended if one fails
+        let topic = json!({"name": "users", "configs": {"cleanup.policy": "compact"}});
+        call(&state, "POST", "/topics", topic);
+        let records = json!({"records": [{"key": "a", "value": "1"}, {"value": "2"}]});
+        assert_eq!(
+            call(&state, "POST", "/topics/users/records", records).0,
+            422
+        );
+        assert_eq!(
+            call(&state, "GET", "/topics/users", Value::Null).1["partitions"][0]["log_end_offset"],
+            0
+        );
+
+        let (_, topics) = call(&state, "GET", "/topics", Value::Null);
+        assert_eq!(topics[0]["name"], "orders");
+        assert_eq!(topics[0]["partitions"][0]["log_end_offset"], 2);
+
+        let path = "/topics/orders/partitions/0/records?limit=1";
+        let (_, page) = call(&state, "GET", path, Value::Null);
+        assert_eq!(page["records"][0]["value"], "1");
+        assert_eq!(page["next_offset"], 1);
+        let path = "/topics/orders/partitions/0/records?offset=1&encoding=base64";
+        let (_, page) = call(&state, "GET", path, Value::Null);
+        assert_eq!(page["records"][0]["key"], "YQ==");
+        assert_eq!(page["records"][0]["value"], Value::Null);
+        assert_eq!(page["next_offset"], 2);
+        let path = "/topics/orders/partitions/1/records";
+        let (_, page) = call(&state, "GET", path, Value::Null);
+        assert_eq!(page["records"][0]["headers"], json!({"h": "v"}));
+        let path = "/topics/orders/partitions/2/records";
+        assert_eq!(call(&state, "GET", path, Value::Null).0, 404);
+
+        assert_eq!(call(&state, "DELETE", "/topics/orders", Value::Null).0, 204);
+        assert_eq!(call(&state, "GET", "/topics/orders", Value::Null).0, 404);
+        assert_eq!(call(&state, "DELETE", "/topics/orders", Value::Null).0, 404);
+    }
+
+    #[test]
+    fn control_the_cluster() {
+        let config = BrokerConfig {
+            brokers: 3,
+            default_replication_factor: 2,
+            ..BrokerConfig::default()
+        };
+        let state = State::new(config).unwrap().into_shared();
+        call(&state, "POST", "/topics", json!({"name": "orders"}));
+        let (_, partition) = call(&state, "GET", "/topics/orders", Value::Null);
+        let partition = &partition["partitions"][0];
+        let replicas = serde_json::from_value::<Vec<u32>>(partition["replicas"].clone()).unwrap();
+        let (leader, follower) = (replicas[0], replicas[1]);
+
+        let path = "/topics/orders/partitions/0/election";
+        let (status, elected) = call(&state, "POST", path, json!({"leader": follower}));
+        assert_eq!(status, 200);
+        assert_eq!(
+            (elected["leader"].clone(), elected["leader_epoch"].clone()),
+            (json!(follower), json!(1))
+        );
+        assert_eq!(
+            call(&state, "POST", path, json!({"leader": follower})).0,
+            409
+        );
+        assert_eq!(call(&state, "POST", path, json!({})).1["leader"], leader);
+
+        let path = "/topics/orders/partitions/0/isr";
+        assert_eq!(
+            call(&state, "PUT", path, json!({"isr": [leader]})).1["isr"],
+            json!([leader])
+        );
+        assert_eq!(call(&state, "PUT", path, json!({"isr": [follower]})).0, 409);
+
+        let broker = format!("/brokers/{}", leader);
+        let (_, brokers) = call(&state, "PUT", &broker, json!({"online": false}));
+        assert_eq!(
+            brokers
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|b| b["online"] == false)
+                .count(),
+            1
+        );
+        let (_, partition) = call(&state, "GET", "/topics/orders", Value::Null);
+        assert_eq!(partition["partitions"][0]["leader"], Value::Null);
+        let path = "/topics/orders/partitions/0/election";
+        assert_eq!(
+            call(&state, "POST", path, json!({"unclean": true})).1["leader"],
+            follower
+        );
+        call(&state, "PUT", &broker, json!({"online": true}));
+        assert_eq!(
+            call(&state, "PUT", "/brokers/1", json!({"online": true})).0,
+            404
+        );
+
+        let all = [1003, 1004, 1005];
+        let other = *all.iter().find(|id| !replicas.contains(id)).unwrap();
+        let path = "/topics/orders/partitions/0/replicas";
+        let (_, reassigned) = call(&state, "PUT", path, json!({"replicas": [other]}));
+        assert_eq!(reassigned["replicas"], json!([other]));
+        let path = "/topics/orders/partitions/0/reassignment";
+        assert_eq!(call(&state, "DELETE", path, json!({})).0, 409);
+        assert_eq!(
+            call(
+                &state,
+                "GET",
+                "/topics/orders/partitions/1/isr",
+                Value::Null
+            )
+            .0,
+            404
+        );
+    }
+
+    #[test]
+    fn groups_and_reset() {
+        let state = State::default().into_shared();
+        let records = json!({"records": [{"value": "1"}, {"value": "2"}, {"value": "3"}]});
+        call(&state, "POST", "/topics/orders/records", records);
+        state
+            .lock()
+            .unwrap()
+            .commit_offset("billing", "orders", 0, 1)
+            .unwrap();
+        let fault = json!({"api_key": 0, "latency_ms": 10});
+        assert_eq!(call(&state, "POST", "/faults", fault).0, 201);
+
+        let (status, group) = call(&state, "GET", "/groups/billing", Value::Null);
+        assert_eq!(status, 200);
+        assert_eq!(group["lag"], 2);
+        assert_eq!(
+            group["offsets"][0],
+            json!({"topic": "orders", "partition": 0, "offset": 1, "log_end_offset": 3, "lag": 2})
+        );
+        assert_eq!(call(&state, "GET", "/groups/other", Value::Null).0, 404);
+
+        let req = HttpRequest {
+            method: "GET".to_string(),
+            path: "/snapshot".to_string(),
+            ..HttpRequest::default()
+        };
+        let snapshot = handle(&state, &req);
+        assert_eq!(snapshot.content_type, "application/octet-stream");
+        assert_eq!(call(&state, "POST", "/reset", Value::Null).0, 204);
+        assert_eq!(
+            call(&state, "GET", "/topics", Value::Null),
+            (200, json!([]))
+        );
+        assert_eq!(
+            call(&state, "GET", "/groups", Value::Null),
+            (200, json!([]))
+        );
+        assert_eq!(
+            call(&state, "GET", "/faults", Value::Null),
+            (200, json!([]))
+        );
+        assert_eq!(call(&state, "PUT", "/reset", Value::Null).0, 405);
+
+        // The snapshot taken before the reset brings the state back
+        let mut state = state.lock().unwrap();
+        let recovered = snapshot::read(&mut &snapshot.body[..]).unwrap();
+        state.replace(recovered).unwrap();
+        assert_eq!(state.topics["orders"].partitions[0].log_end_offset(), 3);
+    }
+}
@@ -0,0 +1,633 @@
+use serde_json::{Map, Value};
+
+use std::collections::{HashMap, HashSet};
+
+/// An Avro type, as needed to check whether data written with a schema can be
+/// read with another one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record {
+        name: String,
+        aliases: Vec<String>,
+        fields: Vec<Field>,
+    },
+    Enum {
+        name: String,
+        aliases: Vec<String>,
+        symbols: Vec<String>,
+        default: Option<String>,
+    },
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Fixed {
+        name: String,
+        aliases: Vec<String>,
+        size: u64,
+    },
+    Union(Vec<Schema>),
+    /// Named type, by full name, defined in the schema or in a referenced one
+    Named(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub schema: Schema,
+    pub has_default: bool,
+}
+
+impl Schema {
+    fn kind(&self) -> &str {
+        match self {
+            Schema::Null => "null",
+            Schema::Boolean => "boolean",
+            Schema::Int => "int",
+            Schema::Long => "long",
+            Schema::Float => "float",
+            Schema::Double => "double",
+            Schema::Bytes => "bytes",
+            Schema::String => "string",
+            Schema::Record { .. } => "record",
+            Schema::Enum { .. } => "enum",
+            Schema::Array(_) => "array",
+            Schema::Map(_) => "map",
+            Schema::Fixed { .. } => "fixed",
+            Schema::Union(_) => "union",
+            Schema::Named(name) => name,
+        }
+    }
+}
+
+/// A parsed schema, with the named types it defines
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedSchema {
+    pub root: Schema,
+    /// Records, enums and fixed types, by full name
+    pub names: HashMap<String, Schema>,
+}
+
+/// Parse a schema given as JSON
+///
+/// * `schema` - schema
+/// * `external` - whether types may be defined by referenced schemas, in which
+///   case unknown names are taken as such
+pub fn parse(schema: &str, external: bool) -> Result<ParsedSchema, String> {
+    let value = serde_json::from_str::<Value>(schema).map_err(|e| e.to_string())?;
+    let mut parser = Parser {
+        names: HashMap::new(),
+        external,
+    };
+    let root = parser.parse(&value, "")?;
+    Ok(ParsedSchema {
+        root,
+        names: parser.names,
+    })
+}
+
+struct Parser {
+    names: HashMap<String, Schema>,
+    external: bool,
+}
+
+impl Parser {
+    fn parse(&mut self, value: &Value, namespace: &str) -> Result<Schema, String> {
+        match value {
+            Value::String(name) => self.parse_name(name, namespace),
+            Value::Array(branches) => {
+                let branches = branches
+                    .iter()
+                    .map(|b| self.parse(b, namespace))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut kinds = HashSet::new();
+                for branch in &branches {
+                    if let Schema::Union(_) = branch {
+                        return Err("unions can't be nested".to_string());
+                    }
+                    if !kinds.insert(branch.kind().to_string()) {
+                        return Err(format!("{} is twice in a union", branch.kind()));
+                    }
+                }
+                Ok(Schema::Union(branches))
+            }
+            Value::Object(object) => self.parse_object(object, namespace),
+            _ => Err(format!("invalid type {}", value)),
+        }
+    }
+
+    fn parse_name(&self, name: &str, namespace: &str) -> Result<Schema, String> {
+        if let Some(schema) = primitive(name) {
+            return Ok(schema);
+        }
+        let full = full_name(name, namespace);
+        if self.names.contains_key(&full) || self.external {
+            Ok(Schema::Named(full))
+        } else if self.names.contains_key(name) {
+            Ok(Schema::Named(name.to_string()))
+        } else {
+            Err(format!("unknown type {}", name))
+        }
+    }
+
+    fn parse_object(
+        &mut self,
+        object: &Map<String, Value>,
+        namespace: &str,
+    ) -> Result<Schema, String> {
+        let kind = match object.get("type") {
+            Some(Value::String(kind)) => kind.as_str(),
+            // Only annotated, as in {"type": {"type": "int"}}
+            Some(value) => return self.parse(value, namespace),
+            None => return Err("missing type".to_string()),
+        };
+        match kind {
+            "record" | "error" | "enum" | "fixed" => (),
+            "array" => {
+                let items = object.get("items").ok_or("missing array items")?;
+                return Ok(Schema::Array(Box::new(self.parse(items, namespace)?)));
+            }
+            "map" => {
+                let values = object.get("values").ok_or("missing map values")?;
+                return Ok(Schema::Map(Box::new(self.parse(values, namespace)?)));
+            }
+            // Primitives, possibly with a logical type
+            kind => return self.parse_name(kind, namespace),
+        }
+
+        let name = object
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("{} without a name", kind))?;
+        let namespace = match object.get("namespace").and_then(Value::as_str) {
+            Some(namespace) if !name.contains('.') => namespace,
+            _ => namespace,
+        };
+        let full = full_name(name, namespace);
+        if !full.split('.').all(is_valid_name) {
+            return Err(format!("invalid name {}", full));
+        }
+        if self.names.contains_key(&full) {
+            return Err(format!("{} is defined twice", full));
+        }
+        let namespace = full.rsplit_once('.').map_or("", |(ns, _)| ns).to_string();
+        let name = full.rsplit('.').next().unwrap_or_default().to_string();
+        let aliases = strings(object.get("aliases"), "aliases")?;
+
+        let schema = match kind {
+            "enum" => {
+                let symbols = strings(object.get("symbols"), "symbols")?;
+                if let Some(s) = symbols.iter().find(|s| !is_valid_name(s)) {
+                    return Err(format!("invalid symbol {}", s));
+                }
+                let default = object
+                    .get("default")
+                    .and_then(Value::as_str)
+                    .map(String::from);
+                if default.as_ref().is_some_and(|d| !symbols.contains(d)) {
+                    return Err(format!("default of {} is not a symbol", full));
+                }
+                Schema::Enum {
+                    name,
+                    aliases,
+                    symbols,
+                    default,
+                }
+            }
+            "fixed" => Schema::Fixed {
+                name,
+                aliases,
+                size: object
+                    .get("size")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| format!("fixed {} without a size", full))?,
+            },
+            _ => {
+                // Defined before its fields, which may refer to it
+                self.names.insert(full.clone(), Schema::Named(full.clone()));
+                let fields = match object.get("fields") {
+                    Some(Value::Array(fields)) => fields,
+                    _ => return Err(format!("record {} without fields", full)),
+                };
+                let mut parsed = Vec::with_capacity(fields.len());
+                for field in fields {
+                    let field_name = field
+                        .get("name")
+                        .and_then(Value::as_str)
+                        .filter(|n| is_valid_name(n))
+                        .ok_or_else(|| format!("field of {} without a valid name", full))?;
+                    if parsed.iter().any(|f: &Field| f.name == field_name) {
+                        return Err(format!("field {} of {} is defined twice", field_name, full));
+                    }
+                    let schema = field.get("type").ok_or_else(|| {
+                        format!("field {} of {} without a type", field_name, full)
+                    })?;
+                    parsed.push(Field {
+                        name: field_name.to_string(),
+                        aliases: strings(field.get("aliases"), "aliases")?,
+                        schema: self.parse(schema, &namespace)?,
+                        has_default: field.get("default").is_some(),
+                    });
+                }
+                Schema::Record {
+                    name,
+                    aliases,
+                    fields: parsed,
+                }
+            }
+        };
+        self.names.insert(full.clone(), schema);
+        Ok(Schema::Named(full))
+    }
+}
+
+fn primitive(name: &str) -> Option<Schema> {
+    match name {
+        "null" => Some(Schema::Null),
+        "boolean" => Some(Schema::Boolean),
+        "int" => Some(Schema::Int),
+        "long" => Some(Schema::Long),
+        "float" => Some(Schema::Float),
+        "double" => Some(Schema::Double),
+        "bytes" => Some(Schema::Bytes),
+        "string" => Some(Schema::String),
+        _ => None,
+    }
+}
+
+fn full_name(name: &str, namespace: &str) -> String {
+    if name.contains('.') || namespace.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", namespace, name)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn strings(value: Option<&Value>, what: &str) -> Result<Vec<String>, String> {
+    match value {
+        None => Ok(vec![]),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|v| v.as_str().map(String::from))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("{} must be strings", what)),
+        Some(_) => Err(format!("{} must be an array", what)),
+    }
+}
+
+/// Check that data written with a schema can be read with another, following
+/// the schema resolution rules of the Avro specification
+/// Returns the first reason it can't.
+///
+/// * `reader` - schema the data is read with
+/// * `writer` - schema the data was written with
+pub fn check_readable(reader: &ParsedSchema, writer: &ParsedSchema) -> Result<(), String> {
+    Checker {
+        reader,
+        writer,
+        seen: HashSet::new(),
+    }
+    .check(&reader.root, &writer.root, "")
+}
+
+struct Checker<'a> {
+    reader: &'a ParsedSchema,
+    writer: &'a ParsedSchema,
+    /// Named types already compared, as recursive types refer to themselves
+    seen: HashSet<(String, String)>,
+}
+
+impl Checker<'_> {
+    fn check(&mut self, reader: &Schema, writer: &Schema, path: &str) -> Result<(), String> {
+        if let (Schema::Named(r), Schema::Named(w)) = (reader, writer) {
+            if !self.seen.insert((r.clone(), w.clone())) {
+                return Ok(());
+            }
+        }
+        // Named types are compared as such across unions, for the check above
+        let (unresolved_reader, unresolved_writer) = (reader, writer);
+        let reader = resolve(self.reader, reader);
+        let writer = resolve(self.writer, writer);
+        let at = if path.is_empty() { "/" } else { path };
+        match (reader, writer) {
+            (_, Schema::Union(branches)) => {
+                for branch in branches {
+                    self.check(unresolved_reader, branch, path)?;
+                }
+                Ok(())
+            }
+            (Schema::Union(branches), _) => {
+                for branch in branches {
+                    let seen = self.seen.clone();
+                    if self.check(branch, unresolved_writer, path).is_ok() {
+                        return Ok(());
+                    }
+                    self.seen = seen;
+                }
+                Err(format!(
+                    "{}: {} is not in the reader's union",
+                    at,
+                    unresolved_writer.kind()
+                ))
+            }
+            (Schema::Null, Schema::Null)
+            | (Schema::Boolean, Schema::Boolean)
+            | (Schema::Int, Schema::Int)
+            | (Schema::Long, Schema::Int | Schema::Long)
+            | (Schema::Float, Schema::Int | Schema::Long | Schema::Float)
+            | (Schema::Double, Schema::Int | Schema::Long | Schema::Float | Schema::Double)
+            | (Schema::Bytes | Schema::String, Schema::Bytes | Schema::String) => Ok(()),
+            (
+                Schema::Record {
+                    name,
+                    aliases,
+                    fields,
+                },
+                Schema::Record {
+                    name: writer_name,
+                    fields: writer_fields,
+                    ..
+                },
+            ) => {
+                check_name(name, aliases, writer_name, at)?;
+                for field in fields {
+                    let field_path = format!("{}/{}", path, field.name);
+                    let written = writer_fields
+                        .iter()
+                        .find(|w| w.name == field.name || field.aliases.contains(&w.name));
+                    match written {
+                        Some(written) => self.check(&field.schema, &written.schema, &field_path)?,
+                        None if field.has_default => (),
+                        None => {
+                            return Err(format!(
+                                "{}: the reader's field has no default and is not written",
+                                field_path
+                            ))
+                        }
+                    }
+                }
+                Ok(())
+            }
+            (
+                Schema::Enum {
+                    name,
+                    aliases,
+                    symbols,
+                    default,
+                },
+                Schema::Enum {
+                    name: writer_name,
+                    symbols: writer_symbols,
+                    ..
+                },
+            ) => {
+                check_name(name, aliases, writer_name, at)?;
+                match writer_symbols.iter().find(|s| !symbols.contains(s)) {
+                    Some(s) if default.is_none() => {
+                        Err(format!("{}: the reader's enum has no symbol {}", at, s))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            (Schema::Array(reader), Schema::Array(writer)) => {
+                self.check(reader, writer, &format!("{}/items", path))
+            }
+            (Schema::Map(reader), Schema::Map(writer)) => {
+                self.check(reader, writer, &format!("{}/values", path))
+            }
+            (
+                Schema::Fixed {
+                    name,
+                    aliases,
+                    size,
+                },
+                Schema::Fixed {
+                    name: writer_name,
+                    size: writer_size,
+                    ..
+                },
+            ) => {
+                check_name(name, aliases, writer_name, at)?;
+                if size != writer_size {
+                    return Err(format!(
+                        "{}: fixed size {} read as {}",
+                        at, writer_size, size
+                    ));
+                }
+                Ok(())
+            }
+            // Defined by referenced schemas
+            (Schema::Named(reader), Schema::Named(writer)) if reader == writer => Ok(()),
+            (reader, writer) => Err(format!(
+                "{}: {} can't be read as {}",
+                at,
+                writer.kind(),
+                reader.kind()
+            )),
+        }
+    }
+}
+
+// The definition of a named type
+fn resolve<'a>(schema: &'a ParsedSchema, s: &'a Schema) -> &'a Schema {
+    match s {
+        Schema::Named(name) => schema.names.get(name).unwrap_or(s),
+        s => s,
+    }
+}
+
+fn check_name(name: &str, aliases: &[String], writer: &str, at: &str) -> Result<(), String> {
+    let unqualified = |n: &str| n.rsplit('.').next().unwrap_or_default().to_string();
+    let writer = unqualified(writer);
+    if unqualified(name) == writer || aliases.iter().any(|a| unqualified(a) == writer) {
+        Ok(())
+    } else {
+        Err(format!("{}: {} is read as {}", at, writer, name))
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readable(reader: &str, writer: &str) -> Result<(), String> {
+        check_readable(
+            &parse(reader, false).unwrap(),
+            &parse(writer, false).unwrap(),
+        )
+    }
+
+    #[test]
+    fn parse_schemas() {
+        let schema = parse(
+            r#"{"type": "record", "name": "Node", "namespace": "a.b", "fields": [
+                {"name": "value", "type": {"type": "string", "logicalType": "uuid"}},
+                {"name": "next", "type": ["null", "Node"], "default": null},
+                {"name": "kind", "type": {"type": "enum", "name": "Kind", "symbols": ["A", "B"]}}
+            ]}"#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(schema.root, Schema::Named("a.b.Node".to_string()));
+        assert!(schema.names.contains_key("a.b.Kind"));
+        assert_eq!(parse("\"long\"", false).unwrap().root, Schema::Long);
+
+        assert!(parse(r#"{"type": "record", "fields": []}"#, false).is_err());
+        assert!(parse(r#"["int", "int"]"#, false).is_err());
+        assert!(parse(r#""Unknown""#, false).is_err());
+        assert!(parse(r#""com.example.Other""#, true).is_ok());
+        assert!(parse("{", false).is_err());
+    }
+
+    #[test]
+    fn resolve_schemas() {
+        let v1 = r#"{"type": "record", "name": "User", "fields": [{"name": "id", "type": "int"}]}"#;
+        let with_default = r#"{"type": "record", "name": "User", "fields": [
+            {"name": "id", "type": "long"},
+            {"name": "email", "type": ["null", "string"], "default": null}
+        ]}"#;
+        let without_default = r#"{"type": "record", "name": "User", "fields": [
+            {"name": "id", "type": "int"},
+            {"name": "email", "type": "string"}
+        ]}"#;
+        assert_eq!(readable(with_default, v1), Ok(()));
+        assert_eq!(
+            readable(v1, with_default),
+            Err("/id: long can't be read as int".to_string())
+        );
+        assert_eq!(
+            readable(without_default, v1),
+            Err("/email: the reader's field has no default and is not written".to_string())
+        );
+        assert_eq!(readable(v1, without_default), Ok(()));
+
+        let enum_ab = r#"{"type": "enum", "name": "E", "symbols": ["A", "B"]}"#;
+        let enum_abc = r#"{"type": "enum", "name": "E", "symbols": ["A", "B", "C"]}"#;
+        assert_eq!(readable(enum_abc, enum_ab), Ok(()));
+        assert!(readable(enum_ab, enum_abc).is_err());
+        assert!(readable(r#"["null", "string"]"#, r#""bytes""#).is_ok());
+        assert!(readable(r#""string""#, r#"["null", "string"]"#).is_err());
+        assert!(readable(
+            r#"{"type": "fixed", "name": "F", "size": 4}"#,
+            r#"{"type": "fixed", "name": "F", "size": 8}"#
+        )
+        .is_err());
+
+        let list = r#"{"type": "record", "name": "List", "fields": [
+            {"name": "next", "type": ["null", "List"], "default": null}
+        ]}"#;
+        assert_eq!(readable(list, list), Ok(()));
+    }
+
+    #[test]
+    fn promote_numbers() {
+        let promotions = [
+            ("int", &["int", "long", "float", "double"][..]),
+            ("long", &["long", "float", "double"]),
+            ("float", &["float", "double"]),
+            ("double", &["double"]),
+            ("string", &["string", "bytes"]),
+            ("bytes", &["bytes", "string"]),
+        ];
+        let types = ["int", "long", "float", "double", "string", "bytes"];
+        for (writer, readers) in &promotions {
+            for reader in &types {
+                let result = readable(&format!("\"{}\"", reader), &format!("\"{}\"", writer));
+                assert_eq!(
+                    result.is_ok(),
+                    readers.contains(reader),
+                    "{} as {}",
+                    writer,
+                    reader
+                );
+            }
+        }
+        assert_eq!(
+            readable(r#""float""#, r#""double""#),
+            Err("/: double can't be read as float".to_string())
+        );
+        // Also within arrays, maps and unions
+        assert!(readable(
+            r#"{"type": "array", "items": "double"}"#,
+            r#"{"type": "array", "items": "int"}"#
+        )
+        .is_ok());
+        assert_eq!(
+            readable(
+                r#"{"type": "map", "values": "int"}"#,
+                r#"{"type": "map", "values": "long"}"#
+            ),
+            Err("/values: long can't be read as int".to_string())
+        );
+        assert!(readable(r#"["null", "double"]"#, r#""long""#).is_ok());
+    }
+
+    #[test]
+    fn resolve_unions() {
+        // Every branch the writer may have written must be readable
+        assert!(readable(r#"["null", "long"]"#, r#"["null", "int"]"#).is_ok());
+        assert!(readable(r#"["string", "null", "long"]"#, r#"["null", "int"]"#).is_ok());
+        assert_eq!(
+            readable(r#"["null", "long"]"#, r#"["null", "int", "string"]"#),
+            Err("/: string is not in the reader's union".to_string())
+        );
+        // A writer's union read as a single type
+        assert!(readable(r#""long""#, r#"["int", "long"]"#).is_ok());
+        assert_eq!(
+            readable(r#""long""#, r#"["null", "int"]"#),
+            Err("/: null can't be read as long".to_string())
+        );
+        // Records are matched by name among the branches
+        let a = r#"{"type": "record", "name": "A", "fields": []}"#;
+        let b = r#"{"type": "record", "name": "B", "fields": []}"#;
+        assert!(readable(&format!("[{}, {}]", a, b), b).is_ok());
+        assert_eq!(
+            readable(&format!("[\"null\", {}]", a), b),
+            Err("/: B is not in the reader's union".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_enums() {
+        let enum_ab = r#"{"type": "enum", "name": "E", "symbols": ["A", "B"]}"#;
+        let enum_abc = r#"{"type": "enum", "name": "E", "symbols": ["A", "B", "C"]}"#;
+        assert_eq!(
+            readable(enum_ab, enum_abc),
+            Err("/: the reader's enum has no symbol C".to_string())
+        );
+        // Unknown symbols are read as the default
+        let with_default =
+            r#"{"type": "enum", "name": "E", "symbols": ["A", "B"], "default": "A"}"#;
+        assert_eq!(readable(with_default, enum_abc), Ok(()));
+        // Names must match, unless the reader has an alias for the writer's
+        let renamed = r#"{"type": "enum", "name": "F", "symbols": ["A", "B"]}"#;
+        assert_eq!(
+            readable(renamed, enum_ab),
+            Err("/: E is read as F".to_string())
+        );
+        let aliased = r#"{"type": "enum", "name": "F", "aliases": ["E"], "symbols": ["A", "B"]}"#;
+        assert_eq!(readable(aliased, enum_ab), Ok(()));
+        assert!(parse(
+            r#"{"type": "enum", "name": "E", "symbols": ["A"], "default": "B"}"#,
+            false
+        )
+        .is_err());
+    }
+}
@@ -1,16 +1,496 @@
+use tracing::error;
+
+use crate::cluster;
+use crate::error::ErrorCode;
+use crate::faults::Injected;
+use crate::log::PartitionLog;
 use crate::messages::*;
+use crate::ser::Serialize;
+use crate::state::{SharedState, State, Topic};
+
+use std::io;
+
+/// Where a request came in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    /// Name of the listener
+    pub listener: String,
+    /// Broker the client is connected to
+    pub node_id: u32,
+}
 
-pub fn process(req: &Request) -> Option<Response> {
+/// Process a request
+///
+/// * `state` - broker state
+/// * `req` - request
+/// * `ctx` - listener and broker the request came in on
+/// * `faults` - errors to answer with instead of processing
+pub fn process(state: &SharedState, req: &Request, ctx: &Context, faults: &Injected) -> Response {
     match &req {
-        Request::ApiVersionsRequest(req) => Some(Response::ApiVersionsResponse(
-            ApiVersionsResponse::new(&req),
-        )),
+        Request::ApiVersionsRequest(req) => {
+            let mut resp = ApiVersionsResponse::new(req);
+            if let Some(e) = faults.error(None, None) {
+                resp.error_code = e.value();
+            }
+            Response::ApiVersionsResponse(resp)
+        }
         Request::MetadataRequest(req) => {
-            Some(Response::MetadataResponse(MetadataResponse::new(&req)))
+            let mut state = state.lock().unwrap();
+            Response::MetadataResponse(metadata(&mut state, req, &ctx.listener, faults))
         }
         Request::ProduceRequest(req) => {
-            // TODO: store message production and deliver to subscribers
-            Some(Response::ProduceResponse(ProduceResponse::new(&req)))
+            let topics = produce(&mut state.lock().unwrap(), req, ctx.node_id, faults);
+            Response::ProduceResponse(ProduceResponse::new(req, topics))
+        }
+        Request::FetchRequest(req) => {
+            let topics = fetch(&state.lock().unwrap(), req, ctx.node_id, faults);
+            Response::FetchResponse(FetchResponse::new(req, topics))
+        }
+        Request::OffsetForLeaderEpochRequest(req) => {
+            let state = state.lock().unwrap();
+            let topics = offset_for_leader_epoch(&state, req, ctx.node_id, faults);
+            Response::OffsetForLeaderEpochResponse(OffsetForLeaderEpochResponse::new(req, topics))
+        }
+        Request::ElectLeadersRequest(req) => {
+            let mut state = state.lock().unwrap();
+            let (error, topics) = match check_controller(&state, ctx.node_id, faults) {
+                Ok(()) => (ErrorCode::NoError, elect_leaders(&mut state, req, faults)),
+                Err(e) => (e, vec![]),
+            };
+            Response::ElectLeadersResponse(ElectLeadersResponse::new(req, error, topics))
+        }
+        Request::AlterPartitionReassignmentsRequest(req) => {
+            let mut state = state.lock().unwrap();
+            let (error, topics) = match check_controller(&state, ctx.node_id, faults) {
+                Ok(()) => (
+                    ErrorCode::NoError,
+                    alter_reassignments(&mut state, req, faults),
+                ),
+                Err(e) => (e, vec![]),
+            };
+            Response::AlterPartitionReassignmentsResponse(AlterPartitionReassignmentsResponse::new(
+                req, error, topics,
+            ))
+        }
+        Request::ListPartitionReassignmentsRequest(req) => {
+            let state = state.lock().unwrap();
+            let (error, topics) = match check_controller(&state, ctx.node_id, faults) {
+                Ok(()) => (ErrorCode::NoError, list_reassignments(&state, req)),
+                Err(e) => (e, vec![]),
+            };
+            Response::ListPartitionReassignmentsResponse(ListPartitionReassignmentsResponse::new(
+                req, error, topics,
+            ))
+        }
+    }
+}
+
+// Describe the brokers and the requested topics, creating them if allowed
+// Brokers are advertised with their address on the listener the request came in on.
+fn metadata(
+    state: &mut State,
+    req: &MetadataRequest,
+    listener: &str,
+    faults: &Injected,
+) -> MetadataResponse {
+    let auto_create = req.allow_auto_topic_creation && state.config.auto_create_topics;
+    let topics = req
+        .topics
+        .iter()
+        .map(|name| match faults.error(Some(name), None) {
+            Some(e) => TopicMetadata {
+                error: e.value(),
+                ..TopicMetadata::unknown(name.clone())
+            },
+            None => topic_metadata(state, name, auto_create, faults),
+        })
+        .collect();
+    let brokers = state
+        .cluster
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| node.online)
+        .map(|(i, node)| {
+            let (host, port) = match node.endpoint(listener) {
+                Some(l) => (l.host.clone(), l.port),
+                None => ("localhost".to_string(), 9092 + i as u16),
+            };
+            BrokerMetadata {
+                node_id: node.id,
+                host,
+                port: port as u32,
+                rack: node.rack.clone(),
+            }
+        })
+        .collect();
+    MetadataResponse::new(req, brokers, &state.config.cluster_id, topics)
+}
+
+fn topic_metadata(
+    state: &mut State,
+    name: &str,
+    auto_create: bool,
+    faults: &Injected,
+) -> TopicMetadata {
+    match find_topic(state, name, auto_create) {
+        Ok(Some(topic)) => {
+            let partitions = (0..topic.partitions.len() as u32)
+                .map(|p| partition_metadata(state, name, p, faults))
+                .collect();
+            TopicMetadata::new(name.to_string(), partitions)
+        }
+        Ok(None) => TopicMetadata::unknown(name.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => TopicMetadata {
+            error: ErrorCode::InvalidTopicException.value(),
+            ..TopicMetadata::unknown(name.to_string())
+        },
+        Err(e) => {
+            error!("Error creating topic {}: {}", name, e);
+            TopicMetadata::unknown(name.to_string())
+        }
+    }
+}
+
+fn partition_metadata(
+    state: &State,
+    topic: &str,
+    partition: u32,
+    faults: &Injected,
+) -> PartitionMetadata {
+    let assignment = state.cluster.assignment(topic, partition);
+    let error = match (faults.check(topic, partition), assignment.leader) {
+        (Err(e), _) => e,
+        (Ok(()), Some(_)) => ErrorCode::NoError,
+        (Ok(()), None) => ErrorCode::LeaderNotAvailable,
+    };
+    PartitionMetadata {
+        error: error.value(),
+        id: partition,
+        // -1 without a leader
+        leader_id: assignment.leader.unwrap_or(u32::MAX),
+        leader_epoch: assignment.leader_epoch,
+        offline_replicas: state.cluster.offline_replicas(&assignment),
+        replicas: assignment.replicas,
+        caught_up_replicas: assignment.isr,
+    }
+}
+
+// Admin requests are only handled by the controller, unless a fault fails them
+fn check_controller(state: &State, node_id: u32, faults: &Injected) -> Result<(), ErrorCode> {
+    if let Some(e) = faults.error(None, None) {
+        Err(e)
+    } else if state.cluster.controller() == Some(node_id) {
+        Ok(())
+    } else {
+        Err(ErrorCode::NotController)
+    }
+}
+
+// Elect the leaders of the requested partitions, or of every partition
+// Partitions that need no election are left out when every partition is requested.
+fn elect_leaders(
+    state: &mut State,
+    req: &ElectLeadersRequest,
+    faults: &Injected,
+) -> Vec<TopicResults> {
+    let partitions = match &req.topics {
+        Some(topics) => topics
+            .iter()
+            .flat_map(|t| t.partitions.iter().map(move |p| (t.name.clone(), *p)))
+            .collect(),
+        None => cluster::partitions(state),
+    };
+    let mut results: Vec<TopicResults> = vec![];
+    for (topic, partition) in partitions {
+        let result = faults.check(&topic, partition).and_then(|()| {
+            match req.election_type {
+                ElectionType::Preferred => state.elect_preferred_leader(&topic, partition),
+                ElectionType::Unclean => state.elect_unclean_leader(&topic, partition),
+            }
+            .map(|_| ())
+        });
+        if req.topics.is_none() && result == Err(ErrorCode::ElectionNotNeeded) {
+            continue;
+        }
+        let partition = PartitionResult::new(partition, result);
+        match results.last_mut() {
+            Some(t) if t.name == topic => t.partitions.push(partition),
+            _ => results.push(TopicResults {
+                name: topic,
+                partitions: vec![partition],
+            }),
+        }
+    }
+    results
+}
+
+// Start or cancel the reassignment of the requested partitions
+fn alter_reassignments(
+    state: &mut State,
+    req: &AlterPartitionReassignmentsRequest,
+    faults: &Injected,
+) -> Vec<TopicResults> {
+    req.topics
+        .iter()
+        .map(|t| TopicResults {
+            name: t.name.clone(),
+            partitions: t
+                .partitions
+                .iter()
+                .map(|p| {
+                    let result = faults.check(&t.name, p.id).and_then(|()| {
+                        match &p.replicas {
+                            Some(replicas) => {
+                                state.reassign_partition(&t.name, p.id, replicas.clone())
+                            }
+                            None => state.cancel_reassignment(&t.name, p.id),
+                        }
+                        .map(|_| ())
+                    });
+                    PartitionResult::new(p.id, result)
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+// Describe the ongoing reassignments of the requested partitions, or of every partition
+fn list_reassignments(
+    state: &State,
+    req: &ListPartitionReassignmentsRequest,
+) -> Vec<OngoingTopicReassignment> {
+    let requested = |topic: &str, partition: u32| match &req.topics {
+        Some(topics) => topics
+            .iter()
+            .any(|t| t.name == topic && t.partitions.contains(&partition)),
+        None => true,
+    };
+    let mut topics: Vec<OngoingTopicReassignment> = vec![];
+    for ((topic, partition), a) in state.cluster.reassignments() {
+        if !requested(topic, *partition) {
+            continue;
+        }
+        let partition = OngoingPartitionReassignment {
+            id: *partition,
+            replicas: a.replicas.clone(),
+            adding_replicas: a.adding.clone(),
+            removing_replicas: a.removing.clone(),
+        };
+        match topics.last_mut() {
+            Some(t) if &t.name == topic => t.partitions.push(partition),
+            _ => topics.push(OngoingTopicReassignment {
+                name: topic.clone(),
+                partitions: vec![partition],
+            }),
+        }
+    }
+    topics
+}
+
+// Get a topic, creating it with the default configuration if allowed
+fn find_topic<'a>(
+    state: &'a mut State,
+    name: &str,
+    auto_create: bool,
+) -> io::Result<Option<&'a mut Topic>> {
+    if auto_create {
+        state.get_or_create_topic(name).map(Some)
+    } else {
+        Ok(state.topics.get_mut(name))
+    }
+}
+
+// Append the produced batches to the partition logs
+// Only partitions led by the broker the request was sent to are accepted.
+fn produce(
+    state: &mut State,
+    req: &ProduceRequest,
+    node_id: u32,
+    faults: &Injected,
+) -> Vec<ProduceTopicResponse> {
+    req.topics
+        .iter()
+        .map(|t| ProduceTopicResponse {
+            name: t.name.clone(),
+            partitions: t
+                .partitions
+                .iter()
+                .map(|p| produce_partition(state, &t.name, p, node_id, faults))
+                .collect(),
+        })
+        .collect()
+}
+
+fn produce_partition(
+    state: &mut State,
+    topic: &str,
+    req: &ProducePartitionRequest,
+    node_id: u32,
+    faults: &Injected,
+) -> ProducePartitionResponse {
+    let error = |error: ErrorCode| ProducePartitionResponse {
+        id: req.id,
+        error: error.value(),
+        base_offset: -1,
+        log_append_time: -1,
+        log_start_offset: -1,
+    };
+    if let Err(e) = faults.check(topic, req.id) {
+        return error(e);
+    }
+    let auto_create = state.config.auto_create_topics;
+    match find_topic(state, topic, auto_create) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            return error(ErrorCode::InvalidTopicException)
         }
+        Err(_) => return error(ErrorCode::KafkaStorageError),
+    }
+    // As of v3, a partition holds exactly one batch
+    let batch = match &req.batches[..] {
+        [batch] => batch,
+        _ => return error(ErrorCode::InvalidRecord),
+    };
+    if let Err(e) = state.check_append(topic, req.id, batch, Some(node_id)) {
+        return error(e);
+    }
+    match state.append(topic, req.id, batch) {
+        Ok(base_offset) => ProducePartitionResponse {
+            id: req.id,
+            error: ErrorCode::NoError.value(),
+            base_offset,
+            log_append_time: -1,
+            log_start_offset: state.topics[topic].partitions[req.id as usize].log_start_offset(),
+        },
+        Err(_) => error(ErrorCode::KafkaStorageError),
     }
 }
+
+// Get the log of a partition led by this broker, checking the leader epoch
+// known to the client, as in Fetch and OffsetForLeaderEpoch requests. Returns
+// the current leader epoch along with the log.
+fn leader_log<'a>(
+    state: &'a State,
+    topic: &str,
+    partition: u32,
+    current_leader_epoch: i32,
+    node_id: u32,
+) -> Result<(&'a PartitionLog, i32), ErrorCode> {
+    let log = state
+        .topics
+        .get(topic)
+        .and_then(|t| t.partitions.get(partition as usize))
+        .ok_or(ErrorCode::UnknownTopicOrPartition)?;
+    let assignment = state.cluster.assignment(topic, partition);
+    let leader_epoch = assignment.leader_epoch as i32;
+    // -1 is a client that doesn't know the epoch
+    if current_leader_epoch >= 0 && current_leader_epoch < leader_epoch {
+        return Err(ErrorCode::FencedLeaderEpoch);
+    }
+    if current_leader_epoch > leader_epoch {
+        return Err(ErrorCode::UnknownLeaderEpoch);
+    }
+    if assignment.leader != Some(node_id) {
+        return Err(ErrorCode::NotLeaderOrFollower);
+    }
+    Ok((log, leader_epoch))
+}
+
+// Read the batches from the requested offsets, up to the size limits
+// Partitions get at least a batch while the response is empty, even a larger
+// one, so that consumers always make progress.
+fn fetch(
+    state: &State,
+    req: &FetchRequest,
+    node_id: u32,
+    faults: &Injected,
+) -> Vec<FetchTopicResponse> {
+    let mut remaining = req.max_bytes as usize;
+    req.topics
+        .iter()
+        .map(|t| FetchTopicResponse {
+            name: t.name.clone(),
+            partitions: t
+                .partitions
+                .iter()
+                .map(|p| {
+                    let empty = remaining == req.max_bytes as usize;
+                    let max_bytes = remaining.min(p.partition_max_bytes as usize);
+                    let partition = match faults.check(&t.name, p.id) {
+                        Ok(()) => fetch_partition(state, &t.name, p, node_id, max_bytes, empty),
+                        Err(e) => FetchPartitionResponse::error(p.id, e),
+                    };
+                    remaining = remaining.saturating_sub(partition.records.len());
+                    partition
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn fetch_partition(
+    state: &State,
+    topic: &str,
+    req: &FetchPartition,
+    node_id: u32,
+    max_bytes: usize,
+    empty: bool,
+) -> FetchPartitionResponse {
+    let error = |error| FetchPartitionResponse::error(req.id, error);
+    let log = match leader_log(state, topic, req.id, req.current_leader_epoch, node_id) {
+        Ok((log, _)) => log,
+        Err(e) => return error(e),
+    };
+    if req.fetch_offset < log.log_start_offset() || req.fetch_offset > log.log_end_offset() {
+        return error(ErrorCode::OffsetOutOfRange);
+    }
+    let mut records = vec![];
+    for batch in log.batches().filter(|b| b.last_offset >= req.fetch_offset) {
+        let bytes = match batch.to_bytes() {
+            Ok(bytes) => bytes,
+            Err(_) => return error(ErrorCode::KafkaStorageError),
+        };
+        let first = empty && records.is_empty();
+        if records.len() + bytes.len() > max_bytes && !first {
+            break;
+        }
+        records.extend(bytes);
+    }
+    FetchPartitionResponse {
+        id: req.id,
+        error: ErrorCode::NoError.value(),
+        high_watermark: log.log_end_offset(),
+        log_start_offset: log.log_start_offset(),
+        records,
+    }
+}
+
+// Find where the requested leader epochs ended, for clients checking whether
+// the log was truncated under them
+fn offset_for_leader_epoch(
+    state: &State,
+    req: &OffsetForLeaderEpochRequest,
+    node_id: u32,
+    faults: &Injected,
+) -> Vec<EpochEndOffsetTopic> {
+    req.topics
+        .iter()
+        .map(|t| EpochEndOffsetTopic {
+            name: t.name.clone(),
+            partitions: t
+                .partitions
+                .iter()
+                .map(|p| {
+                    let result = faults
+                        .check(&t.name, p.id)
+                        .and_then(|()| {
+                            leader_log(state, &t.name, p.id, p.current_leader_epoch, node_id)
+                        })
+                        .map(|(log, epoch)| log.end_offset_for_epoch(p.leader_epoch, epoch));
+                    EpochEndOffset::new(p.id, result)
+                })
+                .collect(),
+        })
+        .collect()
+}
@@ -0,0 +1,791 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+
+use crate::config::{BrokerConfig, Listener};
+use crate::error::ErrorCode;
+use crate::state::State;
+
+/// A virtual broker of the simulated cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub id: u32,
+    pub rack: Option<String>,
+    /// Addresses advertised to clients, one per listener name
+    pub endpoints: Vec<Listener>,
+    /// Offline brokers close their connections and lose their leaderships
+    pub online: bool,
+}
+
+impl Node {
+    /// Address advertised for a listener
+    ///
+    /// * `listener` - listener name
+    pub fn endpoint(&self, listener: &str) -> Option<&Listener> {
+        self.endpoints.iter().find(|l| l.name == listener)
+    }
+}
+
+/// Brokers holding a partition
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    /// None while no replica in sync is online
+    pub leader: Option<u32>,
+    /// Bumped on every leader change
+    pub leader_epoch: u32,
+    pub replicas: Vec<u32>,
+    /// Replicas in sync with the leader
+    pub isr: Vec<u32>,
+    /// Replicas joining the partition in an ongoing reassignment
+    pub adding: Vec<u32>,
+    /// Replicas leaving the partition once the reassignment completes
+    pub removing: Vec<u32>,
+}
+
+impl Assignment {
+    pub fn is_reassigning(&self) -> bool {
+        !self.adding.is_empty() || !self.removing.is_empty()
+    }
+}
+
+/// The brokers a single process presents to clients
+///
+/// Every broker serves the same state, but each partition is led by one of
+/// them, so clients have to find the leader as they would with a real
+/// cluster. Replicas are spread over the brokers in a round-robin, starting
+/// at a broker that depends on the topic, and alternating racks when the
+/// brokers have some, as Kafka's rack-aware assignment does.
+///
+/// Leadership, replicas and ISR can then be changed, and brokers taken
+/// offline, to simulate failovers and reassignments.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub nodes: Vec<Node>,
+    pub replication_factor: usize,
+    /// Indices of the nodes, alternating racks
+    rack_alternated: Vec<usize>,
+    /// Partitions that no longer have their initial assignment
+    assignments: BTreeMap<(String, u32), Assignment>,
+}
+
+impl Cluster {
+    /// Create the brokers of a configuration
+    /// Brokers get consecutive ids, and the advertised listeners consecutive
+    /// ports, starting with the configured ones. Fails if there are no
+    /// brokers, or if their ids or ports would overflow.
+    ///
+    /// * `config` - broker configuration
+    pub fn new(config: &BrokerConfig) -> io::Result<Self> {
+        if config.brokers == 0 {
+            return Err(invalid_config("cluster.brokers must be positive"));
+        }
+        let nodes = (0..config.brokers)
+            .map(|i| {
+                let id = u32::try_from(i)
+                    .ok()
+                    .and_then(|i| config.node_id.checked_add(i))
+                    .ok_or_else(|| invalid_config("too many brokers for node.id"))?;
+                let endpoints = config
+                    .advertised_listeners
+                    .iter()
+                    .map(|l| {
+                        let port = broker_port(l.port, i).ok_or_else(|| {
+                            invalid_config(&format!("too many brokers for {}", l))
+                        })?;
+                        Ok(Listener::new(&l.name, &l.host, port))
+                    })
+                    .collect::<io::Result<_>>()?;
+                Ok(Node {
+                    id,
+                    rack: match config.racks.len() {
+                        0 => None,
+                        n => Some(config.racks[i % n].clone()),
+                    },
+                    endpoints,
+                    online: true,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Racks in order, each with its brokers: take one of each rack in turn
+        let mut racks = Vec::<(Option<&String>, Vec<usize>)>::new();
+        for (i, node) in nodes.iter().enumerate() {
+            match racks.iter_mut().find(|(r, _)| *r == node.rack.as_ref()) {
+                Some((_, indices)) => indices.push(i),
+                None => racks.push((node.rack.as_ref(), vec![i])),
+            }
+        }
+        racks.sort();
+        let rack_alternated = (0..nodes.len())
+            .flat_map(|n| racks.iter().filter_map(move |(_, indices)| indices.get(n)))
+            .copied()
+            .collect();
+
+        Ok(Self {
+            replication_factor: config.default_replication_factor.min(nodes.len()),
+            nodes,
+            rack_alternated,
+            assignments: BTreeMap::new(),
+        })
+    }
+
+    /// Whether the brokers are the ones of a configuration, online or not
+    ///
+    /// * `config` - broker configuration
+    pub fn matches(&self, config: &BrokerConfig) -> bool {
+        let configured = match Cluster::new(config) {
+            Ok(cluster) => cluster,
+            Err(_) => return false,
+        };
+        self.replication_factor == configured.replication_factor
+            && self.nodes.len() == configured.nodes.len()
+            && self
+                .nodes
+                .iter()
+                .zip(&configured.nodes)
+                .all(|(n, c)| n.id == c.id && n.rack == c.rack && n.endpoints == c.endpoints)
+    }
+
+    pub fn node(&self, id: u32) -> Option<&Node> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+
+    pub fn is_online(&self, id: u32) -> bool {
+        self.node(id).is_some_and(|n| n.online)
+    }
+
+    /// Bring every broker back online, and every partition back to its initial assignment
+    pub fn reset(&mut self) {
+        for node in &mut self.nodes {
+            node.online = true;
+        }
+        self.assignments.clear();
+    }
+
+    /// Forget the assignments of a deleted topic, so a new one with the same
+    /// name starts afresh
+    ///
+    /// * `topic` - topic name
+    pub fn remove_topic(&mut self, topic: &str) {
+        self.assignments.retain(|(t, _), _| t != topic);
+    }
+
+    // A reassignment completes once every replica being added is in sync:
+    // replicas being removed leave, and so does the leader if it is one of them
+    fn complete_reassignment(&mut self, topic: &str, partition: u32) {
+        let assignment = self.assignment(topic, partition);
+        if !assignment.is_reassigning()
+            || !assignment
+                .adding
+                .iter()
+                .all(|id| assignment.isr.contains(id))
+        {
+            return;
+        }
+        self.update(topic, partition, |a| {
+            let removing = std::mem::take(&mut a.removing);
+            a.adding.clear();
+            a.replicas.retain(|id| !removing.contains(id));
+            a.isr.retain(|id| !removing.contains(id));
+            if a.leader.is_some_and(|l| removing.contains(&l)) {
+                let replicas = &a.replicas;
+                a.leader = replicas.iter().copied().find(|id| a.isr.contains(id));
+            }
+        });
+    }
+
+    /// Brokers holding a partition
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    pub fn assignment(&self, topic: &str, partition: u32) -> Assignment {
+        if let Some(a) = self.assignments.get(&(topic.to_string(), partition)) {
+            return a.clone();
+        }
+        // Never 0, as clusters have brokers
+        let n = self.rack_alternated.len();
+        let first = (fnv1a(topic) as usize + partition as usize) % n;
+        let replicas = (0..self.replication_factor)
+            .map(|r| self.nodes[self.rack_alternated[(first + r) % n]].id)
+            .collect::<Vec<_>>();
+        // Brokers may have gone offline before the topic was created
+        let isr = replicas
+            .iter()
+            .copied()
+            .filter(|id| self.is_online(*id))
+            .collect::<Vec<_>>();
+        Assignment {
+            leader: isr.first().copied(),
+            leader_epoch: 0,
+            isr,
+            replicas,
+            adding: vec![],
+            removing: vec![],
+        }
+    }
+
+    /// Partitions being reassigned, by topic and partition id
+    pub fn reassignments(&self) -> impl Iterator<Item = (&(String, u32), &Assignment)> {
+        self.assignments.iter().filter(|(_, a)| a.is_reassigning())
+    }
+
+    /// Broker handling admin requests: the first one online
+    pub fn controller(&self) -> Option<u32> {
+        self.nodes.iter().find(|n| n.online).map(|n| n.id)
+    }
+
+    /// Brokers holding a partition that are offline
+    ///
+    /// * `assignment` - partition assignment
+    pub fn offline_replicas(&self, assignment: &Assignment) -> Vec<u32> {
+        assignment
+            .replicas
+            .iter()
+            .copied()
+            .filter(|id| !self.is_online(*id))
+            .collect()
+    }
+
+    // Change the assignment of a partition, bumping the leader epoch if the
+    // leader changes
+    fn update(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        f: impl FnOnce(&mut Assignment),
+    ) -> Assignment {
+        let mut assignment = self.assignment(topic, partition);
+        let leader = assignment.leader;
+        f(&mut assignment);
+        if assignment.leader != leader {
+            assignment.leader_epoch += 1;
+        }
+        self.assignments
+            .insert((topic.to_string(), partition), assignment.clone());
+        assignment
+    }
+}
+
+// Partitions of every topic, by topic name and partition id
+pub(crate) fn partitions(state: &State) -> Vec<(String, u32)> {
+    state
+        .topics
+        .iter()
+        .flat_map(|(name, t)| (0..t.partitions.len() as u32).map(move |p| (name.clone(), p)))
+        .collect()
+}
+
+/// Control of the simulated cluster
+impl State {
+    /// Move the leadership of a partition to another replica in sync
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    /// * `leader` - id of the new leader
+    pub fn elect_leader(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        leader: u32,
+    ) -> Result<Assignment, ErrorCode> {
+        self.check_partition(topic, partition)?;
+        let assignment = self.cluster.assignment(topic, partition);
+        if assignment.leader == Some(leader) {
+            return Err(ErrorCode::ElectionNotNeeded);
+        }
+        if !assignment.isr.contains(&leader) || !self.cluster.is_online(leader) {
+            return Err(ErrorCode::EligibleLeadersNotAvailable);
+        }
+        Ok(self
+            .cluster
+            .update(topic, partition, |a| a.leader = Some(leader)))
+    }
+
+    /// Start the leader epochs of partitions after the ones found in their logs
+    /// As if the partitions had been led before, by the broker that wrote the
+    /// recovered data.
+    pub(crate) fn resume_leader_epochs(&mut self) {
+        for (topic, partition) in partitions(self) {
+            let log = &self.topics[&topic].partitions[partition as usize];
+            if let Some((epoch, _)) = log.leader_epochs().last() {
+                let epoch = *epoch as u32;
+                if epoch > self.cluster.assignment(&topic, partition).leader_epoch {
+                    self.cluster
+                        .update(&topic, partition, |a| a.leader_epoch = epoch);
+                }
+            }
+        }
+    }
+
+    /// Take a broker offline, or bring it back
+    /// An offline broker drops out of every ISR, and the partitions it led
+    /// move to another replica in sync, if any is left. Back online, it
+    /// rejoins the ISR of its partitions, and leads those without a leader.
+    ///
+    /// * `node_id` - broker id
+    /// * `online` - whether the broker is up
+    pub fn set_broker_online(&mut self, node_id: u32, online: bool) -> Result<(), ErrorCode> {
+        let node = self
+            .cluster
+            .nodes
+            .iter()
+            .position(|n| n.id == node_id)
+            .ok_or(ErrorCode::BrokerNotAvailable)?;
+        if self.cluster.nodes[node].online == online {
+            return Ok(());
+        }
+        // The partitions of the broker, as they were before the change
+        let affected = partitions(self)
+            .into_iter()
+            .filter(|(topic, partition)| {
+                let assignment = self.cluster.assignment(topic, *partition);
+                assignment.replicas.contains(&node_id)
+            })
+            .collect::<Vec<_>>();
+        for (topic, partition) in &affected {
+            let assignment = self.cluster.assignment(topic, *partition);
+            self.cluster
+                .assignments
+                .insert((topic.clone(), *partition), assignment);
+        }
+        self.cluster.nodes[node].online = online;
+        for (topic, partition) in affected {
+            self.cluster.update(&topic, partition, |a| {
+                if online {
+                    if !a.isr.contains(&node_id) {
+                        a.isr.push(node_id);
+                    }
+                    a.leader = a.leader.or(Some(node_id));
+                } else if a.leader == Some(node_id) {
+                    // As Kafka, keep the last replica in sync until another one is
+                    // elected, instead of leaving an empty ISR
+                    match a.isr.iter().copied().find(|id| *id != node_id) {
+                        Some(leader) => {
+                            a.isr.retain(|id| *id != node_id);
+                            a.leader = Some(leader);
+                        }
+                        None => a.leader = None,
+                    }
+                } else {
+                    a.isr.retain(|id| *id != node_id);
+                }
+            });
+            if online {
+                self.cluster.complete_reassignment(&topic, partition);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move leadership of a partition back to its preferred replica, the first one
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    pub fn elect_preferred_leader(
+        &mut self,
+        topic: &str,
+        partition: u32,
+    ) -> Result<Assignment, ErrorCode> {
+        self.check_partition(topic, partition)?;
+        let preferred = self.cluster.assignment(topic, partition).replicas[0];
+        self.elect_leader(topic, partition, preferred)
+            .map_err(|e| match e {
+                ErrorCode::EligibleLeadersNotAvailable => ErrorCode::PreferredLeaderNotAvailable,
+                e => e,
+            })
+    }
+
+    /// Elect a leader for a partition without one, even if it is not in sync
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    pub fn elect_unclean_leader(
+        &mut self,
+        topic: &str,
+        partition: u32,
+    ) -> Result<Assignment, ErrorCode> {
+        self.check_partition(topic, partition)?;
+        let assignment = self.cluster.assignment(topic, partition);
+        if assignment.leader.is_some() {
+            return Err(ErrorCode::ElectionNotNeeded);
+        }
+        let leader = assignment
+            .replicas
+            .iter()
+            .copied()
+            .find(|id| self.cluster.is_online(*id))
+            .ok_or(ErrorCode::EligibleLeadersNotAvailable)?;
+        Ok(self.cluster.update(topic, partition, |a| {
+            a.leader = Some(leader);
+            a.isr = vec![leader];
+        }))
+    }
+
+    /// Change the replicas in sync of a partition, e.g. to shrink it
+    /// The leader must stay in it.
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    /// * `isr` - ids of the replicas in sync
+    pub fn set_isr(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        isr: Vec<u32>,
+    ) -> Result<Assignment, ErrorCode> {
+        self.check_partition(topic, partition)?;
+        let assignment = self.cluster.assignment(topic, partition);
+        let valid = isr
+            .iter()
+            .all(|id| assignment.replicas.contains(id) && self.cluster.is_online(*id))
+            && assignment
+                .leader
+                .map_or(!isr.is_empty(), |l| isr.contains(&l));
+        if !valid {
+            return Err(ErrorCode::InvalidReplicaAssignment);
+        }
+        Ok(self.cluster.update(topic, partition, |a| {
+            a.isr = isr;
+            a.leader = a.leader.or_else(|| a.isr.first().copied());
+        }))
+    }
+
+    /// Move a partition to other brokers
+    /// As in Kafka, the new replicas join the partition, and the old ones leave
+    /// once the new ones are in sync. Replicas that are online are in sync
+    /// right away, since every broker serves the same data, so the
+    /// reassignment stays ongoing only while some new replica is offline. A
+    /// new reassignment of the partition replaces the ongoing one.
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    /// * `replicas` - ids of the new replicas, the preferred leader first
+    pub fn reassign_partition(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        replicas: Vec<u32>,
+    ) -> Result<Assignment, ErrorCode> {
+        self.check_partition(topic, partition)?;
+        let mut unique = replicas.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        if replicas.is_empty()
+            || unique.len() < replicas.len()
+            || replicas.iter().any(|id| self.cluster.node(*id).is_none())
+        {
+            return Err(ErrorCode::InvalidReplicaAssignment);
+        }
+        let current = self.cluster.assignment(topic, partition);
+        let original = current
+            .replicas
+            .iter()
+            .copied()
+            .filter(|id| !current.adding.contains(id))
+            .collect::<Vec<_>>();
+        let adding = replicas
+            .iter()
+            .copied()
+            .filter(|id| !original.contains(id))
+            .collect::<Vec<_>>();
+        let removing = original
+            .iter()
+            .copied()
+            .filter(|id| !replicas.contains(id))
+            .collect::<Vec<_>>();
+        let online = replicas
+            .iter()
+            .copied()
+            .filter(|id| self.cluster.is_online(*id))
+            .collect::<Vec<_>>();
+        self.cluster.update(topic, partition, |a| {
+            a.replicas = replicas.iter().chain(&removing).copied().collect();
+            let all = &a.replicas;
+            a.isr.retain(|id| all.contains(id));
+            for id in online {
+                if !a.isr.contains(&id) {
+                    a.isr.push(id);
+                }
+            }
+            a.leader = a.leader.or_else(|| a.isr.first().copied());
+            a.adding = adding;
+            a.removing = removing;
+        });
+        self.cluster.complete_reassignment(topic, partition);
+        Ok(self.cluster.assignment(topic, partition))
+    }
+
+    /// Cancel the ongoing reassignment of a partition, back to its replicas before it
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    pub fn cancel_reassignment(
+        &mut self,
+        topic: &str,
+        partition: u32,
+    ) -> Result<Assignment, ErrorCode> {
+        self.check_partition(topic, partition)?;
+        if !self.cluster.assignment(topic, partition).is_reassigning() {
+            return Err(ErrorCode::NoReassignmentInProgress);
+        }
+        Ok(self.cluster.update(topic, partition, |a| {
+            let adding = std::mem::take(&mut a.adding);
+            a.removing.clear();
+            a.replicas.retain(|id| !adding.contains(id));
+            a.isr.retain(|id| !adding.contains(id));
+            if a.leader.is_some_and(|l| adding.contains(&l)) {
+                let replicas = &a.replicas;
+                a.leader = replicas.iter().copied().find(|id| a.isr.contains(id));
+            }
+        }))
+    }
+
+    fn check_partition(&self, topic: &str, partition: u32) -> Result<(), ErrorCode> {
+        match self.topics.get(topic) {
+            Some(t) if (partition as usize) < t.partitions.len() => Ok(()),
+            _ => Err(ErrorCode::UnknownTopicOrPartition),
+        }
+    }
+}
+
+/// Port of a listener on a broker, given the index of the broker: the
+/// configured port plus the index, or None if it overflows
+///
+/// * `port` - configured port
+/// * `index` - index of the broker, from 0
+pub fn broker_port(port: u16, index: usize) -> Option<u16> {
+    u16::try_from(index).ok().and_then(|i| port.checked_add(i))
+}
+
+fn invalid_config(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// FNV-1a hash, so the assignment of a topic doesn't change between runs
+fn fnv1a(s: &str) -> u32 {
+    s.bytes()
+        .fold(0x811c_9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopicConfig;
+
+    fn cluster(brokers: usize, racks: &str, replication_factor: usize) -> Cluster {
+        let mut config = BrokerConfig {
+            brokers,
+            default_replication_factor: replication_factor,
+            ..BrokerConfig::default()
+        };
+        config.set("broker.rack", racks);
+        Cluster::new(&config).unwrap()
+    }
+
+    #[test]
+    fn spread_leaders() {
+        let cluster = cluster(3, "", 2);
+        let mut leaders = (0..6)
+            .map(|p| cluster.assignment("t", p).leader)
+            .collect::<Vec<_>>();
+        leaders.sort_unstable();
+        assert_eq!(
+            leaders,
+            [1003, 1003, 1004, 1004, 1005, 1005].map(Some).to_vec()
+        );
+        for p in 0..6 {
+            let a = cluster.assignment("t", p);
+            assert_eq!(a.replicas.len(), 2);
+            assert_ne!(a.replicas[0], a.replicas[1]);
+            assert_eq!(a.isr, a.replicas);
+        }
+    }
+
+    #[test]
+    fn alternate_racks() {
+        // Brokers 1003 and 1005 are in rack a, 1004 and 1006 in rack b
+        let cluster = cluster(4, "a,b", 2);
+        assert_eq!(cluster.node(1005).unwrap().rack, Some("a".to_string()));
+        for p in 0..4 {
+            let racks = cluster
+                .assignment("t", p)
+                .replicas
+                .iter()
+                .map(|id| cluster.node(*id).unwrap().rack.clone())
+                .collect::<Vec<_>>();
+            assert_ne!(racks[0], racks[1]);
+        }
+    }
+
+    #[test]
+    fn replication_factor_is_capped() {
+        let cluster = cluster(2, "", 3);
+        assert_eq!(cluster.assignment("t", 0).replicas.len(), 2);
+    }
+
+    #[test]
+    fn reject_overflowing_configs() {
+        let config = |brokers, node_id, port| BrokerConfig {
+            brokers,
+            node_id,
+            advertised_listeners: vec![Listener::new("PLAINTEXT", "kafka", port)],
+            ..BrokerConfig::default()
+        };
+        assert!(Cluster::new(&config(2, u32::MAX - 1, 9092)).is_ok());
+        assert!(Cluster::new(&config(3, u32::MAX - 1, 9092)).is_err());
+        assert!(Cluster::new(&config(2, 1, u16::MAX)).is_err());
+        assert!(Cluster::new(&config(0, 1, 9092)).is_err());
+    }
+
+    fn state(brokers: usize) -> State {
+        let mut state = State::new(BrokerConfig {
+            brokers,
+            default_replication_factor: 3,
+            ..BrokerConfig::default()
+        })
+        .unwrap();
+        state.create_topic("t", 1, TopicConfig::default()).unwrap();
+        state
+    }
+
+    #[test]
+    fn failover() {
+        let mut state = state(3);
+        let initial = state.cluster.assignment("t", 0);
+        let leader = initial.leader.unwrap();
+
+        state.set_broker_online(leader, false).unwrap();
+        let a = state.cluster.assignment("t", 0);
+        assert_eq!(a.leader, Some(initial.replicas[1]));
+        assert_eq!(a.leader_epoch, 1);
+        assert!(!a.isr.contains(&leader));
+        assert_eq!(state.cluster.offline_replicas(&a), vec![leader]);
+
+        // Back online, it is in sync again but doesn't take the lead back
+        state.set_broker_online(leader, true).unwrap();
+        let a = state.cluster.assignment("t", 0);
+        assert!(a.isr.contains(&leader));
+        assert_eq!(a.leader, Some(initial.replicas[1]));
+        assert_eq!(state.elect_leader("t", 0, leader).unwrap().leader_epoch, 2);
+        assert_eq!(
+            state.elect_leader("t", 0, leader),
+            Err(ErrorCode::ElectionNotNeeded)
+        );
+    }
+
+    #[test]
+    fn no_leader_without_replicas_in_sync() {
+        let mut state = state(1);
+        state.set_broker_online(1003, false).unwrap();
+        let a = state.cluster.assignment("t", 0);
+        assert_eq!(a.leader, None);
+        assert_eq!(a.isr, vec![1003]);
+        state.set_broker_online(1003, true).unwrap();
+        assert_eq!(state.cluster.assignment("t", 0).leader, Some(1003));
+        assert_eq!(state.cluster.assignment("t", 0).leader_epoch, 2);
+    }
+
+    #[test]
+    fn shrink_isr_and_reassign() {
+        let mut state = state(3);
+        let a = state.cluster.assignment("t", 0);
+        let (leader, follower) = (a.replicas[0], a.replicas[1]);
+        assert_eq!(
+            state.set_isr("t", 0, vec![leader]).unwrap().isr,
+            vec![leader]
+        );
+        assert_eq!(
+            state.elect_leader("t", 0, follower),
+            Err(ErrorCode::EligibleLeadersNotAvailable)
+        );
+        assert_eq!(
+            state.set_isr("t", 0, vec![follower]),
+            Err(ErrorCode::InvalidReplicaAssignment)
+        );
+
+        let a = state.reassign_partition("t", 0, vec![follower]).unwrap();
+        assert_eq!(a.leader, Some(follower));
+        assert_eq!(a.isr, vec![follower]);
+        assert_eq!(a.leader_epoch, 1);
+        assert_eq!(
+            state.reassign_partition("t", 0, vec![follower, follower]),
+            Err(ErrorCode::InvalidReplicaAssignment)
+        );
+        assert_eq!(
+            state.reassign_partition("u", 0, vec![follower]),
+            Err(ErrorCode::UnknownTopicOrPartition)
+        );
+    }
+
+    #[test]
+    fn ongoing_reassignment() {
+        let mut state = state(4);
+        let a = state.cluster.assignment("t", 0);
+        let outsider = 1003
+            + (1003..1007)
+                .position(|id| !a.replicas.contains(&id))
+                .unwrap() as u32;
+        let (leader, kept) = (a.replicas[0], a.replicas[1]);
+
+        // Stays ongoing while the new replica is offline
+        state.set_broker_online(outsider, false).unwrap();
+        let a = state
+            .reassign_partition("t", 0, vec![outsider, kept])
+            .unwrap();
+        assert_eq!(a.adding, vec![outsider]);
+        assert_eq!(a.removing.len(), 2);
+        assert_eq!(a.replicas.len(), 4);
+        assert_eq!(a.leader, Some(leader));
+        assert_eq!(state.cluster.reassignments().count(), 1);
+
+        state.set_broker_online(outsider, true).unwrap();
+        let a = state.cluster.assignment("t", 0);
+        assert!(!a.is_reassigning());
+        assert_eq!(a.replicas, vec![outsider, kept]);
+        assert_eq!(a.leader, Some(outsider));
+        state.elect_leader("t", 0, kept).unwrap();
+        assert_eq!(
+            state.elect_preferred_leader("t", 0).unwrap().leader,
+            Some(outsider)
+        );
+        assert_eq!(
+            state.cancel_reassignment("t", 0),
+            Err(ErrorCode::NoReassignmentInProgress)
+        );
+    }
+
+    #[test]
+    fn cancel_reassignment() {
+        let mut state = state(4);
+        let before = state.cluster.assignment("t", 0);
+        let outsider = 1003
+            + (1003..1007)
+                .position(|id| !before.replicas.contains(&id))
+                .unwrap() as u32;
+        state.set_broker_online(outsider, false).unwrap();
+        state.reassign_partition("t", 0, vec![outsider]).unwrap();
+        let a = state.cancel_reassignment("t", 0).unwrap();
+        assert_eq!(a.replicas, before.replicas);
+        assert_eq!(a.leader, before.leader);
+        assert!(!a.is_reassigning());
+    }
+
+    #[test]
+    fn unclean_election() {
+        let mut state = state(2);
+        let a = state.cluster.assignment("t", 0);
+        let (leader, follower) = (a.replicas[0], a.replicas[1]);
+        state.set_isr("t", 0, vec![leader]).unwrap();
+        state.set_broker_online(leader, false).unwrap();
+        assert_eq!(state.cluster.assignment("t", 0).leader, None);
+        assert_eq!(
+            state.elect_preferred_leader("t", 0),
+            Err(ErrorCode::PreferredLeaderNotAvailable)
+        );
+        let a = state.elect_unclean_leader("t", 0).unwrap();
+        assert_eq!(a.leader, Some(follower));
+        assert_eq!(a.isr, vec![follower]);
+    }
+}
@@ -0,0 +1,196 @@
+use bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::warn;
+
+use std::convert::TryInto;
+
+use crate::config::DEFAULT_SOCKET_REQUEST_MAX_BYTES;
+use crate::de;
+use crate::error::KafkaError;
+use crate::messages::{ErrorResponse, Request, Response};
+use crate::ser::Serialize;
+
+/// Frames Kafka requests and responses on a byte stream
+///
+/// Each frame is a message prefixed by its length as a 32-bit integer. Requests
+/// are parsed with `de` and responses written with `ser`. A request that
+/// can't be parsed or isn't supported is decoded as the error response to send
+/// back, with its correlation id (see `ErrorResponse::has_layout`).
+///
+/// Decoding only fails, and the connection is to be closed, for requests
+/// without a readable header, and those larger than `socket.request.max.bytes`,
+/// which are never buffered.
+#[derive(Debug)]
+pub struct KafkaCodec {
+    max_request_size: usize,
+}
+
+/// A decoded request, or the error to answer it with
+#[derive(Debug)]
+pub struct Frame {
+    /// Bytes the request took in memory
+    pub size: usize,
+    /// The request as read, without its length
+    pub bytes: Bytes,
+    pub request: Result<Request, ErrorResponse>,
+}
+
+impl KafkaCodec {
+    /// * `max_request_size` - largest request accepted (`socket.request.max.bytes`)
+    pub fn new(max_request_size: usize) -> Self {
+        Self { max_request_size }
+    }
+}
+
+impl Default for KafkaCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_SOCKET_REQUEST_MAX_BYTES)
+    }
+}
+
+impl Decoder for KafkaCodec {
+    type Item = Frame;
+    type Error = KafkaError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, KafkaError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let size = de::request_size(src[..4].try_into().unwrap(), self.max_request_size)?;
+        if src.len() < 4 + size {
+            // Wait for the rest of the message
+            src.reserve(4 + size - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let contents = src.split_to(size);
+        let request = match de::from_bytes(&contents) {
+            Ok(req) => Ok(req),
+            Err(e) => match de::header_prefix(&contents) {
+                Ok((_, (api_key, api_version, correlation_id))) => {
+                    let mut resp =
+                        ErrorResponse::new(api_key, api_version, correlation_id, e.code.value());
+                    resp.topics = de::request_partitions(&contents);
+                    warn!("Invalid request: {}", e);
+                    Err(resp)
+                }
+                Err(_) => return Err(e),
+            },
+        };
+        Ok(Some(Frame {
+            size,
+            bytes: contents.freeze(),
+            request,
+        }))
+    }
+}
+
+impl Encoder<Response> for KafkaCodec {
+    type Error = KafkaError;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> Result<(), KafkaError> {
+        dst.extend_from_slice(&item.to_bytes()?);
+        Ok(())
+    }
+}
+
+// Responses already encoded
+impl Encoder<Bytes> for KafkaCodec {
+    type Error = KafkaError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), KafkaError> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::messages::TopicPartitions;
+
+    #[test]
+    fn decode_partial_frames() {
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let mut codec = KafkaCodec::default();
+        let mut buf = BytesMut::new();
+        for chunk in [&bytes[..2], &bytes[2..50]] {
+            buf.extend_from_slice(chunk);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(&bytes[50..]);
+        buf.extend_from_slice(&bytes[..10]);
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.size, bytes.len() - 4);
+        assert_eq!(&frame.bytes[..], &bytes[4..]);
+        assert!(matches!(frame.request, Ok(Request::ProduceRequest(_))));
+        // The start of the next frame is left in the buffer
+        assert_eq!(&buf[..], &bytes[..10]);
+    }
+
+    fn decode(request: &[u8]) -> Result<Frame, KafkaError> {
+        let mut buf = BytesMut::from(&(request.len() as u32).to_be_bytes()[..]);
+        buf.extend_from_slice(request);
+        KafkaCodec::default().decode(&mut buf).map(Option::unwrap)
+    }
+
+    fn decode_error(request: &[u8]) -> ErrorResponse {
+        decode(request).unwrap().request.unwrap_err()
+    }
+
+    #[test]
+    fn decode_invalid_requests() {
+        // ApiVersions v4
+        let resp = decode_error(&[0, 18, 0, 4, 0, 0, 0, 1, 0, 0]);
+        assert_eq!((resp.api_key, resp.header.correlation_id), (18, 1));
+        assert_eq!(resp.error_code, 35);
+        // Produce with a truncated batch, answered for its partition
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let resp = decode_error(&bytes[4..100]);
+        assert_eq!((resp.header.correlation_id, resp.error_code), (4, 2));
+        assert_eq!(
+            resp.topics,
+            vec![TopicPartitions {
+                name: "my-topic".to_string(),
+                partitions: vec![0],
+            }]
+        );
+    }
+
+    #[test]
+    fn decode_unsupported_requests() {
+        // JoinGroup, not implemented
+        let resp = decode_error(&[0, 11, 0, 6, 0, 0, 0, 2, 0, 0]);
+        assert_eq!((resp.header.correlation_id, resp.error_code), (2, 35));
+        // Unknown API key
+        let resp = decode_error(&[0, 99, 0, 0, 0, 0, 0, 3, 0, 0]);
+        assert_eq!((resp.header.correlation_id, resp.error_code), (3, 42));
+        // Produce truncated before its first topic, with nowhere to put the error
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let resp = decode_error(&bytes[4..40]);
+        assert_eq!((resp.header.correlation_id, resp.error_code), (4, 2));
+        assert!(!resp.has_layout());
+        // A header too short to read
+        let e = decode(&[0, 18, 0, 0, 0]).unwrap_err();
+        assert_eq!(e.code, ErrorCode::CorruptMessage);
+    }
+
+    #[test]
+    fn fail_on_oversized_requests() {
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let mut codec = KafkaCodec::new(64);
+        let mut buf = BytesMut::from(&bytes[..20]);
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.code, ErrorCode::MessageTooLarge);
+    }
+
+    #[test]
+    fn reject_negative_sizes() {
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xfe][..]);
+        let e = KafkaCodec::default().decode(&mut buf).unwrap_err();
+        assert_eq!(e.code, ErrorCode::InvalidRequest);
+    }
+}
@@ -0,0 +1,182 @@
+use tracing::error;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TopicConfig;
+use crate::log::{now_ms, PartitionLog};
+use crate::state::{SharedState, State};
+
+/// Compact a partition log, keeping only the latest record of each key
+///
+/// Batches younger than `min.compaction.lag.ms`, and everything after them,
+/// are left untouched. Tombstones are kept as the latest record of their key
+/// until they are older than `delete.retention.ms`. Offsets never change, so
+/// consumers see the removed records as gaps. Returns the number of records
+/// removed.
+///
+/// * `log` - partition log
+/// * `config` - configuration of the topic the partition belongs to
+/// * `now` - current time in milliseconds
+pub fn compact(log: &mut PartitionLog, config: &TopicConfig, now: i64) -> usize {
+    let limit = log
+        .batches()
+        .find(|b| now - b.max_timestamp < config.min_compaction_lag_ms)
+        .map_or(log.log_end_offset(), |b| b.base_offset);
+
+    let mut latest = HashMap::<Vec<u8>, i64>::new();
+    for batch in log.batches().take_while(|b| b.base_offset < limit) {
+        for record in &batch.records {
+            if let Some(key) = &record.key {
+                latest.insert(key.clone(), record.offset);
+            }
+        }
+    }
+
+    log.retain(|batch, record| {
+        if batch.base_offset >= limit {
+            return true;
+        }
+        match &record.key {
+            // Compacted topics reject records without a key, keep any leftovers
+            None => true,
+            Some(key) => {
+                latest.get(key) == Some(&record.offset)
+                    && !(record.value.is_none()
+                        && now - record.timestamp > config.delete_retention_ms)
+            }
+        }
+    })
+}
+
+/// Compact every partition of the topics whose `cleanup.policy` includes "compact"
+///
+/// * `state` - broker state
+/// * `now` - current time in milliseconds
+pub fn compact_all(state: &mut State, now: i64) -> usize {
+    let mut removed = 0;
+    for (name, topic) in state.topics.iter_mut() {
+        if !topic.config.cleanup_policy.compact {
+            continue;
+        }
+        for (p, log) in topic.partitions.iter_mut().enumerate() {
+            let n = compact(log, &topic.config, now);
+            if n > 0 {
+                if let Err(e) = state.storage.rewrite(name, p as u32, log) {
+                    error!("Error persisting {}-{}: {}", name, p, e);
+                }
+            }
+            removed += n;
+        }
+    }
+    removed
+}
+
+/// Spawn the log cleaner thread, which periodically compacts the partition logs
+/// and flushes the writes an fsync interval left unsynced
+///
+/// * `state` - shared broker state
+/// * `backoff` - time between compaction runs (`log.cleaner.backoff.ms`)
+pub fn spawn(state: &SharedState, backoff: Duration) -> thread::JoinHandle<()> {
+    // Only a weak reference is kept, so the task ends once the state is dropped
+    let state = Arc::downgrade(state);
+    thread::spawn(move || loop {
+        thread::sleep(backoff);
+        match state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock().unwrap();
+                compact_all(&mut state, now_ms());
+                // Writes since the fsync interval last ended, if none came after them
+                if let Err(e) = state.storage.flush() {
+                    error!("Error flushing the storage: {}", e);
+                }
+            }
+            None => break,
+        }
+    })
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CleanupPolicy;
+    use crate::testing::batch;
+
+    fn contents(log: &PartitionLog) -> Vec<(i64, Vec<u8>)> {
+        log.batches()
+            .flat_map(|b| b.records.iter())
+            .map(|r| (r.offset, r.key.clone().unwrap()))
+            .collect()
+    }
+
+    fn config() -> TopicConfig {
+        TopicConfig {
+            cleanup_policy: CleanupPolicy {
+                delete: false,
+                compact: true,
+            },
+            delete_retention_ms: 1_000,
+            min_compaction_lag_ms: 0,
+            ..TopicConfig::default()
+        }
+    }
+
+    #[test]
+    fn keeps_latest_record_per_key() {
+        let mut log = PartitionLog::new();
+        log.append(
+            &batch(0, &[(Some("a"), Some("1")), (Some("b"), Some("1"))]),
+            0,
+        );
+        log.append(&batch(0, &[(Some("a"), Some("2"))]), 0);
+        log.append(
+            &batch(0, &[(Some("a"), Some("3")), (Some("c"), Some("1"))]),
+            0,
+        );
+
+        assert_eq!(compact(&mut log, &config(), 0), 2);
+        assert_eq!(
+            contents(&log),
+            vec![(1, b"b".to_vec()), (3, b"a".to_vec()), (4, b"c".to_vec())]
+        );
+        // Offsets are kept, including the log boundaries
+        assert_eq!(log.log_start_offset(), 0);
+        assert_eq!(log.log_end_offset(), 5);
+        assert_eq!(log.batches().count(), 2);
+    }
+
+    #[test]
+    fn tombstones_expire_after_delete_retention() {
+        let mut log = PartitionLog::new();
+        log.append(
+            &batch(0, &[(Some("a"), Some("1")), (Some("b"), Some("1"))]),
+            0,
+        );
+        log.append(&batch(500, &[(Some("a"), None)]), 0);
+
+        assert_eq!(compact(&mut log, &config(), 1_000), 1);
+        assert_eq!(contents(&log), vec![(1, b"b".to_vec()), (2, b"a".to_vec())]);
+
+        assert_eq!(compact(&mut log, &config(), 2_000), 1);
+        assert_eq!(contents(&log), vec![(1, b"b".to_vec())]);
+    }
+
+    #[test]
+    fn min_compaction_lag_protects_recent_batches() {
+        let mut log = PartitionLog::new();
+        log.append(&batch(0, &[(Some("a"), Some("1"))]), 0);
+        log.append(&batch(900, &[(Some("a"), Some("2"))]), 0);
+        let config = TopicConfig {
+            min_compaction_lag_ms: 500,
+            ..config()
+        };
+
+        assert_eq!(compact(&mut log, &config, 1_000), 0);
+        assert_eq!(compact(&mut log, &config, 1_400), 1);
+        assert_eq!(contents(&log), vec![(1, b"a".to_vec())]);
+    }
+}
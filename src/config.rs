@@ -0,0 +1,737 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::logging::{self, LogFormat};
+
+// Kafka defaults, see https://kafka.apache.org/documentation/#topicconfigs
+const DEFAULT_RETENTION_MS: i64 = 604_800_000; // 7 days
+const DEFAULT_RETENTION_BYTES: i64 = -1; // unlimited
+const DEFAULT_DELETE_RETENTION_MS: i64 = 86_400_000; // 1 day
+const DEFAULT_MIN_COMPACTION_LAG_MS: i64 = 0;
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 1_048_588;
+const DEFAULT_RETENTION_CHECK_INTERVAL_MS: u64 = 300_000; // 5 minutes
+const DEFAULT_CLEANER_BACKOFF_MS: u64 = 15_000;
+const DEFAULT_CONNECTIONS_MAX_IDLE_MS: u64 = 600_000; // 10 minutes
+pub const DEFAULT_SOCKET_REQUEST_MAX_BYTES: usize = 104_857_600; // 100 MiB
+const DEFAULT_NODE_ID: u32 = 1003;
+const DEFAULT_CLUSTER_ID: &str = "0NHLrMQhQe2sWh6PvXAxcA";
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Prefix of the environment variables holding broker properties
+pub const ENV_PREFIX: &str = "PSEUDOKAFKA_";
+
+/// Value of `cleanup.policy`, which may combine both policies ("compact,delete")
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CleanupPolicy {
+    pub delete: bool,
+    pub compact: bool,
+}
+
+impl Default for CleanupPolicy {
+    fn default() -> Self {
+        Self {
+            delete: true,
+            compact: false,
+        }
+    }
+}
+
+impl fmt::Display for CleanupPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.compact, self.delete) {
+            (true, true) => write!(f, "compact,delete"),
+            (true, false) => write!(f, "compact"),
+            _ => write!(f, "delete"),
+        }
+    }
+}
+
+impl CleanupPolicy {
+    /// Parse a comma-separated list of policies
+    /// Returns None if the list is empty or has an unknown policy.
+    ///
+    /// * `value` - e.g. "delete", "compact" or "compact,delete"
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut policy = Self {
+            delete: false,
+            compact: false,
+        };
+        for p in value.split(',').map(str::trim) {
+            match p {
+                "delete" => policy.delete = true,
+                "compact" => policy.compact = true,
+                _ => return None,
+            }
+        }
+        Some(policy)
+    }
+}
+
+/// Per-topic configuration
+///
+/// Only the settings the broker actually enforces are kept here. Values follow
+/// Kafka semantics, so -1 means "no limit" for the retention settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicConfig {
+    pub cleanup_policy: CleanupPolicy,
+    pub retention_ms: i64,
+    pub retention_bytes: i64,
+    pub delete_retention_ms: i64,
+    pub min_compaction_lag_ms: i64,
+    /// Largest record batch accepted (`max.message.bytes`, `message.max.bytes` on the broker)
+    pub max_message_bytes: usize,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        Self {
+            cleanup_policy: CleanupPolicy::default(),
+            retention_ms: DEFAULT_RETENTION_MS,
+            retention_bytes: DEFAULT_RETENTION_BYTES,
+            delete_retention_ms: DEFAULT_DELETE_RETENTION_MS,
+            min_compaction_lag_ms: DEFAULT_MIN_COMPACTION_LAG_MS,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+impl TopicConfig {
+    /// Build a topic configuration from Kafka property names (e.g. `retention.ms`)
+    /// Unknown properties and unparseable values are ignored, keeping the defaults.
+    ///
+    /// * `props` - property name to value map
+    pub fn from_properties(props: &HashMap<String, String>) -> Self {
+        let mut config = Self::default();
+        for (key, value) in props {
+            config.set(key, value);
+        }
+        config
+    }
+
+    /// Kafka property names and values of this configuration
+    pub fn to_properties(&self) -> Vec<(String, String)> {
+        vec![
+            ("cleanup.policy", self.cleanup_policy.to_string()),
+            ("retention.ms", self.retention_ms.to_string()),
+            ("retention.bytes", self.retention_bytes.to_string()),
+            ("delete.retention.ms", self.delete_retention_ms.to_string()),
+            (
+                "min.compaction.lag.ms",
+                self.min_compaction_lag_ms.to_string(),
+            ),
+            ("max.message.bytes", self.max_message_bytes.to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+
+    /// Set a single property by its Kafka name
+    /// Returns false if the property is unknown or the value invalid.
+    ///
+    /// * `key` - Kafka property name
+    /// * `value` - property value
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "cleanup.policy" => CleanupPolicy::parse(value)
+                .map(|v| self.cleanup_policy = v)
+                .is_some(),
+            "retention.ms" => value.parse().map(|v| self.retention_ms = v).is_ok(),
+            "retention.bytes" => value.parse().map(|v| self.retention_bytes = v).is_ok(),
+            "delete.retention.ms" => value.parse().map(|v| self.delete_retention_ms = v).is_ok(),
+            "min.compaction.lag.ms" => value
+                .parse()
+                .map(|v| self.min_compaction_lag_ms = v)
+                .is_ok(),
+            "max.message.bytes" => value.parse().map(|v| self.max_message_bytes = v).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// When the on-disk storage flushes its files to the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// Leave it to the operating system
+    Never,
+    /// After every write
+    Always,
+    /// At most once per interval, on the next write, or on the next pass of the
+    /// log cleaner without one
+    Interval(Duration),
+}
+
+impl FsyncPolicy {
+    /// Parse "never", "always" or an interval in milliseconds
+    ///
+    /// * `value` - policy as given in the configuration
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "never" => Some(FsyncPolicy::Never),
+            "always" => Some(FsyncPolicy::Always),
+            ms => ms
+                .parse()
+                .ok()
+                .map(|ms| FsyncPolicy::Interval(Duration::from_millis(ms))),
+        }
+    }
+}
+
+/// Broker-wide configuration
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    /// How often the retention task checks the partition logs (`log.retention.check.interval.ms`)
+    pub retention_check_interval: Duration,
+    /// Time the log cleaner waits between compaction runs (`log.cleaner.backoff.ms`)
+    pub cleaner_backoff: Duration,
+    /// Addresses the binary listens on (`listeners`)
+    pub listeners: Vec<Listener>,
+    /// Addresses clients are told to connect to in Metadata responses, by
+    /// listener name (`advertised.listeners`). A listener without one
+    /// advertises the address it is bound to.
+    pub advertised_listeners: Vec<Listener>,
+    /// Security protocol of each listener name (`listener.security.protocol.map`)
+    pub security_protocols: BTreeMap<String, SecurityProtocol>,
+    /// Id of the broker (`node.id`), the first one with several brokers
+    pub node_id: u32,
+    /// Brokers simulated by the process (`cluster.brokers`), with consecutive
+    /// ids and ports
+    pub brokers: usize,
+    /// Racks the brokers are spread over, in turn (`broker.rack`)
+    pub racks: Vec<String>,
+    /// Replicas of automatically created topics (`default.replication.factor`)
+    pub default_replication_factor: usize,
+    /// Id of the cluster (`cluster.id`)
+    pub cluster_id: String,
+    /// Partitions of automatically created topics (`num.partitions`)
+    pub num_partitions: usize,
+    /// Create unknown topics when they are produced to or asked for
+    /// (`auto.create.topics.enable`)
+    pub auto_create_topics: bool,
+    /// Connections without requests for this long are closed (`connections.max.idle.ms`)
+    pub connections_max_idle: Duration,
+    /// Largest request accepted on a connection (`socket.request.max.bytes`)
+    pub socket_request_max_bytes: usize,
+    /// Memory all connections may use for requests waiting to be answered, unlimited
+    /// if None (`queued.max.request.bytes`)
+    pub queued_max_request_bytes: Option<usize>,
+    /// Configuration given to topics created without an explicit one
+    pub default_topic_config: TopicConfig,
+    /// Directory where topics, logs and offsets are persisted (in memory only if None)
+    pub data_dir: Option<PathBuf>,
+    pub fsync: FsyncPolicy,
+    /// Address of the HTTP admin endpoint, as host:port (`admin.listener`),
+    /// disabled if None
+    pub admin_listener: Option<String>,
+    /// Address Prometheus metrics are served on, as host:port (`metrics.listener`),
+    /// disabled if None
+    pub metrics_listener: Option<String>,
+    /// Address of the REST Proxy compatible HTTP endpoint, as host:port
+    /// (`rest.listener`), disabled if None
+    pub rest_listener: Option<String>,
+    /// Address of the Schema Registry compatible HTTP endpoint, as host:port
+    /// (`schema.registry.listener`), disabled if None
+    pub schema_registry_listener: Option<String>,
+    /// File every request and response is recorded to, as JSONL (`record.file`)
+    pub record_file: Option<PathBuf>,
+    /// Level of the logs, or filtering directives as in `RUST_LOG` (`logging.level`)
+    pub log_level: String,
+    /// Format of the logs (`logging.format`)
+    pub log_format: LogFormat,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            retention_check_interval: Duration::from_millis(DEFAULT_RETENTION_CHECK_INTERVAL_MS),
+            cleaner_backoff: Duration::from_millis(DEFAULT_CLEANER_BACKOFF_MS),
+            listeners: vec![Listener::new("PLAINTEXT", "0.0.0.0", 9092)],
+            advertised_listeners: vec![],
+            security_protocols: SecurityProtocol::ALL
+                .iter()
+                .map(|p| (p.to_string(), *p))
+                .collect(),
+            node_id: DEFAULT_NODE_ID,
+            brokers: 1,
+            racks: vec![],
+            default_replication_factor: 1,
+            cluster_id: DEFAULT_CLUSTER_ID.to_string(),
+            num_partitions: 1,
+            auto_create_topics: true,
+            connections_max_idle: Duration::from_millis(DEFAULT_CONNECTIONS_MAX_IDLE_MS),
+            socket_request_max_bytes: DEFAULT_SOCKET_REQUEST_MAX_BYTES,
+            queued_max_request_bytes: None,
+            default_topic_config: TopicConfig::default(),
+            data_dir: None,
+            fsync: FsyncPolicy::Never,
+            admin_listener: None,
+            metrics_listener: None,
+            rest_listener: None,
+            schema_registry_listener: None,
+            record_file: None,
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Read a `server.properties` file on top of the defaults
+    /// Properties the broker doesn't know about are ignored, so files written
+    /// for Kafka can be used as they are. Returns them along with the
+    /// configuration, to be reported once logging is set up.
+    ///
+    /// * `path` - properties file
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<(Self, Vec<(String, String)>)> {
+        let mut config = Self::default();
+        let mut ignored = vec![];
+        for (key, value) in parse_properties(&fs::read_to_string(path)?) {
+            if !config.set(&key, &value) {
+                ignored.push((key, value));
+            }
+        }
+        Ok((config, ignored))
+    }
+
+    /// Set properties from `PSEUDOKAFKA_*` environment variables
+    /// The rest of the name is the property in upper case, with underscores
+    /// for dots, e.g. `PSEUDOKAFKA_NUM_PARTITIONS` for `num.partitions`.
+    ///
+    /// * `vars` - environment variables, as given by `std::env::vars`
+    pub fn set_from_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), String> {
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                let key = key.to_lowercase().replace('_', ".");
+                if !self.set(&key, &value) {
+                    return Err(format!("invalid {}={}", name, value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set a single property by its Kafka name
+    /// Topic properties with a broker-wide name (e.g. `log.retention.ms`) set
+    /// the default topic configuration. Returns false if the property is
+    /// unknown or the value invalid.
+    ///
+    /// * `key` - Kafka property name
+    /// * `value` - property value
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        let ms = |value: &str| value.parse().ok().map(Duration::from_millis);
+        match key {
+            "listeners" => parse_listeners(value)
+                .filter(|l| !l.is_empty())
+                .map(|l| {
+                    self.listeners = l
+                        .into_iter()
+                        .map(|l| match l.host.as_str() {
+                            "" => Listener::new(&l.name, "0.0.0.0", l.port),
+                            _ => l,
+                        })
+                        .collect()
+                })
+                .is_some(),
+            "advertised.listeners" => parse_listeners(value)
+                .filter(|l| l.iter().all(|l| !l.host.is_empty()))
+                .map(|l| self.advertised_listeners = l)
+                .is_some(),
+            "listener.security.protocol.map" => value
+                .split(',')
+                .map(|entry| {
+                    let (name, protocol) = entry.trim().split_once(':')?;
+                    Some((name.to_string(), SecurityProtocol::parse(protocol)?))
+                })
+                .collect::<Option<_>>()
+                .map(|map| self.security_protocols = map)
+                .is_some(),
+            "node.id" | "broker.id" => value.parse().map(|v| self.node_id = v).is_ok(),
+            "cluster.brokers" => value
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(|n| self.brokers = n)
+                .is_some(),
+            "broker.rack" => {
+                self.racks = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|r| !r.is_empty())
+                    .map(String::from)
+                    .collect();
+                true
+            }
+            "default.replication.factor" => value
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(|n| self.default_replication_factor = n)
+                .is_some(),
+            "cluster.id" if !value.is_empty() => {
+                self.cluster_id = value.to_string();
+                true
+            }
+            "num.partitions" => value
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .map(|n| self.num_partitions = n)
+                .is_some(),
+            "auto.create.topics.enable" => {
+                value.parse().map(|v| self.auto_create_topics = v).is_ok()
+            }
+            "log.retention.check.interval.ms" => ms(value)
+                .map(|v| self.retention_check_interval = v)
+                .is_some(),
+            "log.cleaner.backoff.ms" => ms(value).map(|v| self.cleaner_backoff = v).is_some(),
+            "connections.max.idle.ms" => ms(value).map(|v| self.connections_max_idle = v).is_some(),
+            "socket.request.max.bytes" => value
+                .parse()
+                .map(|v| self.socket_request_max_bytes = v)
+                .is_ok(),
+            "queued.max.request.bytes" => match value.parse::<i64>() {
+                // Kafka's default of -1 means no limit
+                Ok(v) if v < 0 => {
+                    self.queued_max_request_bytes = None;
+                    true
+                }
+                // A budget of 0 would hold no request
+                Ok(0) | Err(_) => false,
+                Ok(v) => {
+                    self.queued_max_request_bytes = Some(v as usize);
+                    true
+                }
+            },
+            "log.dirs" | "log.dir" | "data.dir" if !value.is_empty() => {
+                self.data_dir = Some(value.into());
+                true
+            }
+            "record.file" => {
+                self.record_file = Some(value.into()).filter(|_| !value.is_empty());
+                true
+            }
+            "logging.level" if logging::is_valid_level(value) => {
+                self.log_level = value.to_string();
+                true
+            }
+            "logging.format" => LogFormat::parse(value)
+                .map(|v| self.log_format = v)
+                .is_some(),
+            "fsync" => FsyncPolicy::parse(value).map(|v| self.fsync = v).is_some(),
+            "admin.listener" => parse_http_listener(value)
+                .map(|v| self.admin_listener = v)
+                .is_some(),
+            "metrics.listener" => parse_http_listener(value)
+                .map(|v| self.metrics_listener = v)
+                .is_some(),
+            "rest.listener" => parse_http_listener(value)
+                .map(|v| self.rest_listener = v)
+                .is_some(),
+            "schema.registry.listener" => parse_http_listener(value)
+                .map(|v| self.schema_registry_listener = v)
+                .is_some(),
+            "log.cleanup.policy" => self.default_topic_config.set("cleanup.policy", value),
+            "log.retention.ms" => self.default_topic_config.set("retention.ms", value),
+            "log.retention.bytes" => self.default_topic_config.set("retention.bytes", value),
+            "log.cleaner.delete.retention.ms" => {
+                self.default_topic_config.set("delete.retention.ms", value)
+            }
+            "log.cleaner.min.compaction.lag.ms" => self
+                .default_topic_config
+                .set("min.compaction.lag.ms", value),
+            "message.max.bytes" => self.default_topic_config.set("max.message.bytes", value),
+            _ => false,
+        }
+    }
+
+    /// Security protocol of a listener, if its name is in the protocol map
+    ///
+    /// * `name` - listener name
+    pub fn security_protocol(&self, name: &str) -> Option<SecurityProtocol> {
+        self.security_protocols.get(name).copied()
+    }
+
+    /// Address advertised for a listener, if one is configured
+    ///
+    /// * `name` - listener name
+    pub fn advertised_listener(&self, name: &str) -> Option<&Listener> {
+        self.advertised_listeners.iter().find(|l| l.name == name)
+    }
+}
+
+/// A named address, as given in `listeners`: `NAME://host:port`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Listener {
+    pub fn new(name: &str, host: &str, port: u16) -> Self {
+        Self {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    /// Parse a listener, as `NAME://host:port`, or `host:port` for a PLAINTEXT one
+    /// The host may be empty, e.g. `PLAINTEXT://:9092`.
+    ///
+    /// * `value` - listener
+    pub fn parse(value: &str) -> Option<Self> {
+        let (name, address) = value
+            .trim()
+            .split_once("://")
+            .unwrap_or(("PLAINTEXT", value));
+        let (host, port) = address.rsplit_once(':')?;
+        if name.is_empty() {
+            return None;
+        }
+        let host = host.trim_matches(|c| c == '[' || c == ']');
+        Some(Self::new(&name.to_uppercase(), host, port.parse().ok()?))
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}:{}", self.name, self.host, self.port)
+    }
+}
+
+/// Parse the address of an HTTP endpoint, as host:port, or an empty value to disable it
+/// Without a host, the endpoint listens on every interface.
+///
+/// * `value` - property value
+pub fn parse_http_listener(value: &str) -> Option<Option<String>> {
+    if value.is_empty() {
+        return Some(None);
+    }
+    let (host, port) = value.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    let host = if host.is_empty() { "0.0.0.0" } else { host };
+    Some(Some(format!("{}:{}", host, port)))
+}
+
+/// Parse a comma-separated list of listeners, which must have different names
+///
+/// * `value` - e.g. "INTERNAL://:9092,EXTERNAL://:29092"
+pub fn parse_listeners(value: &str) -> Option<Vec<Listener>> {
+    let listeners = value
+        .split(',')
+        .filter(|l| !l.trim().is_empty())
+        .map(Listener::parse)
+        .collect::<Option<Vec<_>>>()?;
+    let names = listeners.iter().map(|l| &l.name).collect::<HashSet<_>>();
+    if names.len() < listeners.len() {
+        return None;
+    }
+    Some(listeners)
+}
+
+/// Protocol spoken on a listener
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    pub const ALL: [SecurityProtocol; 4] = [
+        SecurityProtocol::Plaintext,
+        SecurityProtocol::Ssl,
+        SecurityProtocol::SaslPlaintext,
+        SecurityProtocol::SaslSsl,
+    ];
+
+    /// Parse a protocol by its Kafka name, e.g. "SASL_SSL"
+    ///
+    /// * `value` - protocol name
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|p| p.to_string().eq_ignore_ascii_case(value.trim()))
+            .copied()
+    }
+}
+
+impl fmt::Display for SecurityProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SecurityProtocol::Plaintext => "PLAINTEXT",
+            SecurityProtocol::Ssl => "SSL",
+            SecurityProtocol::SaslPlaintext => "SASL_PLAINTEXT",
+            SecurityProtocol::SaslSsl => "SASL_SSL",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parse the contents of a Java properties file
+/// Blank lines and comments (starting with # or !) are skipped. Keys are
+/// separated from values by the first `=` or `:`.
+///
+/// * `contents` - file contents
+pub fn parse_properties(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+        .map(|line| match line.find(['=', ':']) {
+            Some(i) => (
+                line[..i].trim().to_string(),
+                line[i + 1..].trim().to_string(),
+            ),
+            None => (line.to_string(), String::new()),
+        })
+        .collect()
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_server_properties() {
+        let contents = "
+# Kafka settings
+broker.id=7
+listeners=PLAINTEXT://:19092
+advertised.listeners = PLAINTEXT://kafka:19092
+zookeeper.connect: localhost:2181
+num.partitions=3
+log.retention.ms=1000
+";
+        let path =
+            std::env::temp_dir().join(format!("pseudokafka-{}.properties", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let (config, ignored) = BrokerConfig::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            ignored,
+            vec![(
+                "zookeeper.connect".to_string(),
+                "localhost:2181".to_string()
+            )]
+        );
+        assert_eq!(config.node_id, 7);
+        assert_eq!(
+            config.listeners,
+            vec![Listener::new("PLAINTEXT", "0.0.0.0", 19092)]
+        );
+        assert_eq!(
+            config.advertised_listener("PLAINTEXT"),
+            Some(&Listener::new("PLAINTEXT", "kafka", 19092))
+        );
+        assert_eq!(config.num_partitions, 3);
+        assert_eq!(config.default_topic_config.retention_ms, 1000);
+    }
+
+    #[test]
+    fn set_from_env() {
+        let mut config = BrokerConfig::default();
+        let vars = [
+            ("PSEUDOKAFKA_CLUSTER_ID", "test-cluster"),
+            ("PSEUDOKAFKA_AUTO_CREATE_TOPICS_ENABLE", "false"),
+            ("PSEUDOKAFKA_QUEUED_MAX_REQUEST_BYTES", "1024"),
+            ("HOME", "/root"),
+        ];
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        config.set_from_env(vars).unwrap();
+        assert_eq!(config.cluster_id, "test-cluster");
+        assert!(!config.auto_create_topics);
+        assert_eq!(config.queued_max_request_bytes, Some(1024));
+        assert!(!config.set("queued.max.request.bytes", "0"));
+        assert!(config.set("queued.max.request.bytes", "-1"));
+        assert_eq!(config.queued_max_request_bytes, None);
+
+        let vars = vec![("PSEUDOKAFKA_NODE_ID".to_string(), "x".to_string())];
+        assert!(config.set_from_env(vars).is_err());
+    }
+
+    #[test]
+    fn parse_listener() {
+        let listener = |name, host, port| Some(Listener::new(name, host, port));
+        assert_eq!(
+            Listener::parse("PLAINTEXT://host:1"),
+            listener("PLAINTEXT", "host", 1)
+        );
+        assert_eq!(Listener::parse("host:2"), listener("PLAINTEXT", "host", 2));
+        assert_eq!(
+            Listener::parse("external://[::1]:3"),
+            listener("EXTERNAL", "::1", 3)
+        );
+        assert_eq!(Listener::parse("SSL://:4"), listener("SSL", "", 4));
+        assert_eq!(Listener::parse("host"), None);
+        assert_eq!(Listener::parse("://host:5"), None);
+    }
+
+    #[test]
+    fn named_listeners() {
+        let mut config = BrokerConfig::default();
+        assert!(config.set("listeners", "INTERNAL://:9092,EXTERNAL://127.0.0.1:29092"));
+        assert!(config.set(
+            "advertised.listeners",
+            "INTERNAL://pseudokafka:9092,EXTERNAL://localhost:29092"
+        ));
+        assert!(config.set(
+            "listener.security.protocol.map",
+            "INTERNAL:PLAINTEXT,EXTERNAL:SASL_SSL"
+        ));
+        assert_eq!(
+            config.listeners[0],
+            Listener::new("INTERNAL", "0.0.0.0", 9092)
+        );
+        assert_eq!(
+            config.advertised_listener("EXTERNAL"),
+            Some(&Listener::new("EXTERNAL", "localhost", 29092))
+        );
+        assert_eq!(
+            config.security_protocol("EXTERNAL"),
+            Some(SecurityProtocol::SaslSsl)
+        );
+        assert_eq!(config.security_protocol("PLAINTEXT"), None);
+
+        assert!(!config.set("listeners", "A://:1,A://:2"));
+        assert!(!config.set("advertised.listeners", "A://:1"));
+        assert!(!config.set("listener.security.protocol.map", "A:TLS"));
+
+        assert!(config.set("admin.listener", ":8080"));
+        assert_eq!(config.admin_listener.as_deref(), Some("0.0.0.0:8080"));
+        assert!(!config.set("admin.listener", "localhost"));
+        assert!(config.set("admin.listener", ""));
+        assert_eq!(config.admin_listener, None);
+        assert!(config.set("metrics.listener", "127.0.0.1:9404"));
+        assert_eq!(config.metrics_listener.as_deref(), Some("127.0.0.1:9404"));
+        assert!(config.set("rest.listener", ":8082"));
+        assert_eq!(config.rest_listener.as_deref(), Some("0.0.0.0:8082"));
+        assert!(config.set("schema.registry.listener", "127.0.0.1:8081"));
+        assert_eq!(
+            config.schema_registry_listener.as_deref(),
+            Some("127.0.0.1:8081")
+        );
+    }
+
+    #[test]
+    fn logging() {
+        let mut config = BrokerConfig::default();
+        assert!(config.set("logging.level", "warn,pseudokafka::server=debug"));
+        assert!(config.set("logging.format", "json"));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert!(!config.set("logging.level", "loud=="));
+        assert!(!config.set("logging.format", "xml"));
+        assert_eq!(config.log_level, "warn,pseudokafka::server=debug");
+    }
+}
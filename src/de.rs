@@ -1,22 +1,19 @@
 use nom::{
-    count, do_parse,
+    call, cond, do_parse,
     error::{context, ErrorKind},
-    map_res, named,
+    length_value, map_opt, map_res, named,
     number::streaming::{be_i16, be_i32, be_i64, be_u16, be_u32, be_u64, be_u8},
-    take, tuple, IResult,
+    take, tuple, value, verify, IResult,
 };
 use num_traits::FromPrimitive;
 
 use std::io::Read;
 use std::mem;
 
+use crate::config::DEFAULT_SOCKET_REQUEST_MAX_BYTES;
 use crate::error::*;
 use crate::messages::*;
 
-// TODO: make it configurable replica.fetch.max.bytes
-// Default max size
-const MAX_MESSAGE_SIZE: usize = 1_048_576;
-
 type NomResult<T, U> = IResult<T, U, nom::error::Error<T>>;
 
 type DeserializeResult = Result<Request, KafkaError>;
@@ -28,42 +25,225 @@ pub struct RawRequest {
 }
 
 /// Deserialize a Kafka message from a stream
+/// Messages larger than the default `socket.request.max.bytes` are rejected.
 ///
 /// * `stream` - input stream
 pub fn from_stream(mut stream: impl Read) -> DeserializeResult {
     let mut size_buf = [0u8; mem::size_of::<i32>()];
     stream.read_exact(&mut size_buf)?;
-    let size = i32::from_be_bytes(size_buf) as usize;
-    if size > MAX_MESSAGE_SIZE {
-        return Err(KafkaError::MessageTooLargeError);
-    }
+    let size = request_size(size_buf, DEFAULT_SOCKET_REQUEST_MAX_BYTES)?;
 
     let mut contents = vec![0u8; size];
     stream.read_exact(&mut contents)?;
+    from_bytes(&contents)
+}
+
+/// Deserialize a Kafka message, without its length prefix
+///
+/// * `contents` - message bytes, starting with the request header
+pub fn from_bytes(contents: &[u8]) -> DeserializeResult {
+    let (_, (api_key, api_version, _)) =
+        header_prefix(contents).map_err(|e| parse_error(contents, e, "header"))?;
+    let api_key = ApiKey::from_u16(api_key).ok_or_else(|| {
+        KafkaError::new(
+            ErrorCode::InvalidRequest,
+            format!("unknown API key {}", api_key),
+        )
+    })?;
+    match api_key.supported_versions() {
+        Some((min, max)) if (min..=max).contains(&api_version) => (),
+        _ => {
+            return Err(
+                KafkaError::new(ErrorCode::UnsupportedVersion, "version not supported")
+                    .with_api(api_key, api_version),
+            )
+        }
+    }
+
+    let (rest, header) = parse_header(contents)
+        .map_err(|e| parse_error(contents, e, "header").with_api(api_key, api_version))?;
+    let body = match &header.api_key {
+        ApiKey::ApiVersions => ApiVersionsRequest::new_from_bytes(rest, header),
+        ApiKey::Metadata => MetadataRequest::new_from_bytes(rest, header),
+        ApiKey::Produce => ProduceRequest::new_from_bytes(rest, header),
+        ApiKey::Fetch => FetchRequest::new_from_bytes(rest, header),
+        ApiKey::OffsetForLeaderEpoch => OffsetForLeaderEpochRequest::new_from_bytes(rest, header),
+        ApiKey::ElectLeaders => ElectLeadersRequest::new_from_bytes(rest, header),
+        ApiKey::AlterPartitionReassignments => {
+            AlterPartitionReassignmentsRequest::new_from_bytes(rest, header)
+        }
+        ApiKey::ListPartitionReassignments => {
+            ListPartitionReassignmentsRequest::new_from_bytes(rest, header)
+        }
+        _ => Err(KafkaError::new(
+            ErrorCode::UnsupportedVersion,
+            "API not implemented",
+        )),
+    };
+    // Body parsers only see the body, offsets are relative to the whole message
+    let header_size = contents.len() - rest.len();
+    body.map_err(|e| KafkaError {
+        offset: e.offset.map(|offset| header_size + offset),
+        ..e.with_api(api_key, api_version)
+    })
+}
+
+/// Read the topics and partitions of a request, as far as they can be read
+/// A request that fails to parse is answered with an error on each of them, so
+/// this reads what it can: record batches are skipped without looking into
+/// them, and reading stops at the first field that can't be parsed.
+///
+/// * `contents` - message bytes, starting with the request header
+pub fn request_partitions(contents: &[u8]) -> Vec<TopicPartitions> {
+    let mut topics = vec![];
+    let (buf, header) = match parse_header(contents) {
+        Ok(parsed) => parsed,
+        Err(_) => return topics,
+    };
+    match header.api_key.supported_versions() {
+        Some((min, max)) if (min..=max).contains(&header.api_version) => (),
+        _ => return topics,
+    }
+    named!(
+        produce_prefix<()>,
+        do_parse!(_transactional_id: nullable_string >> _acks: be_i16 >> _timeout: be_i32 >> (()))
+    );
+    named!(
+        // Record batches, with an int32 length, -1 meaning null
+        produce_partition<()>,
+        do_parse!(
+            length: be_i32 >> _records: cond!(length > 0, take!(length as usize)) >> (())
+        )
+    );
+    // Fetch: replica id, max wait, min and max bytes, isolation level, session
+    // id and epoch, then current leader epoch, offsets and max bytes
+    named!(fetch_prefix<()>, do_parse!(_fields: take!(21) >> (())));
+    named!(fetch_partition<()>, do_parse!(_fields: take!(24) >> (())));
+    // OffsetForLeaderEpoch: replica id as of v3, then current (as of v2) and
+    // requested epochs
+    named!(epoch_prefix<()>, do_parse!(_replica_id: be_i32 >> (())));
+    named!(epoch_partition<()>, do_parse!(_epochs: take!(8) >> (())));
+    named!(epoch_partition_v0<()>, do_parse!(_epoch: take!(4) >> (())));
+    let _ = match header.api_key {
+        ApiKey::Metadata => partial_metadata_topics(buf, &mut topics),
+        ApiKey::Produce => produce_prefix(buf)
+            .and_then(|(buf, _)| partial_topics(buf, &mut topics, produce_partition)),
+        ApiKey::Fetch => fetch_prefix(buf)
+            .and_then(|(buf, _)| partial_topics(buf, &mut topics, fetch_partition)),
+        ApiKey::OffsetForLeaderEpoch => match header.api_version {
+            3.. => epoch_prefix(buf)
+                .and_then(|(buf, _)| partial_topics(buf, &mut topics, epoch_partition)),
+            2 => partial_topics(buf, &mut topics, epoch_partition),
+            _ => partial_topics(buf, &mut topics, epoch_partition_v0),
+        },
+        _ => Ok((buf, ())),
+    };
+    topics
+}
 
-    let (rest, header) = parse_header(&*contents)?;
-    match &header.api_key {
-        ApiKey::ApiVersions => Ok(ApiVersionsRequest::new_from_bytes(rest, header)?),
-        ApiKey::Metadata => Ok(MetadataRequest::new_from_bytes(rest, header)?),
-        ApiKey::Produce => Ok(ProduceRequest::new_from_bytes(rest, header)?),
-        _ => Err(KafkaError::UnknownMessageError(header.api_key)),
+// Read an array of topics, each a name and an array of partitions, pushing
+// every partition as soon as its id is read
+fn partial_topics<'a>(
+    mut buf: &'a [u8],
+    topics: &mut Vec<TopicPartitions>,
+    partition: fn(&'a [u8]) -> NomResult<&'a [u8], ()>,
+) -> NomResult<&'a [u8], ()> {
+    let (rest, num_topics) = be_u32(buf)?;
+    buf = rest;
+    for _ in 0..num_topics {
+        let (rest, (name, num_partitions)) = tuple!(buf, string, be_u32)?;
+        topics.push(TopicPartitions {
+            name,
+            partitions: vec![],
+        });
+        buf = rest;
+        for _ in 0..num_partitions {
+            let (rest, id) = be_u32(buf)?;
+            if let Some(topic) = topics.last_mut() {
+                topic.partitions.push(id);
+            }
+            let (rest, _) = partition(rest)?;
+            buf = rest;
+        }
+    }
+    Ok((buf, ()))
+}
+
+// Read the names of the topics of a Metadata request
+fn partial_metadata_topics<'a>(
+    buf: &'a [u8],
+    topics: &mut Vec<TopicPartitions>,
+) -> NomResult<&'a [u8], ()> {
+    let (mut buf, (_, num_topics)) = tuple!(buf, tagged_fields, unsigned_varint)?;
+    // Compact array, 0 is null
+    for _ in 1..num_topics {
+        let (rest, (name, _)) = tuple!(buf, compact_string, tagged_fields)?;
+        topics.extend(name.map(|name| TopicPartitions {
+            name,
+            partitions: vec![],
+        }));
+        buf = rest;
     }
+    Ok((buf, ()))
 }
 
+/// Read the size of a request from its length prefix
+///
+/// * `prefix` - length prefix of the request
+/// * `max_size` - largest request accepted (`socket.request.max.bytes`)
+pub fn request_size(prefix: [u8; 4], max_size: usize) -> Result<usize, KafkaError> {
+    let size = i32::from_be_bytes(prefix);
+    if size < 0 {
+        Err(KafkaError::new(
+            ErrorCode::InvalidRequest,
+            format!("negative request size {}", size),
+        ))
+    } else if size as usize > max_size {
+        Err(KafkaError::new(
+            ErrorCode::MessageTooLarge,
+            format!("request of {} bytes, the maximum is {}", size, max_size),
+        ))
+    } else {
+        Ok(size as usize)
+    }
+}
+
+/// Error for a failed parse, pointing at the byte where it stopped
+///
+/// * `buf` - input buffer the parser started on
+/// * `err` - parser error
+/// * `field` - part of the message being parsed
+fn parse_error(
+    buf: &[u8],
+    err: nom::Err<nom::error::Error<&[u8]>>,
+    field: &'static str,
+) -> KafkaError {
+    let (offset, message) = match err {
+        nom::Err::Incomplete(_) => (buf.len(), "truncated message".to_string()),
+        nom::Err::Error(e) | nom::Err::Failure(e) => (
+            // Inner parsers may work on a copy, so the input isn't always within buf
+            (e.input.as_ptr() as usize)
+                .saturating_sub(buf.as_ptr() as usize)
+                .min(buf.len()),
+            format!("{:?} failed", e.code),
+        ),
+    };
+    KafkaError::new(ErrorCode::CorruptMessage, message).at(offset, field)
+}
+
+// Parse api_key, api_version and correlation_id, which even a request that
+// can't be processed has, so it can be answered with an error
+named!(pub header_prefix<(u16, u16, u32)>, tuple!(be_u16, be_u16, be_u32));
+
 /// Parse the header of the Kafka messages, so the specific type can be instantiated
 /// Returns a NomResult so parsing can continue within each specialized message type.
 ///
 /// * `buf` - input buffer as bytes
 pub fn parse_header(buf: &[u8]) -> NomResult<&[u8], RequestHeader> {
     named!(
-        client_id,
-        // client_id is a length-prefixed string
-        do_parse!(length: be_u16 >> bytes: take!(length) >> (bytes))
-    );
-    named!(
-        fixed_size_fields<(u16, u16, u32, &[u8])>,
+        fixed_size_fields<(u16, u16, u32, Option<String>)>,
         // Parse api_key, api_version, correlation_id, client_id
-        tuple!(be_u16, be_u16, be_u32, client_id)
+        tuple!(be_u16, be_u16, be_u32, nullable_string)
     );
     named!(
         header<&[u8], RequestHeader>,
@@ -76,7 +256,7 @@ pub fn parse_header(buf: &[u8]) -> NomResult<&[u8], RequestHeader> {
                         api_key,
                         api_version,
                         correlation_id,
-                        client_id: Some(std::str::from_utf8(client_id).unwrap().to_string()),
+                        client_id,
                     }),
                     None => Err(nom::Err::Error((api_key_u16, ErrorKind::Digit))),
                 }
@@ -90,13 +270,299 @@ pub fn parse_header(buf: &[u8]) -> NomResult<&[u8], RequestHeader> {
 // Common parsers
 //
 
+/// Parse an unsigned variable length integer, as used by flexible versions
+///
+/// * `buf` - input buffer as bytes
+fn unsigned_varint(buf: &[u8]) -> NomResult<&[u8], u64> {
+    let mut value: u64 = 0;
+    for (i, b) in buf.iter().enumerate().take(10) {
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((&buf[i + 1..], value));
+        }
+    }
+    if buf.len() < 10 {
+        Err(nom::Err::Incomplete(nom::Needed::new(1)))
+    } else {
+        Err(nom::Err::Error(nom::error::Error::new(
+            buf,
+            ErrorKind::TooLarge,
+        )))
+    }
+}
+
+/// Parse a zigzag-encoded variable length integer, as used inside record batches
+///
+/// * `buf` - input buffer as bytes
+fn varint(buf: &[u8]) -> NomResult<&[u8], i64> {
+    let (rest, value) = unsigned_varint(buf)?;
+    Ok((rest, ((value >> 1) as i64) ^ -((value & 1) as i64)))
+}
+
+/// Apply a parser as many times as a count read from the message
+/// Every item takes at least a byte, so a count beyond the end of the input is
+/// rejected before allocating anything.
+///
+/// * `buf` - input buffer as bytes
+/// * `f` - parser of each item
+/// * `n` - number of items
+fn bounded_count<'a, O>(
+    buf: &'a [u8],
+    f: impl FnMut(&'a [u8]) -> NomResult<&'a [u8], O>,
+    n: usize,
+) -> NomResult<&'a [u8], Vec<O>> {
+    if n > buf.len() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            buf,
+            ErrorKind::Count,
+        )));
+    }
+    nom::multi::count(f, n)(buf)
+}
+
+/// Apply a parser to the items of a compact array, as used by flexible versions
+/// Returns None for a null array.
+///
+/// * `buf` - input buffer as bytes
+/// * `f` - parser of each item
+fn compact_array<'a, O>(
+    buf: &'a [u8],
+    f: fn(&'a [u8]) -> NomResult<&'a [u8], O>,
+) -> NomResult<&'a [u8], Option<Vec<O>>> {
+    // The length plus one, 0 meaning null
+    let (buf, n) = unsigned_varint(buf)?;
+    if n == 0 {
+        return Ok((buf, None));
+    }
+    let (buf, items) = bounded_count(buf, f, (n - 1) as usize)?;
+    Ok((buf, Some(items)))
+}
+
+fn utf8(bytes: &[u8]) -> Result<String, std::str::Utf8Error> {
+    std::str::from_utf8(bytes).map(|s| s.to_string())
+}
+
+named!(
+    // String with an int16 length
+    string<String>,
+    map_res!(do_parse!(length: be_u16 >> bytes: take!(length) >> (bytes)), utf8)
+);
+
+named!(
+    // Nullable string with an int16 length, -1 meaning null
+    nullable_string<Option<String>>,
+    do_parse!(
+        length: verify!(be_i16, |l: &i16| *l >= -1)
+            >> string: cond!(length >= 0, map_res!(take!(length as usize), utf8))
+            >> (string)
+    )
+);
+
+named!(
+    // Nullable string with an unsigned varint length plus one, 0 meaning null
+    compact_string<Option<String>>,
+    do_parse!(
+        length: unsigned_varint
+            >> string: cond!(length > 0, map_res!(take!(length - 1), utf8))
+            >> (string)
+    )
+);
+
+named!(
+    // Compact string that can't be null
+    compact_name<String>,
+    map_opt!(compact_string, |s: Option<String>| s)
+);
+
+named!(
+    // Compact array of int32 that can't be null
+    compact_ids<Vec<u32>>,
+    map_opt!(call!(compact_array, be_u32), |ids: Option<Vec<u32>>| ids)
+);
+
+named!(
+    // Nullable bytes with a varint length, -1 meaning null
+    varint_bytes<Option<Vec<u8>>>,
+    do_parse!(
+        length: verify!(varint, |l: &i64| *l >= -1)
+            >> bytes: cond!(length >= 0, take!(length as usize))
+            >> (bytes.map(|b| b.to_vec()))
+    )
+);
+
 named!(
-    tagged_fields,
+    tagged_field,
+    do_parse!(_tag: unsigned_varint >> size: unsigned_varint >> data: take!(size) >> (data))
+);
+
+named!(
+    tagged_fields<Vec<&[u8]>>,
     // TODO: implement real support for tagged fields
     // We are currently ignoring these
-    do_parse!(length: be_u8 >> bytes: take!(length) >> (bytes))
+    do_parse!(
+        n: unsigned_varint
+            >> fields: call!(bounded_count, tagged_field, n as usize)
+            >> (fields)
+    )
 );
 
+named!(
+    // Topic and partition ids, in admin requests
+    topic_partitions<TopicPartitions>,
+    do_parse!(
+        name: compact_name
+            >> partitions: compact_ids
+            >> _tags: tagged_fields
+            >> (TopicPartitions { name, partitions })
+    )
+);
+
+named!(
+    record_header<RecordHeader>,
+    do_parse!(
+        key_length: verify!(varint, |l: &i64| *l >= 0)
+            >> key: map_res!(take!(key_length as usize), utf8)
+            >> value: varint_bytes
+            >> (RecordHeader { key, value })
+    )
+);
+
+named!(
+    record_body<ProduceRecordRequest>,
+    do_parse!(
+        _attributes: be_u8
+            >> timestamp_delta: varint
+            >> offset_delta: varint
+            >> key: varint_bytes
+            >> value: varint_bytes
+            >> num_headers: verify!(varint, |n: &i64| *n >= 0)
+            >> headers: call!(bounded_count, record_header, num_headers as usize)
+            >> (ProduceRecordRequest {
+                timestamp_delta,
+                offset_delta: offset_delta as i32,
+                key,
+                value,
+                headers,
+            }))
+);
+
+/// Parse a record, which must take exactly the length it starts with
+///
+/// * `buf` - input buffer as bytes
+fn record(buf: &[u8]) -> NomResult<&[u8], ProduceRecordRequest> {
+    let (rest, length) = varint(buf)?;
+    if length < 0 || length as usize > rest.len() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            buf,
+            ErrorKind::LengthValue,
+        )));
+    }
+    let (body, rest) = rest.split_at(length as usize);
+    match record_body(body) {
+        Ok((&[], record)) => Ok((rest, record)),
+        Ok((left, _)) => Err(nom::Err::Error(nom::error::Error::new(
+            left,
+            ErrorKind::LengthValue,
+        ))),
+        // Within its length, a record that ends early is corrupt, not truncated
+        Err(nom::Err::Incomplete(_)) => Err(nom::Err::Error(nom::error::Error::new(
+            body,
+            ErrorKind::Complete,
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse the records of a batch, which take the rest of its length
+/// Compressed records are skipped, as they can't be read.
+///
+/// * `buf` - input buffer, after the record count
+/// * `length` - length of the batch, from the leader epoch on
+/// * `options` - attributes of the batch
+/// * `count` - number of records
+fn batch_records(
+    buf: &[u8],
+    length: u32,
+    options: u16,
+    count: u32,
+) -> NomResult<&[u8], Vec<ProduceRecordRequest>> {
+    // Leader epoch to record count, as counted in the length
+    const HEADER_LENGTH: u32 = 49;
+    let (rest, records) = match length.checked_sub(HEADER_LENGTH) {
+        Some(length) => take!(buf, length as usize)?,
+        None => {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                buf,
+                ErrorKind::LengthValue,
+            )))
+        }
+    };
+    if options & 0x07 != 0 {
+        return Ok((rest, vec![]));
+    }
+    match bounded_count(records, record, count as usize) {
+        Ok((&[], records)) => Ok((rest, records)),
+        Ok((left, _)) => Err(nom::Err::Error(nom::error::Error::new(
+            left,
+            ErrorKind::LengthValue,
+        ))),
+        Err(nom::Err::Incomplete(_)) => Err(nom::Err::Error(nom::error::Error::new(
+            records,
+            ErrorKind::Complete,
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+// Parse a record batch (magic v2), as found in Produce requests and segment files
+named!(
+    pub record_batch<ProduceRecordBatchRequest>,
+    do_parse!(
+        offset: be_u64
+            >> batch_length: verify!(be_u32, |l: &u32| *l <= i32::MAX as u32)
+            >> leader_epoch: be_i32
+            >> _magic_byte: be_u8 // ignored
+            >> _crc32: be_u32 // ignored
+            >> options: be_u16
+            >> last_offset_delta: be_u32
+            >> first_timestamp: be_u64
+            >> last_timestamp: be_u64
+            >> producer_id: be_i64
+            >> producer_epoch: be_i16
+            >> base_sequence: be_i32
+            >> size: be_u32
+            >> records: call!(batch_records, batch_length, options, size)
+            >> (ProduceRecordBatchRequest {
+                offset,
+                leader_epoch,
+                options,
+                last_offset_delta,
+                first_timestamp,
+                last_timestamp,
+                producer_id,
+                producer_epoch,
+                base_sequence,
+                // base offset and batch length fields are not part of the length
+                size: batch_length + 12,
+                records,
+            })
+    )
+);
+
+/// Parse the record batches of a Produce partition, which take all the input
+///
+/// * `buf` - input buffer, bounded to the length of the batches
+fn record_batches(buf: &[u8]) -> NomResult<&[u8], Vec<ProduceRecordBatchRequest>> {
+    let mut batches = vec![];
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let (left, batch) = record_batch(rest)?;
+        batches.push(batch);
+        rest = left;
+    }
+    Ok((rest, batches))
+}
+
 /// Deserialize trait
 ///
 /// All the message body types need to implement this for deserialization
@@ -114,25 +580,21 @@ impl Deserialize for ApiVersionsRequest {
 impl Deserialize for MetadataRequest {
     fn new_from_bytes(buf: &[u8], header: RequestHeader) -> DeserializeResult {
         named!(
-            topic<String>,
-            do_parse!(
-                length: be_u8
-                >> bytes: take!(length-1)
-                >> ignore: take!(1) // it seems Kakfa uses also a null-ending
-                >> (std::str::from_utf8(bytes).unwrap().to_string())
-            )
+            topic<Option<String>>,
+            do_parse!(name: compact_string >> _tags: tagged_fields >> (name))
         );
         named!(
             topics<Vec<String>>,
             do_parse!(
-                num_topics: be_u8
-                    >> topics_ls: count!(topic, (num_topics - 1) as usize)
-                    >> (topics_ls)
+                // Compact array, 0 is null
+                num_topics: unsigned_varint
+                    >> topics_ls: call!(bounded_count, topic, num_topics.saturating_sub(1) as usize)
+                    >> (topics_ls.into_iter().flatten().collect())
             )
         );
         named!(options<(u8, u8, u8)>, tuple!(be_u8, be_u8, be_u8));
         named!(
-            metadata<(&[u8], Vec<String>, (u8, u8, u8), &[u8])>,
+            metadata<(Vec<&[u8]>, Vec<String>, (u8, u8, u8), Vec<&[u8]>)>,
             tuple!(tagged_fields, topics, options, tagged_fields)
         );
         match metadata(buf) {
@@ -145,87 +607,48 @@ impl Deserialize for MetadataRequest {
                     include_topic_authorized_operations: o3 != 0,
                 }))
             }
-            Err(_) => Err(KafkaError::DeserializeError),
+            Err(e) => Err(parse_error(buf, e, "metadata request")),
         }
     }
 }
 
 impl Deserialize for ProduceRequest {
     fn new_from_bytes(buf: &[u8], header: RequestHeader) -> DeserializeResult {
-        named!(
-            record<ProduceRecordRequest>,
-            do_parse!(
-                unknown: be_u8
-                    >> record_attributes: be_u8
-                    >> timestamp: be_u8 // this u8 is according to wireshark a timestamp (?)
-                    >> offset: be_u8
-                    >> unknown2: be_u8 // TODO
-                    >> unknown3: be_u8 // TODO
-                    >> value: take!(1)
-                    >> headers: be_u8 // TODO
-                    >> (ProduceRecordRequest {
-                        value: value.to_vec()
-                    }))
-        );
         named!(
             partition<ProducePartitionRequest>,
             do_parse!(
                 partition_id: be_u32
-                    >> num_bytes: be_u32 // ignored
-                    >> offset: be_u64
-                    >> msg_size: be_u32 // ignored
-                    >> leader_epoch: be_i32
-                    >> magic_byte: be_u8 // ignored
-                    >> crc32: be_u32 // ignored
-                    >> options: be_u16
-                    >> last_offset_delta: be_u32
-                    >> first_timestamp: be_u64
-                    >> last_timestamp: be_u64
-                    >> producer_id: be_i64
-                    >> producer_epoch: be_i16
-                    >> base_sequence: be_i32
-                    >> size: be_u32
-                    >> records: count!(record, size as usize)
+                    // Record batches, with an int32 length, -1 meaning null
+                    >> num_bytes: verify!(be_i32, |l: &i32| *l >= -1)
+                    >> batches: cond!(
+                        num_bytes >= 0,
+                        length_value!(value!(num_bytes as u32), record_batches)
+                    )
                     >> (ProducePartitionRequest {
                         id: partition_id,
-                        message_set: ProduceRecordBatchRequest {
-                            offset,
-                            leader_epoch,
-                            options,
-                            last_offset_delta,
-                            first_timestamp,
-                            last_timestamp,
-                            producer_id,
-                            producer_epoch,
-                            base_sequence,
-                            records,
-                        }
+                        batches: batches.unwrap_or_default(),
                     })
             )
         );
         named!(
             topic<ProduceTopicRequest>,
             do_parse!(
-                name_length: be_u16
-                    >> name_bytes: take!(name_length)
+                name: string
                     >> num_partitions: be_u32
-                    >> partitions: count!(partition, num_partitions as usize)
-                    >> (ProduceTopicRequest {
-                        name: std::str::from_utf8(name_bytes).unwrap().to_string(),
-                        partitions: partitions,
-                    })
+                    >> partitions: call!(bounded_count, partition, num_partitions as usize)
+                    >> (ProduceTopicRequest { name, partitions })
             )
         );
         named!(
             topics<Vec<ProduceTopicRequest>>,
-            do_parse!(n: be_u32 >> topics: count!(topic, n as usize) >> (topics))
+            do_parse!(n: be_u32 >> topics: call!(bounded_count, topic, n as usize) >> (topics))
         );
         named!(
-            produce_header<(i16, u16, u32)>,
-            tuple!(be_i16, be_u16, be_u32)
+            produce_header<(Option<String>, i16, u32)>,
+            tuple!(nullable_string, be_i16, be_u32)
         );
         named!(
-            produce_request<((i16, u16, u32), Vec<ProduceTopicRequest>)>,
+            produce_request<((Option<String>, i16, u32), Vec<ProduceTopicRequest>)>,
             tuple!(produce_header, topics)
         );
         match produce_request(buf) {
@@ -235,10 +658,223 @@ impl Deserialize for ProduceRequest {
                     transactional_id: tx_id,
                     required_acks: req_acks,
                     timeout: tout,
-                    topics: topics,
+                    topics,
+                }))
+            }
+            Err(e) => Err(parse_error(buf, e, "produce request")),
+        }
+    }
+}
+
+impl Deserialize for FetchRequest {
+    fn new_from_bytes(buf: &[u8], header: RequestHeader) -> DeserializeResult {
+        named!(
+            partition<FetchPartition>,
+            do_parse!(
+                id: be_u32
+                    >> current_leader_epoch: be_i32
+                    >> fetch_offset: be_i64
+                    >> _log_start_offset: be_i64
+                    >> partition_max_bytes: be_u32
+                    >> (FetchPartition {
+                        id,
+                        current_leader_epoch,
+                        fetch_offset,
+                        partition_max_bytes,
+                    })
+            )
+        );
+        named!(
+            topic<FetchTopic>,
+            do_parse!(
+                name: string
+                    >> n: be_u32
+                    >> partitions: call!(bounded_count, partition, n as usize)
+                    >> (FetchTopic { name, partitions })
+            )
+        );
+        named!(
+            forgotten_topic<Vec<u32>>,
+            do_parse!(
+                _name: string
+                    >> n: be_u32
+                    >> partitions: call!(bounded_count, be_u32, n as usize)
+                    >> (partitions)
+            )
+        );
+        named!(
+            fetch_request<(u32, u32, u32, Vec<FetchTopic>)>,
+            do_parse!(
+                _replica_id: be_i32
+                    >> max_wait: be_u32
+                    >> min_bytes: be_u32
+                    >> max_bytes: be_u32
+                    >> _isolation_level: be_u8
+                    >> _session_id: be_u32
+                    >> _session_epoch: be_i32
+                    >> n: be_u32
+                    >> topics: call!(bounded_count, topic, n as usize)
+                    >> n_forgotten: be_u32
+                    >> _forgotten: call!(bounded_count, forgotten_topic, n_forgotten as usize)
+                    >> _rack_id: string
+                    >> ((max_wait, min_bytes, max_bytes, topics))
+            )
+        );
+        match fetch_request(buf) {
+            Ok((_, (max_wait, min_bytes, max_bytes, topics))) => {
+                Ok(Request::FetchRequest(FetchRequest {
+                    header,
+                    max_wait,
+                    min_bytes,
+                    max_bytes,
+                    topics,
                 }))
             }
-            Err(_) => Err(KafkaError::DeserializeError),
+            Err(e) => Err(parse_error(buf, e, "fetch request")),
+        }
+    }
+}
+
+impl Deserialize for OffsetForLeaderEpochRequest {
+    fn new_from_bytes<'a>(buf: &'a [u8], header: RequestHeader) -> DeserializeResult {
+        // The current leader epoch was added in v2, and the replica id in v3
+        let version = header.api_version;
+        let partition = |buf: &'a [u8]| {
+            do_parse!(
+                buf,
+                id: be_u32
+                    >> current_leader_epoch: cond!(version >= 2, be_i32)
+                    >> leader_epoch: be_i32
+                    >> (OffsetForLeaderEpochPartition {
+                        id,
+                        current_leader_epoch: current_leader_epoch.unwrap_or(-1),
+                        leader_epoch,
+                    })
+            )
+        };
+        let topic = |buf: &'a [u8]| {
+            do_parse!(
+                buf,
+                name: string
+                    >> n: be_u32
+                    >> partitions: call!(bounded_count, partition, n as usize)
+                    >> (OffsetForLeaderEpochTopic { name, partitions })
+            )
+        };
+        let offset_for_leader_epoch = do_parse!(
+            buf,
+            replica_id: cond!(version >= 3, be_i32)
+                >> n: be_u32
+                >> topics: call!(bounded_count, topic, n as usize)
+                >> ((replica_id.unwrap_or(-1), topics))
+        );
+        match offset_for_leader_epoch {
+            Ok((_, (replica_id, topics))) => Ok(Request::OffsetForLeaderEpochRequest(
+                OffsetForLeaderEpochRequest {
+                    header,
+                    replica_id,
+                    topics,
+                },
+            )),
+            Err(e) => Err(parse_error(buf, e, "offset for leader epoch request")),
+        }
+    }
+}
+
+impl Deserialize for ElectLeadersRequest {
+    fn new_from_bytes(buf: &[u8], header: RequestHeader) -> DeserializeResult {
+        named!(
+            elect_leaders<(u8, Option<Vec<TopicPartitions>>, u32)>,
+            do_parse!(
+                _header_tags: tagged_fields
+                    >> election_type: verify!(be_u8, |t: &u8| *t <= 1)
+                    >> topics: call!(compact_array, topic_partitions)
+                    >> timeout: be_u32
+                    >> _tags: tagged_fields
+                    >> ((election_type, topics, timeout))
+            )
+        );
+        match elect_leaders(buf) {
+            Ok((_, (election_type, topics, timeout))) => {
+                Ok(Request::ElectLeadersRequest(ElectLeadersRequest {
+                    header,
+                    election_type: match election_type {
+                        0 => ElectionType::Preferred,
+                        _ => ElectionType::Unclean,
+                    },
+                    topics,
+                    timeout,
+                }))
+            }
+            Err(e) => Err(parse_error(buf, e, "elect leaders request")),
+        }
+    }
+}
+
+impl Deserialize for AlterPartitionReassignmentsRequest {
+    fn new_from_bytes(buf: &[u8], header: RequestHeader) -> DeserializeResult {
+        named!(
+            partition<ReassignablePartition>,
+            do_parse!(
+                id: be_u32
+                    >> replicas: call!(compact_array, be_u32)
+                    >> _tags: tagged_fields
+                    >> (ReassignablePartition { id, replicas })
+            )
+        );
+        named!(
+            topic<ReassignableTopic>,
+            do_parse!(
+                name: compact_name
+                    >> partitions: map_opt!(call!(compact_array, partition), |p| p)
+                    >> _tags: tagged_fields
+                    >> (ReassignableTopic { name, partitions })
+            )
+        );
+        named!(
+            alter_reassignments<(u32, Vec<ReassignableTopic>)>,
+            do_parse!(
+                _header_tags: tagged_fields
+                    >> timeout: be_u32
+                    >> topics: map_opt!(call!(compact_array, topic), |t| t)
+                    >> _tags: tagged_fields
+                    >> ((timeout, topics))
+            )
+        );
+        match alter_reassignments(buf) {
+            Ok((_, (timeout, topics))) => Ok(Request::AlterPartitionReassignmentsRequest(
+                AlterPartitionReassignmentsRequest {
+                    header,
+                    timeout,
+                    topics,
+                },
+            )),
+            Err(e) => Err(parse_error(buf, e, "alter partition reassignments request")),
+        }
+    }
+}
+
+impl Deserialize for ListPartitionReassignmentsRequest {
+    fn new_from_bytes(buf: &[u8], header: RequestHeader) -> DeserializeResult {
+        named!(
+            list_reassignments<(u32, Option<Vec<TopicPartitions>>)>,
+            do_parse!(
+                _header_tags: tagged_fields
+                    >> timeout: be_u32
+                    >> topics: call!(compact_array, topic_partitions)
+                    >> _tags: tagged_fields
+                    >> ((timeout, topics))
+            )
+        );
+        match list_reassignments(buf) {
+            Ok((_, (timeout, topics))) => Ok(Request::ListPartitionReassignmentsRequest(
+                ListPartitionReassignmentsRequest {
+                    header,
+                    timeout,
+                    topics,
+                },
+            )),
+            Err(e) => Err(parse_error(buf, e, "list partition reassignments request")),
         }
     }
 }
@@ -249,11 +885,67 @@ impl Deserialize for ProduceRequest {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_varints() {
+        assert_eq!(varint(&[0x00]), Ok((&[][..], 0)));
+        assert_eq!(varint(&[0x01]), Ok((&[][..], -1)));
+        assert_eq!(varint(&[0x0e, 0xff]), Ok((&[0xff][..], 7)));
+        assert_eq!(varint(&[0xac, 0x02]), Ok((&[][..], 150)));
+        assert!(varint(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn malformed_requests_are_errors() {
+        // Metadata v9 with a compact array of 0 (null) topics
+        let metadata = [0, 0, 0, 16, 0, 3, 0, 9, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0];
+        assert!(matches!(
+            from_stream(&metadata[..]),
+            Ok(Request::MetadataRequest(_))
+        ));
+
+        let produce = include_bytes!("../res/produce_request.bin");
+        for bytes in [&metadata[..], &produce[..]] {
+            // Every truncation, and lots of pseudo-random corruptions
+            for len in 0..bytes.len() {
+                let _ = from_stream(&bytes[..len]);
+            }
+            let mut seed: u32 = 0x9e37_79b9;
+            for _ in 0..20_000 {
+                let mut bytes = bytes.to_vec();
+                for _ in 0..1 + seed % 4 {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 17;
+                    seed ^= seed << 5;
+                    let i = 4 + seed as usize % (bytes.len() - 4);
+                    bytes[i] = (seed >> 24) as u8;
+                }
+                let _ = from_stream(&bytes[..]);
+            }
+        }
+
+        // Invalid UTF-8 in the client id
+        let bytes = [0, 0, 0, 11, 0, 18, 0, 3, 0, 0, 0, 1, 0, 1, 0xff];
+        let e = from_stream(&bytes[..]).unwrap_err();
+        assert_eq!(e.code, ErrorCode::CorruptMessage);
+    }
+
+    #[test]
+    fn errors_point_at_the_failure() {
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let e = from_bytes(&bytes[4..40]).unwrap_err();
+        assert_eq!(e.code, ErrorCode::CorruptMessage);
+        assert_eq!(e.api, Some((ApiKey::Produce, 8)));
+        assert_eq!((e.offset, e.field), (Some(36), Some("produce request")));
+
+        let e = from_bytes(&[0, 18, 0, 9, 0, 0, 0, 1]).unwrap_err();
+        assert_eq!(e.code, ErrorCode::UnsupportedVersion);
+        assert_eq!(e.api, Some((ApiKey::ApiVersions, 9)));
+    }
+
     #[test]
     fn deserialize_produce_request() {
         let bytes = include_bytes!("../res/produce_request.bin");
         if let Request::ProduceRequest(r) = from_stream(&bytes[..]).unwrap() {
-            dbg!(&r);
             assert_eq!(
                 r,
                 ProduceRequest {
@@ -263,14 +955,14 @@ mod tests {
                         correlation_id: 4,
                         client_id: Some("console-producer".to_string()),
                     },
-                    transactional_id: -1,
+                    transactional_id: None,
                     required_acks: 1,
                     timeout: 1500,
                     topics: vec![ProduceTopicRequest {
                         name: "my-topic".to_string(),
                         partitions: vec![ProducePartitionRequest {
                             id: 0,
-                            message_set: ProduceRecordBatchRequest {
+                            batches: vec![ProduceRecordBatchRequest {
                                 offset: 0,
                                 leader_epoch: -1,
                                 options: 0,
@@ -280,16 +972,114 @@ mod tests {
                                 producer_id: -1,
                                 producer_epoch: -1,
                                 base_sequence: -1,
+                                size: 69,
                                 records: vec![
                                     ProduceRecordRequest{
-                                        value: vec!['a' as u8]
+                                        timestamp_delta: 0,
+                                        offset_delta: 0,
+                                        key: None,
+                                        value: Some(vec![b'a']),
+                                        headers: vec![],
                                     }
                                 ],
-                            },
+                            }],
                         }]
                     }],
                 }
             );
         }
     }
+
+    #[test]
+    fn read_every_produce_batch() {
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let batches = |body: Vec<u8>| match from_bytes(&body) {
+            Ok(Request::ProduceRequest(r)) => Some(r.topics[0].partitions[0].batches.len()),
+            _ => None,
+        };
+        let with_batches = |num_bytes: i32, batches: &[u8]| {
+            let mut body = bytes[4..60].to_vec();
+            body.extend_from_slice(&num_bytes.to_be_bytes());
+            body.extend_from_slice(batches);
+            body
+        };
+        let batch = &bytes[64..];
+        assert_eq!(batches(bytes[4..].to_vec()), Some(1));
+
+        // Null, empty, and two batches
+        assert_eq!(batches(with_batches(-1, &[])), Some(0));
+        assert_eq!(batches(with_batches(0, &[])), Some(0));
+        let two = [batch, batch].concat();
+        assert_eq!(batches(with_batches(two.len() as i32, &two)), Some(2));
+
+        // Batches must take all of their length, and no more
+        assert_eq!(batches(with_batches(two.len() as i32 - 1, &two)), None);
+        assert_eq!(batches(with_batches(batch.len() as i32 - 1, batch)), None);
+        assert_eq!(batches(with_batches(-2, &[])), None);
+    }
+
+    #[test]
+    fn read_records_within_their_lengths() {
+        let bytes = include_bytes!("../res/produce_request.bin");
+        let batch = |bytes: &[u8]| record_batch(&bytes[64..]).ok().map(|(_, batch)| batch);
+        assert_eq!(batch(bytes).unwrap().records.len(), 1);
+
+        // Compressed records are skipped, whatever they hold
+        let mut compressed = bytes.to_vec();
+        compressed[86] = 4; // zstd
+        compressed[125..].copy_from_slice(&[0xff; 8]);
+        let parsed = batch(&compressed).unwrap();
+        assert_eq!((parsed.compression(), parsed.records.len()), (4, 0));
+
+        // A record shorter or longer than its length
+        for length in [12, 16] {
+            let mut bytes = bytes.to_vec();
+            bytes[125] = length;
+            assert!(batch(&bytes).is_none());
+        }
+        // A batch length shorter than its header
+        let mut bytes = bytes.to_vec();
+        bytes[72..76].copy_from_slice(&[0, 0, 0, 20]);
+        assert!(batch(&bytes).is_none());
+    }
+
+    #[test]
+    fn deserialize_alter_partition_reassignments() {
+        let bytes = [
+            0, 45, 0, 0, 0, 0, 0, 1, 0, 1, b'c', 0, // header
+            0, 0, 0x75, 0x30, 2, 2, b't', 3, // timeout, topic t, 2 partitions
+            0, 0, 0, 0, 3, 0, 0, 3, 0xeb, 0, 0, 3, 0xec, 0, // 0 moves to 1003, 1004
+            0, 0, 0, 1, 0, 0, // 1 cancels
+            0, 0,
+        ];
+        let r = match from_bytes(&bytes).unwrap() {
+            Request::AlterPartitionReassignmentsRequest(r) => r,
+            r => panic!("unexpected request {:?}", r),
+        };
+        assert_eq!(
+            r,
+            AlterPartitionReassignmentsRequest {
+                header: RequestHeader {
+                    api_key: ApiKey::AlterPartitionReassignments,
+                    api_version: 0,
+                    correlation_id: 1,
+                    client_id: Some("c".to_string()),
+                },
+                timeout: 30_000,
+                topics: vec![ReassignableTopic {
+                    name: "t".to_string(),
+                    partitions: vec![
+                        ReassignablePartition {
+                            id: 0,
+                            replicas: Some(vec![1003, 1004]),
+                        },
+                        ReassignablePartition {
+                            id: 1,
+                            replicas: None,
+                        },
+                    ],
+                }],
+            }
+        );
+    }
 }
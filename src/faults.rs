@@ -0,0 +1,418 @@
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::error::ErrorCode;
+use crate::messages::Request;
+
+/// A failure to inject into the requests it matches
+///
+/// Every criterion left out matches anything. Effects can be combined, e.g. an
+/// error answered late.
+///
+/// ```json
+/// {"api_key": 0, "topic": "orders", "partition": 1, "every": 3, "error": "NOT_LEADER_OR_FOLLOWER"}
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fault {
+    /// API key of the requests, e.g. 0 for Produce
+    pub api_key: Option<u16>,
+    pub client_id: Option<String>,
+    /// Topic the requests refer to
+    pub topic: Option<String>,
+    /// Partition the requests refer to, of any topic unless one is given
+    pub partition: Option<u32>,
+    /// Only fail every Nth matching request, all of them when missing
+    pub every: Option<u32>,
+    /// Number of requests to fail before the fault is removed, unlimited when missing
+    pub times: Option<u32>,
+
+    /// Error to answer with, for the matching partitions (or the whole request)
+    pub error: Option<ErrorCode>,
+    /// Time to wait before answering
+    pub latency_ms: Option<u64>,
+    /// Close the connection instead of answering
+    pub disconnect: Option<Disconnect>,
+    /// Send only the first bytes of the response (after its length), in a
+    /// frame of that size
+    pub truncate: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disconnect {
+    /// Without writing anything
+    BeforeResponse,
+    /// After writing half the response
+    MidResponse,
+}
+
+/// How a response is written back
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Delivery {
+    #[default]
+    Respond,
+    /// Keep only the first bytes of the response body
+    Truncate(usize),
+    CloseMidResponse,
+    Close,
+}
+
+impl Delivery {
+    /// Apply to an encoded response, length included
+    /// Returns the bytes to write, and whether to close the connection after them.
+    ///
+    /// * `bytes` - encoded response
+    pub fn apply(self, mut bytes: Vec<u8>) -> (Vec<u8>, bool) {
+        match self {
+            Delivery::Respond => (bytes, false),
+            Delivery::Truncate(size) => {
+                let size = size.min(bytes.len() - 4);
+                bytes.truncate(4 + size);
+                bytes[..4].copy_from_slice(&(size as u32).to_be_bytes());
+                (bytes, false)
+            }
+            Delivery::CloseMidResponse => {
+                bytes.truncate(bytes.len() / 2);
+                (bytes, true)
+            }
+            Delivery::Close => (vec![], true),
+        }
+    }
+}
+
+/// Faults triggered by a request
+#[derive(Debug, Default)]
+pub struct Injected {
+    /// Errors, with the topic and partition they apply to
+    errors: Vec<(Option<String>, Option<u32>, ErrorCode)>,
+    pub latency: Duration,
+    pub delivery: Delivery,
+}
+
+impl Injected {
+    /// Error to answer with for the whole request, a topic or a partition
+    ///
+    /// * `topic` - topic name, None for the request
+    /// * `partition` - partition id, None for the topic
+    pub fn error(&self, topic: Option<&str>, partition: Option<u32>) -> Option<ErrorCode> {
+        self.errors
+            .iter()
+            .find(|(t, p, _)| {
+                (t.is_none() || topic.is_some() && t.as_deref() == topic)
+                    && (p.is_none() || partition.is_some() && *p == partition)
+            })
+            .map(|(_, _, error)| *error)
+    }
+
+    /// Result for a partition, unless a fault fails it
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    pub fn check(&self, topic: &str, partition: u32) -> Result<(), ErrorCode> {
+        match self.error(Some(topic), Some(partition)) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    fault: Fault,
+    /// Requests matched so far
+    matched: u64,
+    /// Requests failed so far
+    injected: u32,
+}
+
+/// Faults injected into the requests, configurable at runtime
+#[derive(Debug, Default)]
+pub struct Faults {
+    rules: BTreeMap<u64, Rule>,
+    next_id: u64,
+}
+
+impl Faults {
+    /// Add a fault, returning its id
+    ///
+    /// * `fault` - requests to fail, and how
+    pub fn add(&mut self, fault: Fault) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let rule = Rule {
+            fault,
+            matched: 0,
+            injected: 0,
+        };
+        self.rules.insert(id, rule);
+        id
+    }
+
+    /// Remove a fault, returning false if there was none with this id
+    ///
+    /// * `id` - id returned by `add`
+    pub fn remove(&mut self, id: u64) -> bool {
+        self.rules.remove(&id).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Faults still active, by id
+    pub fn list(&self) -> impl Iterator<Item = (u64, &Fault)> {
+        self.rules.iter().map(|(id, rule)| (*id, &rule.fault))
+    }
+
+    /// Find the faults to inject into a request
+    /// Faults limited in number are removed once used up.
+    ///
+    /// * `req` - request being processed
+    pub fn inject(&mut self, req: &Request) -> Injected {
+        let mut injected = Injected::default();
+        let partitions = partitions(req);
+        for rule in self.rules.values_mut() {
+            let fault = &rule.fault;
+            if !matches(fault, req, &partitions) {
+                continue;
+            }
+            rule.matched += 1;
+            if rule.matched % fault.every.unwrap_or(1).max(1) as u64 != 0 {
+                continue;
+            }
+            rule.injected += 1;
+            if let Some(error) = fault.error {
+                injected
+                    .errors
+                    .push((fault.topic.clone(), fault.partition, error));
+            }
+            injected.latency += Duration::from_millis(fault.latency_ms.unwrap_or(0));
+            // The most disruptive one wins
+            let delivery = match (fault.disconnect, fault.truncate) {
+                (Some(Disconnect::BeforeResponse), _) => Delivery::Close,
+                (Some(Disconnect::MidResponse), _) => Delivery::CloseMidResponse,
+                (None, Some(bytes)) => Delivery::Truncate(bytes),
+                (None, None) => Delivery::Respond,
+            };
+            injected.delivery = match (injected.delivery, delivery) {
+                (Delivery::Close, _) | (_, Delivery::Close) => Delivery::Close,
+                (Delivery::CloseMidResponse, _) | (_, Delivery::CloseMidResponse) => {
+                    Delivery::CloseMidResponse
+                }
+                (Delivery::Truncate(a), Delivery::Truncate(b)) => Delivery::Truncate(a.min(b)),
+                (Delivery::Truncate(a), _) | (_, Delivery::Truncate(a)) => Delivery::Truncate(a),
+                _ => Delivery::Respond,
+            };
+        }
+        self.rules
+            .retain(|_, rule| rule.fault.times.is_none_or(|n| rule.injected < n));
+        injected
+    }
+}
+
+fn matches(fault: &Fault, req: &Request, partitions: &[(&str, Option<u32>)]) -> bool {
+    let header = req.header();
+    if fault
+        .api_key
+        .is_some_and(|key| header.api_key.to_u16() != Some(key))
+    {
+        return false;
+    }
+    if fault.client_id.is_some() && fault.client_id != header.client_id {
+        return false;
+    }
+    if fault.topic.is_none() && fault.partition.is_none() {
+        return true;
+    }
+    // Requests for a whole topic match any of its partitions
+    partitions.iter().any(|(t, p)| {
+        fault.topic.as_deref().is_none_or(|topic| *t == topic)
+            && (fault.partition.is_none() || p.is_none() || *p == fault.partition)
+    })
+}
+
+// Topics and partitions a request refers to, without a partition for whole topics
+fn partitions(req: &Request) -> Vec<(&str, Option<u32>)> {
+    fn each(name: &str, ids: impl Iterator<Item = u32>) -> Vec<(&str, Option<u32>)> {
+        ids.map(|id| (name, Some(id))).collect()
+    }
+    match req {
+        Request::ApiVersionsRequest(_) => vec![],
+        Request::MetadataRequest(req) => req.topics.iter().map(|t| (t.as_str(), None)).collect(),
+        Request::ProduceRequest(req) => req
+            .topics
+            .iter()
+            .flat_map(|t| each(&t.name, t.partitions.iter().map(|p| p.id)))
+            .collect(),
+        Request::FetchRequest(req) => req
+            .topics
+            .iter()
+            .flat_map(|t| each(&t.name, t.partitions.iter().map(|p| p.id)))
+            .collect(),
+        Request::OffsetForLeaderEpochRequest(req) => req
+            .topics
+            .iter()
+            .flat_map(|t| each(&t.name, t.partitions.iter().map(|p| p.id)))
+            .collect(),
+        Request::ElectLeadersRequest(req) => req
+            .topics
+            .iter()
+            .flatten()
+            .flat_map(|t| each(&t.name, t.partitions.iter().copied()))
+            .collect(),
+        Request::AlterPartitionReassignmentsRequest(req) => req
+            .topics
+            .iter()
+            .flat_map(|t| each(&t.name, t.partitions.iter().map(|p| p.id)))
+            .collect(),
+        Request::ListPartitionReassignmentsRequest(req) => req
+            .topics
+            .iter()
+            .flatten()
+            .flat_map(|t| each(&t.name, t.partitions.iter().copied()))
+            .collect(),
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::*;
+
+    fn metadata(client_id: &str, topics: &[&str]) -> Request {
+        Request::MetadataRequest(MetadataRequest {
+            header: RequestHeader {
+                api_key: ApiKey::Metadata,
+                api_version: 9,
+                correlation_id: 1,
+                client_id: Some(client_id.to_string()),
+            },
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            allow_auto_topic_creation: false,
+            include_cluster_authorized_operations: false,
+            include_topic_authorized_operations: false,
+        })
+    }
+
+    #[test]
+    fn match_requests() {
+        let mut faults = Faults::default();
+        faults.add(Fault {
+            api_key: Some(3),
+            client_id: Some("app".to_string()),
+            topic: Some("t".to_string()),
+            partition: Some(1),
+            error: Some(ErrorCode::LeaderNotAvailable),
+            ..Fault::default()
+        });
+        assert_eq!(
+            faults
+                .inject(&metadata("other", &["t"]))
+                .error(Some("t"), Some(1)),
+            None
+        );
+        assert_eq!(
+            faults
+                .inject(&metadata("app", &["u"]))
+                .error(Some("t"), Some(1)),
+            None
+        );
+
+        let injected = faults.inject(&metadata("app", &["t", "u"]));
+        assert_eq!(
+            injected.error(Some("t"), Some(1)),
+            Some(ErrorCode::LeaderNotAvailable)
+        );
+        assert_eq!(injected.error(Some("t"), Some(0)), None);
+        assert_eq!(injected.error(Some("t"), None), None);
+        assert_eq!(injected.error(Some("u"), Some(1)), None);
+        assert_eq!(injected.error(None, None), None);
+    }
+
+    #[test]
+    fn match_partitions_of_any_topic() {
+        let mut faults = Faults::default();
+        faults.add(Fault {
+            partition: Some(1),
+            error: Some(ErrorCode::LeaderNotAvailable),
+            ..Fault::default()
+        });
+        // Only requests referring to a topic
+        assert_eq!(faults.inject(&metadata("app", &[])).error(None, None), None);
+        let injected = faults.inject(&metadata("app", &["t", "u"]));
+        for topic in ["t", "u"] {
+            assert_eq!(
+                injected.error(Some(topic), Some(1)),
+                Some(ErrorCode::LeaderNotAvailable)
+            );
+            assert_eq!(injected.error(Some(topic), Some(0)), None);
+        }
+    }
+
+    #[test]
+    fn every_nth_request_a_few_times() {
+        let mut faults = Faults::default();
+        let id = faults.add(Fault {
+            every: Some(2),
+            times: Some(2),
+            latency_ms: Some(100),
+            disconnect: Some(Disconnect::MidResponse),
+            truncate: Some(10),
+            ..Fault::default()
+        });
+        let delivered = (0..6)
+            .map(|_| faults.inject(&metadata("app", &[])).delivery)
+            .collect::<Vec<_>>();
+        use Delivery::*;
+        assert_eq!(
+            delivered,
+            vec![
+                Respond,
+                CloseMidResponse,
+                Respond,
+                CloseMidResponse,
+                Respond,
+                Respond
+            ]
+        );
+        assert!(!faults.remove(id));
+
+        faults.add(Fault {
+            truncate: Some(10),
+            ..Fault::default()
+        });
+        faults.add(Fault {
+            latency_ms: Some(5),
+            error: Some(ErrorCode::RequestTimedOut),
+            ..Fault::default()
+        });
+        let injected = faults.inject(&metadata("app", &[]));
+        assert_eq!(injected.delivery, Truncate(10));
+        assert_eq!(injected.latency, Duration::from_millis(5));
+        assert_eq!(
+            injected.error(Some("t"), Some(0)),
+            Some(ErrorCode::RequestTimedOut)
+        );
+    }
+
+    #[test]
+    fn parse_faults() {
+        let fault: Fault = serde_json::from_str(
+            r#"{"api_key": 0, "error": "NOT_LEADER_OR_FOLLOWER", "disconnect": "mid_response"}"#,
+        )
+        .unwrap();
+        assert_eq!(fault.api_key, Some(0));
+        assert_eq!(fault.error, Some(ErrorCode::NotLeaderOrFollower));
+        assert_eq!(fault.disconnect, Some(Disconnect::MidResponse));
+        let fault: Fault = serde_json::from_str(r#"{"error": 7}"#).unwrap();
+        assert_eq!(fault.error, Some(ErrorCode::RequestTimedOut));
+        assert!(serde_json::from_str::<Fault>(r#"{"error": "NOPE"}"#).is_err());
+        assert!(serde_json::from_str::<Fault>(r#"{"eror": 7}"#).is_err());
+    }
+}
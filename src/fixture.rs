@@ -0,0 +1,363 @@
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::config::TopicConfig;
+use crate::log::{now_ms, single_record_batch};
+use crate::messages::{ProduceRecordBatchRequest, RecordHeader};
+use crate::state::{State, Topic};
+use crate::storage::invalid_data;
+
+/// Declarative description of topics, records and committed offsets to seed the broker with
+///
+/// ```yaml
+/// topics:
+///   - name: orders
+///     partitions: 3
+///     configs:
+///       cleanup.policy: compact
+///     records:
+///       - partition: 1
+///         key: order-1
+///         value: '{"amount": 10}'
+///         headers:
+///           source: fixture
+///         timestamp: 1612447466097
+/// groups:
+///   - group: billing
+///     offsets:
+///       - topic: orders
+///         partition: 1
+///         offset: 1
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    #[serde(default)]
+    pub topics: Vec<FixtureTopic>,
+    #[serde(default)]
+    pub groups: Vec<FixtureGroup>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureTopic {
+    pub name: String,
+    #[serde(default = "default_partitions")]
+    pub partitions: usize,
+    #[serde(default)]
+    pub configs: HashMap<String, String>,
+    #[serde(default)]
+    pub records: Vec<FixtureRecord>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureRecord {
+    #[serde(default)]
+    pub partition: u32,
+    pub key: Option<String>,
+    /// A missing value makes the record a tombstone
+    pub value: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Defaults to the time the fixture is loaded
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureGroup {
+    pub group: String,
+    #[serde(default)]
+    pub offsets: Vec<FixtureOffset>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureOffset {
+    pub topic: String,
+    #[serde(default)]
+    pub partition: u32,
+    pub offset: i64,
+}
+
+fn default_partitions() -> usize {
+    1
+}
+
+impl Fixture {
+    /// Read a fixture file, in YAML, JSON or TOML depending on its extension
+    ///
+    /// * `path` - fixture file (.yaml, .yml, .json or .toml)
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents),
+            Some("json") => Self::from_json(&contents),
+            Some("toml") => Self::from_toml(&contents),
+            _ => Err(invalid_data(format!(
+                "{}: unknown fixture format, expected .yaml, .json or .toml",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn from_yaml(contents: &str) -> io::Result<Self> {
+        serde_yaml::from_str(contents).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    pub fn from_json(contents: &str) -> io::Result<Self> {
+        serde_json::from_str(contents).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    pub fn from_toml(contents: &str) -> io::Result<Self> {
+        toml::from_str(contents).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    /// Load the fixture into the broker state
+    /// Topics are created if missing, and records are appended in order, one
+    /// batch per record. The whole fixture is checked first, so nothing is
+    /// loaded from an invalid one.
+    ///
+    /// * `state` - broker state
+    pub fn apply(&self, state: &mut State) -> io::Result<()> {
+        let configs = self.validate(state)?;
+        let now = now_ms();
+        for (topic, config) in self.topics.iter().zip(configs) {
+            state.create_topic(&topic.name, topic.partitions, config)?;
+            for record in &topic.records {
+                state.append(&topic.name, record.partition, &record.to_batch(now))?;
+            }
+        }
+        for group in &self.groups {
+            for o in &group.offsets {
+                state.commit_offset(&group.group, &o.topic, o.partition, o.offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Check the fixture against the state, returning the config of each topic
+    //
+    // Topics already in the state, or declared more than once, must have the
+    // same number of partitions and configs everywhere, or no configs.
+    fn validate(&self, state: &State) -> io::Result<Vec<TopicConfig>> {
+        let mut topics = state
+            .topics
+            .iter()
+            .map(|(name, t)| (name.as_str(), (t.partitions.len(), t.config.clone())))
+            .collect::<HashMap<_, _>>();
+        let mut configs = Vec::with_capacity(self.topics.len());
+        for topic in &self.topics {
+            let invalid = |msg: String| invalid_data(format!("{}: {}", topic.name, msg));
+            Topic::validate_name(&topic.name).map_err(|e| invalid(e.to_string()))?;
+            if topic.partitions == 0 {
+                return Err(invalid("partitions must be at least 1".to_string()));
+            }
+            let mut config = state.config.default_topic_config.clone();
+            for (key, value) in &topic.configs {
+                if !config.set(key, value) {
+                    return Err(invalid(format!("invalid config {}={}", key, value)));
+                }
+            }
+            let (partitions, existing) = topics
+                .entry(topic.name.as_str())
+                .or_insert((topic.partitions, config.clone()));
+            if *partitions != topic.partitions {
+                return Err(invalid(format!(
+                    "{} partitions, but the topic has {}",
+                    topic.partitions, partitions
+                )));
+            }
+            if !topic.configs.is_empty() && *existing != config {
+                return Err(invalid("configs differ from the topic's".to_string()));
+            }
+            let config = existing;
+            for record in &topic.records {
+                if record.partition as usize >= *partitions {
+                    return Err(invalid(format!("no partition {}", record.partition)));
+                }
+                if record.key.is_none() && config.cleanup_policy.compact {
+                    return Err(invalid(
+                        "records of a compacted topic need a key".to_string(),
+                    ));
+                }
+            }
+            configs.push(config.clone());
+        }
+        for group in &self.groups {
+            for o in &group.offsets {
+                let partitions = topics.get(o.topic.as_str()).map_or(0, |(p, _)| *p);
+                if o.partition as usize >= partitions {
+                    return Err(invalid_data(format!(
+                        "{}: no partition {} in topic {}",
+                        group.group, o.partition, o.topic
+                    )));
+                }
+                if o.offset < 0 {
+                    return Err(invalid_data(format!(
+                        "{}: negative offset {} on {}",
+                        group.group, o.offset, o.topic
+                    )));
+                }
+            }
+        }
+        Ok(configs)
+    }
+}
+
+impl FixtureRecord {
+    /// A batch holding only this record
+    ///
+    /// * `now` - timestamp of the record, unless it has one
+    pub fn to_batch(&self, now: i64) -> ProduceRecordBatchRequest {
+        single_record_batch(
+            self.timestamp.unwrap_or(now),
+            self.key.as_ref().map(|k| k.as_bytes().to_vec()),
+            self.value.as_ref().map(|v| v.as_bytes().to_vec()),
+            self.headers
+                .iter()
+                .map(|(k, v)| RecordHeader {
+                    key: k.clone(),
+                    value: Some(v.as_bytes().to_vec()),
+                })
+                .collect(),
+        )
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "
+topics:
+  - name: orders
+    partitions: 2
+    configs:
+      cleanup.policy: compact
+    records:
+      - key: a
+        value: '1'
+        timestamp: 1000
+      - partition: 1
+        key: b
+        headers:
+          source: fixture
+groups:
+  - group: billing
+    offsets:
+      - topic: orders
+        offset: 1
+";
+
+    const TOML: &str = r#"
+[[topics]]
+name = "orders"
+partitions = 2
+configs = { "cleanup.policy" = "compact" }
+
+[[topics.records]]
+key = "a"
+value = "1"
+timestamp = 1000
+
+[[topics.records]]
+partition = 1
+key = "b"
+headers = { source = "fixture" }
+
+[[groups]]
+group = "billing"
+offsets = [{ topic = "orders", offset = 1 }]
+"#;
+
+    const JSON: &str = r#"{
+  "topics": [{
+    "name": "orders",
+    "partitions": 2,
+    "configs": {"cleanup.policy": "compact"},
+    "records": [
+      {"key": "a", "value": "1", "timestamp": 1000},
+      {"partition": 1, "key": "b", "headers": {"source": "fixture"}}
+    ]
+  }],
+  "groups": [{"group": "billing", "offsets": [{"topic": "orders", "offset": 1}]}]
+}"#;
+
+    #[test]
+    fn formats_are_equivalent() {
+        let fixture = Fixture::from_yaml(YAML).unwrap();
+        assert_eq!(fixture, Fixture::from_toml(TOML).unwrap());
+        assert_eq!(fixture, Fixture::from_json(JSON).unwrap());
+    }
+
+    #[test]
+    fn apply_fixture() {
+        let mut state = State::default();
+        Fixture::from_yaml(YAML).unwrap().apply(&mut state).unwrap();
+
+        let topic = &state.topics["orders"];
+        assert!(topic.config.cleanup_policy.compact);
+        let first = topic.partitions[0].batches().next().unwrap();
+        assert_eq!(first.size, first.encoded_size());
+        let record = &first.records[0];
+        assert_eq!(record.key, Some(b"a".to_vec()));
+        assert_eq!(record.value, Some(b"1".to_vec()));
+        assert_eq!(record.timestamp, 1000);
+        let tombstone = &topic.partitions[1].batches().next().unwrap().records[0];
+        assert_eq!(tombstone.value, None);
+        assert_eq!(tombstone.headers[0].key, "source");
+        assert_eq!(state.offsets["billing"][&("orders".to_string(), 0)], 1);
+    }
+
+    #[test]
+    fn reject_invalid_fixtures() {
+        let mut state = State::default();
+        let fixture = Fixture::from_yaml("topics: [{name: t, records: [{partition: 3}]}]").unwrap();
+        assert!(fixture.apply(&mut state).is_err());
+        let fixture =
+            Fixture::from_yaml("topics: [{name: u, configs: {retention.ms: x}}]").unwrap();
+        assert!(fixture.apply(&mut state).is_err());
+        assert!(Fixture::from_yaml("topic: []").is_err());
+        for yaml in &[
+            "topics: [{name: t, partitions: 0}]",
+            "topics: [{name: t/u}]",
+            "topics: [{name: t, configs: {cleanup.policy: compact}, records: [{value: a}]}]",
+            "groups: [{group: g, offsets: [{topic: t, offset: 1}]}]",
+            "topics: [{name: t}, {name: t, partitions: 2}]",
+            "topics: [{name: t}, {name: t, configs: {retention.ms: 1}}]",
+        ] {
+            let fixture = Fixture::from_yaml(yaml).unwrap();
+            assert!(fixture.apply(&mut state).is_err(), "{}", yaml);
+        }
+        assert!(state.topics.is_empty());
+    }
+
+    #[test]
+    fn reject_partition_count_mismatches() {
+        let mut state = State::default();
+        Fixture::from_yaml(YAML).unwrap().apply(&mut state).unwrap();
+        // The first topic is valid, but nothing is loaded as the second isn't
+        let fixture =
+            Fixture::from_yaml("topics: [{name: t, records: [{}]}, {name: orders, partitions: 3}]")
+                .unwrap();
+        let e = fixture.apply(&mut state).unwrap_err();
+        assert_eq!(e.to_string(), "orders: 3 partitions, but the topic has 2");
+        assert!(!state.topics.contains_key("t"));
+
+        // Existing topics can get more records, if their partitions match
+        let fixture =
+            Fixture::from_yaml("topics: [{name: orders, partitions: 2, records: [{key: c}]}]")
+                .unwrap();
+        fixture.apply(&mut state).unwrap();
+        assert_eq!(state.topics["orders"].partitions[0].log_end_offset(), 2);
+    }
+}
@@ -0,0 +1,399 @@
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tracing::error;
+
+use std::io;
+use std::sync::Arc;
+
+/// Largest request head (request line and headers) accepted
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Largest request body accepted
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// An HTTP request, with its body read
+#[derive(Debug, Default)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Path as sent, without the query string, and still percent-encoded
+    pub path: String,
+    /// Query string parameters, decoded
+    pub query: Vec<(String, String)>,
+    /// Headers, with lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Value of a query string parameter
+    ///
+    /// * `name` - parameter name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of a header
+    ///
+    /// * `name` - header name, in lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Path segments, decoded, e.g. ["topics", "a/b"] for /topics/a%2Fb
+    pub fn segments(&self) -> Vec<String> {
+        self.path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn json(status: u16, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json".to_string(),
+            body: serde_json::to_vec(value).unwrap_or_default(),
+        }
+    }
+
+    /// Error, as a JSON object with the status and a message
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        #[derive(Serialize)]
+        struct Error {
+            error_code: u16,
+            message: String,
+        }
+        Self::json(
+            status,
+            &Error {
+                error_code: status,
+                message: message.into(),
+            },
+        )
+    }
+
+    pub fn no_content() -> Self {
+        Self {
+            status: 204,
+            content_type: "text/plain".to_string(),
+            body: vec![],
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::error(404, "not found")
+    }
+}
+
+/// Handles the requests of an HTTP server
+pub type Handler = Arc<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+/// Accept HTTP connections, and answer their requests with a handler
+/// The handler runs on a blocking section, as it may wait on the broker state.
+///
+/// * `listener` - bound socket
+/// * `handler` - function answering each request
+pub async fn accept(listener: TcpListener, handler: Handler) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(stream, handler.clone()));
+            }
+            Err(e) => error!("Error accepting HTTP connection: {}", e),
+        }
+    }
+}
+
+// Answer the requests of a connection until it's closed
+async fn handle_connection(stream: TcpStream, handler: Handler) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let req = match read_request(&mut reader).await {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(e) => {
+                let status = match e.kind() {
+                    io::ErrorKind::Unsupported => 501,
+                    _ => 400,
+                };
+                let resp = HttpResponse::error(status, e.to_string());
+                let _ = writer.write_all(&encode(&resp, true)).await;
+                break;
+            }
+        };
+        let close = req
+            .header("connection")
+            .is_some_and(|c| c.eq_ignore_ascii_case("close"));
+        let resp = task::block_in_place(|| handler(&req));
+        if writer.write_all(&encode(&resp, close)).await.is_err() || close {
+            break;
+        }
+    }
+}
+
+// Read a request, or None if the connection was closed before one started
+async fn read_request<R>(reader: &mut R) -> io::Result<Option<HttpRequest>>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut head = Vec::new();
+    let mut limited = (&mut *reader).take(MAX_HEAD_SIZE as u64);
+    loop {
+        let n = limited.read_until(b'\n', &mut head).await?;
+        if n == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            if limited.limit() == 0 {
+                return Err(invalid("request head too large"));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+    let head = String::from_utf8(head).map_err(|_| invalid("request head is not UTF-8"))?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(invalid("invalid request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect::<Vec<_>>();
+
+    let header = |name: &str| headers.iter().find(|(k, _)| k == name).map(|(_, v)| v);
+    let body = match (header("transfer-encoding"), header("content-length")) {
+        (Some(encoding), _) if encoding.eq_ignore_ascii_case("chunked") => {
+            read_chunked(reader).await?
+        }
+        (Some(encoding), _) => {
+            let message = format!("unsupported transfer encoding {}", encoding);
+            return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+        }
+        (None, Some(length)) => {
+            let length = length
+                .parse()
+                .map_err(|_| invalid("invalid content length"))?;
+            if length > MAX_BODY_SIZE {
+                return Err(invalid("request body too large"));
+            }
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).await?;
+            body
+        }
+        (None, None) => vec![],
+    };
+    Ok(Some(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (k, v) = p.split_once('=').unwrap_or((p, ""));
+                (percent_decode(k), percent_decode(&v.replace('+', " ")))
+            })
+            .collect(),
+        headers,
+        body,
+    }))
+}
+
+// Read a body sent with the chunked transfer encoding, skipping chunk
+// extensions and trailers
+async fn read_chunked<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        if size > MAX_BODY_SIZE - body.len() {
+            return Err(invalid("request body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        if !read_line(reader).await?.is_empty() {
+            return Err(invalid("invalid chunk"));
+        }
+    }
+    while !read_line(reader).await?.is_empty() {}
+    Ok(body)
+}
+
+// Read a line of a chunked body, without its line ending
+async fn read_line<R>(reader: &mut R) -> io::Result<String>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_HEAD_SIZE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 || !line.ends_with(b"\n") {
+        return Err(invalid("invalid chunk"));
+    }
+    let line = String::from_utf8(line).map_err(|_| invalid("invalid chunk"))?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn encode(resp: &HttpResponse, close: bool) -> Vec<u8> {
+    let mut bytes = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        resp.status,
+        reason(resp.status),
+        resp.content_type,
+        resp.body.len()
+    );
+    if close {
+        bytes.push_str("Connection: close\r\n");
+    }
+    bytes.push_str("\r\n");
+    let mut bytes = bytes.into_bytes();
+    bytes.extend_from_slice(&resp.body);
+    bytes
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "",
+    }
+}
+
+/// Decode %XX escapes, leaving invalid ones as they are
+///
+/// * `s` - URL component
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_requests() {
+        let bytes = b"POST /faults/1?a=b%20c&d HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\n{}GET / HTTP/1.1\r\n\r\n";
+        let mut reader = &bytes[..];
+        let req = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(
+            (req.method.as_str(), req.path.as_str()),
+            ("POST", "/faults/1")
+        );
+        assert_eq!(req.segments(), vec!["faults", "1"]);
+        assert_eq!(req.param("a"), Some("b c"));
+        assert_eq!(req.param("d"), Some(""));
+        assert_eq!(req.header("host"), Some("x"));
+        assert_eq!(req.body, b"{}");
+
+        let req = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(req.path, "/");
+        assert!(read_request(&mut reader).await.unwrap().is_none());
+        assert!(read_request(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn decode_segments_after_splitting() {
+        let bytes = b"GET /topics/a%2Fb/records HTTP/1.1\r\n\r\n";
+        let req = read_request(&mut &bytes[..]).await.unwrap().unwrap();
+        assert_eq!(req.path, "/topics/a%2Fb/records");
+        assert_eq!(req.segments(), vec!["topics", "a/b", "records"]);
+    }
+
+    #[tokio::test]
+    async fn read_chunked_bodies() {
+        let bytes = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\n{\"a\r\n4\r\n\": 1\r\n1\r\n}\r\n0\r\nTrailer: t\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut reader = &bytes[..];
+        let req = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(req.body, b"{\"a\": 1}");
+        assert_eq!(
+            read_request(&mut reader).await.unwrap().unwrap().method,
+            "GET"
+        );
+
+        let bytes = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        let err = read_request(&mut &bytes[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let bytes = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n";
+        assert!(read_request(&mut &bytes[..]).await.is_err());
+        // A chunk size that would overflow, after a first chunk
+        let bytes =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        let err = read_request(&mut &bytes[..]).await.unwrap_err();
+        assert_eq!(err.to_string(), "request body too large");
+    }
+
+    #[tokio::test]
+    async fn limit_the_head() {
+        // A single header line larger than the limit, without a line ending
+        let mut bytes = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        bytes.resize(MAX_HEAD_SIZE * 2, b'a');
+        let err = read_request(&mut &bytes[..]).await.unwrap_err();
+        assert_eq!(err.to_string(), "request head too large");
+    }
+}
@@ -1,5 +1,27 @@
+pub mod admin;
+pub mod avro;
 pub mod broker;
+pub mod cluster;
+pub mod codec;
+pub mod compaction;
+pub mod config;
 pub mod de;
 pub mod error;
+pub mod faults;
+pub mod fixture;
+pub mod http;
+pub mod log;
+pub mod logging;
 pub mod messages;
+pub mod metrics;
+pub mod recorder;
+pub mod rest;
+pub mod retention;
+pub mod schema_registry;
 pub mod ser;
+pub mod server;
+pub mod snapshot;
+pub mod state;
+pub mod storage;
+#[cfg(test)]
+mod testing;
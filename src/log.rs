@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::messages::{ProduceRecordBatchRequest, ProduceRecordRequest, RecordHeader};
+
+// Size of the record batch header (magic v2), without the records
+const BATCH_OVERHEAD: usize = 61;
+
+/// Current wall-clock time in milliseconds, as used by Kafka timestamps
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// Length of a zigzag-encoded varint
+fn varint_size(value: i64) -> usize {
+    let mut v = ((value << 1) ^ (value >> 63)) as u64;
+    let mut size = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        size += 1;
+    }
+    size
+}
+
+fn varint_bytes_size(bytes: &Option<Vec<u8>>) -> usize {
+    match bytes {
+        Some(b) => varint_size(b.len() as i64) + b.len(),
+        None => varint_size(-1),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub offset: i64,
+    pub timestamp: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>, // None is a tombstone
+    pub headers: Vec<RecordHeader>,
+}
+
+/// A record batch as stored in the partition log
+///
+/// Offsets are absolute, so removing records from a batch never changes the
+/// offsets of the remaining ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub base_offset: i64,
+    pub last_offset: i64,
+    pub leader_epoch: i32,
+    pub first_timestamp: i64,
+    pub max_timestamp: i64,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub base_sequence: i32,
+    // Size in bytes of the batch as it would be sent on the wire
+    pub size: usize,
+    pub records: Vec<Record>,
+}
+
+impl Batch {
+    /// Build a batch from its wire representation, with the given base offset
+    ///
+    /// * `base_offset` - offset of the first record
+    /// * `batch` - batch as parsed from a request or a segment file
+    pub fn from_request(base_offset: i64, batch: &ProduceRecordBatchRequest) -> Self {
+        let first_timestamp = batch.first_timestamp as i64;
+        let records = batch
+            .records
+            .iter()
+            .map(|r| Record {
+                offset: base_offset + r.offset_delta as i64,
+                timestamp: first_timestamp.wrapping_add(r.timestamp_delta),
+                key: r.key.clone(),
+                value: r.value.clone(),
+                headers: r.headers.clone(),
+            })
+            .collect();
+        Self {
+            base_offset,
+            last_offset: base_offset + batch.last_offset_delta as i64,
+            leader_epoch: batch.leader_epoch,
+            first_timestamp,
+            max_timestamp: batch.last_timestamp as i64,
+            producer_id: batch.producer_id,
+            producer_epoch: batch.producer_epoch,
+            base_sequence: batch.base_sequence,
+            size: batch.size as usize,
+            records,
+        }
+    }
+
+    /// Size of a record of this batch once encoded
+    /// Deltas are relative to the batch, so the size depends on it.
+    ///
+    /// * `record` - record belonging to this batch
+    pub fn record_size(&self, record: &Record) -> usize {
+        let body = 1 // attributes
+            + varint_size(record.timestamp - self.first_timestamp)
+            + varint_size(record.offset - self.base_offset)
+            + varint_bytes_size(&record.key)
+            + varint_bytes_size(&record.value)
+            + varint_size(record.headers.len() as i64)
+            + record
+                .headers
+                .iter()
+                .map(|h| {
+                    varint_size(h.key.len() as i64) + h.key.len() + varint_bytes_size(&h.value)
+                })
+                .sum::<usize>();
+        varint_size(body as i64) + body
+    }
+
+    /// Compute the encoded size of the batch from its records
+    pub fn encoded_size(&self) -> usize {
+        BATCH_OVERHEAD
+            + self
+                .records
+                .iter()
+                .map(|r| self.record_size(r))
+                .sum::<usize>()
+    }
+}
+
+/// A batch holding a single record, for records produced without a Kafka client
+///
+/// * `timestamp` - timestamp of the record
+/// * `key` - record key
+/// * `value` - record value, None for a tombstone
+/// * `headers` - record headers
+pub fn single_record_batch(
+    timestamp: i64,
+    key: Option<Vec<u8>>,
+    value: Option<Vec<u8>>,
+    headers: Vec<RecordHeader>,
+) -> ProduceRecordBatchRequest {
+    let mut batch = ProduceRecordBatchRequest {
+        offset: 0,
+        leader_epoch: -1,
+        options: 0,
+        last_offset_delta: 0,
+        first_timestamp: timestamp as u64,
+        last_timestamp: timestamp as u64,
+        producer_id: -1,
+        producer_epoch: -1,
+        base_sequence: -1,
+        size: 0,
+        records: vec![ProduceRecordRequest {
+            timestamp_delta: 0,
+            offset_delta: 0,
+            key,
+            value,
+            headers,
+        }],
+    };
+    batch.size = Batch::from_request(0, &batch).encoded_size() as u32;
+    batch
+}
+
+/// Last known state of an idempotent producer on a partition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProducerState {
+    pub epoch: i16,
+    pub last_sequence: i32,
+}
+
+/// In-memory log of a single partition
+#[derive(Debug, Default)]
+pub struct PartitionLog {
+    batches: VecDeque<Batch>,
+    log_start_offset: i64,
+    next_offset: i64,
+    size: usize,
+    producers: BTreeMap<i64, ProducerState>,
+    // Leader epochs and the offset each one started at, in order
+    epochs: Vec<(i32, i64)>,
+}
+
+impl PartitionLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuild a log from its persisted parts
+    ///
+    /// * `log_start_offset` - first offset available in the log
+    /// * `log_end_offset` - checkpointed end offset, for logs that lost their last batches
+    /// * `batches` - batches in offset order
+    /// * `producers` - producer state snapshot
+    pub fn restore(
+        log_start_offset: i64,
+        log_end_offset: i64,
+        batches: Vec<Batch>,
+        producers: BTreeMap<i64, ProducerState>,
+    ) -> Self {
+        let next_offset = batches
+            .last()
+            .map_or(log_end_offset, |b| log_end_offset.max(b.last_offset + 1));
+        let mut log = Self {
+            size: batches.iter().map(|b| b.size).sum(),
+            log_start_offset,
+            next_offset,
+            producers,
+            ..Self::default()
+        };
+        // The epoch cache is not persisted: batches carry their leader epoch
+        for batch in &batches {
+            log.assign_epoch(batch.leader_epoch, batch.base_offset);
+        }
+        log.truncate_epochs();
+        log.batches = batches.into();
+        log
+    }
+
+    /// First offset still available in the log
+    pub fn log_start_offset(&self) -> i64 {
+        self.log_start_offset
+    }
+
+    /// Offset that will be assigned to the next appended record
+    pub fn log_end_offset(&self) -> i64 {
+        self.next_offset
+    }
+
+    /// Total size in bytes of the batches held by the log
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn batches(&self) -> impl Iterator<Item = &Batch> {
+        self.batches.iter()
+    }
+
+    /// Append a produced batch, assigning offsets to its records
+    /// Returns the base offset of the appended batch.
+    ///
+    /// * `batch` - batch as received in a Produce request
+    /// * `leader_epoch` - epoch of the leader writing the batch
+    pub fn append(&mut self, batch: &ProduceRecordBatchRequest, leader_epoch: i32) -> i64 {
+        let base_offset = self.next_offset;
+        let mut batch = Batch::from_request(base_offset, batch);
+        batch.leader_epoch = leader_epoch;
+        self.assign_epoch(leader_epoch, base_offset);
+        self.push(batch);
+        base_offset
+    }
+
+    // Record the start of a leader epoch, if newer than the last one
+    fn assign_epoch(&mut self, epoch: i32, start_offset: i64) {
+        if epoch >= 0 && self.epochs.last().is_none_or(|(last, _)| epoch > *last) {
+            self.epochs.push((epoch, start_offset));
+        }
+    }
+
+    // Forget the epochs that ended before the log start offset
+    fn truncate_epochs(&mut self) {
+        let start = self.log_start_offset;
+        let ended = self
+            .epochs
+            .iter()
+            .skip(1)
+            .take_while(|(_, o)| *o <= start)
+            .count();
+        self.epochs.drain(..ended);
+        if let Some((_, offset)) = self.epochs.first_mut() {
+            *offset = (*offset).max(start);
+        }
+    }
+
+    /// Leader epochs found in the log, with the offset each one starts at
+    pub fn leader_epochs(&self) -> &[(i32, i64)] {
+        &self.epochs
+    }
+
+    /// Find where an epoch ended, as OffsetForLeaderEpoch requests ask
+    /// Returns the largest epoch up to the requested one, and the start offset
+    /// of the epoch after it, the log end offset for the current epoch. A
+    /// current epoch that has not written anything yet starts at the end of
+    /// the log. Returns None for an epoch after the current one.
+    ///
+    /// * `epoch` - requested leader epoch
+    /// * `current_epoch` - epoch of the current leader
+    pub fn end_offset_for_epoch(&self, epoch: i32, current_epoch: i32) -> Option<(i32, i64)> {
+        if epoch < 0 || epoch > current_epoch {
+            return None;
+        }
+        if epoch == current_epoch {
+            return Some((epoch, self.next_offset));
+        }
+        let (before, after) = self
+            .epochs
+            .split_at(self.epochs.partition_point(|(e, _)| *e <= epoch));
+        let end_offset = after
+            .first()
+            .map_or(self.next_offset, |(_, offset)| *offset);
+        // Epochs older than the log get the requested epoch back
+        let found = before.last().map_or(epoch, |(e, _)| *e);
+        Some((found, end_offset))
+    }
+
+    fn push(&mut self, batch: Batch) {
+        if batch.producer_id >= 0 {
+            self.producers.insert(
+                batch.producer_id,
+                ProducerState {
+                    epoch: batch.producer_epoch,
+                    // Sequence numbers wrap around, as in Kafka
+                    last_sequence: batch
+                        .base_sequence
+                        .wrapping_add((batch.last_offset - batch.base_offset) as i32),
+                },
+            );
+        }
+        self.next_offset = batch.last_offset + 1;
+        self.size += batch.size;
+        self.batches.push_back(batch);
+    }
+
+    /// Last batch in the log, if any
+    pub fn last(&self) -> Option<&Batch> {
+        self.batches.back()
+    }
+
+    /// State of the idempotent producers that wrote to this partition
+    pub fn producers(&self) -> &BTreeMap<i64, ProducerState> {
+        &self.producers
+    }
+
+    /// Oldest batch in the log, if any
+    pub fn head(&self) -> Option<&Batch> {
+        self.batches.front()
+    }
+
+    /// Drop the oldest batch, advancing the log start offset past it
+    pub fn pop_head(&mut self) -> Option<Batch> {
+        let batch = self.batches.pop_front()?;
+        self.size -= batch.size;
+        self.log_start_offset = self
+            .batches
+            .front()
+            .map_or(self.next_offset, |b| b.base_offset);
+        self.truncate_epochs();
+        Some(batch)
+    }
+
+    /// Keep only the records for which `f` returns true
+    /// Batches left empty are dropped, but the log start and end offsets do not
+    /// move, so the removed offsets show up as gaps. Returns the number of
+    /// records removed.
+    ///
+    /// * `f` - predicate on each record and the batch holding it
+    pub fn retain<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&Batch, &Record) -> bool,
+    {
+        let mut removed = 0;
+        for batch in self.batches.iter_mut() {
+            let records = std::mem::take(&mut batch.records);
+            let (kept, dropped): (Vec<_>, Vec<_>) = records.into_iter().partition(|r| f(batch, r));
+            let dropped_size: usize = dropped.iter().map(|r| batch.record_size(r)).sum();
+            let new_size = batch.size.saturating_sub(dropped_size);
+            self.size -= batch.size - new_size;
+            batch.size = new_size;
+            batch.records = kept;
+            removed += dropped.len();
+        }
+        let size = &mut self.size;
+        self.batches.retain(|b| {
+            if b.records.is_empty() {
+                *size -= b.size;
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::batch;
+
+    #[test]
+    fn end_offsets_of_leader_epochs() {
+        let mut log = PartitionLog::new();
+        log.append(&batch(0, &[(None, Some("")); 2]), 0);
+        log.append(&batch(0, &[(None, Some("")); 1]), 0);
+        log.append(&batch(0, &[(None, Some("")); 2]), 2);
+        log.append(&batch(0, &[(None, Some("")); 1]), 5);
+        assert_eq!(log.leader_epochs(), &[(0, 0), (2, 3), (5, 5)]);
+
+        assert_eq!(log.end_offset_for_epoch(0, 6), Some((0, 3)));
+        // Epoch 1 never wrote anything, it ended where epoch 2 started
+        assert_eq!(log.end_offset_for_epoch(1, 6), Some((0, 3)));
+        assert_eq!(log.end_offset_for_epoch(2, 6), Some((2, 5)));
+        // Epoch 6 is current, but hasn't written yet
+        assert_eq!(log.end_offset_for_epoch(5, 6), Some((5, 6)));
+        assert_eq!(log.end_offset_for_epoch(6, 6), Some((6, 6)));
+        assert_eq!(log.end_offset_for_epoch(7, 6), None);
+        assert_eq!(log.end_offset_for_epoch(-1, 6), None);
+
+        // Batches carry their epoch, so restoring the log restores the cache
+        let restored =
+            PartitionLog::restore(0, 6, log.batches().cloned().collect(), BTreeMap::new());
+        assert_eq!(restored.leader_epochs(), log.leader_epochs());
+    }
+
+    #[test]
+    fn leader_epochs_follow_the_log_start() {
+        let mut log = PartitionLog::new();
+        log.append(&batch(0, &[(None, Some("")); 2]), 0);
+        log.append(&batch(0, &[(None, Some("")); 2]), 1);
+        log.append(&batch(0, &[(None, Some("")); 2]), 1);
+        log.pop_head();
+        assert_eq!(log.leader_epochs(), &[(1, 2)]);
+        log.pop_head();
+        assert_eq!(log.leader_epochs(), &[(1, 4)]);
+        assert_eq!(log.end_offset_for_epoch(0, 1), Some((0, 4)));
+    }
+}
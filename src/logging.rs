@@ -0,0 +1,65 @@
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// A JSON object per line, with the fields of the event and its spans
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Check a level, or list of filtering directives, as given to `init`
+///
+/// * `level` - e.g. `debug` or `info,pseudokafka::server=trace`
+pub fn is_valid_level(level: &str) -> bool {
+    EnvFilter::try_new(level).is_ok()
+}
+
+/// Write the logs of the broker to stderr, unless a subscriber is already set
+///
+/// Each connection gets an `info` span, with the peer address, listener and
+/// broker id, and each request a `debug` one, with the client id, API key,
+/// version and correlation id. Request spans are logged when the response is
+/// written, along with its latency and error code. Requests themselves are
+/// logged at `trace` level.
+///
+/// * `level` - level, or filtering directives as in `RUST_LOG`
+/// * `format` - text or JSON lines
+pub fn init(level: &str, format: LogFormat) -> Result<(), String> {
+    let filter = EnvFilter::try_new(level).map_err(|e| e.to_string())?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_span_events(FmtSpan::CLOSE);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    result.map_err(|e| e.to_string())
+}
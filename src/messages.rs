@@ -1,5 +1,9 @@
 use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::ToPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
+
+use std::iter;
+
+use crate::error::ErrorCode;
 
 #[derive(Debug, PartialEq)]
 pub struct RequestHeader {
@@ -14,6 +18,11 @@ pub enum Request {
     ApiVersionsRequest(ApiVersionsRequest),
     MetadataRequest(MetadataRequest),
     ProduceRequest(ProduceRequest),
+    FetchRequest(FetchRequest),
+    OffsetForLeaderEpochRequest(OffsetForLeaderEpochRequest),
+    ElectLeadersRequest(ElectLeadersRequest),
+    AlterPartitionReassignmentsRequest(AlterPartitionReassignmentsRequest),
+    ListPartitionReassignmentsRequest(ListPartitionReassignmentsRequest),
 }
 
 #[derive(Debug)]
@@ -21,9 +30,15 @@ pub enum Response {
     ApiVersionsResponse(ApiVersionsResponse),
     MetadataResponse(MetadataResponse),
     ProduceResponse(ProduceResponse),
+    FetchResponse(FetchResponse),
+    OffsetForLeaderEpochResponse(OffsetForLeaderEpochResponse),
+    ElectLeadersResponse(ElectLeadersResponse),
+    AlterPartitionReassignmentsResponse(AlterPartitionReassignmentsResponse),
+    ListPartitionReassignmentsResponse(ListPartitionReassignmentsResponse),
+    ErrorResponse(ErrorResponse),
 }
 
-#[derive(Debug, FromPrimitive, ToPrimitive, PartialEq)]
+#[derive(Debug, Clone, Copy, FromPrimitive, ToPrimitive, PartialEq, Eq)]
 pub enum ApiKey {
     Produce = 0,
     Fetch = 1,
@@ -75,6 +90,23 @@ pub enum ApiKey {
     OffsetDelete = 47,
 }
 
+impl ApiKey {
+    /// Range of versions of the API that can be parsed and answered
+    pub fn supported_versions(&self) -> Option<(u16, u16)> {
+        match self {
+            ApiKey::ApiVersions => Some((0, 3)),
+            ApiKey::Metadata => Some((9, 9)),
+            ApiKey::Produce => Some((8, 8)),
+            ApiKey::Fetch => Some((11, 11)),
+            ApiKey::OffsetForLeaderEpoch => Some((0, 3)),
+            ApiKey::ElectLeaders => Some((2, 2)),
+            ApiKey::AlterPartitionReassignments => Some((0, 0)),
+            ApiKey::ListPartitionReassignments => Some((0, 0)),
+            _ => None,
+        }
+    }
+}
+
 //
 // Requests
 //
@@ -93,11 +125,20 @@ pub struct MetadataRequest {
     pub include_topic_authorized_operations: bool,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordHeader {
+    pub key: String,
+    pub value: Option<Vec<u8>>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ProduceRecordRequest {
-    // Ignored fields: record attributeds, timestamp, offset, key, headers
-    // They seem to be null and inherited from batch
-    pub value: Vec<u8>,
+    // Ignored fields: record attributes (unused by the protocol)
+    pub timestamp_delta: i64,
+    pub offset_delta: i32,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>, // None is a tombstone
+    pub headers: Vec<RecordHeader>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -106,19 +147,22 @@ pub struct ProduceRecordBatchRequest {
     pub leader_epoch: i32,
     // magic byte and crc32 ignored
     pub options: u16,
+    // records are only read when they aren't compressed
     pub last_offset_delta: u32,
     pub first_timestamp: u64,
     pub last_timestamp: u64,
     pub producer_id: i64,
     pub producer_epoch: i16,
     pub base_sequence: i32,
+    // size in bytes of the whole batch, as sent on the wire
+    pub size: u32,
     pub records: Vec<ProduceRecordRequest>,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct ProducePartitionRequest {
     pub id: u32,
-    pub message_set: ProduceRecordBatchRequest,
+    pub batches: Vec<ProduceRecordBatchRequest>, // empty if null
 }
 
 #[derive(Debug, PartialEq)]
@@ -130,12 +174,113 @@ pub struct ProduceTopicRequest {
 #[derive(Debug, PartialEq)]
 pub struct ProduceRequest {
     pub header: RequestHeader,
-    pub transactional_id: i16, // -1 is no transaction
-    pub required_acks: u16,
+    pub transactional_id: Option<String>, // None is no transaction
+    pub required_acks: i16,               // -1 is all in-sync replicas
     pub timeout: u32,
     pub topics: Vec<ProduceTopicRequest>,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct FetchPartition {
+    pub id: u32,
+    /// Leader epoch known to the client, -1 when unknown
+    pub current_leader_epoch: i32,
+    pub fetch_offset: i64,
+    // Ignored fields: log start offset (only sent by followers)
+    pub partition_max_bytes: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FetchTopic {
+    pub name: String,
+    pub partitions: Vec<FetchPartition>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FetchRequest {
+    // Ignored fields: replica id, isolation level, session id and epoch (no
+    // fetch sessions), forgotten topics and rack id
+    pub header: RequestHeader,
+    pub max_wait: u32,
+    pub min_bytes: u32,
+    pub max_bytes: u32,
+    pub topics: Vec<FetchTopic>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OffsetForLeaderEpochPartition {
+    pub id: u32,
+    /// Leader epoch known to the client, -1 when unknown
+    pub current_leader_epoch: i32,
+    /// Epoch to find the end offset of
+    pub leader_epoch: i32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OffsetForLeaderEpochTopic {
+    pub name: String,
+    pub partitions: Vec<OffsetForLeaderEpochPartition>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OffsetForLeaderEpochRequest {
+    pub header: RequestHeader,
+    pub replica_id: i32, // -1 for consumers
+    pub topics: Vec<OffsetForLeaderEpochTopic>,
+}
+
+/// Partitions of a topic, as listed in admin requests
+#[derive(Debug, PartialEq)]
+pub struct TopicPartitions {
+    pub name: String,
+    pub partitions: Vec<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ElectionType {
+    /// Move leadership back to the first replica
+    Preferred = 0,
+    /// Elect a replica that is not in sync if no replica in sync is left
+    Unclean = 1,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ElectLeadersRequest {
+    pub header: RequestHeader,
+    pub election_type: ElectionType,
+    /// None for every partition
+    pub topics: Option<Vec<TopicPartitions>>,
+    pub timeout: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReassignablePartition {
+    pub id: u32,
+    /// None cancels the ongoing reassignment
+    pub replicas: Option<Vec<u32>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReassignableTopic {
+    pub name: String,
+    pub partitions: Vec<ReassignablePartition>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AlterPartitionReassignmentsRequest {
+    pub header: RequestHeader,
+    pub timeout: u32,
+    pub topics: Vec<ReassignableTopic>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ListPartitionReassignmentsRequest {
+    pub header: RequestHeader,
+    pub timeout: u32,
+    /// None for every partition being reassigned
+    pub topics: Option<Vec<TopicPartitions>>,
+}
+
 //
 // Responses
 //
@@ -155,16 +300,32 @@ pub struct ApiVersion {
 #[derive(Debug)]
 pub struct ApiVersionsResponse {
     pub header: ResponseHeader,
+    /// Version to answer in, that of the request
+    pub version: u16,
     pub error_code: u16,
     pub api_versions: Vec<ApiVersion>,
     pub throttle_time: u32,
 }
 
+/// Answer to a request that couldn't be parsed or isn't supported, written in
+/// the layout of the response to that request
+#[derive(Debug)]
+pub struct ErrorResponse {
+    pub header: ResponseHeader,
+    pub api_key: u16,
+    pub api_version: u16,
+    pub error_code: u16,
+    /// Topics and partitions of the request, as far as they could be read,
+    /// each answered with the error
+    pub topics: Vec<TopicPartitions>,
+}
+
 #[derive(Debug)]
 pub struct BrokerMetadata {
     pub node_id: u32,
     pub host: String,
     pub port: u32,
+    pub rack: Option<String>,
 }
 
 #[derive(Debug)]
@@ -198,9 +359,127 @@ pub struct MetadataResponse {
     pub cluster_authorized_operations: u32,
 }
 
+#[derive(Debug)]
+pub struct ProducePartitionResponse {
+    pub id: u32,
+    pub error: u16,
+    pub base_offset: i64,
+    pub log_append_time: i64, // -1 when using CreateTime
+    pub log_start_offset: i64,
+}
+
+#[derive(Debug)]
+pub struct ProduceTopicResponse {
+    pub name: String,
+    pub partitions: Vec<ProducePartitionResponse>,
+}
+
 #[derive(Debug)]
 pub struct ProduceResponse {
-    // TODO
+    pub header: ResponseHeader,
+    pub topics: Vec<ProduceTopicResponse>,
+    pub throttle_time: u32,
+}
+
+#[derive(Debug)]
+pub struct FetchPartitionResponse {
+    pub id: u32,
+    pub error: u16,
+    pub high_watermark: i64,
+    pub log_start_offset: i64,
+    /// Record batches, as stored in the log
+    pub records: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct FetchTopicResponse {
+    pub name: String,
+    pub partitions: Vec<FetchPartitionResponse>,
+}
+
+#[derive(Debug)]
+pub struct FetchResponse {
+    pub header: ResponseHeader,
+    pub throttle_time: u32,
+    pub error: u16,
+    pub topics: Vec<FetchTopicResponse>,
+}
+
+#[derive(Debug)]
+pub struct EpochEndOffset {
+    pub error: u16,
+    pub id: u32,
+    pub leader_epoch: i32, // -1 when unknown
+    pub end_offset: i64,   // -1 when unknown
+}
+
+#[derive(Debug)]
+pub struct EpochEndOffsetTopic {
+    pub name: String,
+    pub partitions: Vec<EpochEndOffset>,
+}
+
+#[derive(Debug)]
+pub struct OffsetForLeaderEpochResponse {
+    pub header: ResponseHeader,
+    /// Version to answer in, that of the request
+    pub version: u16,
+    pub throttle_time: u32,
+    pub topics: Vec<EpochEndOffsetTopic>,
+}
+
+/// Outcome of an admin operation on a partition
+#[derive(Debug)]
+pub struct PartitionResult {
+    pub id: u32,
+    pub error: u16,
+    pub error_message: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TopicResults {
+    pub name: String,
+    pub partitions: Vec<PartitionResult>,
+}
+
+#[derive(Debug)]
+pub struct ElectLeadersResponse {
+    pub header: ResponseHeader,
+    pub throttle_time: u32,
+    pub error: u16,
+    pub topics: Vec<TopicResults>,
+}
+
+#[derive(Debug)]
+pub struct AlterPartitionReassignmentsResponse {
+    pub header: ResponseHeader,
+    pub throttle_time: u32,
+    pub error: u16,
+    pub error_message: Option<String>,
+    pub topics: Vec<TopicResults>,
+}
+
+#[derive(Debug)]
+pub struct OngoingPartitionReassignment {
+    pub id: u32,
+    pub replicas: Vec<u32>,
+    pub adding_replicas: Vec<u32>,
+    pub removing_replicas: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct OngoingTopicReassignment {
+    pub name: String,
+    pub partitions: Vec<OngoingPartitionReassignment>,
+}
+
+#[derive(Debug)]
+pub struct ListPartitionReassignmentsResponse {
+    pub header: ResponseHeader,
+    pub throttle_time: u32,
+    pub error: u16,
+    pub error_message: Option<String>,
+    pub topics: Vec<OngoingTopicReassignment>,
 }
 
 //
@@ -208,312 +487,354 @@ pub struct ProduceResponse {
 //
 
 impl ApiVersionsResponse {
-    // Create a new ApiVersionsResponse, field values extracted from a Wireshark analysis
+    // Create a new ApiVersionsResponse, advertising the versions that can be
+    // parsed and answered
     pub fn new(req: &ApiVersionsRequest) -> Self {
+        let api_versions = (0..)
+            .map_while(ApiKey::from_u16)
+            .filter_map(|api_key| {
+                let (min_version, max_version) = api_key.supported_versions()?;
+                Some(ApiVersion {
+                    api_key: ToPrimitive::to_u16(&api_key).unwrap(),
+                    min_version,
+                    max_version,
+                })
+            })
+            .collect();
         Self {
             header: ResponseHeader {
                 correlation_id: req.header.correlation_id,
             },
+            version: req.header.api_version,
             error_code: 0,
             throttle_time: 0,
-            api_versions: vec![
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::Produce).unwrap(),
-                    min_version: 0,
-                    max_version: 8,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::Fetch).unwrap(),
-                    min_version: 0,
-                    max_version: 11,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::Offsets).unwrap(),
-                    min_version: 0,
-                    max_version: 5,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::Metadata).unwrap(),
-                    min_version: 0,
-                    max_version: 9,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::LeaderAndIsr).unwrap(),
-                    min_version: 0,
-                    max_version: 4,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::StopReplica).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::UpdateMetadata).unwrap(),
-                    min_version: 0,
-                    max_version: 6,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::ControlledShutdown).unwrap(),
-                    min_version: 0,
-                    max_version: 3,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::OffsetCommit).unwrap(),
-                    min_version: 0,
-                    max_version: 8,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::OffsetFetch).unwrap(),
-                    min_version: 0,
-                    max_version: 6,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::GroupCoordinator).unwrap(),
-                    min_version: 0,
-                    max_version: 3,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::JoinGroup).unwrap(),
-                    min_version: 0,
-                    max_version: 6,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::Heartbeat).unwrap(),
-                    min_version: 0,
-                    max_version: 4,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::LeaveGroup).unwrap(),
-                    min_version: 0,
-                    max_version: 4,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::SyncGroup).unwrap(),
-                    min_version: 0,
-                    max_version: 4,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DescribeGroups).unwrap(),
-                    min_version: 0,
-                    max_version: 5,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::ListGroups).unwrap(),
-                    min_version: 0,
-                    max_version: 3,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::SaslHandshake).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::ApiVersions).unwrap(),
-                    min_version: 0,
-                    max_version: 3,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::CreateTopics).unwrap(),
-                    min_version: 0,
-                    max_version: 5,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DeleteTopics).unwrap(),
-                    min_version: 0,
-                    max_version: 4,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DeleteRecords).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::InitProducerId).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::OffsetForLeaderEpoch).unwrap(),
-                    min_version: 0,
-                    max_version: 3,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::AddPartitionsToTxn).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::AddOffsetsToTxn).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::EndTxn).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::WriteTxnMarkers).unwrap(),
-                    min_version: 0,
-                    max_version: 0,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::TxnOffsetCommit).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DescribeAcls).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::CreateAcls).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DeleteAcls).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DescribeConfigs).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::AlterConfigs).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::AlterReplicaLogDirs).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DescribeLogDirs).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::SaslAuthenticate).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::CreatePartitions).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::CreateDelegationToken).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::RenewDelegationToken).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::ExpireDelegationToken).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DescribeDelegationToken).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::DeleteGroups).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::ElectLeaders).unwrap(),
-                    min_version: 0,
-                    max_version: 2,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::IncrementalAlterConfigs).unwrap(),
-                    min_version: 0,
-                    max_version: 1,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::AlterPartitionReassignments).unwrap(),
-                    min_version: 0,
-                    max_version: 0,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::ListPartitionReassignments).unwrap(),
-                    min_version: 0,
-                    max_version: 0,
-                },
-                ApiVersion {
-                    api_key: ToPrimitive::to_u16(&ApiKey::OffsetDelete).unwrap(),
-                    min_version: 0,
-                    max_version: 0,
-                },
-            ],
+            api_versions,
         }
     }
 }
 
-const NODE_ID: u32 = 1003;
-
 impl TopicMetadata {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, partitions: Vec<PartitionMetadata>) -> Self {
         Self {
             error: 0,
             name,
             is_internal: false,
-            partitions: vec![PartitionMetadata {
-                error: 0,
-                id: 0,
-                leader_id: NODE_ID,
-                leader_epoch: 0,
-                replicas: vec![NODE_ID],
-                caught_up_replicas: vec![NODE_ID],
-                offline_replicas: Vec::<u32>::new(),
-            }],
+            partitions,
+            topic_authorized_operations: 0,
+        }
+    }
+
+    // Create the metadata of a topic that doesn't exist
+    pub fn unknown(name: String) -> Self {
+        Self {
+            error: ErrorCode::UnknownTopicOrPartition.value(),
+            name,
+            is_internal: false,
+            partitions: vec![],
             topic_authorized_operations: 0,
         }
     }
 }
 
 impl MetadataResponse {
-    pub fn new(req: &MetadataRequest) -> Self {
-        // TODO: value customization
-        let topics = req
-            .topics
-            .iter()
-            .map(|t| TopicMetadata::new(t.clone()))
-            .collect::<Vec<_>>();
+    // Create a new MetadataResponse, the first broker being the controller
+    pub fn new(
+        req: &MetadataRequest,
+        brokers: Vec<BrokerMetadata>,
+        cluster_id: &str,
+        topics: Vec<TopicMetadata>,
+    ) -> Self {
         Self {
             header: ResponseHeader {
                 correlation_id: req.header.correlation_id,
             },
             throttle_time: 0,
-            // We only ever have a broker. That's the whole point of the project.
-            brokers: vec![BrokerMetadata {
-                node_id: NODE_ID,
-                host: "localhost".to_string(),
-                port: 9092,
-            }],
-            cluster_id: "0NHLrMQhQe2sWh6PvXAxcA".to_string(),
-            controller_id: NODE_ID,
-            topics: topics,
+            controller_id: brokers.first().map_or(u32::MAX, |b| b.node_id),
+            brokers,
+            cluster_id: cluster_id.to_string(),
+            topics,
             cluster_authorized_operations: 0,
         }
     }
 }
 
 impl ProduceResponse {
-    // Create a new ApiVersionsResponse, field values extracted from a Wireshark analysis
-    pub fn new(_req: &ProduceRequest) -> Self {
-        unimplemented!();
+    // Create a new ProduceResponse, with the results of appending each partition
+    pub fn new(req: &ProduceRequest, topics: Vec<ProduceTopicResponse>) -> Self {
+        Self {
+            header: ResponseHeader {
+                correlation_id: req.header.correlation_id,
+            },
+            topics,
+            throttle_time: 0,
+        }
+    }
+}
+
+impl FetchResponse {
+    // Create a new FetchResponse, with the records read from each partition
+    pub fn new(req: &FetchRequest, topics: Vec<FetchTopicResponse>) -> Self {
+        Self {
+            header: ResponseHeader {
+                correlation_id: req.header.correlation_id,
+            },
+            throttle_time: 0,
+            error: 0,
+            topics,
+        }
+    }
+
+    /// Size of the records in the response
+    pub fn records_size(&self) -> usize {
+        self.topics
+            .iter()
+            .flat_map(|t| &t.partitions)
+            .map(|p| p.records.len())
+            .sum()
+    }
+}
+
+impl FetchPartitionResponse {
+    pub fn error(id: u32, error: ErrorCode) -> Self {
+        Self {
+            id,
+            error: error.value(),
+            high_watermark: -1,
+            log_start_offset: -1,
+            records: vec![],
+        }
+    }
+}
+
+impl OffsetForLeaderEpochResponse {
+    pub fn new(req: &OffsetForLeaderEpochRequest, topics: Vec<EpochEndOffsetTopic>) -> Self {
+        Self {
+            header: ResponseHeader {
+                correlation_id: req.header.correlation_id,
+            },
+            version: req.header.api_version,
+            throttle_time: 0,
+            topics,
+        }
+    }
+}
+
+impl EpochEndOffset {
+    // End offset of an epoch, None when it's unknown
+    pub fn new(id: u32, result: Result<Option<(i32, i64)>, ErrorCode>) -> Self {
+        let (error, (leader_epoch, end_offset)) = match result {
+            Ok(found) => (ErrorCode::NoError, found.unwrap_or((-1, -1))),
+            Err(e) => (e, (-1, -1)),
+        };
+        Self {
+            error: error.value(),
+            id,
+            leader_epoch,
+            end_offset,
+        }
+    }
+}
+
+impl ElectLeadersResponse {
+    // Create a new ElectLeadersResponse, with an error for the whole request or
+    // the result of each election
+    pub fn new(req: &ElectLeadersRequest, error: ErrorCode, topics: Vec<TopicResults>) -> Self {
+        Self {
+            header: ResponseHeader {
+                correlation_id: req.header.correlation_id,
+            },
+            throttle_time: 0,
+            error: error.value(),
+            topics,
+        }
+    }
+}
+
+impl AlterPartitionReassignmentsResponse {
+    pub fn new(
+        req: &AlterPartitionReassignmentsRequest,
+        error: ErrorCode,
+        topics: Vec<TopicResults>,
+    ) -> Self {
+        Self {
+            header: ResponseHeader {
+                correlation_id: req.header.correlation_id,
+            },
+            throttle_time: 0,
+            error: error.value(),
+            error_message: None,
+            topics,
+        }
+    }
+}
+
+impl ListPartitionReassignmentsResponse {
+    pub fn new(
+        req: &ListPartitionReassignmentsRequest,
+        error: ErrorCode,
+        topics: Vec<OngoingTopicReassignment>,
+    ) -> Self {
+        Self {
+            header: ResponseHeader {
+                correlation_id: req.header.correlation_id,
+            },
+            throttle_time: 0,
+            error: error.value(),
+            error_message: None,
+            topics,
+        }
+    }
+}
+
+impl PartitionResult {
+    pub fn new(id: u32, result: Result<(), ErrorCode>) -> Self {
+        let error = result.err().unwrap_or(ErrorCode::NoError);
+        Self {
+            id,
+            error: error.value(),
+            error_message: None,
+        }
+    }
+}
+
+impl ErrorResponse {
+    pub fn new(api_key: u16, api_version: u16, correlation_id: u32, error_code: u16) -> Self {
+        Self {
+            header: ResponseHeader { correlation_id },
+            api_key,
+            api_version,
+            error_code,
+            topics: vec![],
+        }
+    }
+
+    /// Whether the error can be written in the layout of the response
+    /// That takes a supported version, and a place for the error: a
+    /// top-level error code, or the topics read from the request. Other
+    /// requests are answered with their correlation id and the error code
+    /// alone, except ApiVersions, always answered in v0.
+    pub fn has_layout(&self) -> bool {
+        let api_key = match ApiKey::from_u16(self.api_key) {
+            Some(api_key) => api_key,
+            None => return false,
+        };
+        let supported = matches!(
+            api_key.supported_versions(),
+            Some((min, max)) if (min..=max).contains(&self.api_version)
+        );
+        match api_key {
+            ApiKey::Metadata | ApiKey::Produce | ApiKey::OffsetForLeaderEpoch => {
+                supported && !self.topics.is_empty()
+            }
+            _ => supported,
+        }
+    }
+}
+
+//
+// Accessors
+//
+
+impl ProduceRecordBatchRequest {
+    /// Compression codec of the records (gzip, snappy, lz4 or zstd), 0 if none
+    pub fn compression(&self) -> u16 {
+        self.options & 0x07
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.options & 0x10 != 0
+    }
+
+    /// Whether the batch holds control records, e.g. transaction markers
+    pub fn is_control(&self) -> bool {
+        self.options & 0x20 != 0
+    }
+}
+
+impl Request {
+    pub fn header(&self) -> &RequestHeader {
+        match self {
+            Request::ApiVersionsRequest(req) => &req.header,
+            Request::MetadataRequest(req) => &req.header,
+            Request::ProduceRequest(req) => &req.header,
+            Request::FetchRequest(req) => &req.header,
+            Request::OffsetForLeaderEpochRequest(req) => &req.header,
+            Request::ElectLeadersRequest(req) => &req.header,
+            Request::AlterPartitionReassignmentsRequest(req) => &req.header,
+            Request::ListPartitionReassignmentsRequest(req) => &req.header,
+        }
+    }
+
+    /// Whether the client waits for a response
+    /// Produce requests with no acks required are processed without one.
+    pub fn expects_response(&self) -> bool {
+        !matches!(self, Request::ProduceRequest(req) if req.required_acks == 0)
+    }
+}
+
+impl Response {
+    pub fn header(&self) -> &ResponseHeader {
+        match self {
+            Response::ApiVersionsResponse(resp) => &resp.header,
+            Response::MetadataResponse(resp) => &resp.header,
+            Response::ProduceResponse(resp) => &resp.header,
+            Response::FetchResponse(resp) => &resp.header,
+            Response::OffsetForLeaderEpochResponse(resp) => &resp.header,
+            Response::ElectLeadersResponse(resp) => &resp.header,
+            Response::AlterPartitionReassignmentsResponse(resp) => &resp.header,
+            Response::ListPartitionReassignmentsResponse(resp) => &resp.header,
+            Response::ErrorResponse(resp) => &resp.header,
+        }
+    }
+
+    /// Error code of the response, or of the first topic or partition that failed,
+    /// 0 if none did
+    pub fn error_code(&self) -> u16 {
+        self.errors().first().copied().unwrap_or(0)
+    }
+
+    /// Error codes of the response and of every topic or partition that failed
+    pub fn errors(&self) -> Vec<u16> {
+        fn results(error: u16, topics: &[TopicResults]) -> Vec<u16> {
+            iter::once(error)
+                .chain(
+                    topics
+                        .iter()
+                        .flat_map(|t| t.partitions.iter().map(|p| p.error)),
+                )
+                .collect()
+        }
+        let errors = match self {
+            Response::ApiVersionsResponse(resp) => vec![resp.error_code],
+            Response::MetadataResponse(resp) => resp
+                .topics
+                .iter()
+                .flat_map(|t| iter::once(t.error).chain(t.partitions.iter().map(|p| p.error)))
+                .collect(),
+            Response::ProduceResponse(resp) => resp
+                .topics
+                .iter()
+                .flat_map(|t| t.partitions.iter().map(|p| p.error))
+                .collect(),
+            Response::FetchResponse(resp) => iter::once(resp.error)
+                .chain(
+                    resp.topics
+                        .iter()
+                        .flat_map(|t| t.partitions.iter().map(|p| p.error)),
+                )
+                .collect(),
+            Response::OffsetForLeaderEpochResponse(resp) => resp
+                .topics
+                .iter()
+                .flat_map(|t| t.partitions.iter().map(|p| p.error))
+                .collect(),
+            Response::ElectLeadersResponse(resp) => results(resp.error, &resp.topics),
+            Response::AlterPartitionReassignmentsResponse(resp) => {
+                results(resp.error, &resp.topics)
+            }
+            Response::ListPartitionReassignmentsResponse(resp) => vec![resp.error],
+            Response::ErrorResponse(resp) => vec![resp.error_code],
+        };
+        errors.into_iter().filter(|&e| e != 0).collect()
     }
 }
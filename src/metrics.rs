@@ -0,0 +1,419 @@
+use num_traits::FromPrimitive;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::error::ErrorCode;
+use crate::log::PartitionLog;
+use crate::messages::{ApiKey, Request, Response};
+use crate::state::State;
+
+/// Upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations up to each bucket's bound, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| value <= le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// Latency of the requests, by API and version
+    requests: BTreeMap<(String, u16), Histogram>,
+    /// Errors in the responses, by error name, once per failed topic or partition
+    errors: BTreeMap<&'static str, u64>,
+    /// Record batch bytes produced, by topic
+    bytes_in: BTreeMap<String, u64>,
+    /// Record batch bytes fetched, by topic
+    bytes_out: BTreeMap<String, u64>,
+}
+
+/// Broker metrics, exposed in the Prometheus text format
+///
+/// Traffic is counted as requests are answered, and the state of partitions
+/// and consumer groups is read when the metrics are rendered.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicI64,
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count the records produced by a request, as it's read
+    ///
+    /// * `req` - request
+    pub fn observe_received(&self, req: &Request) {
+        if let Request::ProduceRequest(req) = req {
+            let mut counters = self.counters.lock().unwrap();
+            for topic in &req.topics {
+                let size = topic
+                    .partitions
+                    .iter()
+                    .flat_map(|p| &p.batches)
+                    .map(|b| b.size as u64)
+                    .sum::<u64>();
+                *counters.bytes_in.entry(topic.name.clone()).or_default() += size;
+            }
+        }
+    }
+
+    /// Count a request once its response is written, with the records it fetched
+    ///
+    /// * `api_key` - API key of the request
+    /// * `api_version` - version of the request
+    /// * `latency` - time from reading the request to writing the response
+    /// * `resp` - response
+    pub fn observe_answered(
+        &self,
+        api_key: u16,
+        api_version: u16,
+        latency: Duration,
+        resp: &Response,
+    ) {
+        let api = api_name(api_key);
+        let mut counters = self.counters.lock().unwrap();
+        counters
+            .requests
+            .entry((api, api_version))
+            .or_default()
+            .observe(latency.as_secs_f64());
+        for error_code in resp.errors() {
+            let name = ErrorCode::from_code(error_code as i16).map_or("UNKNOWN", |e| e.name());
+            *counters.errors.entry(name).or_default() += 1;
+        }
+        if let Response::FetchResponse(resp) = resp {
+            for topic in &resp.topics {
+                let size = topic
+                    .partitions
+                    .iter()
+                    .map(|p| p.records.len() as u64)
+                    .sum::<u64>();
+                *counters.bytes_out.entry(topic.name.clone()).or_default() += size;
+            }
+        }
+    }
+
+    /// Render the metrics in the Prometheus text format
+    ///
+    /// * `state` - broker state, for the partition and consumer group metrics
+    pub fn render(&self, state: &State) -> String {
+        let mut out = String::new();
+        let counters = self.counters.lock().unwrap();
+
+        header(
+            &mut out,
+            "pseudokafka_connections",
+            "gauge",
+            "Open client connections",
+        );
+        let connections = self.connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "pseudokafka_connections {}", connections);
+
+        header(
+            &mut out,
+            "pseudokafka_request_duration_seconds",
+            "histogram",
+            "Time to answer requests, by API and version",
+        );
+        for ((api, version), histogram) in &counters.requests {
+            let labels = format!("api=\"{}\",version=\"{}\"", api, version);
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += n;
+                let _ = writeln!(
+                    out,
+                    "pseudokafka_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+            let name = "pseudokafka_request_duration_seconds";
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name, labels, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+
+        header(
+            &mut out,
+            "pseudokafka_errors_total",
+            "counter",
+            "Errors in the responses, by error code, once per failed topic or partition",
+        );
+        for (error, n) in &counters.errors {
+            let _ = writeln!(out, "pseudokafka_errors_total{{error=\"{}\"}} {}", error, n);
+        }
+
+        for (name, help, bytes) in [
+            (
+                "pseudokafka_bytes_in_total",
+                "Record bytes produced, by topic",
+                &counters.bytes_in,
+            ),
+            (
+                "pseudokafka_bytes_out_total",
+                "Record bytes fetched, by topic",
+                &counters.bytes_out,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for (topic, n) in bytes {
+                let _ = writeln!(out, "{}{{topic=\"{}\"}} {}", name, escape(topic), n);
+            }
+        }
+
+        type PartitionMetric = (&'static str, &'static str, fn(&PartitionLog) -> i64);
+        let partition_metrics: [PartitionMetric; 4] = [
+            (
+                "pseudokafka_partition_messages",
+                "Records in the log, without those removed by compaction",
+                |log| log.batches().map(|b| b.records.len() as i64).sum(),
+            ),
+            (
+                "pseudokafka_partition_log_start_offset",
+                "First offset in the log",
+                |log| log.log_start_offset(),
+            ),
+            (
+                "pseudokafka_partition_log_end_offset",
+                "Offset of the next record",
+                |log| log.log_end_offset(),
+            ),
+            (
+                "pseudokafka_partition_log_size_bytes",
+                "Size of the log",
+                |log| log.size() as i64,
+            ),
+        ];
+        for (name, help, value) in &partition_metrics {
+            header(&mut out, name, "gauge", help);
+            for (topic, t) in &state.topics {
+                for (p, log) in t.partitions.iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "{}{{topic=\"{}\",partition=\"{}\"}} {}",
+                        name,
+                        escape(topic),
+                        p,
+                        value(log)
+                    );
+                }
+            }
+        }
+
+        header(
+            &mut out,
+            "pseudokafka_consumer_group_lag",
+            "gauge",
+            "Offsets between the committed offset of a group and the end of the log",
+        );
+        for (group, offsets) in &state.offsets {
+            for ((topic, p), offset) in offsets {
+                let end = state
+                    .topics
+                    .get(topic)
+                    .and_then(|t| t.partitions.get(*p as usize))
+                    .map(|log| log.log_end_offset());
+                if let Some(end) = end {
+                    let _ = writeln!(
+                        out,
+                        "pseudokafka_consumer_group_lag{{group=\"{}\",topic=\"{}\",partition=\"{}\"}} {}",
+                        escape(group),
+                        escape(topic),
+                        p,
+                        (end - offset).max(0)
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn api_name(api_key: u16) -> String {
+    ApiKey::from_u16(api_key).map_or_else(|| api_key.to_string(), |k| format!("{:?}", k))
+}
+
+// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::compact;
+    use crate::config::{CleanupPolicy, TopicConfig};
+    use crate::messages::{
+        ProducePartitionRequest, ProducePartitionResponse, ProduceRequest, ProduceResponse,
+        ProduceTopicRequest, ProduceTopicResponse, RequestHeader, ResponseHeader,
+    };
+    use crate::testing::batch;
+
+    fn lines(out: &str, prefix: &str) -> Vec<String> {
+        out.lines()
+            .filter(|l| l.starts_with(prefix))
+            .map(String::from)
+            .collect()
+    }
+
+    fn produce_response(errors: &[u16]) -> Response {
+        let partitions = errors
+            .iter()
+            .enumerate()
+            .map(|(id, &error)| ProducePartitionResponse {
+                id: id as u32,
+                error,
+                base_offset: -1,
+                log_append_time: -1,
+                log_start_offset: -1,
+            })
+            .collect();
+        Response::ProduceResponse(ProduceResponse {
+            header: ResponseHeader { correlation_id: 1 },
+            topics: vec![ProduceTopicResponse {
+                name: "events".to_string(),
+                partitions,
+            }],
+            throttle_time: 0,
+        })
+    }
+
+    #[test]
+    fn render_requests_and_errors() {
+        let metrics = Metrics::default();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        let latency = Duration::from_millis(20);
+        metrics.observe_answered(0, 8, latency, &produce_response(&[0, 0]));
+        metrics.observe_answered(0, 8, latency, &produce_response(&[3, 0, 3, 10]));
+
+        let out = metrics.render(&State::default());
+        assert!(out.contains("# TYPE pseudokafka_connections gauge\npseudokafka_connections 1\n"));
+        let labels = "api=\"Produce\",version=\"8\"";
+        assert_eq!(
+            lines(&out, "pseudokafka_request_duration_seconds_bucket")[2..5],
+            [
+                format!(
+                    "pseudokafka_request_duration_seconds_bucket{{{},le=\"0.01\"}} 0",
+                    labels
+                ),
+                format!(
+                    "pseudokafka_request_duration_seconds_bucket{{{},le=\"0.025\"}} 2",
+                    labels
+                ),
+                format!(
+                    "pseudokafka_request_duration_seconds_bucket{{{},le=\"0.05\"}} 2",
+                    labels
+                ),
+            ]
+        );
+        assert!(out.contains(&format!(
+            "pseudokafka_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "pseudokafka_request_duration_seconds_count{{{}}} 2\n",
+            labels
+        )));
+        // Every failed partition is counted, not only the first
+        assert_eq!(
+            lines(&out, "pseudokafka_errors_total"),
+            [
+                "pseudokafka_errors_total{error=\"MESSAGE_TOO_LARGE\"} 1",
+                "pseudokafka_errors_total{error=\"UNKNOWN_TOPIC_OR_PARTITION\"} 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_partitions_and_groups() {
+        let metrics = Metrics::default();
+        let mut state = State::default();
+        let config = TopicConfig {
+            cleanup_policy: CleanupPolicy {
+                delete: false,
+                compact: true,
+            },
+            min_compaction_lag_ms: 0,
+            ..TopicConfig::default()
+        };
+        state.create_topic("events", 1, config.clone()).unwrap();
+        let produced = batch(0, &[(Some("k"), Some("1")), (Some("k"), Some("2"))]);
+        state.append("events", 0, &produced).unwrap();
+        state
+            .append("events", 0, &batch(0, &[(Some("k"), Some("3"))]))
+            .unwrap();
+        state.commit_offset("g\"1", "events", 0, 1).unwrap();
+        let log = &mut state.topics.get_mut("events").unwrap().partitions[0];
+        compact(log, &config, 1);
+        metrics.observe_received(&Request::ProduceRequest(ProduceRequest {
+            header: RequestHeader {
+                api_key: ApiKey::Produce,
+                api_version: 8,
+                correlation_id: 1,
+                client_id: None,
+            },
+            transactional_id: None,
+            required_acks: 1,
+            timeout: 1500,
+            topics: vec![ProduceTopicRequest {
+                name: "events".to_string(),
+                partitions: vec![ProducePartitionRequest {
+                    id: 0,
+                    batches: vec![produced],
+                }],
+            }],
+        }));
+
+        let out = metrics.render(&state);
+        let labels = "topic=\"events\",partition=\"0\"";
+        assert!(out.contains("pseudokafka_bytes_in_total{topic=\"events\"} 100\n"));
+        // Compaction leaves a single record in the offsets 0 to 3
+        assert!(out.contains(&format!("pseudokafka_partition_messages{{{}}} 1\n", labels)));
+        assert!(out.contains(&format!(
+            "pseudokafka_partition_log_end_offset{{{}}} 3\n",
+            labels
+        )));
+        assert!(out.contains(
+            "pseudokafka_consumer_group_lag{group=\"g\\\"1\",topic=\"events\",partition=\"0\"} 2\n"
+        ));
+    }
+}
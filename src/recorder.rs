@@ -0,0 +1,466 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::broker::Context;
+use crate::de;
+use crate::log::now_ms;
+use crate::messages::ApiKey;
+use crate::storage::invalid_data;
+
+/// How long replay waits for a response
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request and the response written back, as a line of a recording
+///
+/// Requests and responses are kept as sent on the wire, without their
+/// length, so they can be replayed as they are or used as test fixtures.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Connection the request came in on, numbered from 0 in each recording
+    pub connection: u64,
+    /// Broker and listener the connection was made to
+    pub node_id: u32,
+    pub listener: String,
+    /// When the request was read, in milliseconds since the epoch
+    pub received_ms: i64,
+    /// When the response was written
+    pub sent_ms: i64,
+    pub api_key: u16,
+    pub api_version: u16,
+    pub correlation_id: u32,
+    /// Request bytes, in base64
+    #[serde(with = "base64_bytes")]
+    pub request: Vec<u8>,
+    /// Response bytes as written, in base64, None if the connection was
+    /// closed without writing it
+    #[serde(with = "base64_option")]
+    pub response: Option<Vec<u8>>,
+    /// Whether the connection was closed after writing the response
+    #[serde(default)]
+    pub closed: bool,
+}
+
+impl Exchange {
+    /// A request just read, not answered yet
+    ///
+    /// * `connection` - connection id, from `Recorder::connection`
+    /// * `ctx` - broker and listener the request came in on
+    /// * `request` - request, without its length
+    pub fn new(connection: u64, ctx: &Context, request: &[u8]) -> Self {
+        let (api_key, api_version, correlation_id) = match de::header_prefix(request) {
+            Ok((_, header)) => header,
+            Err(_) => (0, 0, 0),
+        };
+        let now = now_ms();
+        Self {
+            connection,
+            node_id: ctx.node_id,
+            listener: ctx.listener.clone(),
+            received_ms: now,
+            sent_ms: now,
+            api_key,
+            api_version,
+            correlation_id,
+            request: request.to_vec(),
+            response: None,
+            closed: false,
+        }
+    }
+
+    /// Set the response written back, length included
+    ///
+    /// * `written` - bytes written, empty if none
+    /// * `closed` - whether the connection was closed after them
+    pub fn respond(&mut self, written: &[u8], closed: bool) {
+        self.sent_ms = now_ms();
+        self.response = written.get(4..).map(|r| r.to_vec());
+        self.closed = closed;
+    }
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let api = ApiKey::from_u16(self.api_key)
+            .map(|key| format!("{:?}", key))
+            .unwrap_or_else(|| format!("API key {}", self.api_key));
+        write!(
+            f,
+            "connection {}, {} v{}, correlation id {}",
+            self.connection, api, self.api_version, self.correlation_id
+        )
+    }
+}
+
+/// Writes the traffic of a broker to a JSONL file, an exchange per line
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+    connections: AtomicU64,
+}
+
+impl Recorder {
+    /// Start a recording, replacing the file if it exists
+    ///
+    /// * `path` - JSONL file
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(File::create(path)?),
+            connections: AtomicU64::new(0),
+        })
+    }
+
+    /// Id for a new connection
+    pub fn connection(&self) -> u64 {
+        self.connections.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Append an exchange, as soon as its response is written
+    ///
+    /// * `exchange` - request and response
+    pub fn record(&self, exchange: &Exchange) {
+        let mut line = serde_json::to_vec(exchange).unwrap_or_default();
+        line.push(b'\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            error!("Error recording traffic: {}", e);
+        }
+    }
+}
+
+/// Read a recording
+///
+/// * `path` - JSONL file written by a `Recorder`
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Exchange>> {
+    fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| invalid_data(format!("line {}: {}", i + 1, e)))
+        })
+        .collect()
+}
+
+/// A response that differs from the recorded one
+#[derive(Debug)]
+pub struct Difference {
+    pub exchange: Exchange,
+    /// Response received on replay, None if the connection was closed instead
+    pub actual: Option<Vec<u8>>,
+}
+
+impl Difference {
+    /// Offset of the first byte that differs
+    pub fn offset(&self) -> usize {
+        let expected = self.exchange.response.as_deref().unwrap_or_default();
+        let actual = self.actual.as_deref().unwrap_or_default();
+        expected
+            .iter()
+            .zip(actual)
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| expected.len().min(actual.len()))
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.exchange.response, &self.actual) {
+            (Some(expected), Some(actual)) => write!(
+                f,
+                "{}: responses differ at byte {} ({} bytes recorded, {} received)",
+                self.exchange,
+                self.offset(),
+                expected.len(),
+                actual.len()
+            ),
+            (Some(_), None) => write!(f, "{}: connection closed", self.exchange),
+            (None, _) => write!(f, "{}: connection not closed", self.exchange),
+        }
+    }
+}
+
+/// Send the requests of a recording to a broker, and compare the responses
+///
+/// Each recorded connection is replayed on its own connection, and requests
+/// are sent one at a time, in the order they were answered. Returns the
+/// exchanges whose response differs.
+///
+/// * `exchanges` - recording
+/// * `target` - address to send each exchange to
+pub fn replay(
+    exchanges: &[Exchange],
+    target: impl Fn(&Exchange) -> SocketAddr,
+) -> io::Result<Vec<Difference>> {
+    let mut connections = HashMap::new();
+    let mut differences = vec![];
+    for exchange in exchanges {
+        let stream = match connections.entry(exchange.connection) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let stream = TcpStream::connect(target(exchange))?;
+                stream.set_read_timeout(Some(REPLAY_TIMEOUT))?;
+                e.insert(stream)
+            }
+        };
+        let request = &exchange.request;
+        stream.write_all(&[&(request.len() as u32).to_be_bytes()[..], request].concat())?;
+        let actual = if exchange.closed {
+            // Whatever is written before the connection is closed
+            let mut written = vec![];
+            stream.read_to_end(&mut written)?;
+            connections.remove(&exchange.connection);
+            written.get(4..).map(|r| r.to_vec())
+        } else if exchange.response.is_none() {
+            // Processed without a response
+            None
+        } else {
+            read_response(stream)?
+        };
+        let normalized = |response: &Option<Vec<u8>>| {
+            response
+                .as_deref()
+                .map(|r| normalize(exchange.api_key, exchange.api_version, r))
+        };
+        if normalized(&actual) != normalized(&exchange.response) {
+            differences.push(Difference {
+                exchange: exchange.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(differences)
+}
+
+/// A response without the parts that depend on where the broker runs rather
+/// than on what it answers: the hosts and ports of the brokers and the
+/// cluster id, in Metadata responses. Other responses are kept as they are.
+///
+/// * `api_key` - API key of the request
+/// * `api_version` - version of the request
+/// * `response` - response, without its length
+pub fn normalize(api_key: u16, api_version: u16, response: &[u8]) -> Vec<u8> {
+    match ApiKey::from_u16(api_key) {
+        Some(ApiKey::Metadata) if api_version >= 9 => normalize_metadata(response),
+        _ => None,
+    }
+    .unwrap_or_else(|| response.to_vec())
+}
+
+// A flexible Metadata response without the host and port of each broker, and
+// the cluster id, or None if it can't be read
+fn normalize_metadata(response: &[u8]) -> Option<Vec<u8>> {
+    let mut r = ResponseReader {
+        bytes: response,
+        pos: 0,
+    };
+    r.skip(4)?; // Correlation id
+    r.tagged_fields()?;
+    r.skip(4)?; // Throttle time
+    let brokers = r.uvarint()?.checked_sub(1)?;
+    let mut normalized = response[..r.pos].to_vec();
+    for _ in 0..brokers {
+        let start = r.pos;
+        r.skip(4)?; // Node id
+        normalized.extend_from_slice(&response[start..r.pos]);
+        r.compact_string()?; // Host
+        r.skip(4)?; // Port
+        let start = r.pos;
+        r.compact_string()?; // Rack
+        r.tagged_fields()?;
+        normalized.extend_from_slice(&response[start..r.pos]);
+    }
+    r.compact_string()?; // Cluster id
+    normalized.extend_from_slice(&response[r.pos..]);
+    Some(normalized)
+}
+
+// Reads the fields of a response that are skipped or copied as they are
+struct ResponseReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ResponseReader<'_> {
+    fn skip(&mut self, n: usize) -> Option<()> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len())?;
+        self.pos = end;
+        Some(())
+    }
+
+    fn uvarint(&mut self) -> Option<usize> {
+        let mut value = 0usize;
+        for shift in (0..35).step_by(7) {
+            let byte = *self.bytes.get(self.pos)?;
+            self.pos += 1;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    // Nullable too: its length plus one, 0 for null
+    fn compact_string(&mut self) -> Option<()> {
+        let length = self.uvarint()?;
+        self.skip(length.saturating_sub(1))
+    }
+
+    fn tagged_fields(&mut self) -> Option<()> {
+        for _ in 0..self.uvarint()? {
+            self.uvarint()?;
+            let size = self.uvarint()?;
+            self.skip(size)?;
+        }
+        Some(())
+    }
+}
+
+// Read a response, or None if the connection is closed
+fn read_response(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut size = [0u8; 4];
+    match stream.read_exact(&mut size) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut response = vec![0u8; u32::from_be_bytes(size) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(Some(response))
+}
+
+mod base64_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        BASE64.decode(s).map_err(serde::de::Error::custom)
+    }
+}
+
+mod base64_option {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => s.serialize_str(&BASE64.encode(bytes)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| BASE64.decode(s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{BrokerMetadata, MetadataResponse, ResponseHeader};
+    use crate::ser::Serialize as _;
+
+    #[test]
+    fn exchange_as_json() {
+        let ctx = Context {
+            listener: "PLAINTEXT".to_string(),
+            node_id: 1003,
+        };
+        let mut exchange = Exchange::new(2, &ctx, &[0, 18, 0, 3, 0, 0, 0, 7, 0xff]);
+        assert_eq!(
+            (
+                exchange.api_key,
+                exchange.api_version,
+                exchange.correlation_id
+            ),
+            (18, 3, 7)
+        );
+        exchange.respond(&[0, 0, 0, 2, 1, 2], false);
+        assert_eq!(exchange.response, Some(vec![1, 2]));
+        assert_eq!(
+            exchange.to_string(),
+            "connection 2, ApiVersions v3, correlation id 7"
+        );
+
+        let json = serde_json::to_value(&exchange).unwrap();
+        assert_eq!(json["request"], "ABIAAwAAAAf/");
+        assert_eq!(json["response"], "AQI=");
+        assert_eq!(serde_json::from_value::<Exchange>(json).unwrap(), exchange);
+
+        exchange.respond(&[], true);
+        let json = serde_json::to_string(&exchange).unwrap();
+        assert!(json.contains(r#""response":null,"closed":true"#));
+        assert_eq!(serde_json::from_str::<Exchange>(&json).unwrap(), exchange);
+    }
+
+    #[test]
+    fn first_difference() {
+        let ctx = Context {
+            listener: "PLAINTEXT".to_string(),
+            node_id: 1003,
+        };
+        let mut exchange = Exchange::new(0, &ctx, &[0, 3, 0, 9, 0, 0, 0, 1]);
+        exchange.respond(&[0, 0, 0, 3, 1, 2, 3], false);
+        let difference = |actual: Option<&[u8]>| Difference {
+            exchange: exchange.clone(),
+            actual: actual.map(|a| a.to_vec()),
+        };
+        assert_eq!(difference(Some(&[1, 4, 3])).offset(), 1);
+        assert_eq!(difference(Some(&[1, 2])).offset(), 2);
+        assert_eq!(
+            difference(None).to_string(),
+            "connection 0, Metadata v9, correlation id 1: connection closed"
+        );
+    }
+
+    #[test]
+    fn normalize_metadata_addresses() {
+        let metadata = |node_id, host: &str, port, cluster_id: &str| {
+            let response = MetadataResponse {
+                header: ResponseHeader { correlation_id: 1 },
+                throttle_time: 0,
+                brokers: vec![BrokerMetadata {
+                    node_id,
+                    host: host.to_string(),
+                    port,
+                    rack: Some("a".to_string()),
+                }],
+                cluster_id: cluster_id.to_string(),
+                controller_id: node_id,
+                topics: vec![],
+                cluster_authorized_operations: 0,
+            };
+            normalize(3, 9, &response.to_bytes().unwrap()[4..])
+        };
+        let recorded = metadata(1003, "kafka", 9092, "a");
+        assert_eq!(metadata(1003, "localhost", 41234, "other"), recorded);
+        assert_ne!(metadata(1004, "kafka", 9092, "a"), recorded);
+        // Other responses are compared as they are
+        assert_eq!(normalize(0, 8, &[1, 2, 3]), vec![1, 2, 3]);
+        assert_eq!(normalize(3, 9, &[1, 2, 3]), vec![1, 2, 3]);
+    }
+}
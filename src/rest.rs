@@ -0,0 +1,1462 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::info;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::http::{HttpRequest, HttpResponse};
+use crate::log::{now_ms, single_record_batch};
+use crate::messages::RecordHeader;
+use crate::state::{SharedState, State, Topic};
+
+/// Content type of the v2 API
+const V2_CONTENT_TYPE: &str = "application/vnd.kafka.v2+json";
+/// Most records returned by a consumer instance at once, as `max.poll.records`
+const MAX_POLL_RECORDS: usize = 500;
+
+/// How keys and values are embedded in JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Base64 strings
+    Binary,
+    /// Any JSON value, stored as its serialization
+    Json,
+    /// Strings, stored as UTF-8 (v3 only)
+    String,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "binary" => Some(Format::Binary),
+            "json" => Some(Format::Json),
+            "string" => Some(Format::String),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Binary => "BINARY",
+            Format::Json => "JSON",
+            Format::String => "STRING",
+        }
+    }
+
+    // Bytes of a key or value, None if null
+    fn decode(self, value: &Value) -> Result<Option<Vec<u8>>, String> {
+        match (self, value) {
+            (_, Value::Null) => Ok(None),
+            (Format::Binary, Value::String(s)) => BASE64
+                .decode(s)
+                .map(Some)
+                .map_err(|e| format!("invalid base64: {}", e)),
+            (Format::Json, value) => Ok(serde_json::to_vec(value).ok()),
+            (Format::String, Value::String(s)) => Ok(Some(s.as_bytes().to_vec())),
+            (format, _) => Err(format!("{} data must be a string", format.name())),
+        }
+    }
+
+    // A key or value as JSON, as a string if it isn't valid JSON
+    fn encode(self, bytes: &[u8]) -> Value {
+        match self {
+            Format::Binary => Value::String(BASE64.encode(bytes)),
+            Format::Json => serde_json::from_slice(bytes)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned())),
+            Format::String => Value::String(String::from_utf8_lossy(bytes).into_owned()),
+        }
+    }
+}
+
+/// A consumer instance of the v2 API
+#[derive(Debug)]
+struct Consumer {
+    format: Format,
+    /// Start from the log start rather than its end, without a committed offset
+    earliest: bool,
+    auto_commit: bool,
+    subscription: Vec<String>,
+    assignments: Vec<(String, u32)>,
+    /// Next offset to fetch, by topic and partition
+    positions: BTreeMap<(String, u32), i64>,
+}
+
+impl Consumer {
+    // Partitions assigned, or of the topics subscribed to
+    fn partitions(&self, state: &State) -> Vec<(String, u32)> {
+        if !self.assignments.is_empty() {
+            return self.assignments.clone();
+        }
+        self.subscription
+            .iter()
+            .filter_map(|name| state.topics.get(name).map(|t| (name, t.partitions.len())))
+            .flat_map(|(name, n)| (0..n as u32).map(move |p| (name.clone(), p)))
+            .collect()
+    }
+}
+
+/// Serves the v2 and v3 APIs of Confluent's REST Proxy on the broker state
+///
+/// Consumer instances are kept in memory, and read every partition of the
+/// topics they subscribe to, as groups are not coordinated by the broker.
+#[derive(Debug)]
+pub struct RestProxy {
+    state: SharedState,
+    /// Consumer instances, by group and name
+    consumers: Mutex<HashMap<(String, String), Consumer>>,
+    /// Numbers the consumer instances created without a name
+    instances: AtomicU64,
+    /// Partition of the next record produced with neither a key nor a partition
+    round_robin: AtomicU32,
+}
+
+impl RestProxy {
+    pub fn new(state: SharedState) -> Self {
+        Self {
+            state,
+            consumers: Mutex::new(HashMap::new()),
+            instances: AtomicU64::new(0),
+            round_robin: AtomicU32::new(0),
+        }
+    }
+
+    /// Answer a request to the REST Proxy endpoint
+    ///
+    /// v2, with keys and values embedded as `binary` (the default) or `json`,
+    /// as given by the content type, e.g. `application/vnd.kafka.json.v2+json`:
+    ///
+    /// * `GET /topics`, `GET /topics/{topic}` - topics
+    /// * `GET /topics/{topic}/partitions[/{partition}[/offsets]]` - partitions
+    /// * `POST /topics/{topic}[/partitions/{partition}]` - produce records
+    /// * `GET /brokers` - broker ids
+    /// * `POST /consumers/{group}` - create a consumer instance
+    /// * `DELETE /consumers/{group}/instances/{name}` - delete it
+    /// * `POST|GET|DELETE .../subscription` - subscribe to topics
+    /// * `POST|GET .../assignments` - assign partitions
+    /// * `POST .../positions[/beginning|/end]` - seek
+    /// * `POST|GET .../offsets` - commit offsets, or get the committed ones
+    /// * `GET .../records` - fetch records
+    ///
+    /// v3, with keys and values embedded as `BINARY`, `JSON` or `STRING`:
+    ///
+    /// * `GET /v3/clusters[/{cluster}]` - the cluster
+    /// * `GET .../brokers[/{id}]` - brokers
+    /// * `GET|POST .../topics`, `GET|DELETE .../topics/{topic}` - topics
+    /// * `GET .../topics/{topic}/partitions[/{partition}]` - partitions
+    /// * `GET .../topics/{topic}/configs[/{name}]` - topic configs
+    /// * `POST .../topics/{topic}/records` - produce records, one JSON object
+    ///   each, in a single request or streamed in one body
+    ///
+    /// * `req` - HTTP request
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        let segments = req.segments();
+        let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        let result = match segments.as_slice() {
+            ["v3", rest @ ..] => self.v3(req, rest),
+            segments => self.v2(req, segments).map(|mut resp| {
+                if resp.content_type == "application/json" {
+                    resp.content_type = V2_CONTENT_TYPE.to_string();
+                }
+                resp
+            }),
+        };
+        result.unwrap_or_else(|e| match e.kind() {
+            // Invalid topic names, from topics auto-created
+            io::ErrorKind::InvalidInput => error(40002, e.to_string()),
+            _ => error(50001, e.to_string()),
+        })
+    }
+
+    fn v2(&self, req: &HttpRequest, segments: &[&str]) -> io::Result<HttpResponse> {
+        match (req.method.as_str(), segments) {
+            ("GET", ["topics"]) => {
+                let state = self.state.lock().unwrap();
+                Ok(HttpResponse::json(
+                    200,
+                    &state.topics.keys().collect::<Vec<_>>(),
+                ))
+            }
+            ("GET", ["topics", topic]) => {
+                let state = self.state.lock().unwrap();
+                Ok(match state.topics.get(*topic) {
+                    Some(t) => {
+                        let partitions = (0..t.partitions.len() as u32)
+                            .map(|p| v2_partition(&state, topic, p))
+                            .collect::<Vec<_>>();
+                        let configs = t
+                            .config
+                            .to_properties()
+                            .into_iter()
+                            .collect::<BTreeMap<_, _>>();
+                        let topic =
+                            json!({"name": topic, "configs": configs, "partitions": partitions});
+                        HttpResponse::json(200, &topic)
+                    }
+                    None => topic_not_found(topic),
+                })
+            }
+            ("GET", ["topics", topic, "partitions"]) => {
+                let state = self.state.lock().unwrap();
+                Ok(match state.topics.get(*topic) {
+                    Some(t) => {
+                        let partitions = (0..t.partitions.len() as u32)
+                            .map(|p| v2_partition(&state, topic, p))
+                            .collect::<Vec<_>>();
+                        HttpResponse::json(200, &partitions)
+                    }
+                    None => topic_not_found(topic),
+                })
+            }
+            ("GET", ["topics", topic, "partitions", partition]) => {
+                let state = self.state.lock().unwrap();
+                Ok(match find_partition(&state, topic, partition) {
+                    Ok(p) => HttpResponse::json(200, &v2_partition(&state, topic, p)),
+                    Err(resp) => resp,
+                })
+            }
+            ("GET", ["topics", topic, "partitions", partition, "offsets"]) => {
+                let state = self.state.lock().unwrap();
+                Ok(match find_partition(&state, topic, partition) {
+                    Ok(p) => {
+                        let log = &state.topics[*topic].partitions[p as usize];
+                        let offsets = json!({
+                            "beginning_offset": log.log_start_offset(),
+                            "end_offset": log.log_end_offset(),
+                        });
+                        HttpResponse::json(200, &offsets)
+                    }
+                    Err(resp) => resp,
+                })
+            }
+            ("POST", ["topics", topic]) => self.produce_v2(req, topic, None),
+            ("POST", ["topics", topic, "partitions", partition]) => {
+                self.produce_v2(req, topic, Some(partition))
+            }
+            ("GET", ["brokers"]) => {
+                let state = self.state.lock().unwrap();
+                let brokers = state.cluster.nodes.iter().map(|n| n.id).collect::<Vec<_>>();
+                Ok(HttpResponse::json(200, &json!({ "brokers": brokers })))
+            }
+            ("POST", ["consumers", group]) => Ok(self.create_consumer(req, group)),
+            (_, ["consumers", group, "instances", name, rest @ ..]) => {
+                self.consumer(req, group, name, rest)
+            }
+            (_, ["topics", ..]) | (_, ["brokers"]) | (_, ["consumers", ..]) => {
+                Ok(error(40501, "HTTP method not allowed"))
+            }
+            _ => Ok(error(40400, "not found")),
+        }
+    }
+
+    // Produce the records of a v2 request
+    fn produce_v2(
+        &self,
+        req: &HttpRequest,
+        topic: &str,
+        partition: Option<&str>,
+    ) -> io::Result<HttpResponse> {
+        #[derive(Deserialize)]
+        struct Records {
+            records: Vec<ProduceRecord>,
+        }
+        #[derive(Deserialize)]
+        struct ProduceRecord {
+            #[serde(default)]
+            key: Value,
+            #[serde(default)]
+            value: Value,
+            partition: Option<u32>,
+        }
+        #[derive(Serialize)]
+        struct Offset {
+            partition: Option<u32>,
+            offset: Option<i64>,
+            error_code: Option<u32>,
+            error: Option<String>,
+        }
+
+        let format = match embedded_format(req.header("content-type")) {
+            Ok(format) => format.unwrap_or(Format::Binary),
+            Err(resp) => return Ok(resp),
+        };
+        let records = match serde_json::from_slice::<Records>(&req.body) {
+            Ok(records) => records.records,
+            Err(e) => return Ok(error(42201, format!("invalid records: {}", e))),
+        };
+        let mut state = self.state.lock().unwrap();
+        let num_partitions = match topic_partitions(&mut state, topic)? {
+            Some(n) => n,
+            None => return Ok(topic_not_found(topic)),
+        };
+        let partition = match partition {
+            Some(p) => match find_partition(&state, topic, p) {
+                Ok(p) => Some(p),
+                Err(resp) => return Ok(resp),
+            },
+            None => None,
+        };
+
+        // Every record is decoded before any is appended, and the ones that
+        // can't be appended get an error of their own
+        let decoded = records
+            .iter()
+            .map(|r| {
+                let key = format.decode(&r.key)?;
+                Ok((r.partition, key, format.decode(&r.value)?))
+            })
+            .collect::<Result<Vec<_>, String>>();
+        let decoded = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => return Ok(error(42205, e)),
+        };
+        let now = now_ms();
+        let mut offsets = vec![];
+        for (record_partition, key, value) in decoded {
+            let p = partition
+                .or(record_partition)
+                .unwrap_or_else(|| self.partition_for(key.as_deref(), num_partitions));
+            let failed = |error_code, error: &str| Offset {
+                partition: Some(p),
+                offset: None,
+                error_code: Some(error_code),
+                error: Some(error.to_string()),
+            };
+            if p >= num_partitions {
+                offsets.push(failed(40402, "Partition not found"));
+                continue;
+            }
+            let batch = single_record_batch(now, key, value, vec![]);
+            if let Err(e) = state.check_append(topic, p, &batch, None) {
+                // 1 and 2 are non-retriable and retriable Kafka errors
+                offsets.push(failed(if e.is_retriable() { 2 } else { 1 }, e.name()));
+                continue;
+            }
+            offsets.push(Offset {
+                partition: Some(p),
+                offset: Some(state.append(topic, p, &batch)?),
+                error_code: None,
+                error: None,
+            });
+        }
+        let produced = json!({
+            "key_schema_id": null,
+            "value_schema_id": null,
+            "offsets": offsets,
+        });
+        Ok(HttpResponse::json(200, &produced))
+    }
+
+    fn create_consumer(&self, req: &HttpRequest, group: &str) -> HttpResponse {
+        #[derive(Deserialize)]
+        struct NewConsumer {
+            name: Option<String>,
+            format: Option<String>,
+            #[serde(rename = "auto.offset.reset")]
+            auto_offset_reset: Option<String>,
+            #[serde(rename = "auto.commit.enable")]
+            auto_commit_enable: Option<String>,
+        }
+
+        let new = match parse_body::<NewConsumer>(req) {
+            Ok(new) => new,
+            Err(resp) => return resp,
+        };
+        let format = match new.format.as_deref().map(Format::parse) {
+            None => Format::Binary,
+            Some(Some(Format::String)) | Some(None) => {
+                return unsupported_format(new.format.as_deref().unwrap_or_default())
+            }
+            Some(Some(format)) => format,
+        };
+        let earliest = match new.auto_offset_reset.as_deref() {
+            None | Some("latest") => false,
+            Some("earliest" | "smallest") => true,
+            Some(reset) => return error(42204, format!("invalid auto.offset.reset {}", reset)),
+        };
+        let auto_commit = match new.auto_commit_enable.as_deref().map(str::parse) {
+            None => true,
+            Some(Ok(enable)) => enable,
+            Some(Err(_)) => return error(42204, "invalid auto.commit.enable"),
+        };
+
+        let name = new.name.unwrap_or_else(|| {
+            let n = self.instances.fetch_add(1, Ordering::Relaxed);
+            format!("rest-consumer-{}", n)
+        });
+        let mut consumers = self.consumers.lock().unwrap();
+        let key = (group.to_string(), name.clone());
+        if consumers.contains_key(&key) {
+            return error(
+                40902,
+                "Consumer with specified consumer ID already exists in the specified consumer group.",
+            );
+        }
+        consumers.insert(
+            key,
+            Consumer {
+                format,
+                earliest,
+                auto_commit,
+                subscription: vec![],
+                assignments: vec![],
+                positions: BTreeMap::new(),
+            },
+        );
+        info!(group, name = %name, "Consumer instance created");
+        let base_uri = format!(
+            "http://{}/consumers/{}/instances/{}",
+            req.header("host").unwrap_or("localhost"),
+            group,
+            name
+        );
+        HttpResponse::json(200, &json!({"instance_id": name, "base_uri": base_uri}))
+    }
+
+    // Answer a request to a consumer instance
+    fn consumer(
+        &self,
+        req: &HttpRequest,
+        group: &str,
+        name: &str,
+        segments: &[&str],
+    ) -> io::Result<HttpResponse> {
+        #[derive(Deserialize)]
+        struct Subscription {
+            #[serde(default)]
+            topics: Vec<String>,
+            topic_pattern: Option<String>,
+        }
+        #[derive(Deserialize, Serialize)]
+        struct Partition {
+            topic: String,
+            partition: u32,
+        }
+        #[derive(Deserialize)]
+        struct Partitions {
+            partitions: Vec<Partition>,
+        }
+        #[derive(Deserialize, Serialize)]
+        struct Offset {
+            topic: String,
+            partition: u32,
+            offset: i64,
+            #[serde(default)]
+            metadata: String,
+        }
+        #[derive(Deserialize, Serialize)]
+        struct Offsets {
+            offsets: Vec<Offset>,
+        }
+
+        let mut consumers = self.consumers.lock().unwrap();
+        let key = (group.to_string(), name.to_string());
+        let consumer = match consumers.get_mut(&key) {
+            Some(consumer) => consumer,
+            None => return Ok(error(40403, "Consumer instance not found.")),
+        };
+        let resp = match (req.method.as_str(), segments) {
+            ("DELETE", []) => {
+                consumers.remove(&key);
+                info!(group, name, "Consumer instance deleted");
+                HttpResponse::no_content()
+            }
+            ("POST", ["subscription"]) => match parse_body::<Subscription>(req) {
+                Ok(_) if !consumer.assignments.is_empty() => mutually_exclusive(),
+                Ok(s) if s.topic_pattern.is_some() => {
+                    error(42202, "topic_pattern is not supported, list the topics")
+                }
+                Ok(s) => {
+                    consumer.subscription = s.topics;
+                    HttpResponse::no_content()
+                }
+                Err(resp) => resp,
+            },
+            ("GET", ["subscription"]) => {
+                HttpResponse::json(200, &json!({ "topics": consumer.subscription }))
+            }
+            ("DELETE", ["subscription"]) => {
+                consumer.subscription.clear();
+                HttpResponse::no_content()
+            }
+            ("POST", ["assignments"]) => match parse_body::<Partitions>(req) {
+                Ok(_) if !consumer.subscription.is_empty() => mutually_exclusive(),
+                Ok(a) => {
+                    consumer.assignments = a
+                        .partitions
+                        .into_iter()
+                        .map(|p| (p.topic, p.partition))
+                        .collect();
+                    HttpResponse::no_content()
+                }
+                Err(resp) => resp,
+            },
+            ("GET", ["assignments"]) => {
+                let partitions = consumer
+                    .assignments
+                    .iter()
+                    .map(|(topic, partition)| Partition {
+                        topic: topic.clone(),
+                        partition: *partition,
+                    })
+                    .collect::<Vec<_>>();
+                HttpResponse::json(200, &json!({ "partitions": partitions }))
+            }
+            ("POST", ["positions"]) => match parse_body::<Offsets>(req) {
+                Ok(o) => {
+                    for o in o.offsets {
+                        consumer.positions.insert((o.topic, o.partition), o.offset);
+                    }
+                    HttpResponse::no_content()
+                }
+                Err(resp) => resp,
+            },
+            ("POST", ["positions", whence @ ("beginning" | "end")]) => {
+                match parse_body::<Partitions>(req) {
+                    Ok(p) => {
+                        let state = self.state.lock().unwrap();
+                        for p in p.partitions {
+                            let log = state
+                                .topics
+                                .get(&p.topic)
+                                .and_then(|t| t.partitions.get(p.partition as usize));
+                            if let Some(log) = log {
+                                let offset = match *whence {
+                                    "beginning" => log.log_start_offset(),
+                                    _ => log.log_end_offset(),
+                                };
+                                consumer.positions.insert((p.topic, p.partition), offset);
+                            }
+                        }
+                        HttpResponse::no_content()
+                    }
+                    Err(resp) => resp,
+                }
+            }
+            ("POST", ["offsets"]) => {
+                // The positions of every partition fetched, without a body
+                let offsets = if req.body.is_empty() {
+                    consumer.positions.clone()
+                } else {
+                    match parse_body::<Offsets>(req) {
+                        Ok(o) => o
+                            .offsets
+                            .into_iter()
+                            .map(|o| ((o.topic, o.partition), o.offset))
+                            .collect(),
+                        Err(resp) => return Ok(resp),
+                    }
+                };
+                let mut state = self.state.lock().unwrap();
+                for ((topic, partition), offset) in offsets {
+                    state.commit_offset(group, &topic, partition, offset)?;
+                }
+                HttpResponse::no_content()
+            }
+            ("GET", ["offsets"]) => match parse_body::<Partitions>(req) {
+                Ok(p) => {
+                    let state = self.state.lock().unwrap();
+                    let committed = state.offsets.get(group);
+                    let offsets = p
+                        .partitions
+                        .into_iter()
+                        .filter_map(|p| {
+                            let offset = *committed?.get(&(p.topic.clone(), p.partition))?;
+                            Some(Offset {
+                                topic: p.topic,
+                                partition: p.partition,
+                                offset,
+                                metadata: String::new(),
+                            })
+                        })
+                        .collect();
+                    HttpResponse::json(200, &Offsets { offsets })
+                }
+                Err(resp) => resp,
+            },
+            ("GET", ["records"]) => {
+                match embedded_format(req.header("accept")) {
+                    Ok(Some(format)) if format != consumer.format => {
+                        return Ok(error(
+                            40601,
+                            "The requested embedded data format does not match the consumer's",
+                        ))
+                    }
+                    Ok(_) => (),
+                    Err(resp) => return Ok(resp),
+                }
+                let max_bytes = match req.param("max_bytes").map(str::parse::<usize>) {
+                    None => None,
+                    Some(Ok(max_bytes)) => Some(max_bytes),
+                    Some(Err(_)) => return Ok(error(42204, "invalid max_bytes")),
+                };
+                let mut state = self.state.lock().unwrap();
+                let records = fetch(consumer, group, &state, max_bytes);
+                if consumer.auto_commit {
+                    for ((topic, partition), offset) in &consumer.positions {
+                        state.commit_offset(group, topic, *partition, *offset)?;
+                    }
+                }
+                HttpResponse::json(200, &records)
+            }
+            _ => error(40400, "not found"),
+        };
+        Ok(resp)
+    }
+
+    fn v3(&self, req: &HttpRequest, segments: &[&str]) -> io::Result<HttpResponse> {
+        #[derive(Deserialize)]
+        struct NewTopic {
+            topic_name: String,
+            partitions_count: Option<usize>,
+            #[serde(default)]
+            configs: Vec<Config>,
+        }
+        #[derive(Deserialize)]
+        struct Config {
+            name: String,
+            value: String,
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let host = req.header("host").unwrap_or("localhost");
+        let cluster_id = state.config.cluster_id.clone();
+        let base = format!("http://{}/v3/clusters/{}", host, cluster_id);
+        let (id, segments) = match segments {
+            ["clusters"] if req.method == "GET" => {
+                let clusters = list(
+                    "KafkaClusterList",
+                    &format!("http://{}/v3/clusters", host),
+                    vec![cluster(&state, &base)],
+                );
+                return Ok(HttpResponse::json(200, &clusters));
+            }
+            ["clusters", id, rest @ ..] => (*id, rest),
+            _ => return Ok(error(40400, "not found")),
+        };
+        if id != cluster_id {
+            return Ok(error(40401, format!("Cluster {} cannot be found.", id)));
+        }
+
+        let resp = match (req.method.as_str(), segments) {
+            ("GET", []) => HttpResponse::json(200, &cluster(&state, &base)),
+            ("GET", ["brokers"]) => {
+                let brokers = state
+                    .cluster
+                    .nodes
+                    .iter()
+                    .map(|n| broker(&state, &base, n.id))
+                    .collect();
+                let brokers = list("KafkaBrokerList", &format!("{}/brokers", base), brokers);
+                HttpResponse::json(200, &brokers)
+            }
+            ("GET", ["brokers", id]) => match id
+                .parse()
+                .ok()
+                .filter(|id| state.cluster.node(*id).is_some())
+            {
+                Some(id) => HttpResponse::json(200, &broker(&state, &base, id)),
+                None => error(40403, format!("Broker {} cannot be found.", id)),
+            },
+            ("GET", ["topics"]) => {
+                let topics = state
+                    .topics
+                    .keys()
+                    .map(|t| topic(&state, &base, t))
+                    .collect();
+                let topics = list("KafkaTopicList", &format!("{}/topics", base), topics);
+                HttpResponse::json(200, &topics)
+            }
+            ("POST", ["topics"]) => {
+                let new = match parse_body::<NewTopic>(req) {
+                    Ok(new) => new,
+                    Err(resp) => return Ok(resp),
+                };
+                let partitions = new.partitions_count.unwrap_or(state.config.num_partitions);
+                if partitions == 0 {
+                    return Ok(error(40002, "Invalid partitions count."));
+                }
+                if let Err(e) = Topic::validate_name(&new.topic_name) {
+                    return Ok(error(40002, e.to_string()));
+                }
+                let mut config = state.config.default_topic_config.clone();
+                for c in &new.configs {
+                    if !config.set(&c.name, &c.value) {
+                        return Ok(error(
+                            40002,
+                            format!("Invalid config {}={}.", c.name, c.value),
+                        ));
+                    }
+                }
+                if !state.create_topic(&new.topic_name, partitions, config)? {
+                    let message = format!("Topic '{}' already exists.", new.topic_name);
+                    return Ok(error(40002, message));
+                }
+                info!(topic = %new.topic_name, partitions, "Topic created");
+                HttpResponse::json(201, &topic(&state, &base, &new.topic_name))
+            }
+            (method, ["topics", name, ..]) if !state.topics.contains_key(*name) => {
+                // Produce auto-creates topics
+                if method == "POST"
+                    && segments.len() == 3
+                    && segments[2] == "records"
+                    && state.config.auto_create_topics
+                {
+                    state.get_or_create_topic(name)?;
+                    self.produce_v3(req, &mut state, name)?
+                } else {
+                    topic_not_found(name)
+                }
+            }
+            ("GET", ["topics", name]) => HttpResponse::json(200, &topic(&state, &base, name)),
+            ("DELETE", ["topics", name]) => {
+                state.delete_topic(name)?;
+                info!(topic = name, "Topic deleted");
+                HttpResponse::no_content()
+            }
+            ("GET", ["topics", name, "partitions"]) => {
+                let n = state.topics[*name].partitions.len() as u32;
+                let partitions = (0..n).map(|p| partition(&state, &base, name, p)).collect();
+                let url = format!("{}/topics/{}/partitions", base, name);
+                HttpResponse::json(200, &list("KafkaPartitionList", &url, partitions))
+            }
+            ("GET", ["topics", name, "partitions", p]) => match find_partition(&state, name, p) {
+                Ok(p) => HttpResponse::json(200, &partition(&state, &base, name, p)),
+                Err(resp) => resp,
+            },
+            ("GET", ["topics", name, "configs"]) => {
+                let configs = topic_configs(&state, &base, name);
+                let url = format!("{}/topics/{}/configs", base, name);
+                HttpResponse::json(200, &list("KafkaTopicConfigList", &url, configs))
+            }
+            ("GET", ["topics", name, "configs", config]) => {
+                let found = topic_configs(&state, &base, name)
+                    .into_iter()
+                    .find(|c| c["name"] == *config);
+                match found {
+                    Some(found) => HttpResponse::json(200, &found),
+                    None => error(40404, format!("Config {} cannot be found.", config)),
+                }
+            }
+            ("POST", ["topics", name, "records"]) => self.produce_v3(req, &mut state, name)?,
+            (_, ["brokers", ..]) | (_, ["topics", ..]) | (_, []) => {
+                error(40501, "HTTP method not allowed")
+            }
+            _ => error(40400, "not found"),
+        };
+        Ok(resp)
+    }
+
+    // Produce the records of a v3 request, one JSON object per record
+    fn produce_v3(
+        &self,
+        req: &HttpRequest,
+        state: &mut State,
+        topic: &str,
+    ) -> io::Result<HttpResponse> {
+        #[derive(Deserialize)]
+        struct ProduceRecord {
+            partition_id: Option<u32>,
+            #[serde(default)]
+            headers: Vec<Header>,
+            key: Option<Data>,
+            value: Option<Data>,
+        }
+        #[derive(Deserialize)]
+        struct Header {
+            name: String,
+            /// Base64
+            value: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            #[serde(rename = "type")]
+            format: Option<String>,
+            #[serde(default)]
+            data: Value,
+        }
+
+        // Decode a key or value, with its format
+        fn decode(data: &Option<Data>) -> Result<(Option<Vec<u8>>, Format), String> {
+            let data = match data {
+                Some(data) => data,
+                None => return Ok((None, Format::Json)),
+            };
+            let format = match data.format.as_deref() {
+                None => Format::Json,
+                Some(name) => Format::parse(name).ok_or_else(|| {
+                    format!("{} is not supported without a schema registry", name)
+                })?,
+            };
+            Ok((format.decode(&data.data)?, format))
+        }
+
+        let cluster_id = state.config.cluster_id.clone();
+        let num_partitions = state.topics[topic].partitions.len() as u32;
+        let mut results = vec![];
+        for record in serde_json::Deserializer::from_slice(&req.body).into_iter::<ProduceRecord>() {
+            let result = record.map_err(|e| e.to_string()).and_then(|record| {
+                let (key, key_format) = decode(&record.key)?;
+                let (value, value_format) = decode(&record.value)?;
+                let headers = record
+                    .headers
+                    .iter()
+                    .map(|h| {
+                        let value = h.value.as_ref().map(|v| BASE64.decode(v)).transpose();
+                        let value =
+                            value.map_err(|e| format!("invalid header {}: {}", h.name, e))?;
+                        Ok(RecordHeader {
+                            key: h.name.clone(),
+                            value,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok((
+                    record.partition_id,
+                    key,
+                    key_format,
+                    value,
+                    value_format,
+                    headers,
+                ))
+            });
+            let (partition_id, key, key_format, value, value_format, headers) = match result {
+                Ok(record) => record,
+                Err(e) => {
+                    results.push(json!({"error_code": 400, "message": e}));
+                    continue;
+                }
+            };
+            let p =
+                partition_id.unwrap_or_else(|| self.partition_for(key.as_deref(), num_partitions));
+            if p >= num_partitions {
+                let message = format!("Partition {} of topic {} cannot be found.", p, topic);
+                results.push(json!({"error_code": 404, "message": message}));
+                continue;
+            }
+            let size = |data: &Option<Vec<u8>>, format: Format| {
+                data.as_ref()
+                    .map(|d| json!({"type": format.name(), "size": d.len()}))
+            };
+            let timestamp = now_ms();
+            let key_data = size(&key, key_format);
+            let value_data = size(&value, value_format);
+            let batch = single_record_batch(timestamp, key, value, headers);
+            if let Err(e) = state.check_append(topic, p, &batch, None) {
+                let error_code = if e.is_retriable() { 503 } else { 400 };
+                results.push(json!({"error_code": error_code, "message": e.to_string()}));
+                continue;
+            }
+            let offset = state.append(topic, p, &batch)?;
+            results.push(json!({
+                "error_code": 200,
+                "cluster_id": cluster_id,
+                "topic_name": topic,
+                "partition_id": p,
+                "offset": offset,
+                "timestamp": format_timestamp(timestamp),
+                "key": key_data,
+                "value": value_data,
+            }));
+        }
+        // A single record is answered with its status, streamed ones a line each
+        Ok(match results.as_slice() {
+            [result] => {
+                let status = result["error_code"].as_u64().unwrap_or(200) as u16;
+                HttpResponse::json(status, result)
+            }
+            results => {
+                let mut resp = HttpResponse::json(200, &Value::Null);
+                resp.body = results
+                    .iter()
+                    .map(|r| format!("{}\n", r))
+                    .collect::<String>()
+                    .into_bytes();
+                resp
+            }
+        })
+    }
+
+    // Partition of a record produced without one, as Kafka's default partitioner
+    // (without stickiness)
+    fn partition_for(&self, key: Option<&[u8]>, num_partitions: u32) -> u32 {
+        match key {
+            Some(key) => (murmur2(key) & 0x7fffffff) % num_partitions,
+            None => self.round_robin.fetch_add(1, Ordering::Relaxed) % num_partitions,
+        }
+    }
+}
+
+// Fetch the next records of a consumer instance, and move its positions past them
+// Partitions without a position start from the offset committed by the group.
+fn fetch(
+    consumer: &mut Consumer,
+    group: &str,
+    state: &State,
+    max_bytes: Option<usize>,
+) -> Vec<Value> {
+    let committed = state.offsets.get(group);
+    let mut records = vec![];
+    let mut bytes = 0;
+    for (topic, p) in consumer.partitions(state) {
+        let log = match state
+            .topics
+            .get(&topic)
+            .and_then(|t| t.partitions.get(p as usize))
+        {
+            Some(log) => log,
+            None => continue,
+        };
+        let reset = if consumer.earliest {
+            log.log_start_offset()
+        } else {
+            log.log_end_offset()
+        };
+        let key = (topic.clone(), p);
+        let mut position = match consumer.positions.get(&key) {
+            Some(&position) => position,
+            None => committed
+                .and_then(|offsets| offsets.get(&key))
+                .copied()
+                .unwrap_or(reset),
+        };
+        if position < log.log_start_offset() || position > log.log_end_offset() {
+            position = reset;
+        }
+        let full = |records: &Vec<Value>, bytes| {
+            records.len() >= MAX_POLL_RECORDS
+                || max_bytes.is_some_and(|max| bytes >= max && !records.is_empty())
+        };
+        let start = position;
+        for record in log
+            .batches()
+            .filter(|b| b.last_offset >= start)
+            .flat_map(|b| &b.records)
+            .filter(|r| r.offset >= start)
+        {
+            if full(&records, bytes) {
+                break;
+            }
+            bytes +=
+                record.key.as_ref().map_or(0, Vec::len) + record.value.as_ref().map_or(0, Vec::len);
+            records.push(json!({
+                "topic": topic,
+                "key": record.key.as_deref().map(|k| consumer.format.encode(k)),
+                "value": record.value.as_deref().map(|v| consumer.format.encode(v)),
+                "partition": p,
+                "offset": record.offset,
+                "timestamp": record.timestamp,
+            }));
+            position = record.offset + 1;
+        }
+        if !full(&records, bytes) {
+            // Past the records removed by compaction, up to the end
+            position = log.log_end_offset();
+        }
+        consumer.positions.insert(key, position);
+    }
+    records
+}
+
+// Embedded format given by a media type, e.g. application/vnd.kafka.json.v2+json,
+// None if it has none
+fn embedded_format(media_type: Option<&str>) -> Result<Option<Format>, HttpResponse> {
+    let media_type = media_type.unwrap_or_default();
+    let name = match media_type
+        .split(',')
+        .filter_map(|t| t.trim().strip_prefix("application/vnd.kafka."))
+        .next()
+    {
+        Some(rest) => rest.split('.').next().unwrap_or_default(),
+        None => return Ok(None),
+    };
+    match name {
+        "v2+json" | "v1+json" => Ok(None),
+        "binary" => Ok(Some(Format::Binary)),
+        "json" => Ok(Some(Format::Json)),
+        name => Err(unsupported_format(name)),
+    }
+}
+
+fn unsupported_format(name: &str) -> HttpResponse {
+    error(
+        41501,
+        format!(
+            "Embedded format {} is not supported, use binary or json",
+            name
+        ),
+    )
+}
+
+fn mutually_exclusive() -> HttpResponse {
+    error(
+        40903,
+        "Subscription to topics, partitions and pattern are mutually exclusive.",
+    )
+}
+
+fn topic_not_found(topic: &str) -> HttpResponse {
+    error(40401, format!("Topic {} not found.", topic))
+}
+
+// Number of partitions of a topic, creating it if enabled
+fn topic_partitions(state: &mut State, topic: &str) -> io::Result<Option<u32>> {
+    if !state.topics.contains_key(topic) && !state.config.auto_create_topics {
+        return Ok(None);
+    }
+    Ok(Some(
+        state.get_or_create_topic(topic)?.partitions.len() as u32
+    ))
+}
+
+// Id of an existing partition, given in a path
+fn find_partition(state: &State, topic: &str, partition: &str) -> Result<u32, HttpResponse> {
+    let num_partitions = match state.topics.get(topic) {
+        Some(t) => t.partitions.len() as u32,
+        None => return Err(topic_not_found(topic)),
+    };
+    match partition.parse::<u32>() {
+        Ok(p) if p < num_partitions => Ok(p),
+        _ => Err(error(40402, "Partition not found.")),
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(req: &HttpRequest) -> Result<T, HttpResponse> {
+    let body = if req.body.is_empty() {
+        &b"{}"[..]
+    } else {
+        &req.body
+    };
+    serde_json::from_slice(body).map_err(|e| error(42201, format!("invalid request: {}", e)))
+}
+
+/// An error as answered by the REST Proxy, with an HTTP status given by the
+/// first 3 digits of its code, e.g. 404 for 40401
+fn error(error_code: u32, message: impl Into<String>) -> HttpResponse {
+    let body = json!({"error_code": error_code, "message": message.into()});
+    HttpResponse::json((error_code / 100) as u16, &body)
+}
+
+fn v2_partition(state: &State, topic: &str, partition: u32) -> Value {
+    let assignment = state.cluster.assignment(topic, partition);
+    let replicas = assignment
+        .replicas
+        .iter()
+        .map(|&id| {
+            json!({
+                "broker": id,
+                "leader": assignment.leader == Some(id),
+                "in_sync": assignment.isr.contains(&id),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({"partition": partition, "leader": assignment.leader, "replicas": replicas})
+}
+
+fn list(kind: &str, url: &str, data: Vec<Value>) -> Value {
+    json!({"kind": kind, "metadata": {"self": url, "next": null}, "data": data})
+}
+
+fn metadata(url: String, resource_name: String) -> Value {
+    json!({"self": url, "resource_name": resource_name})
+}
+
+fn related(url: String) -> Value {
+    json!({ "related": url })
+}
+
+fn cluster(state: &State, base: &str) -> Value {
+    let id = &state.config.cluster_id;
+    json!({
+        "kind": "KafkaCluster",
+        "metadata": metadata(base.to_string(), format!("crn:///kafka={}", id)),
+        "cluster_id": id,
+        "controller": state.cluster.controller().map(|c| related(format!("{}/brokers/{}", base, c))),
+        "brokers": related(format!("{}/brokers", base)),
+        "topics": related(format!("{}/topics", base)),
+    })
+}
+
+fn broker(state: &State, base: &str, id: u32) -> Value {
+    let cluster_id = &state.config.cluster_id;
+    let node = state.cluster.node(id);
+    let endpoint = node.and_then(|n| n.endpoints.first());
+    json!({
+        "kind": "KafkaBroker",
+        "metadata": metadata(
+            format!("{}/brokers/{}", base, id),
+            format!("crn:///kafka={}/broker={}", cluster_id, id),
+        ),
+        "cluster_id": cluster_id,
+        "broker_id": id,
+        "host": endpoint.map(|e| &e.host),
+        "port": endpoint.map(|e| e.port),
+        "rack": node.and_then(|n| n.rack.as_ref()),
+    })
+}
+
+fn topic(state: &State, base: &str, name: &str) -> Value {
+    let cluster_id = &state.config.cluster_id;
+    let url = format!("{}/topics/{}", base, name);
+    json!({
+        "kind": "KafkaTopic",
+        "metadata": metadata(url.clone(), format!("crn:///kafka={}/topic={}", cluster_id, name)),
+        "cluster_id": cluster_id,
+        "topic_name": name,
+        "is_internal": name.starts_with('_'),
+        "replication_factor": state.cluster.assignment(name, 0).replicas.len(),
+        "partitions_count": state.topics[name].partitions.len(),
+        "partitions": related(format!("{}/partitions", url)),
+        "configs": related(format!("{}/configs", url)),
+    })
+}
+
+fn partition(state: &State, base: &str, topic: &str, partition: u32) -> Value {
+    let cluster_id = &state.config.cluster_id;
+    let url = format!("{}/topics/{}/partitions/{}", base, topic, partition);
+    let leader = state.cluster.assignment(topic, partition).leader;
+    json!({
+        "kind": "KafkaPartition",
+        "metadata": metadata(
+            url,
+            format!("crn:///kafka={}/topic={}/partition={}", cluster_id, topic, partition),
+        ),
+        "cluster_id": cluster_id,
+        "topic_name": topic,
+        "partition_id": partition,
+        "leader": leader.map(|id| related(format!("{}/brokers/{}", base, id))),
+    })
+}
+
+fn topic_configs(state: &State, base: &str, topic: &str) -> Vec<Value> {
+    let cluster_id = &state.config.cluster_id;
+    let defaults = state
+        .config
+        .default_topic_config
+        .to_properties()
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    state.topics[topic]
+        .config
+        .to_properties()
+        .into_iter()
+        .map(|(name, value)| {
+            let is_default = defaults.get(&name) == Some(&value);
+            json!({
+                "kind": "KafkaTopicConfig",
+                "metadata": metadata(
+                    format!("{}/topics/{}/configs/{}", base, topic, name),
+                    format!("crn:///kafka={}/topic={}/config={}", cluster_id, topic, name),
+                ),
+                "cluster_id": cluster_id,
+                "topic_name": topic,
+                "name": name,
+                "value": value,
+                "is_default": is_default,
+                "is_read_only": false,
+                "is_sensitive": false,
+                "source": if is_default { "DEFAULT_CONFIG" } else { "DYNAMIC_TOPIC_CONFIG" },
+                "synonyms": [],
+            })
+        })
+        .collect()
+}
+
+/// Kafka's murmur2 hash, as used to partition records by key
+///
+/// * `data` - key
+fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate().rev() {
+            h ^= (b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// A timestamp as RFC 3339, in UTC, e.g. 2021-02-04T14:04:26.097Z
+///
+/// * `ms` - milliseconds since the epoch
+fn format_timestamp(ms: i64) -> String {
+    let (days, ms) = (ms.div_euclid(86_400_000), ms.rem_euclid(86_400_000));
+    // Civil date from days since the epoch, as in Howard Hinnant's algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TopicConfig;
+
+    fn call(
+        proxy: &RestProxy,
+        method: &str,
+        target: &str,
+        content_type: &str,
+        body: &str,
+    ) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let req = HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|p| p.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            headers: vec![
+                ("content-type".to_string(), content_type.to_string()),
+                ("host".to_string(), "proxy:8082".to_string()),
+            ],
+            body: body.as_bytes().to_vec(),
+        };
+        let resp = proxy.handle(&req);
+        let body = serde_json::from_slice(&resp.body).unwrap_or(Value::Null);
+        (resp.status, body)
+    }
+
+    #[test]
+    fn hash_keys() {
+        // As in Kafka's UtilsTest
+        assert_eq!(murmur2(b"21") as i32, -973932308);
+        assert_eq!(murmur2(b"foobar") as i32, -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string") as i32, -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string") as i32, -1486304829);
+        assert_eq!(murmur2(b"abc") as i32, 479470107);
+        assert_eq!(format_timestamp(1612447466097), "2021-02-04T14:04:26.097Z");
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn produce_and_consume_v2() {
+        let proxy = RestProxy::new(State::default().into_shared());
+        let json = "application/vnd.kafka.json.v2+json";
+        let body = r#"{"records": [{"key": {"id": 1}, "value": {"amount": 10}}, {"value": null, "partition": 0}]}"#;
+        let (status, produced) = call(&proxy, "POST", "/topics/orders", json, body);
+        assert_eq!(status, 200);
+        assert_eq!(
+            produced["offsets"][1],
+            json!({"partition": 0, "offset": 1, "error_code": null, "error": null})
+        );
+        let body = r#"{"records": [{"value": "aGk="}]}"#;
+        let binary = "application/vnd.kafka.binary.v2+json";
+        let (_, produced) = call(&proxy, "POST", "/topics/orders/partitions/0", binary, body);
+        assert_eq!(produced["offsets"][0]["offset"], 2);
+        assert_eq!(
+            call(&proxy, "POST", "/topics/orders/partitions/1", binary, body).0,
+            404
+        );
+        let avro = "application/vnd.kafka.avro.v2+json";
+        assert_eq!(call(&proxy, "POST", "/topics/orders", avro, body).0, 415);
+        // Nothing is appended unless every record can be decoded
+        let body = r#"{"records": [{"value": "aGk="}, {"value": "!"}]}"#;
+        assert_eq!(
+            call(&proxy, "POST", "/topics/orders", binary, body).1["error_code"],
+            42205
+        );
+
+        let (_, topic) = call(&proxy, "GET", "/topics/orders", "", "");
+        assert_eq!(topic["partitions"][0]["replicas"][0]["leader"], true);
+        let mut config = TopicConfig::default();
+        assert!(config.set("cleanup.policy", "compact"));
+        let mut state = proxy.state.lock().unwrap();
+        state.create_topic("users", 1, config).unwrap();
+        drop(state);
+        let body = r#"{"records": [{"key": "a", "value": 1}, {"value": 2}]}"#;
+        let (_, produced) = call(&proxy, "POST", "/topics/users", json, body);
+        assert_eq!(produced["offsets"][0]["offset"], 0);
+        assert_eq!(
+            produced["offsets"][1],
+            json!({"partition": 0, "offset": null, "error_code": 1, "error": "INVALID_RECORD"})
+        );
+        let (_, offsets) = call(&proxy, "GET", "/topics/orders/partitions/0/offsets", "", "");
+        assert_eq!(offsets, json!({"beginning_offset": 0, "end_offset": 3}));
+
+        let body = r#"{"name": "c1", "format": "json", "auto.offset.reset": "earliest"}"#;
+        let (status, created) = call(&proxy, "POST", "/consumers/billing", V2_CONTENT_TYPE, body);
+        assert_eq!(status, 200);
+        assert_eq!(
+            created["base_uri"],
+            "http://proxy:8082/consumers/billing/instances/c1"
+        );
+        assert_eq!(
+            call(&proxy, "POST", "/consumers/billing", V2_CONTENT_TYPE, body).0,
+            409
+        );
+        let base = "/consumers/billing/instances/c1";
+        let subscription = format!("{}/subscription", base);
+        let topics = r#"{"topics": ["orders"]}"#;
+        assert_eq!(
+            call(&proxy, "POST", &subscription, V2_CONTENT_TYPE, topics).0,
+            204
+        );
+        let assignments = format!("{}/assignments", base);
+        let partitions = r#"{"partitions": [{"topic": "orders", "partition": 0}]}"#;
+        assert_eq!(
+            call(&proxy, "POST", &assignments, V2_CONTENT_TYPE, partitions).0,
+            409
+        );
+
+        let records = format!("{}/records?max_bytes=1", base);
+        let (_, records) = call(&proxy, "GET", &records, "", "");
+        assert_eq!(
+            records,
+            json!([{"topic": "orders", "key": {"id": 1}, "value": {"amount": 10}, "partition": 0,
+                    "offset": 0, "timestamp": records[0]["timestamp"]}])
+        );
+        let records = format!("{}/records", base);
+        let (_, fetched) = call(&proxy, "GET", &records, "", "");
+        assert_eq!(fetched[0]["value"], Value::Null);
+        assert_eq!(fetched[1]["value"], "hi");
+        assert_eq!(call(&proxy, "GET", &records, "", "").1, json!([]));
+
+        // Auto commit
+        let offsets = format!("{}/offsets", base);
+        let (_, committed) = call(&proxy, "GET", &offsets, V2_CONTENT_TYPE, partitions);
+        assert_eq!(committed["offsets"][0]["offset"], 3);
+        let beginning = format!("{}/positions/beginning", base);
+        assert_eq!(
+            call(&proxy, "POST", &beginning, V2_CONTENT_TYPE, partitions).0,
+            204
+        );
+        assert_eq!(
+            call(&proxy, "GET", &records, "", "")
+                .1
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        let accept_binary = HttpRequest {
+            method: "GET".to_string(),
+            path: records.clone(),
+            headers: vec![("accept".to_string(), binary.to_string())],
+            ..HttpRequest::default()
+        };
+        assert_eq!(proxy.handle(&accept_binary).status, 406);
+
+        assert_eq!(call(&proxy, "DELETE", base, "", "").0, 204);
+        assert_eq!(call(&proxy, "GET", &records, "", "").0, 404);
+    }
+
+    #[test]
+    fn produce_v3() {
+        let proxy = RestProxy::new(State::default().into_shared());
+        let cluster_id = proxy.state.lock().unwrap().config.cluster_id.clone();
+        let base = format!("/v3/clusters/{}", cluster_id);
+        let (_, clusters) = call(&proxy, "GET", "/v3/clusters", "", "");
+        assert_eq!(clusters["data"][0]["cluster_id"], cluster_id.as_str());
+        assert_eq!(
+            call(&proxy, "GET", "/v3/clusters/other/topics", "", "").0,
+            404
+        );
+
+        let topic = r#"{"topic_name": "orders", "partitions_count": 2, "configs": [{"name": "cleanup.policy", "value": "compact"}]}"#;
+        let topics = format!("{}/topics", base);
+        let (status, created) = call(&proxy, "POST", &topics, "application/json", topic);
+        assert_eq!(status, 201);
+        assert_eq!(created["partitions_count"], 2);
+        assert_eq!(
+            call(&proxy, "POST", &topics, "application/json", topic).0,
+            400
+        );
+        let invalid = r#"{"topic_name": "..", "partitions_count": 1}"#;
+        let (status, error) = call(&proxy, "POST", &topics, "application/json", invalid);
+        assert_eq!((status, error["error_code"].as_u64()), (400, Some(40002)));
+        let configs = format!("{}/topics/orders/configs/cleanup.policy", base);
+        let (_, config) = call(&proxy, "GET", &configs, "", "");
+        assert_eq!(
+            (config["value"].as_str(), config["is_default"].as_bool()),
+            (Some("compact"), Some(false))
+        );
+
+        let records = format!("{}/topics/orders/records", base);
+        let other = format!("{}/topics/other/records", base);
+        assert_eq!(call(&proxy, "GET", &other, "", "").0, 404);
+        assert!(!proxy.state.lock().unwrap().topics.contains_key("other"));
+        let record = r#"{"partition_id": 1, "key": {"type": "STRING", "data": "a"}, "value": {"type": "JSON", "data": {"n": 1}}, "headers": [{"name": "h", "value": "dg=="}]}"#;
+        let (status, produced) = call(&proxy, "POST", &records, "application/json", record);
+        assert_eq!(status, 200);
+        assert_eq!(produced["partition_id"], 1);
+        assert_eq!(produced["offset"], 0);
+        assert_eq!(produced["key"], json!({"type": "STRING", "size": 1}));
+        assert_eq!(produced["value"], json!({"type": "JSON", "size": 7}));
+        let stream = r#"{"partition_id": 1, "key": {"data": "b"}, "value": {"type": "BINARY", "data": "aGk="}} {"partition_id": 5}"#;
+        let req = HttpRequest {
+            method: "POST".to_string(),
+            path: records.clone(),
+            body: stream.as_bytes().to_vec(),
+            ..HttpRequest::default()
+        };
+        let resp = proxy.handle(&req);
+        let lines = String::from_utf8(resp.body).unwrap();
+        let lines = lines
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines[0]["offset"], 1);
+        assert_eq!(lines[1]["error_code"], 404);
+        let unkeyed = r#"{"partition_id": 0, "value": {"type": "JSON", "data": 1}}"#;
+        assert_eq!(
+            call(&proxy, "POST", &records, "application/json", unkeyed).0,
+            400
+        );
+
+        let state = proxy.state.lock().unwrap();
+        let record = &state.topics["orders"].partitions[1]
+            .batches()
+            .next()
+            .unwrap()
+            .records[0];
+        assert_eq!(record.value.as_deref(), Some(&b"{\"n\":1}"[..]));
+        assert_eq!(record.headers[0].value.as_deref(), Some(&b"v"[..]));
+        drop(state);
+
+        let topic = format!("{}/topics/orders", base);
+        assert_eq!(call(&proxy, "DELETE", &topic, "", "").0, 204);
+        assert_eq!(call(&proxy, "GET", &topic, "", "").0, 404);
+    }
+}
@@ -0,0 +1,161 @@
+use tracing::error;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TopicConfig;
+use crate::log::{now_ms, PartitionLog};
+use crate::state::{SharedState, State};
+
+/// Enforce `retention.ms` and `retention.bytes` on a partition log
+/// Whole batches are dropped from the head of the log, as Kafka does with
+/// segments. Nothing is removed unless `cleanup.policy` includes "delete".
+/// Returns the number of batches removed.
+///
+/// * `log` - partition log
+/// * `config` - configuration of the topic the partition belongs to
+/// * `now` - current time in milliseconds
+pub fn enforce(log: &mut PartitionLog, config: &TopicConfig, now: i64) -> usize {
+    if !config.cleanup_policy.delete {
+        return 0;
+    }
+    let mut removed = 0;
+    if config.retention_ms >= 0 {
+        while log
+            .head()
+            .is_some_and(|b| now - b.max_timestamp > config.retention_ms)
+        {
+            log.pop_head();
+            removed += 1;
+        }
+    }
+    if config.retention_bytes >= 0 {
+        // Only delete a batch if the log stays at or above the limit afterwards
+        while log
+            .head()
+            .is_some_and(|b| log.size() as i64 - b.size as i64 >= config.retention_bytes)
+        {
+            log.pop_head();
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Enforce retention on every partition of every topic
+///
+/// * `state` - broker state
+/// * `now` - current time in milliseconds
+pub fn enforce_all(state: &mut State, now: i64) -> usize {
+    let mut removed = 0;
+    for (name, topic) in state.topics.iter_mut() {
+        for (p, log) in topic.partitions.iter_mut().enumerate() {
+            let n = enforce(log, &topic.config, now);
+            if n > 0 {
+                if let Err(e) = state.storage.rewrite(name, p as u32, log) {
+                    error!("Error persisting {}-{}: {}", name, p, e);
+                }
+            }
+            removed += n;
+        }
+    }
+    removed
+}
+
+/// Spawn the background thread that periodically enforces retention
+///
+/// * `state` - shared broker state
+/// * `interval` - time between checks (`log.retention.check.interval.ms`)
+pub fn spawn(state: &SharedState, interval: Duration) -> thread::JoinHandle<()> {
+    // Only a weak reference is kept, so the task ends once the state is dropped
+    let state = Arc::downgrade(state);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match state.upgrade() {
+            Some(state) => {
+                enforce_all(&mut state.lock().unwrap(), now_ms());
+            }
+            None => break,
+        }
+    })
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CleanupPolicy;
+    use crate::testing::batch;
+
+    #[test]
+    fn time_retention_drops_expired_batches() {
+        let mut log = PartitionLog::new();
+        log.append(&batch(1_000, &[(None, Some("a")); 2]), 0);
+        log.append(&batch(2_000, &[(None, Some("a")); 3]), 0);
+        log.append(&batch(3_000, &[(None, Some("a")); 1]), 0);
+        let config = TopicConfig {
+            retention_ms: 1_500,
+            retention_bytes: -1,
+            ..TopicConfig::default()
+        };
+
+        assert_eq!(enforce(&mut log, &config, 4_000), 2);
+        assert_eq!(log.log_start_offset(), 5);
+        assert_eq!(log.log_end_offset(), 6);
+        assert_eq!(log.size(), 100);
+
+        // Everything expired: the log is empty but keeps its end offset
+        assert_eq!(enforce(&mut log, &config, 10_000), 1);
+        assert_eq!(log.log_start_offset(), 6);
+        assert_eq!(log.log_end_offset(), 6);
+    }
+
+    #[test]
+    fn size_retention_keeps_at_least_the_limit() {
+        let mut log = PartitionLog::new();
+        for _ in 0..4 {
+            log.append(&batch(1_000, &[(None, Some("a")); 1]), 0);
+        }
+        let config = TopicConfig {
+            retention_ms: -1,
+            retention_bytes: 250,
+            ..TopicConfig::default()
+        };
+
+        assert_eq!(enforce(&mut log, &config, 1_000), 1);
+        assert_eq!(log.size(), 300);
+        assert_eq!(log.log_start_offset(), 1);
+    }
+
+    #[test]
+    fn unlimited_retention_keeps_everything() {
+        let mut log = PartitionLog::new();
+        log.append(&batch(0, &[(None, Some("a")); 1]), 0);
+        let config = TopicConfig {
+            retention_ms: -1,
+            retention_bytes: -1,
+            ..TopicConfig::default()
+        };
+
+        assert_eq!(enforce(&mut log, &config, i64::MAX), 0);
+        assert_eq!(log.size(), 100);
+    }
+
+    #[test]
+    fn compact_only_topics_are_not_deleted() {
+        let mut log = PartitionLog::new();
+        log.append(&batch(0, &[(None, Some("a")); 1]), 0);
+        let config = TopicConfig {
+            cleanup_policy: CleanupPolicy {
+                delete: false,
+                compact: true,
+            },
+            retention_ms: 0,
+            ..TopicConfig::default()
+        };
+
+        assert_eq!(enforce(&mut log, &config, 1_000), 0);
+    }
+}
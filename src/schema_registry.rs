@@ -0,0 +1,1269 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+use crate::avro;
+use crate::config::CleanupPolicy;
+use crate::http::{HttpRequest, HttpResponse};
+use crate::log::{now_ms, single_record_batch};
+use crate::state::{SharedState, State};
+
+/// Topic the schemas and compatibility levels are stored in, as by Confluent's
+/// Schema Registry
+pub const SCHEMAS_TOPIC: &str = "_schemas";
+const CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SchemaType {
+    #[default]
+    Avro,
+    Json,
+    Protobuf,
+}
+
+impl SchemaType {
+    fn is_avro(&self) -> bool {
+        *self == SchemaType::Avro
+    }
+}
+
+/// How a new version of a subject must relate to the earlier ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Compatibility {
+    None,
+    /// Data written with the previous version can be read with the new one
+    #[default]
+    Backward,
+    /// ... with every earlier version
+    BackwardTransitive,
+    /// Data written with the new version can be read with the previous one
+    Forward,
+    ForwardTransitive,
+    /// Both backward and forward
+    Full,
+    FullTransitive,
+}
+
+impl Compatibility {
+    fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(Value::String(value.to_string())).ok()
+    }
+
+    fn is_transitive(self) -> bool {
+        matches!(
+            self,
+            Compatibility::BackwardTransitive
+                | Compatibility::ForwardTransitive
+                | Compatibility::FullTransitive
+        )
+    }
+
+    /// Check a new schema against an earlier one
+    /// Only Avro schemas are checked, others are taken as compatible.
+    fn check(self, new: &Parsed, old: &Parsed) -> Result<(), String> {
+        let (new, old) = match (new, old) {
+            (Parsed::Avro(new), Parsed::Avro(old)) => (new, old),
+            (Parsed::Avro(_), _) | (_, Parsed::Avro(_)) => {
+                return Err("the schema types differ".to_string())
+            }
+            _ => return Ok(()),
+        };
+        let backward = || avro::check_readable(new, old).map_err(|e| format!("backward: {}", e));
+        let forward = || avro::check_readable(old, new).map_err(|e| format!("forward: {}", e));
+        match self {
+            Compatibility::None => Ok(()),
+            Compatibility::Backward | Compatibility::BackwardTransitive => backward(),
+            Compatibility::Forward | Compatibility::ForwardTransitive => forward(),
+            Compatibility::Full | Compatibility::FullTransitive => {
+                backward().and_then(|_| forward())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(Value::String(s)) => write!(f, "{}", s),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+enum Parsed {
+    Avro(avro::ParsedSchema),
+    Json,
+    Protobuf,
+}
+
+/// Parse a schema, returning it along with its canonical form: compact JSON
+/// for Avro and JSON schemas, as given for Protobuf
+///
+/// * `schema_type` - type of the schema
+/// * `schema` - schema
+/// * `references` - schemas it refers to
+fn parse(
+    schema_type: SchemaType,
+    schema: &str,
+    references: &[Value],
+) -> Result<(Parsed, String), String> {
+    match schema_type {
+        SchemaType::Avro => {
+            let parsed = avro::parse(schema, !references.is_empty())?;
+            Ok((Parsed::Avro(parsed), canonical_json(schema)?))
+        }
+        SchemaType::Json => match serde_json::from_str::<Value>(schema) {
+            Ok(Value::Object(_) | Value::Bool(_)) => Ok((Parsed::Json, canonical_json(schema)?)),
+            Ok(_) => Err("a JSON schema must be an object or a boolean".to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        SchemaType::Protobuf if schema.trim().is_empty() => Err("empty schema".to_string()),
+        SchemaType::Protobuf => Ok((Parsed::Protobuf, schema.trim().to_string())),
+    }
+}
+
+fn canonical_json(schema: &str) -> Result<String, String> {
+    serde_json::from_str::<Value>(schema)
+        .map(|v| v.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Key of a record of the schemas topic
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "keytype", rename_all = "SCREAMING_SNAKE_CASE")]
+enum Key {
+    Schema {
+        subject: String,
+        version: u32,
+        magic: u8,
+    },
+    /// Compatibility level of a subject, or the global one without
+    Config { subject: Option<String>, magic: u8 },
+}
+
+/// A version of a subject, as stored in the schemas topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SchemaValue {
+    subject: String,
+    version: u32,
+    id: u32,
+    #[serde(default, skip_serializing_if = "SchemaType::is_avro")]
+    schema_type: SchemaType,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    references: Vec<Value>,
+    schema: String,
+    /// Soft deleted
+    #[serde(default)]
+    deleted: bool,
+}
+
+impl SchemaValue {
+    fn to_json(&self) -> Value {
+        let mut value = json!({
+            "subject": self.subject,
+            "version": self.version,
+            "id": self.id,
+            "schema": self.schema,
+        });
+        if !self.schema_type.is_avro() {
+            value["schemaType"] = json!(self.schema_type);
+        }
+        if !self.references.is_empty() {
+            value["references"] = json!(self.references);
+        }
+        value
+    }
+
+    fn same_schema(&self, schema_type: SchemaType, schema: &str, references: &[Value]) -> bool {
+        self.schema_type == schema_type && self.schema == schema && self.references == references
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConfigValue {
+    #[serde(rename = "compatibilityLevel")]
+    compatibility_level: Compatibility,
+}
+
+/// The content of the schemas topic, read up to an offset
+#[derive(Debug, Default)]
+struct Registry {
+    /// Offset of the next record to read
+    offset: i64,
+    /// Versions of each subject, soft deleted ones included
+    subjects: BTreeMap<String, BTreeMap<u32, SchemaValue>>,
+    global: Option<Compatibility>,
+    configs: BTreeMap<String, Compatibility>,
+    /// Highest id ever read, deleted versions included, so that ids aren't
+    /// given to another schema after a permanent delete
+    max_id: u32,
+    /// Permanently deleted versions, whose ids are kept for their schemas
+    purged: Vec<SchemaValue>,
+}
+
+impl Registry {
+    // Read the records appended to the schemas topic since the last time, or
+    // all of them if it was deleted meanwhile
+    fn sync(&mut self, state: &State) {
+        let log = match state
+            .topics
+            .get(SCHEMAS_TOPIC)
+            .and_then(|t| t.partitions.first())
+        {
+            Some(log) => log,
+            None => {
+                *self = Registry::default();
+                return;
+            }
+        };
+        if log.log_end_offset() < self.offset {
+            *self = Registry::default();
+        }
+        let start = self.offset;
+        for record in log
+            .batches()
+            .filter(|b| b.last_offset >= start)
+            .flat_map(|b| &b.records)
+            .filter(|r| r.offset >= start)
+        {
+            if let Some(key) = &record.key {
+                self.apply(key, record.value.as_deref());
+            }
+        }
+        self.offset = log.log_end_offset();
+    }
+
+    fn apply(&mut self, key: &[u8], value: Option<&[u8]>) {
+        // Other records written by Confluent's registry are skipped
+        let key = match serde_json::from_slice::<Key>(key) {
+            Ok(key) => key,
+            Err(_) => return,
+        };
+        match (key, value) {
+            (
+                Key::Schema {
+                    subject, version, ..
+                },
+                None,
+            ) => {
+                if let Some(versions) = self.subjects.get_mut(&subject) {
+                    self.purged.extend(versions.remove(&version));
+                    if versions.is_empty() {
+                        self.subjects.remove(&subject);
+                    }
+                }
+            }
+            (
+                Key::Schema {
+                    subject, version, ..
+                },
+                Some(value),
+            ) => match serde_json::from_slice::<SchemaValue>(value) {
+                Ok(value) => {
+                    self.max_id = self.max_id.max(value.id);
+                    self.subjects
+                        .entry(subject)
+                        .or_default()
+                        .insert(version, value);
+                }
+                Err(e) => warn!("Invalid schema {} version {}: {}", subject, version, e),
+            },
+            (Key::Config { subject, .. }, value) => {
+                let level = value
+                    .and_then(|v| serde_json::from_slice::<ConfigValue>(v).ok())
+                    .map(|v| v.compatibility_level);
+                match (subject, level) {
+                    (Some(subject), Some(level)) => {
+                        self.configs.insert(subject, level);
+                    }
+                    (Some(subject), None) => {
+                        self.configs.remove(&subject);
+                    }
+                    (None, level) => self.global = level,
+                }
+            }
+        }
+    }
+
+    /// Versions of a subject, in order
+    fn versions(&self, subject: &str, deleted: bool) -> Vec<&SchemaValue> {
+        self.subjects
+            .get(subject)
+            .into_iter()
+            .flat_map(|versions| versions.values())
+            .filter(|v| deleted || !v.deleted)
+            .collect()
+    }
+
+    /// A version of a subject, given as a number or `latest`
+    fn version(
+        &self,
+        subject: &str,
+        version: &str,
+        deleted: bool,
+    ) -> Result<&SchemaValue, HttpResponse> {
+        let versions = self.versions(subject, deleted);
+        if versions.is_empty() {
+            return Err(subject_not_found(subject));
+        }
+        let found = match version {
+            "latest" | "-1" => versions.last().copied(),
+            version => match version.parse::<u32>() {
+                Ok(v) if v > 0 && v <= i32::MAX as u32 => {
+                    versions.into_iter().find(|s| s.version == v)
+                }
+                _ => return Err(invalid_version(version)),
+            },
+        };
+        found.ok_or_else(|| error(40402, format!("Version {} not found.", version)))
+    }
+
+    fn schema(&self, id: u32) -> Option<&SchemaValue> {
+        self.subjects
+            .values()
+            .flat_map(|versions| versions.values())
+            .find(|v| v.id == id)
+    }
+
+    fn compatibility(&self, subject: &str) -> Compatibility {
+        self.configs
+            .get(subject)
+            .copied()
+            .or(self.global)
+            .unwrap_or_default()
+    }
+
+    /// Reasons a schema is incompatible with the versions of a subject it must
+    /// be checked against, as given by the compatibility level of the subject
+    fn incompatibilities(
+        &self,
+        subject: &str,
+        new: &Parsed,
+        only: Option<&SchemaValue>,
+    ) -> Vec<String> {
+        let level = self.compatibility(subject);
+        let versions = match only {
+            Some(version) => vec![version],
+            None if level.is_transitive() => self.versions(subject, false),
+            None => self
+                .versions(subject, false)
+                .into_iter()
+                .rev()
+                .take(1)
+                .collect(),
+        };
+        versions
+            .into_iter()
+            .filter_map(|old| {
+                let parsed = parse(old.schema_type, &old.schema, &old.references);
+                let result = parsed
+                    .map_err(|e| e.to_string())
+                    .and_then(|(old_parsed, _)| level.check(new, &old_parsed));
+                result
+                    .err()
+                    .map(|e| format!("version {}: {}", old.version, e))
+            })
+            .collect()
+    }
+}
+
+/// A Schema Registry, with the API of Confluent's, storing schemas in the
+/// `_schemas` topic of the broker
+///
+/// Schemas are looked up and registered under subjects, and given ids shared
+/// by every subject registering the same schema. Avro schemas are checked
+/// against the earlier versions of their subject, as required by its
+/// compatibility level, while JSON and Protobuf ones are only checked for
+/// syntax.
+#[derive(Debug)]
+pub struct SchemaRegistry {
+    state: SharedState,
+    registry: Mutex<Registry>,
+}
+
+impl SchemaRegistry {
+    pub fn new(state: SharedState) -> Self {
+        Self {
+            state,
+            registry: Mutex::new(Registry::default()),
+        }
+    }
+
+    /// Answer a request to the Schema Registry endpoint
+    ///
+    /// * `GET /subjects`, `GET /subjects/{subject}/versions` - subjects and their
+    ///   versions, soft deleted ones included with `deleted=true`
+    /// * `GET /subjects/{subject}/versions/{version}[/schema]` - a version
+    /// * `POST /subjects/{subject}/versions` - register a schema
+    /// * `POST /subjects/{subject}` - look up the version of a schema
+    /// * `DELETE /subjects/{subject}[/versions/{version}]` - soft delete, or
+    ///   delete for good with `permanent=true`
+    /// * `GET /schemas/ids/{id}[/schema|/versions|/subjects]` - a schema by id
+    /// * `GET /schemas/types` - schema types
+    /// * `POST /compatibility/subjects/{subject}/versions[/{version}]` - check
+    ///   a schema against a subject
+    /// * `GET|PUT|DELETE /config[/{subject}]` - compatibility levels
+    ///
+    /// * `req` - HTTP request
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        let mut state = self.state.lock().unwrap();
+        let mut registry = self.registry.lock().unwrap();
+        registry.sync(&state);
+        let mut resp = self
+            .route(&mut state, &mut registry, req)
+            .unwrap_or_else(|e| error(50001, e.to_string()));
+        if resp.content_type == "application/json" {
+            resp.content_type = CONTENT_TYPE.to_string();
+        }
+        resp
+    }
+
+    fn route(
+        &self,
+        state: &mut State,
+        registry: &mut Registry,
+        req: &HttpRequest,
+    ) -> io::Result<HttpResponse> {
+        let deleted = req.param("deleted") == Some("true");
+        let segments = req.segments();
+        let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        let resp = match (req.method.as_str(), segments.as_slice()) {
+            ("GET", []) => HttpResponse::json(200, &json!({})),
+            ("GET", ["subjects"]) => {
+                let subjects = registry
+                    .subjects
+                    .keys()
+                    .filter(|s| !registry.versions(s, deleted).is_empty())
+                    .collect::<Vec<_>>();
+                HttpResponse::json(200, &subjects)
+            }
+            ("GET", ["subjects", subject, "versions"]) => {
+                let versions = registry.versions(subject, deleted);
+                if versions.is_empty() {
+                    return Ok(subject_not_found(subject));
+                }
+                let versions = versions.iter().map(|v| v.version).collect::<Vec<_>>();
+                HttpResponse::json(200, &versions)
+            }
+            ("GET", ["subjects", subject, "versions", version]) => {
+                match registry.version(subject, version, deleted) {
+                    Ok(version) => HttpResponse::json(200, &version.to_json()),
+                    Err(resp) => resp,
+                }
+            }
+            ("GET", ["subjects", subject, "versions", version, "schema"]) => {
+                match registry.version(subject, version, deleted) {
+                    Ok(version) => raw_schema(&version.schema),
+                    Err(resp) => resp,
+                }
+            }
+            ("POST", ["subjects", subject, "versions"]) => register(state, registry, subject, req)?,
+            ("POST", ["subjects", subject]) => {
+                let (schema_type, schema, references) = match schema_request(req) {
+                    Ok((request, _, canonical)) => {
+                        (request.schema_type, canonical, request.references)
+                    }
+                    Err(resp) => return Ok(resp),
+                };
+                let versions = registry.versions(subject, deleted);
+                if versions.is_empty() {
+                    return Ok(subject_not_found(subject));
+                }
+                match versions
+                    .iter()
+                    .find(|v| v.same_schema(schema_type, &schema, &references))
+                {
+                    Some(version) => HttpResponse::json(200, &version.to_json()),
+                    None => error(40403, "Schema not found"),
+                }
+            }
+            ("DELETE", ["subjects", subject]) => {
+                let permanent = req.param("permanent") == Some("true");
+                let versions = registry.versions(subject, true);
+                if versions.is_empty() {
+                    return Ok(subject_not_found(subject));
+                }
+                let versions = versions.into_iter().cloned().collect::<Vec<_>>();
+                match delete_versions(state, registry, &versions, permanent)? {
+                    Ok(()) => {
+                        info!(subject, permanent, "Subject deleted");
+                        // Versions soft deleted earlier were already answered then
+                        let versions = versions
+                            .iter()
+                            .filter(|v| permanent || !v.deleted)
+                            .map(|v| v.version)
+                            .collect::<Vec<_>>();
+                        HttpResponse::json(200, &versions)
+                    }
+                    Err(resp) => resp,
+                }
+            }
+            ("DELETE", ["subjects", subject, "versions", version]) => {
+                let permanent = req.param("permanent") == Some("true");
+                let version = match registry.version(subject, version, true) {
+                    Ok(version) => version.clone(),
+                    Err(resp) => return Ok(resp),
+                };
+                match delete_versions(state, registry, std::slice::from_ref(&version), permanent)? {
+                    Ok(()) => HttpResponse::json(200, &version.version),
+                    Err(resp) => resp,
+                }
+            }
+            ("GET", ["schemas", "types"]) => HttpResponse::json(200, &["JSON", "PROTOBUF", "AVRO"]),
+            ("GET", ["schemas", "ids", id, rest @ ..]) => {
+                let schema = match id.parse().ok().and_then(|id| registry.schema(id)) {
+                    Some(schema) => schema,
+                    None => return Ok(error(40403, format!("Schema {} not found", id))),
+                };
+                let versions = || {
+                    registry
+                        .subjects
+                        .values()
+                        .flat_map(|versions| versions.values())
+                        .filter(|v| v.id == schema.id && (deleted || !v.deleted))
+                };
+                match rest {
+                    [] => {
+                        let mut value = schema.to_json();
+                        if let Value::Object(o) = &mut value {
+                            o.retain(|k, _| {
+                                k == "schema" || k == "schemaType" || k == "references"
+                            });
+                        }
+                        HttpResponse::json(200, &value)
+                    }
+                    ["schema"] => raw_schema(&schema.schema),
+                    ["versions"] => {
+                        let versions = versions()
+                            .map(|v| json!({"subject": v.subject, "version": v.version}))
+                            .collect::<Vec<_>>();
+                        HttpResponse::json(200, &versions)
+                    }
+                    ["subjects"] => {
+                        let mut subjects = versions().map(|v| &v.subject).collect::<Vec<_>>();
+                        subjects.dedup();
+                        HttpResponse::json(200, &subjects)
+                    }
+                    _ => error(40400, "not found"),
+                }
+            }
+            ("POST", ["compatibility", "subjects", subject, "versions", rest @ ..]) => {
+                let parsed = match schema_request(req) {
+                    Ok((_, parsed, _)) => parsed,
+                    Err(resp) => return Ok(resp),
+                };
+                let only = match rest {
+                    [] => None,
+                    [version] => match registry.version(subject, version, false) {
+                        Ok(version) => Some(version),
+                        Err(resp) => return Ok(resp),
+                    },
+                    _ => return Ok(error(40400, "not found")),
+                };
+                let messages = registry.incompatibilities(subject, &parsed, only);
+                let mut result = json!({ "is_compatible": messages.is_empty() });
+                if req.param("verbose") == Some("true") {
+                    result["messages"] = json!(messages);
+                }
+                HttpResponse::json(200, &result)
+            }
+            ("GET", ["config"]) => {
+                let level = registry.global.unwrap_or_default();
+                HttpResponse::json(200, &json!({ "compatibilityLevel": level }))
+            }
+            ("GET", ["config", subject]) => {
+                let default_to_global = req.param("defaultToGlobal") == Some("true");
+                match registry.configs.get(*subject) {
+                    Some(level) => HttpResponse::json(200, &json!({ "compatibilityLevel": level })),
+                    None if default_to_global => {
+                        let level = registry.global.unwrap_or_default();
+                        HttpResponse::json(200, &json!({ "compatibilityLevel": level }))
+                    }
+                    None => subject_not_configured(subject),
+                }
+            }
+            ("PUT", ["config", rest @ ..]) if rest.len() <= 1 => {
+                #[derive(Deserialize)]
+                struct Config {
+                    compatibility: String,
+                }
+                let level = match serde_json::from_slice::<Config>(&req.body) {
+                    Ok(config) => Compatibility::parse(&config.compatibility),
+                    Err(e) => return Ok(error(42201, format!("invalid config: {}", e))),
+                };
+                let level = match level {
+                    Some(level) => level,
+                    None => return Ok(error(42203, "Invalid compatibility level")),
+                };
+                let subject = rest.first().map(|s| s.to_string());
+                info!(subject, %level, "Compatibility level set");
+                let key = Key::Config { subject, magic: 0 };
+                let value = ConfigValue {
+                    compatibility_level: level,
+                };
+                write(state, registry, &key, Some(&value))?;
+                HttpResponse::json(200, &json!({ "compatibility": level }))
+            }
+            ("DELETE", ["config", rest @ ..]) if rest.len() <= 1 => {
+                let subject = rest.first().map(|s| s.to_string());
+                let previous = match &subject {
+                    Some(subject) => match registry.configs.get(subject) {
+                        Some(level) => *level,
+                        None => return Ok(subject_not_configured(subject)),
+                    },
+                    None => registry.global.unwrap_or_default(),
+                };
+                write(
+                    state,
+                    registry,
+                    &Key::Config { subject, magic: 0 },
+                    None::<&ConfigValue>,
+                )?;
+                HttpResponse::json(200, &json!({ "compatibilityLevel": previous }))
+            }
+            (_, ["subjects", ..])
+            | (_, ["schemas", ..])
+            | (_, ["compatibility", ..])
+            | (_, ["config", ..]) => error(40501, "HTTP method not allowed"),
+            _ => error(40400, "not found"),
+        };
+        Ok(resp)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SchemaRequest {
+    schema: String,
+    #[serde(default)]
+    schema_type: SchemaType,
+    #[serde(default)]
+    references: Vec<Value>,
+}
+
+// The schema of a request, parsed and in its canonical form
+fn schema_request(req: &HttpRequest) -> Result<(SchemaRequest, Parsed, String), HttpResponse> {
+    let request = serde_json::from_slice::<SchemaRequest>(&req.body)
+        .map_err(|e| error(42201, format!("Invalid schema: {}", e)))?;
+    let (parsed, canonical) = parse(request.schema_type, &request.schema, &request.references)
+        .map_err(|e| error(42201, format!("Invalid schema: {}", e)))?;
+    Ok((request, parsed, canonical))
+}
+
+// Register a schema under a subject, unless it's already there
+fn register(
+    state: &mut State,
+    registry: &mut Registry,
+    subject: &str,
+    req: &HttpRequest,
+) -> io::Result<HttpResponse> {
+    let (request, parsed, schema) = match schema_request(req) {
+        Ok(request) => request,
+        Err(resp) => return Ok(resp),
+    };
+    let SchemaRequest {
+        schema_type,
+        references,
+        ..
+    } = request;
+    let registered = registry
+        .versions(subject, false)
+        .into_iter()
+        .find(|v| v.same_schema(schema_type, &schema, &references));
+    if let Some(version) = registered {
+        return Ok(HttpResponse::json(200, &json!({ "id": version.id })));
+    }
+    let incompatibilities = registry.incompatibilities(subject, &parsed, None);
+    if !incompatibilities.is_empty() {
+        let message = format!(
+            "Schema being registered is incompatible with an earlier schema for subject \"{}\", details: [{}]",
+            subject,
+            incompatibilities.join(", ")
+        );
+        return Ok(error(409, message));
+    }
+
+    // Ids are shared by subjects, and versions keep counting after deletions
+    let id = registry
+        .subjects
+        .values()
+        .flat_map(|versions| versions.values())
+        .chain(&registry.purged)
+        .find(|v| v.same_schema(schema_type, &schema, &references))
+        .map_or(registry.max_id + 1, |v| v.id);
+    let version = registry
+        .subjects
+        .get(subject)
+        .and_then(|versions| versions.keys().next_back())
+        .map_or(1, |v| v + 1);
+    let value = SchemaValue {
+        subject: subject.to_string(),
+        version,
+        id,
+        schema_type,
+        references,
+        schema,
+        deleted: false,
+    };
+    let key = Key::Schema {
+        subject: subject.to_string(),
+        version,
+        magic: 1,
+    };
+    write(state, registry, &key, Some(&value))?;
+    info!(subject, version, id, "Schema registered");
+    Ok(HttpResponse::json(200, &json!({ "id": id })))
+}
+
+// Soft delete versions, or delete soft deleted ones for good
+fn delete_versions(
+    state: &mut State,
+    registry: &mut Registry,
+    versions: &[SchemaValue],
+    permanent: bool,
+) -> io::Result<Result<(), HttpResponse>> {
+    let subject = &versions[0].subject;
+    if permanent && versions.iter().any(|v| !v.deleted) {
+        let message = format!(
+            "Subject '{}' was not deleted first before being permanently deleted",
+            subject
+        );
+        return Ok(Err(error(40405, message)));
+    }
+    if !permanent && versions.iter().all(|v| v.deleted) {
+        let message = format!(
+            "Subject '{}' was soft deleted. Set permanent=true to delete permanently",
+            subject
+        );
+        return Ok(Err(error(40404, message)));
+    }
+    for version in versions {
+        let key = Key::Schema {
+            subject: version.subject.clone(),
+            version: version.version,
+            magic: 1,
+        };
+        if permanent {
+            write(state, registry, &key, None::<&SchemaValue>)?;
+        } else if !version.deleted {
+            let value = SchemaValue {
+                deleted: true,
+                ..version.clone()
+            };
+            write(state, registry, &key, Some(&value))?;
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Append a record to the schemas topic, creating it if needed, and read it back
+fn write(
+    state: &mut State,
+    registry: &mut Registry,
+    key: &Key,
+    value: Option<&impl Serialize>,
+) -> io::Result<()> {
+    if !state.topics.contains_key(SCHEMAS_TOPIC) {
+        let mut config = state.config.default_topic_config.clone();
+        config.cleanup_policy = CleanupPolicy {
+            delete: false,
+            compact: true,
+        };
+        state.create_topic(SCHEMAS_TOPIC, 1, config)?;
+    }
+    let key = serde_json::to_vec(key).ok();
+    let value = value.and_then(|v| serde_json::to_vec(v).ok());
+    state.append(
+        SCHEMAS_TOPIC,
+        0,
+        &single_record_batch(now_ms(), key, value, vec![]),
+    )?;
+    registry.sync(state);
+    Ok(())
+}
+
+fn raw_schema(schema: &str) -> HttpResponse {
+    HttpResponse {
+        status: 200,
+        content_type: CONTENT_TYPE.to_string(),
+        body: schema.as_bytes().to_vec(),
+    }
+}
+
+fn subject_not_found(subject: &str) -> HttpResponse {
+    error(40401, format!("Subject '{}' not found.", subject))
+}
+
+fn subject_not_configured(subject: &str) -> HttpResponse {
+    let message = format!(
+        "Subject '{}' does not have subject-level compatibility configured",
+        subject
+    );
+    error(40408, message)
+}
+
+fn invalid_version(version: &str) -> HttpResponse {
+    let message = format!(
+        "The specified version '{}' is not a valid version id. Allowed values are between [1, 2^31-1] and the string \"latest\"",
+        version
+    );
+    error(42202, message)
+}
+
+/// An error as answered by the Schema Registry, with an HTTP status given by
+/// the first 3 digits of its code, e.g. 404 for 40401
+fn error(error_code: u32, message: impl Into<String>) -> HttpResponse {
+    let status = if error_code >= 1000 {
+        error_code / 100
+    } else {
+        error_code
+    };
+    let body = json!({"error_code": error_code, "message": message.into()});
+    HttpResponse::json(status as u16, &body)
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot;
+
+    fn call(registry: &SchemaRegistry, method: &str, target: &str, body: Value) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let req = HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|p| p.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: if body.is_null() {
+                vec![]
+            } else {
+                serde_json::to_vec(&body).unwrap()
+            },
+            ..HttpRequest::default()
+        };
+        let resp = registry.handle(&req);
+        let body = serde_json::from_slice(&resp.body).unwrap_or(Value::Null);
+        (resp.status, body)
+    }
+
+    fn user(fields: &str) -> Value {
+        let schema = format!(
+            r#"{{"type": "record", "name": "User", "fields": [{}]}}"#,
+            fields
+        );
+        json!({ "schema": schema })
+    }
+
+    #[test]
+    fn register_and_look_up() {
+        let state = State::default().into_shared();
+        let registry = SchemaRegistry::new(state.clone());
+        let v1 = user(r#"{"name": "id", "type": "int"}"#);
+        let (status, id) = call(
+            &registry,
+            "POST",
+            "/subjects/users-value/versions",
+            v1.clone(),
+        );
+        assert_eq!((status, id), (200, json!({"id": 1})));
+        // Same schema, same id, in any subject
+        assert_eq!(
+            call(
+                &registry,
+                "POST",
+                "/subjects/users-value/versions",
+                v1.clone()
+            )
+            .1,
+            json!({"id": 1})
+        );
+        assert_eq!(
+            call(&registry, "POST", "/subjects/other/versions", v1.clone()).1,
+            json!({"id": 1})
+        );
+
+        let v2 = user(
+            r#"{"name": "id", "type": "long"}, {"name": "email", "type": ["null", "string"], "default": null}"#,
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "POST",
+                "/subjects/users-value/versions",
+                v2.clone()
+            )
+            .1,
+            json!({"id": 2})
+        );
+        let narrowed = user(r#"{"name": "id", "type": "string"}"#);
+        let (status, incompatible) = call(
+            &registry,
+            "POST",
+            "/subjects/users-value/versions",
+            narrowed,
+        );
+        assert_eq!(status, 409);
+        assert!(incompatible["message"]
+            .as_str()
+            .unwrap()
+            .contains("/id: long can't be read as string"));
+        let invalid = json!({"schema": "{\"type\": \"record\"}"});
+        assert_eq!(
+            call(&registry, "POST", "/subjects/users-value/versions", invalid).1["error_code"],
+            42201
+        );
+
+        let (_, latest) = call(
+            &registry,
+            "GET",
+            "/subjects/users-value/versions/latest",
+            Value::Null,
+        );
+        assert_eq!(
+            (latest["version"].clone(), latest["id"].clone()),
+            (json!(2), json!(2))
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "GET",
+                "/subjects/users-value/versions",
+                Value::Null
+            )
+            .1,
+            json!([1, 2])
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "GET",
+                "/subjects/users-value/versions/3",
+                Value::Null
+            )
+            .1["error_code"],
+            40402
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "GET",
+                "/subjects/users-value/versions/x",
+                Value::Null
+            )
+            .1["error_code"],
+            42202
+        );
+        let (_, found) = call(&registry, "POST", "/subjects/users-value", v1);
+        assert_eq!(found["version"], 1);
+        let (_, by_id) = call(&registry, "GET", "/schemas/ids/1", Value::Null);
+        assert_eq!(
+            by_id,
+            json!({"schema": "{\"fields\":[{\"name\":\"id\",\"type\":\"int\"}],\"name\":\"User\",\"type\":\"record\"}"})
+        );
+        assert_eq!(
+            call(&registry, "GET", "/schemas/ids/1/versions", Value::Null).1,
+            json!([{"subject": "other", "version": 1}, {"subject": "users-value", "version": 1}])
+        );
+        assert_eq!(
+            call(&registry, "GET", "/schemas/ids/9", Value::Null).1["error_code"],
+            40403
+        );
+
+        // Schemas are read back from the topic
+        let registry = SchemaRegistry::new(state.clone());
+        assert_eq!(
+            call(&registry, "GET", "/subjects", Value::Null).1,
+            json!(["other", "users-value"])
+        );
+        let json_schema = json!({"schema": "{\"type\": \"object\"}", "schemaType": "JSON"});
+        let (_, id) = call(&registry, "POST", "/subjects/events/versions", json_schema);
+        assert_eq!(id, json!({"id": 3}));
+        assert_eq!(
+            call(&registry, "GET", "/subjects/events/versions/1", Value::Null).1["schemaType"],
+            "JSON"
+        );
+        let state = state.lock().unwrap();
+        assert!(state.topics[SCHEMAS_TOPIC].config.cleanup_policy.compact);
+    }
+
+    #[test]
+    fn compatibility_and_deletes() {
+        let registry = SchemaRegistry::new(State::default().into_shared());
+        let v1 = user(r#"{"name": "id", "type": "int"}"#);
+        let v2 = user(r#"{"name": "id", "type": "int"}, {"name": "name", "type": "string"}"#);
+        call(&registry, "POST", "/subjects/users/versions", v1.clone());
+
+        // A new field without a default can't read older data, but can be read by older readers
+        let check = "/compatibility/subjects/users/versions/latest?verbose=true";
+        let (_, result) = call(&registry, "POST", check, v2.clone());
+        assert_eq!(result["is_compatible"], false);
+        assert_eq!(
+            result["messages"][0],
+            "version 1: backward: /name: the reader's field has no default and is not written"
+        );
+        let forward = json!({"compatibility": "FORWARD"});
+        assert_eq!(
+            call(&registry, "PUT", "/config/users", forward).1,
+            json!({"compatibility": "FORWARD"})
+        );
+        assert_eq!(
+            call(&registry, "POST", check, v2.clone()).1["is_compatible"],
+            true
+        );
+        assert_eq!(
+            call(&registry, "GET", "/config/users", Value::Null).1,
+            json!({"compatibilityLevel": "FORWARD"})
+        );
+        assert_eq!(
+            call(&registry, "GET", "/config", Value::Null).1,
+            json!({"compatibilityLevel": "BACKWARD"})
+        );
+        assert_eq!(
+            call(&registry, "GET", "/config/other", Value::Null).1["error_code"],
+            40408
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "PUT",
+                "/config",
+                json!({"compatibility": "SOME"})
+            )
+            .1["error_code"],
+            42203
+        );
+        call(
+            &registry,
+            "PUT",
+            "/config",
+            json!({"compatibility": "FULL"}),
+        );
+        assert_eq!(
+            call(&registry, "DELETE", "/config/users", Value::Null).1,
+            json!({"compatibilityLevel": "FORWARD"})
+        );
+        assert_eq!(
+            call(&registry, "POST", "/subjects/users/versions", v2.clone()).0,
+            409
+        );
+        call(
+            &registry,
+            "PUT",
+            "/config/users",
+            json!({"compatibility": "NONE"}),
+        );
+        assert_eq!(
+            call(&registry, "POST", "/subjects/users/versions", v2).1,
+            json!({"id": 2})
+        );
+
+        // Soft, then permanent deletes
+        assert_eq!(
+            call(
+                &registry,
+                "DELETE",
+                "/subjects/users/versions/1?permanent=true",
+                Value::Null
+            )
+            .1["error_code"],
+            40405
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "DELETE",
+                "/subjects/users/versions/1",
+                Value::Null
+            )
+            .1,
+            json!(1)
+        );
+        assert_eq!(
+            call(&registry, "GET", "/subjects/users/versions", Value::Null).1,
+            json!([2])
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "GET",
+                "/subjects/users/versions?deleted=true",
+                Value::Null
+            )
+            .1,
+            json!([1, 2])
+        );
+        assert_eq!(
+            call(&registry, "DELETE", "/subjects/users", Value::Null).1,
+            json!([2])
+        );
+        assert_eq!(
+            call(&registry, "GET", "/subjects", Value::Null).1,
+            json!([])
+        );
+        assert_eq!(
+            call(&registry, "DELETE", "/subjects/users", Value::Null).1["error_code"],
+            40404
+        );
+        assert_eq!(
+            call(
+                &registry,
+                "DELETE",
+                "/subjects/users?permanent=true",
+                Value::Null
+            )
+            .1,
+            json!([1, 2])
+        );
+        assert_eq!(
+            call(&registry, "GET", "/subjects?deleted=true", Value::Null).1,
+            json!([])
+        );
+        // Ids are given again to the same schema, but never to another one
+        assert_eq!(
+            call(&registry, "POST", "/subjects/users/versions", v1).1,
+            json!({"id": 1})
+        );
+        let v3 = user(r#"{"name": "id", "type": "string"}"#);
+        assert_eq!(
+            call(&registry, "POST", "/subjects/other/versions", v3).1,
+            json!({"id": 3})
+        );
+    }
+
+    #[test]
+    fn compatibility_levels() {
+        let registry = SchemaRegistry::new(State::default().into_shared());
+        let id = user(r#"{"name": "id", "type": "int"}"#);
+        let optional_name = user(
+            r#"{"name": "id", "type": "int"}, {"name": "name", "type": "string", "default": ""}"#,
+        );
+        let name = user(r#"{"name": "id", "type": "int"}, {"name": "name", "type": "string"}"#);
+        // The last schema of each subject is checked against the two before it:
+        // it only adds or removes a field without a default, or is compatible
+        // with the latest version both ways, but with the first one only
+        // backward or forward
+        let subjects = [
+            ("added", &optional_name, &id, &name),
+            ("removed", &optional_name, &name, &id),
+            ("backward", &id, &optional_name, &name),
+            ("forward", &name, &optional_name, &id),
+        ];
+        call(
+            &registry,
+            "PUT",
+            "/config",
+            json!({"compatibility": "NONE"}),
+        );
+        for (subject, v1, v2, _) in &subjects {
+            for v in [v1, v2] {
+                let target = format!("/subjects/{}/versions", subject);
+                assert_eq!(call(&registry, "POST", &target, (*v).clone()).0, 200);
+            }
+        }
+
+        let levels = [
+            ("NONE", "added", true),
+            ("BACKWARD", "added", false),
+            ("BACKWARD", "removed", true),
+            ("BACKWARD", "backward", true),
+            ("BACKWARD_TRANSITIVE", "backward", false),
+            ("FORWARD", "added", true),
+            ("FORWARD", "removed", false),
+            ("FORWARD", "forward", true),
+            ("FORWARD_TRANSITIVE", "forward", false),
+            ("FULL", "added", false),
+            ("FULL", "removed", false),
+            ("FULL", "backward", true),
+            ("FULL", "forward", true),
+            ("FULL_TRANSITIVE", "backward", false),
+            ("FULL_TRANSITIVE", "forward", false),
+        ];
+        for (level, subject, compatible) in &levels {
+            let config = json!({ "compatibility": level });
+            call(&registry, "PUT", &format!("/config/{}", subject), config);
+            let (_, _, _, v3) = subjects.iter().find(|s| s.0 == *subject).unwrap();
+            let check = format!("/compatibility/subjects/{}/versions", subject);
+            let (_, result) = call(&registry, "POST", &check, (*v3).clone());
+            assert_eq!(
+                result["is_compatible"], *compatible,
+                "{} {}",
+                level, subject
+            );
+            // Registering an incompatible schema is refused
+            if *level != "NONE" {
+                let target = format!("/subjects/{}/versions", subject);
+                let (status, _) = call(&registry, "POST", &target, json!({"schema": "\"null\""}));
+                assert_eq!(status, 409, "{} {}", level, subject);
+            }
+        }
+    }
+
+    #[test]
+    fn restart_and_reset() {
+        let state = State::default().into_shared();
+        let registry = SchemaRegistry::new(state.clone());
+        let v1 = user(r#"{"name": "id", "type": "int"}"#);
+        let v2 = user(r#"{"name": "id", "type": "long"}"#);
+        call(&registry, "POST", "/subjects/users/versions", v1.clone());
+        call(&registry, "POST", "/subjects/users/versions", v2);
+        call(
+            &registry,
+            "DELETE",
+            "/subjects/users/versions/1",
+            Value::Null,
+        );
+        call(
+            &registry,
+            "PUT",
+            "/config",
+            json!({"compatibility": "NONE"}),
+        );
+        let snapshot = state.lock().unwrap().snapshot().unwrap();
+        call(&registry, "POST", "/subjects/other/versions", v1.clone());
+
+        // A restarted registry reads everything back from the topic
+        let restarted = SchemaRegistry::new(state.clone());
+        let get = |registry, target| call(registry, "GET", target, Value::Null).1;
+        assert_eq!(get(&restarted, "/subjects"), json!(["other", "users"]));
+        assert_eq!(get(&restarted, "/subjects/users/versions"), json!([2]));
+        assert_eq!(
+            get(&restarted, "/subjects/users/versions?deleted=true"),
+            json!([1, 2])
+        );
+        assert_eq!(
+            get(&restarted, "/config"),
+            json!({"compatibilityLevel": "NONE"})
+        );
+
+        // Restoring an earlier snapshot brings back the schemas it had
+        let recovered = snapshot::read(&mut &snapshot[..]).unwrap();
+        state.lock().unwrap().replace(recovered).unwrap();
+        assert_eq!(get(&registry, "/subjects"), json!(["users"]));
+
+        // Without the topic, there are no schemas left, and ids start over
+        state.lock().unwrap().reset().unwrap();
+        assert_eq!(get(&registry, "/subjects?deleted=true"), json!([]));
+        assert_eq!(
+            get(&registry, "/config"),
+            json!({"compatibilityLevel": "BACKWARD"})
+        );
+        let v3 = user(r#"{"name": "id", "type": "string"}"#);
+        assert_eq!(
+            call(&registry, "POST", "/subjects/users/versions", v3).1,
+            json!({"id": 1})
+        );
+    }
+}
@@ -1,9 +1,11 @@
 use byteorder::{NetworkEndian, WriteBytesExt};
+use num_traits::FromPrimitive;
 
 use std::io::{Cursor, Write};
 use std::mem;
 
 use crate::error::*;
+use crate::log::{Batch, Record};
 use crate::messages::*;
 
 macro_rules! encode_with {
@@ -45,17 +47,47 @@ impl SerializeCursor for u32 {
     }
 }
 
+impl SerializeCursor for i16 {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        cursor.write_i16::<NetworkEndian>(*self)
+    }
+}
+
+impl SerializeCursor for i64 {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        cursor.write_i64::<NetworkEndian>(*self)
+    }
+}
+
+impl SerializeCursor for i32 {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        cursor.write_i32::<NetworkEndian>(*self)
+    }
+}
+
+// Compact string, as used by flexible versions
 impl SerializeCursor for String {
     fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
-        cursor.write_u8(self.len() as u8 + 1)?;
-        cursor.write(self.as_bytes())?;
+        write_unsigned_varint(cursor, self.len() as u64 + 1)?;
+        cursor.write_all(self.as_bytes())?;
         Ok(())
     }
 }
 
+// Nullable compact string
+impl SerializeCursor for Option<String> {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        match self {
+            Some(s) => s.encode(cursor),
+            None => cursor.write_u8(0),
+        }
+    }
+}
+
+// Compact array, as used by flexible versions
 impl<T: SerializeCursor> SerializeCursor for Vec<T> {
     fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
-        cursor.write_u8(self.len() as u8 + 1)?;
+        write_unsigned_varint(cursor, self.len() as u64 + 1)?;
         for e in self {
             e.encode(cursor)?;
         }
@@ -83,7 +115,7 @@ impl SerializeCursor for BrokerMetadata {
             self.node_id,
             self.host,
             self.port,
-            0u8, // Rack (none)
+            self.rack,
             0u8 // Tagged fields (none)
         }
         Ok(())
@@ -122,6 +154,181 @@ impl SerializeCursor for TopicMetadata {
     }
 }
 
+impl SerializeCursor for PartitionResult {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        encode_with! {
+            cursor:
+            self.id,
+            self.error,
+            self.error_message,
+            0u8 // Tagged fields (none)
+        }
+        Ok(())
+    }
+}
+
+impl SerializeCursor for TopicResults {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        encode_with! {
+            cursor:
+            self.name,
+            self.partitions,
+            0u8 // Tagged fields (none)
+        }
+        Ok(())
+    }
+}
+
+impl SerializeCursor for OngoingPartitionReassignment {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        encode_with! {
+            cursor:
+            self.id,
+            self.replicas,
+            self.adding_replicas,
+            self.removing_replicas,
+            0u8 // Tagged fields (none)
+        }
+        Ok(())
+    }
+}
+
+impl SerializeCursor for OngoingTopicReassignment {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        encode_with! {
+            cursor:
+            self.name,
+            self.partitions,
+            0u8 // Tagged fields (none)
+        }
+        Ok(())
+    }
+}
+
+impl SerializeCursor for ProducePartitionResponse {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        encode_with! {
+            cursor:
+            self.id,
+            self.error,
+            self.base_offset,
+            self.log_append_time,
+            self.log_start_offset,
+            0u32, // Record errors (none)
+            -1i16 // Error message (null)
+        }
+        Ok(())
+    }
+}
+
+impl SerializeCursor for ProduceTopicResponse {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        // Produce v8 is not a flexible version: plain strings and arrays
+        (self.name.len() as u16).encode(cursor)?;
+        cursor.write_all(self.name.as_bytes())?;
+        (self.partitions.len() as u32).encode(cursor)?;
+        for p in &self.partitions {
+            p.encode(cursor)?;
+        }
+        Ok(())
+    }
+}
+
+impl SerializeCursor for FetchPartitionResponse {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        encode_with! {
+            cursor:
+            self.id,
+            self.error,
+            self.high_watermark,
+            self.high_watermark, // Last stable offset, no transactions
+            self.log_start_offset,
+            -1i32, // Aborted transactions (null)
+            -1i32, // Preferred read replica (none)
+            (self.records.len() as u32)
+        }
+        cursor.write_all(&self.records)
+    }
+}
+
+impl SerializeCursor for FetchTopicResponse {
+    fn encode(&self, cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
+        // Fetch v11 is not a flexible version: plain strings and arrays
+        (self.name.len() as u16).encode(cursor)?;
+        cursor.write_all(self.name.as_bytes())?;
+        (self.partitions.len() as u32).encode(cursor)?;
+        for p in &self.partitions {
+            p.encode(cursor)?;
+        }
+        Ok(())
+    }
+}
+
+// Unsigned variable length integer, as used by flexible versions
+fn write_unsigned_varint(cursor: &mut Cursor<Vec<u8>>, mut v: u64) -> std::io::Result<()> {
+    while v >= 0x80 {
+        cursor.write_u8((v as u8 & 0x7f) | 0x80)?;
+        v >>= 7;
+    }
+    cursor.write_u8(v as u8)
+}
+
+// Zigzag-encoded variable length integer, as used inside record batches
+fn write_varint(cursor: &mut Cursor<Vec<u8>>, value: i64) -> std::io::Result<()> {
+    write_unsigned_varint(cursor, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn write_varint_bytes(
+    cursor: &mut Cursor<Vec<u8>>,
+    bytes: &Option<Vec<u8>>,
+) -> std::io::Result<()> {
+    match bytes {
+        Some(b) => {
+            write_varint(cursor, b.len() as i64)?;
+            cursor.write_all(b)
+        }
+        None => write_varint(cursor, -1),
+    }
+}
+
+// CRC-32C (Castagnoli), the checksum used by record batches
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn encode_record(
+    batch: &Batch,
+    record: &Record,
+    cursor: &mut Cursor<Vec<u8>>,
+) -> std::io::Result<()> {
+    let body = &mut Cursor::new(Vec::<u8>::new());
+    0u8.encode(body)?; // Attributes (unused)
+    write_varint(body, record.timestamp - batch.first_timestamp)?;
+    write_varint(body, record.offset - batch.base_offset)?;
+    write_varint_bytes(body, &record.key)?;
+    write_varint_bytes(body, &record.value)?;
+    write_varint(body, record.headers.len() as i64)?;
+    for h in &record.headers {
+        write_varint(body, h.key.len() as i64)?;
+        body.write_all(h.key.as_bytes())?;
+        write_varint_bytes(body, &h.value)?;
+    }
+    let body = body.get_ref();
+    write_varint(cursor, body.len() as i64)?;
+    cursor.write_all(body)
+}
+
 fn write_msg_length(cursor: &mut Cursor<Vec<u8>>) -> std::io::Result<()> {
     let msg_length = cursor.position();
     cursor.set_position(0);
@@ -143,6 +350,12 @@ impl Serialize for Response {
             Response::ApiVersionsResponse(msg) => msg.to_bytes(),
             Response::MetadataResponse(msg) => msg.to_bytes(),
             Response::ProduceResponse(msg) => msg.to_bytes(),
+            Response::FetchResponse(msg) => msg.to_bytes(),
+            Response::OffsetForLeaderEpochResponse(msg) => msg.to_bytes(),
+            Response::ElectLeadersResponse(msg) => msg.to_bytes(),
+            Response::AlterPartitionReassignmentsResponse(msg) => msg.to_bytes(),
+            Response::ListPartitionReassignmentsResponse(msg) => msg.to_bytes(),
+            Response::ErrorResponse(msg) => msg.to_bytes(),
         }
     }
 }
@@ -154,10 +367,29 @@ impl Serialize for ApiVersionsResponse {
             cursor:
             0u32, // Length
             self.header.correlation_id,
-            self.error_code,
-            &self.api_versions,
-            self.throttle_time,
-            0u8 // Tagged fields (none)
+            self.error_code
+        }
+        if self.version >= 3 {
+            encode_with! {
+                cursor:
+                &self.api_versions,
+                self.throttle_time,
+                0u8 // Tagged fields (none)
+            }
+        } else {
+            // Versions before 3 are not flexible: plain arrays, no tagged fields
+            (self.api_versions.len() as u32).encode(cursor)?;
+            for v in &self.api_versions {
+                encode_with! {
+                    cursor:
+                    v.api_key,
+                    v.min_version,
+                    v.max_version
+                }
+            }
+            if self.version >= 1 {
+                self.throttle_time.encode(cursor)?;
+            }
         }
         write_msg_length(cursor)?;
 
@@ -187,14 +419,336 @@ impl Serialize for MetadataResponse {
     }
 }
 
+impl Serialize for FetchResponse {
+    fn to_bytes(&self) -> SerializeResult {
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            cursor:
+            0u32, // Length
+            self.header.correlation_id,
+            self.throttle_time,
+            self.error,
+            0u32, // Session id (no fetch sessions)
+            (self.topics.len() as u32)
+        }
+        for t in &self.topics {
+            t.encode(cursor)?;
+        }
+        write_msg_length(cursor)?;
+
+        Ok(cursor.to_owned().into_inner())
+    }
+}
+
+impl Serialize for OffsetForLeaderEpochResponse {
+    fn to_bytes(&self) -> SerializeResult {
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            cursor:
+            0u32, // Length
+            self.header.correlation_id
+        }
+        // No version up to 3 is flexible. The throttle time was added in v2,
+        // and the leader epoch of each partition in v1.
+        if self.version >= 2 {
+            self.throttle_time.encode(cursor)?;
+        }
+        (self.topics.len() as u32).encode(cursor)?;
+        for t in &self.topics {
+            (t.name.len() as u16).encode(cursor)?;
+            cursor.write_all(t.name.as_bytes())?;
+            (t.partitions.len() as u32).encode(cursor)?;
+            for p in &t.partitions {
+                encode_with! {
+                    cursor:
+                    p.error,
+                    p.id
+                }
+                if self.version >= 1 {
+                    p.leader_epoch.encode(cursor)?;
+                }
+                p.end_offset.encode(cursor)?;
+            }
+        }
+        write_msg_length(cursor)?;
+
+        Ok(cursor.to_owned().into_inner())
+    }
+}
+
+impl Serialize for ElectLeadersResponse {
+    fn to_bytes(&self) -> SerializeResult {
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            cursor:
+            0u32, // Length
+            self.header.correlation_id,
+            0u8, // Tagged fields (none)
+            self.throttle_time,
+            self.error,
+            self.topics,
+            0u8 // Tagged fields (none)
+        }
+        write_msg_length(cursor)?;
+
+        Ok(cursor.to_owned().into_inner())
+    }
+}
+
+impl Serialize for AlterPartitionReassignmentsResponse {
+    fn to_bytes(&self) -> SerializeResult {
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            cursor:
+            0u32, // Length
+            self.header.correlation_id,
+            0u8, // Tagged fields (none)
+            self.throttle_time,
+            self.error,
+            self.error_message,
+            self.topics,
+            0u8 // Tagged fields (none)
+        }
+        write_msg_length(cursor)?;
+
+        Ok(cursor.to_owned().into_inner())
+    }
+}
+
+impl Serialize for ListPartitionReassignmentsResponse {
+    fn to_bytes(&self) -> SerializeResult {
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            cursor:
+            0u32, // Length
+            self.header.correlation_id,
+            0u8, // Tagged fields (none)
+            self.throttle_time,
+            self.error,
+            self.error_message,
+            self.topics,
+            0u8 // Tagged fields (none)
+        }
+        write_msg_length(cursor)?;
+
+        Ok(cursor.to_owned().into_inner())
+    }
+}
+
+impl Serialize for Batch {
+    fn to_bytes(&self) -> SerializeResult {
+        // Everything after the CRC is covered by it, so it is encoded first
+        let crc_data = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            crc_data:
+            0i16, // Attributes (no compression, create time)
+            ((self.last_offset - self.base_offset) as u32),
+            self.first_timestamp,
+            self.max_timestamp,
+            self.producer_id,
+            self.producer_epoch,
+            self.base_sequence,
+            (self.records.len() as u32)
+        }
+        for r in &self.records {
+            encode_record(self, r, crc_data)?;
+        }
+        let crc_data = crc_data.get_ref();
+
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        encode_with! {
+            cursor:
+            self.base_offset,
+            ((crc_data.len() + 9) as u32), // Length, from the leader epoch onwards
+            self.leader_epoch,
+            2u8, // Magic
+            crc32c(crc_data)
+        }
+        cursor.write_all(crc_data)?;
+
+        Ok(cursor.to_owned().into_inner())
+    }
+}
+
 impl Serialize for ProduceResponse {
     fn to_bytes(&self) -> SerializeResult {
         let cursor = &mut Cursor::new(Vec::<u8>::new());
-        // TODO
+        encode_with! {
+            cursor:
+            0u32, // Length
+            self.header.correlation_id,
+            (self.topics.len() as u32)
+        }
+        for t in &self.topics {
+            t.encode(cursor)?;
+        }
+        self.throttle_time.encode(cursor)?;
+        write_msg_length(cursor)?;
+
         Ok(cursor.to_owned().into_inner())
     }
 }
 
+impl Serialize for ErrorResponse {
+    fn to_bytes(&self) -> SerializeResult {
+        let header = ResponseHeader {
+            correlation_id: self.header.correlation_id,
+        };
+        let error = self.error_code;
+        let api_key = ApiKey::from_u16(self.api_key);
+        match api_key {
+            Some(ApiKey::ApiVersions) => {
+                // Version 0, which every client can read, unless the version is supported
+                let (min_version, max_version) = ApiKey::ApiVersions.supported_versions().unwrap();
+                let version = if (min_version..=max_version).contains(&self.api_version) {
+                    self.api_version
+                } else {
+                    0
+                };
+                let api_versions = vec![ApiVersion {
+                    api_key: self.api_key,
+                    min_version,
+                    max_version,
+                }];
+                ApiVersionsResponse {
+                    header,
+                    version,
+                    error_code: error,
+                    api_versions,
+                    throttle_time: 0,
+                }
+                .to_bytes()
+            }
+            _ if !self.has_layout() => header_and_error(&header, error),
+            Some(ApiKey::Metadata) => MetadataResponse {
+                header,
+                throttle_time: 0,
+                brokers: vec![],
+                cluster_id: String::new(),
+                controller_id: u32::MAX, // -1, unknown
+                topics: self
+                    .topics
+                    .iter()
+                    .map(|t| TopicMetadata {
+                        error,
+                        ..TopicMetadata::unknown(t.name.clone())
+                    })
+                    .collect(),
+                cluster_authorized_operations: 0,
+            }
+            .to_bytes(),
+            Some(ApiKey::Produce) => ProduceResponse {
+                header,
+                topics: self
+                    .topics
+                    .iter()
+                    .map(|t| ProduceTopicResponse {
+                        name: t.name.clone(),
+                        partitions: t
+                            .partitions
+                            .iter()
+                            .map(|&id| ProducePartitionResponse {
+                                id,
+                                error,
+                                base_offset: -1,
+                                log_append_time: -1,
+                                log_start_offset: -1,
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+                throttle_time: 0,
+            }
+            .to_bytes(),
+            Some(ApiKey::Fetch) => FetchResponse {
+                header,
+                throttle_time: 0,
+                error,
+                topics: self
+                    .topics
+                    .iter()
+                    .map(|t| FetchTopicResponse {
+                        name: t.name.clone(),
+                        partitions: t
+                            .partitions
+                            .iter()
+                            .map(|&id| FetchPartitionResponse {
+                                id,
+                                error,
+                                high_watermark: -1,
+                                log_start_offset: -1,
+                                records: vec![],
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            }
+            .to_bytes(),
+            Some(ApiKey::OffsetForLeaderEpoch) => OffsetForLeaderEpochResponse {
+                header,
+                version: self.api_version,
+                throttle_time: 0,
+                topics: self
+                    .topics
+                    .iter()
+                    .map(|t| EpochEndOffsetTopic {
+                        name: t.name.clone(),
+                        partitions: t
+                            .partitions
+                            .iter()
+                            .map(|&id| EpochEndOffset {
+                                error,
+                                id,
+                                leader_epoch: -1,
+                                end_offset: -1,
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            }
+            .to_bytes(),
+            Some(ApiKey::ElectLeaders) => ElectLeadersResponse {
+                header,
+                throttle_time: 0,
+                error,
+                topics: vec![],
+            }
+            .to_bytes(),
+            Some(ApiKey::AlterPartitionReassignments) => AlterPartitionReassignmentsResponse {
+                header,
+                throttle_time: 0,
+                error,
+                error_message: None,
+                topics: vec![],
+            }
+            .to_bytes(),
+            Some(ApiKey::ListPartitionReassignments) => ListPartitionReassignmentsResponse {
+                header,
+                throttle_time: 0,
+                error,
+                error_message: None,
+                topics: vec![],
+            }
+            .to_bytes(),
+            _ => header_and_error(&header, error),
+        }
+    }
+}
+
+// A response with only the correlation id and an error code, for requests
+// whose response layout is unknown
+fn header_and_error(header: &ResponseHeader, error: u16) -> SerializeResult {
+    let cursor = &mut Cursor::new(Vec::<u8>::new());
+    encode_with! {
+        cursor:
+        0u32, // Length
+        header.correlation_id,
+        error
+    }
+    write_msg_length(cursor)?;
+
+    Ok(cursor.to_owned().into_inner())
+}
 
 // -----------------------------------------------------------------------------
 
@@ -204,10 +758,23 @@ mod tests {
 
     use super::*;
 
+    const CLUSTER_ID: &str = "0NHLrMQhQe2sWh6PvXAxcA";
+
+    // The broker the captured responses come from
+    fn broker() -> BrokerMetadata {
+        BrokerMetadata {
+            node_id: 1003,
+            host: "localhost".to_string(),
+            port: 9092,
+            rack: None,
+        }
+    }
+
     #[test]
     fn serialize_api_version_response() {
-        let msg = ApiVersionsResponse {
+        let mut msg = ApiVersionsResponse {
             header: ResponseHeader { correlation_id: 0 },
+            version: 3,
             error_code: 1,
             throttle_time: 0,
             api_versions: vec![
@@ -230,23 +797,57 @@ mod tests {
                 0, 0, 0
             ]
         );
+        // Older versions have plain arrays, and v0 no throttle time
+        msg.version = 1;
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![
+                0, 0, 0, 26, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 8, 0, 1, 0, 0, 0, 11, 0,
+                0, 0, 0
+            ]
+        );
+        msg.version = 0;
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![
+                0, 0, 0, 22, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 8, 0, 1, 0, 0, 0, 11
+            ]
+        );
     }
 
     #[test]
     fn serialize_full_api_version_response() {
         let req = &ApiVersionsRequest {
             header: RequestHeader {
-                api_key: ApiKey::Produce,
-                api_version: 0,
+                api_key: ApiKey::ApiVersions,
+                api_version: 3,
                 correlation_id: 0,
                 client_id: None,
             },
         };
         let msg = ApiVersionsResponse::new(req);
+        // Only the versions that can be parsed and answered are advertised
+        let advertised = msg
+            .api_versions
+            .iter()
+            .map(|v| (v.api_key, v.min_version, v.max_version))
+            .collect::<Vec<_>>();
         assert_eq!(
-            msg.to_bytes().unwrap(),
-            include_bytes!("../res/api_versions_response.bin")
+            advertised,
+            vec![
+                (0, 8, 8),
+                (1, 11, 11),
+                (3, 9, 9),
+                (18, 0, 3),
+                (23, 0, 3),
+                (43, 2, 2),
+                (45, 0, 0),
+                (46, 0, 0)
+            ]
         );
+        let bytes = msg.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 4 + 4 + 2 + 1 + 8 * 7 + 4 + 1);
+        assert_eq!(&bytes[8..19], &[0, 0, 9, 0, 0, 0, 8, 0, 8, 0, 0]);
     }
 
     #[test]
@@ -263,7 +864,7 @@ mod tests {
             include_cluster_authorized_operations: false,
             include_topic_authorized_operations: false,
         };
-        let msg = MetadataResponse::new(req);
+        let msg = MetadataResponse::new(req, vec![broker()], CLUSTER_ID, vec![]);
         assert_eq!(
             msg.to_bytes().unwrap(),
             include_bytes!("../res/metadata_no_topics_response.bin")
@@ -284,10 +885,110 @@ mod tests {
             include_cluster_authorized_operations: false,
             include_topic_authorized_operations: false,
         };
-        let msg = MetadataResponse::new(req);
+        let partition = PartitionMetadata {
+            error: 0,
+            id: 0,
+            leader_id: 1003,
+            leader_epoch: 0,
+            replicas: vec![1003],
+            caught_up_replicas: vec![1003],
+            offline_replicas: vec![],
+        };
+        let topics = vec![TopicMetadata::new("my-topic".to_string(), vec![partition])];
+        let msg = MetadataResponse::new(req, vec![broker()], CLUSTER_ID, topics);
         assert_eq!(
             msg.to_bytes().unwrap(),
             include_bytes!("../res/metadata_response.bin")
         );
     }
+
+    #[test]
+    fn serialize_produce_response() {
+        let msg = ProduceResponse {
+            header: ResponseHeader { correlation_id: 4 },
+            topics: vec![ProduceTopicResponse {
+                name: "t".to_string(),
+                partitions: vec![ProducePartitionResponse {
+                    id: 0,
+                    error: 0,
+                    base_offset: 2,
+                    log_append_time: -1,
+                    log_start_offset: 0,
+                }],
+            }],
+            throttle_time: 0,
+        };
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![
+                0, 0, 0, 55, 0, 0, 0, 4, 0, 0, 0, 1, 0, 1, b't', 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 2, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 255, 255, 0, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn serialize_error_responses() {
+        // ApiVersions v5 is answered in v0, with UNSUPPORTED_VERSION and the supported range
+        let msg = ErrorResponse::new(18, 5, 5, 35);
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![0, 0, 0, 16, 0, 0, 0, 5, 0, 35, 0, 0, 0, 1, 0, 18, 0, 0, 0, 3]
+        );
+
+        // Others in the layout of their response, with the error on every partition
+        let mut msg = ErrorResponse::new(0, 8, 4, 2);
+        msg.topics = vec![TopicPartitions {
+            name: "t".to_string(),
+            partitions: vec![0],
+        }];
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![
+                0, 0, 0, 55, 0, 0, 0, 4, 0, 0, 0, 1, 0, 1, b't', 0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 255,
+                255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+                255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 255, 255, 0, 0, 0, 0
+            ]
+        );
+        msg.api_key = 1;
+        msg.api_version = 11;
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![
+                0, 0, 0, 67, 0, 0, 0, 4, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, b't', 0,
+                0, 0, 1, 0, 0, 0, 0, 0, 2, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+                255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+                255, 255, 255, 255, 255, 0, 0, 0, 0
+            ]
+        );
+
+        // Unknown APIs, unsupported versions, and requests without the topics
+        // to put the error on, with the correlation id and the error alone
+        let msg = ErrorResponse::new(99, 0, 3, 42);
+        assert_eq!(msg.to_bytes().unwrap(), vec![0, 0, 0, 6, 0, 0, 0, 3, 0, 42]);
+        let msg = ErrorResponse::new(0, 3, 3, 35);
+        assert_eq!(msg.to_bytes().unwrap(), vec![0, 0, 0, 6, 0, 0, 0, 3, 0, 35]);
+        let msg = ErrorResponse::new(0, 8, 3, 2);
+        assert_eq!(msg.to_bytes().unwrap(), vec![0, 0, 0, 6, 0, 0, 0, 3, 0, 2]);
+
+        // ElectLeaders v2, with a top-level error
+        let msg = ErrorResponse::new(43, 2, 7, 42);
+        assert_eq!(
+            msg.to_bytes().unwrap(),
+            vec![0, 0, 0, 13, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 42, 1, 0]
+        );
+    }
+
+    #[test]
+    fn serialize_record_batch() {
+        // The batch embedded in the Produce request capture, CRC included
+        let bytes = include_bytes!("../res/produce_request.bin");
+        if let Request::ProduceRequest(r) = crate::de::from_stream(&bytes[..]).unwrap() {
+            let batch = Batch::from_request(0, &r.topics[0].partitions[0].batches[0]);
+            assert_eq!(batch.to_bytes().unwrap(), &bytes[64..]);
+        } else {
+            panic!("not a Produce request");
+        }
+    }
 }
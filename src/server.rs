@@ -0,0 +1,1390 @@
+use bytes::Bytes;
+use futures::future::{self, Future, FutureExt};
+use futures::stream::FuturesOrdered;
+use futures::{SinkExt, StreamExt};
+use num_traits::FromPrimitive;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use tokio_util::codec::Framed;
+use tracing::{debug, debug_span, error, field, info_span, trace, warn, Instrument, Span};
+
+use std::io;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::admin;
+use crate::broker::{self, Context};
+use crate::cluster::{broker_port, Cluster};
+use crate::codec::{Frame, KafkaCodec};
+use crate::compaction;
+use crate::config::{BrokerConfig, Listener, SecurityProtocol};
+use crate::de;
+use crate::error::ErrorCode;
+use crate::faults::Delivery;
+use crate::http::{self, HttpRequest, HttpResponse};
+use crate::messages::{ApiKey, ErrorResponse, Request, Response};
+use crate::metrics::Metrics;
+use crate::recorder::{Exchange, Recorder};
+use crate::rest::RestProxy;
+use crate::retention;
+use crate::schema_registry::SchemaRegistry;
+use crate::ser::Serialize;
+use crate::state::{SharedState, State};
+
+/// Requests read ahead on a connection, before their responses are written
+const MAX_IN_FLIGHT_REQUESTS: usize = 32;
+
+/// A broker serving the Kafka protocol on a TCP socket
+///
+/// The server runs on its own async runtime, with a task per connection.
+/// Binding to port 0 picks a free port, so each test can run its own
+/// isolated broker. Each listener advertises the address it is bound to in
+/// Metadata responses, unless the configuration has an advertised address for
+/// its name, and the broker shuts down when dropped.
+///
+/// With several brokers in the configuration, each one listens on its own
+/// ports: the configured ones plus its index in the cluster (or free ports).
+///
+/// ```no_run
+/// use pseudokafka::{server::Server, state::State};
+///
+/// let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+/// let bootstrap_servers = server.local_addr().to_string();
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    /// Bound address of each broker and listener
+    addrs: Vec<(Context, SocketAddr)>,
+    /// Bound address of the HTTP admin endpoint, if enabled
+    admin_addr: Option<SocketAddr>,
+    /// Bound address of the metrics endpoint, if enabled
+    metrics_addr: Option<SocketAddr>,
+    /// Bound address of the REST Proxy endpoint, if enabled
+    rest_addr: Option<SocketAddr>,
+    /// Bound address of the Schema Registry endpoint, if enabled
+    schema_registry_addr: Option<SocketAddr>,
+    metrics: Arc<Metrics>,
+    state: SharedState,
+    runtime: Option<Runtime>,
+    acceptors: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Start serving a broker state, along with its retention and compaction tasks
+    /// The server has a single PLAINTEXT listener.
+    ///
+    /// * `state` - broker state
+    /// * `addr` - address to listen on, with port 0 for any free port
+    pub fn start(state: State, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| invalid_input("no address to listen on".to_string()))?;
+        let listener = Listener::new("PLAINTEXT", &addr.ip().to_string(), addr.port());
+        Self::serve(state, vec![listener])
+    }
+
+    /// Start serving a broker state on the listeners of its configuration
+    /// Only PLAINTEXT listeners are supported.
+    ///
+    /// * `state` - broker state
+    pub fn start_listeners(state: State) -> io::Result<Self> {
+        let config = &state.config;
+        for l in &config.listeners {
+            match config.security_protocol(&l.name) {
+                Some(SecurityProtocol::Plaintext) => (),
+                Some(protocol) => {
+                    return Err(invalid_input(format!(
+                        "{}: {} is not supported",
+                        l, protocol
+                    )))
+                }
+                None => return Err(invalid_input(format!("{}: no security protocol", l))),
+            }
+        }
+        let listeners = config.listeners.clone();
+        Self::serve(state, listeners)
+    }
+
+    fn serve(mut state: State, listeners: Vec<Listener>) -> io::Result<Self> {
+        // The configuration may have changed since the state was created, but
+        // a cluster already made for it keeps its offline brokers and assignments
+        if !state.cluster.matches(&state.config) {
+            state.cluster = Cluster::new(&state.config)?;
+            state.resume_leader_epochs();
+        }
+        let mut bound = Vec::new();
+        for (i, node) in state.cluster.nodes.iter_mut().enumerate() {
+            for l in &listeners {
+                let port = match l.port {
+                    0 => 0,
+                    port => broker_port(port, i)
+                        .ok_or_else(|| invalid_input(format!("{}: too many brokers", l)))?,
+                };
+                let listener = net::TcpListener::bind((l.host.as_str(), port))?;
+                listener.set_nonblocking(true)?;
+                let addr = listener.local_addr()?;
+                if node.endpoint(&l.name).is_none() {
+                    let host = if addr.ip().is_unspecified() {
+                        "localhost".to_string()
+                    } else {
+                        addr.ip().to_string()
+                    };
+                    node.endpoints
+                        .push(Listener::new(&l.name, &host, addr.port()));
+                }
+                let ctx = Context {
+                    listener: l.name.clone(),
+                    node_id: node.id,
+                };
+                bound.push((ctx, listener));
+            }
+        }
+        let addrs = bound
+            .iter()
+            .map(|(ctx, listener)| Ok((ctx.clone(), listener.local_addr()?)))
+            .collect::<io::Result<_>>()?;
+        let retention_check_interval = state.config.retention_check_interval;
+        let cleaner_backoff = state.config.cleaner_backoff;
+        let limits = Limits::new(&state.config);
+        let admin = bind_http(&state.config.admin_listener)?;
+        let admin_addr = admin.as_ref().map(|l| l.local_addr()).transpose()?;
+        let metrics_listener = bind_http(&state.config.metrics_listener)?;
+        let metrics_addr = metrics_listener
+            .as_ref()
+            .map(|l| l.local_addr())
+            .transpose()?;
+        let rest_listener = bind_http(&state.config.rest_listener)?;
+        let rest_addr = rest_listener.as_ref().map(|l| l.local_addr()).transpose()?;
+        let schema_registry_listener = bind_http(&state.config.schema_registry_listener)?;
+        let schema_registry_addr = schema_registry_listener
+            .as_ref()
+            .map(|l| l.local_addr())
+            .transpose()?;
+        let metrics = Arc::new(Metrics::default());
+        let recorder = match &state.config.record_file {
+            Some(path) => Some(Arc::new(Recorder::create(path)?)),
+            None => None,
+        };
+
+        let state = state.into_shared();
+        retention::spawn(&state, retention_check_interval);
+        compaction::spawn(&state, cleaner_backoff);
+        let runtime = runtime::Builder::new_multi_thread()
+            .thread_name("pseudokafka")
+            .enable_all()
+            .build()?;
+        let acceptors = {
+            let _guard = runtime.enter();
+            let mut acceptors = bound
+                .into_iter()
+                .map(|(ctx, listener)| {
+                    let listener = TcpListener::from_std(listener)?;
+                    let accept = accept(
+                        listener,
+                        Arc::new(ctx),
+                        state.clone(),
+                        limits.clone(),
+                        recorder.clone(),
+                        metrics.clone(),
+                    );
+                    Ok(runtime.spawn(accept))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            if let Some(listener) = admin {
+                let admin_state = state.clone();
+                let handler: http::Handler = Arc::new(move |req| admin::handle(&admin_state, req));
+                let listener = TcpListener::from_std(listener)?;
+                acceptors.push(runtime.spawn(http::accept(listener, handler)));
+            }
+            if let Some(listener) = metrics_listener {
+                let (state, metrics) = (state.clone(), metrics.clone());
+                let handler: http::Handler =
+                    Arc::new(move |req| serve_metrics(&state, &metrics, req));
+                let listener = TcpListener::from_std(listener)?;
+                acceptors.push(runtime.spawn(http::accept(listener, handler)));
+            }
+            if let Some(listener) = rest_listener {
+                let proxy = RestProxy::new(state.clone());
+                let handler: http::Handler = Arc::new(move |req| proxy.handle(req));
+                let listener = TcpListener::from_std(listener)?;
+                acceptors.push(runtime.spawn(http::accept(listener, handler)));
+            }
+            if let Some(listener) = schema_registry_listener {
+                let registry = SchemaRegistry::new(state.clone());
+                let handler: http::Handler = Arc::new(move |req| registry.handle(req));
+                let listener = TcpListener::from_std(listener)?;
+                acceptors.push(runtime.spawn(http::accept(listener, handler)));
+            }
+            acceptors
+        };
+        Ok(Self {
+            addrs,
+            admin_addr,
+            metrics_addr,
+            rest_addr,
+            schema_registry_addr,
+            metrics,
+            state,
+            runtime: Some(runtime),
+            acceptors,
+        })
+    }
+
+    /// Address the server is listening on, the first one with several listeners
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0].1
+    }
+
+    /// Address a listener of the first broker is bound to
+    ///
+    /// * `name` - listener name
+    pub fn listener_addr(&self, name: &str) -> Option<SocketAddr> {
+        self.addrs
+            .iter()
+            .find(|(ctx, _)| ctx.listener == name)
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Address a listener of a broker is bound to
+    ///
+    /// * `node_id` - broker id
+    /// * `name` - listener name
+    pub fn broker_addr(&self, node_id: u32, name: &str) -> Option<SocketAddr> {
+        self.addrs
+            .iter()
+            .find(|(ctx, _)| ctx.node_id == node_id && ctx.listener == name)
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Address the HTTP admin endpoint is bound to, if enabled
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// Address Prometheus metrics are served on, if enabled
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Address the REST Proxy endpoint is bound to, if enabled
+    pub fn rest_addr(&self) -> Option<SocketAddr> {
+        self.rest_addr
+    }
+
+    /// Address the Schema Registry endpoint is bound to, if enabled
+    pub fn schema_registry_addr(&self) -> Option<SocketAddr> {
+        self.schema_registry_addr
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn state(&self) -> &SharedState {
+        &self.state
+    }
+
+    /// Block the current thread for as long as the server runs
+    pub fn wait(mut self) {
+        if let Some(runtime) = &self.runtime {
+            let acceptors = std::mem::take(&mut self.acceptors);
+            runtime.block_on(future::join_all(acceptors));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // Dropping the tasks closes the listener and every connection
+        if let Some(runtime) = self.runtime.take() {
+            if runtime::Handle::try_current().is_ok() {
+                // Blocking is not allowed inside another runtime
+                runtime.shutdown_background();
+            } else {
+                runtime.shutdown_timeout(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+/// Limits applied to connections
+#[derive(Debug, Clone)]
+struct Limits {
+    max_idle: Duration,
+    max_request_size: usize,
+    /// Memory budget for queued requests, shared by all connections, and its size
+    budget: Option<(Arc<Semaphore>, u32)>,
+}
+
+impl Limits {
+    fn new(config: &BrokerConfig) -> Self {
+        let budget = config.queued_max_request_bytes.map(|bytes| {
+            let bytes = bytes.min(u32::MAX as usize) as u32;
+            (Arc::new(Semaphore::new(bytes as usize)), bytes)
+        });
+        Self {
+            max_idle: config.connections_max_idle,
+            max_request_size: config.socket_request_max_bytes,
+            budget,
+        }
+    }
+
+    // Wait for the memory a request takes to be available
+    async fn reserve(&self, size: usize) -> Option<OwnedSemaphorePermit> {
+        let (budget, total) = self.budget.as_ref()?;
+        // A request larger than the whole budget waits for all of it
+        let permits = size.min(*total as usize) as u32;
+        budget.clone().acquire_many_owned(permits).await.ok()
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// Bind the listener of an HTTP endpoint, if enabled
+fn bind_http(addr: &Option<String>) -> io::Result<Option<net::TcpListener>> {
+    match addr {
+        Some(addr) => {
+            let listener = net::TcpListener::bind(addr.as_str())?;
+            listener.set_nonblocking(true)?;
+            Ok(Some(listener))
+        }
+        None => Ok(None),
+    }
+}
+
+// Answer a request to the metrics endpoint
+fn serve_metrics(state: &SharedState, metrics: &Metrics, req: &HttpRequest) -> HttpResponse {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/metrics") => HttpResponse {
+            status: 200,
+            content_type: "text/plain; version=0.0.4".to_string(),
+            body: metrics.render(&state.lock().unwrap()).into_bytes(),
+        },
+        _ => HttpResponse::not_found(),
+    }
+}
+
+// Accept connections on a listener and process them, spawning a new task for each one
+async fn accept(
+    listener: TcpListener,
+    ctx: Arc<Context>,
+    state: SharedState,
+    limits: Limits,
+    recorder: Option<Arc<Recorder>>,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let span = info_span!(
+                    "connection",
+                    %peer,
+                    listener = %ctx.listener,
+                    node_id = ctx.node_id
+                );
+                let client = handle_client(
+                    state.clone(),
+                    stream,
+                    ctx.clone(),
+                    limits.clone(),
+                    recorder.clone(),
+                    metrics.clone(),
+                );
+                tokio::spawn(client.instrument(span));
+            }
+            Err(e) => {
+                error!("Error accepting connection: {}", e);
+            }
+        }
+    }
+}
+
+// Requests are processed in the order they arrive, so appends keep the order
+// the client sent them in, but responses are queued: one that isn't ready yet
+// doesn't stop the requests behind it. Responses are written in request order,
+// skipping those of requests sent without expecting one (Produce with acks=0),
+// and no more than MAX_IN_FLIGHT_REQUESTS are queued, so slow clients push
+// back on the broker. Queued requests also hold their size from the memory
+// budget until answered: reading stops while it's exhausted.
+async fn handle_client(
+    state: SharedState,
+    stream: TcpStream,
+    ctx: Arc<Context>,
+    limits: Limits,
+    recorder: Option<Arc<Recorder>>,
+    metrics: Arc<Metrics>,
+) {
+    metrics.connection_opened();
+    let connection = recorder.as_ref().map(|r| r.connection());
+    let codec = KafkaCodec::new(limits.max_request_size);
+    let (mut responses, mut requests) = Framed::new(stream, codec).split();
+    let mut in_flight = FuturesOrdered::new();
+    // Request waiting for memory
+    let mut pending: Option<Frame> = None;
+    let mut reading = true;
+    loop {
+        tokio::select! {
+            req = time::timeout(limits.max_idle, requests.next()),
+                if reading && pending.is_none() && in_flight.len() < MAX_IN_FLIGHT_REQUESTS =>
+            {
+                match req {
+                    // An offline broker drops its clients
+                    Ok(Some(Ok(frame))) if !is_online(&state, &ctx) => {
+                        if let (Some(recorder), Some(connection)) = (&recorder, connection) {
+                            let mut exchange = Exchange::new(connection, &ctx, &frame.bytes);
+                            exchange.respond(&[], true);
+                            recorder.record(&exchange);
+                        }
+                        break;
+                    }
+                    Ok(Some(Ok(frame))) => pending = Some(frame),
+                    Ok(Some(Err(e))) => {
+                        warn!("Error reading message: {}", e);
+                        reading = false;
+                    }
+                    Ok(None) => reading = false,
+                    // Idle for too long
+                    Err(_) if in_flight.is_empty() => break,
+                    Err(_) => (),
+                }
+            }
+            permit = limits.reserve(pending.as_ref().map_or(0, |f| f.size)), if pending.is_some() => {
+                let frame = pending.take().unwrap();
+                let exchange = connection.map(|c| Exchange::new(c, &ctx, &frame.bytes));
+                let answer = Answer::new(&frame, exchange);
+                if let Ok(req) = &frame.request {
+                    metrics.observe_received(req);
+                }
+                let resp = answer.span.in_scope(|| dispatch(&state, frame.request, &ctx));
+                let resp = resp.instrument(answer.span.clone());
+                in_flight.push_back(resp.map(|resp| (resp, answer, permit)));
+            }
+            Some(((correlation_id, resp, delivery), mut answer, _permit)) = in_flight.next() => {
+                debug_assert_eq!(resp.header().correlation_id, correlation_id);
+                let bytes = match resp.to_bytes() {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        error!(parent: &answer.span, "Error writing message: {}", e);
+                        break;
+                    }
+                };
+                // Injected faults may mangle the response, or close the connection
+                let (bytes, close) = delivery.apply(bytes);
+                let bytes = if answer.respond { bytes } else { vec![] };
+                if let (Some(recorder), Some(mut exchange)) = (&recorder, answer.exchange.take()) {
+                    exchange.respond(&bytes, close);
+                    recorder.record(&exchange);
+                }
+                if !bytes.is_empty() {
+                    if let Err(e) = responses.send(Bytes::from(bytes)).await {
+                        warn!(parent: &answer.span, "Error writing message: {}", e);
+                        break;
+                    }
+                }
+                let latency = answer.started.elapsed();
+                let error = ErrorCode::from_code(resp.error_code() as i16).map_or("", |e| e.name());
+                answer.span.record("latency_ms", latency.as_millis() as u64);
+                answer.span.record("error_code", error);
+                metrics.observe_answered(answer.api_key, answer.api_version, latency, &resp);
+                if close {
+                    debug!("Closing connection");
+                    break;
+                }
+            }
+            // Nothing more to read, and every response written
+            else => break,
+        }
+    }
+    metrics.connection_closed();
+}
+
+// A request being answered
+struct Answer {
+    api_key: u16,
+    api_version: u16,
+    /// Whether the response is written back, or only processed
+    respond: bool,
+    /// Request, to be recorded along with the response
+    exchange: Option<Exchange>,
+    /// Span with the fields of the request header, and those of the response
+    /// to be filled in
+    span: Span,
+    started: Instant,
+}
+
+impl Answer {
+    fn new(frame: &Frame, exchange: Option<Exchange>) -> Self {
+        let (api_key, api_version, correlation_id) = match de::header_prefix(&frame.bytes) {
+            Ok((_, header)) => header,
+            Err(_) => (0, 0, 0),
+        };
+        let client_id = match &frame.request {
+            Ok(req) => req.header().client_id.as_deref(),
+            Err(_) => None,
+        };
+        let api =
+            ApiKey::from_u16(api_key).map_or_else(|| api_key.to_string(), |k| format!("{:?}", k));
+        let span = debug_span!(
+            "request",
+            client_id,
+            api_key = %api,
+            api_version,
+            correlation_id,
+            latency_ms = field::Empty,
+            error_code = field::Empty
+        );
+        Self {
+            api_key,
+            api_version,
+            respond: frame
+                .request
+                .as_ref()
+                .map_or(true, |r| r.expects_response()),
+            exchange,
+            span,
+            started: Instant::now(),
+        }
+    }
+}
+
+fn is_online(state: &SharedState, ctx: &Context) -> bool {
+    state.lock().unwrap().cluster.is_online(ctx.node_id)
+}
+
+// Process a request, returning its correlation id and the future response
+// Requests that couldn't be decoded already come with their error response.
+// Fetch requests without enough records to answer are processed again after
+// each append, and answered once they have enough or their max wait time is
+// over. Faults injected into the request delay the response, or change how
+// it's written.
+fn dispatch(
+    state: &SharedState,
+    frame: Result<Request, ErrorResponse>,
+    ctx: &Arc<Context>,
+) -> impl Future<Output = (u32, Response, Delivery)> {
+    let req = match frame {
+        Ok(req) => req,
+        Err(resp) => {
+            let correlation_id = resp.header.correlation_id;
+            let resp = Response::ErrorResponse(resp);
+            return future::ready((correlation_id, resp, Delivery::Respond)).left_future();
+        }
+    };
+    trace!(?req, "Processing request");
+    let (faults, appended) = {
+        let mut state = state.lock().unwrap();
+        (state.faults.inject(&req), state.appended.clone())
+    };
+    // The state is behind a blocking lock, and storage may hit the disk
+    let resp = task::block_in_place(|| broker::process(state, &req, ctx, &faults));
+    let correlation_id = req.header().correlation_id;
+    let wait = match &req {
+        Request::FetchRequest(fetch) if !fetched_enough(&resp, fetch.min_bytes) => {
+            let deadline = time::Instant::now() + Duration::from_millis(fetch.max_wait as u64);
+            Some((deadline, fetch.min_bytes))
+        }
+        _ => None,
+    };
+    if wait.is_none() && faults.latency.is_zero() {
+        return future::ready((correlation_id, resp, faults.delivery)).left_future();
+    }
+    let (state, ctx) = (state.clone(), ctx.clone());
+    async move {
+        let mut resp = resp;
+        if let Some((deadline, min_bytes)) = wait {
+            loop {
+                // Waiting before processing, so that no append is missed in between
+                let notified = appended.notified();
+                resp = task::block_in_place(|| broker::process(&state, &req, &ctx, &faults));
+                if fetched_enough(&resp, min_bytes) || time::Instant::now() >= deadline {
+                    break;
+                }
+                tokio::select! {
+                    _ = notified => (),
+                    _ = time::sleep_until(deadline) => (),
+                }
+            }
+        }
+        time::sleep(faults.latency).await;
+        (correlation_id, resp, faults.delivery)
+    }
+    .right_future()
+}
+
+// Whether a fetch response can be sent, having at least min bytes of records
+fn fetched_enough(resp: &Response, min_bytes: u32) -> bool {
+    match resp {
+        Response::FetchResponse(r) => r.records_size() >= min_bytes as usize,
+        _ => true,
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::faults::Fault;
+    use crate::recorder;
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+
+    // Send a Metadata v9 request without topics
+    fn send_metadata_request(stream: &mut net::TcpStream, correlation_id: u32) {
+        let body = [
+            &[0, 3, 0, 9][..],
+            &correlation_id.to_be_bytes(),
+            &[0, 4],
+            b"test",
+            &[0, 1, 1, 0, 0, 0],
+        ]
+        .concat();
+        stream
+            .write_all(&[&(body.len() as u32).to_be_bytes()[..], &body].concat())
+            .unwrap();
+    }
+
+    // Read a response, returning its correlation id and body
+    fn read_response(stream: &mut net::TcpStream) -> (u32, Vec<u8>) {
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size).unwrap();
+        let mut response = vec![0u8; u32::from_be_bytes(size) as usize];
+        stream.read_exact(&mut response).unwrap();
+        let correlation_id = u32::from_be_bytes(response[..4].try_into().unwrap());
+        (correlation_id, response[4..].to_vec())
+    }
+
+    // Ask for metadata and return the advertised port
+    fn advertised_port(addr: SocketAddr) -> u32 {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        send_metadata_request(&mut stream, 7);
+        let (_, response) = read_response(&mut stream);
+        // tagged fields, throttle time, brokers, node id and host are before the
+        // port, the host as a compact string: its length + 1, then the bytes
+        let port = 10 + response[10] as usize;
+        u32::from_be_bytes(response[port..port + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn isolated_servers_on_ephemeral_ports() {
+        let a = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let b = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        assert_ne!(a.local_addr(), b.local_addr());
+        assert_eq!(
+            advertised_port(a.local_addr()),
+            a.local_addr().port() as u32
+        );
+        assert_eq!(
+            advertised_port(b.local_addr()),
+            b.local_addr().port() as u32
+        );
+
+        let addr = a.local_addr();
+        drop(a);
+        assert!(net::TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn configured_advertised_listener() {
+        let mut state = State::default();
+        let advertised = Listener::new("PLAINTEXT", "kafka", 19092);
+        state.config.advertised_listeners.push(advertised);
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        assert_eq!(advertised_port(server.local_addr()), 19092);
+    }
+
+    #[test]
+    fn advertise_the_listener_of_each_request() {
+        let mut state = State::default();
+        assert!(state
+            .config
+            .set("listeners", "INTERNAL://127.0.0.1:0,EXTERNAL://127.0.0.1:0"));
+        assert!(state.config.set(
+            "listener.security.protocol.map",
+            "INTERNAL:PLAINTEXT,EXTERNAL:PLAINTEXT"
+        ));
+        assert!(state
+            .config
+            .set("advertised.listeners", "INTERNAL://pseudokafka:9092"));
+        let server = Server::start_listeners(state).unwrap();
+        let external = server.listener_addr("EXTERNAL").unwrap();
+        assert_eq!(
+            advertised_port(server.listener_addr("INTERNAL").unwrap()),
+            9092
+        );
+        assert_eq!(advertised_port(external), external.port() as u32);
+    }
+
+    #[test]
+    fn reject_unsupported_security_protocols() {
+        let mut state = State::default();
+        assert!(state.config.set("listeners", "SSL://127.0.0.1:0"));
+        assert!(Server::start_listeners(state).is_err());
+    }
+
+    #[test]
+    fn simulated_cluster() {
+        let mut state = State::default();
+        state.config.brokers = 3;
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let leader = server
+            .state()
+            .lock()
+            .unwrap()
+            .cluster
+            .assignment("my-topic", 0)
+            .leader
+            .unwrap();
+        let follower = if leader == 1003 { 1004 } else { 1003 };
+
+        let mut stream =
+            net::TcpStream::connect(server.broker_addr(follower, "PLAINTEXT").unwrap()).unwrap();
+        send_metadata_request(&mut stream, 1);
+        let (_, response) = read_response(&mut stream);
+        // tagged fields and throttle time are before the brokers
+        assert_eq!(response[5], 3 + 1);
+
+        // Partition errors come after the topic and partition ids
+        let produce = |node_id| {
+            let addr = server.broker_addr(node_id, "PLAINTEXT").unwrap();
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(include_bytes!("../res/produce_request.bin"))
+                .unwrap();
+            let (_, response) = read_response(&mut stream);
+            i16::from_be_bytes(response[22..24].try_into().unwrap())
+        };
+        assert_eq!(produce(follower), ErrorCode::NotLeaderOrFollower.code());
+        assert_eq!(produce(leader), ErrorCode::NoError.code());
+
+        // Connections to an offline broker are closed
+        server
+            .state()
+            .lock()
+            .unwrap()
+            .set_broker_online(follower, false)
+            .unwrap();
+        let addr = server.broker_addr(follower, "PLAINTEXT").unwrap();
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        send_metadata_request(&mut stream, 2);
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn admin_requests_go_to_the_controller() {
+        let mut state = State::default();
+        state.config.brokers = 2;
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        // ElectLeaders v2 of the preferred leaders of every partition
+        let elect = |node_id| {
+            let addr = server.broker_addr(node_id, "PLAINTEXT").unwrap();
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            let body = [
+                0, 43, 0, 2, 0, 0, 0, 1, 0xff, 0xff, 0, 0, 0, 0, 0, 0x75, 0x30, 0,
+            ];
+            stream
+                .write_all(&[&(body.len() as u32).to_be_bytes()[..], &body].concat())
+                .unwrap();
+            let (_, response) = read_response(&mut stream);
+            // after the tagged fields and throttle time
+            i16::from_be_bytes(response[5..7].try_into().unwrap())
+        };
+        assert_eq!(elect(1004), ErrorCode::NotController.code());
+        assert_eq!(elect(1003), ErrorCode::NoError.code());
+    }
+
+    #[test]
+    fn keep_a_configured_cluster() {
+        let mut state = State::default();
+        state.config.brokers = 2;
+        state.cluster = Cluster::new(&state.config).unwrap();
+        state.set_broker_online(1004, false).unwrap();
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let state = server.state().lock().unwrap();
+        assert_eq!(state.cluster.nodes.len(), 2);
+        assert!(!state.cluster.is_online(1004));
+    }
+
+    // Send a request to a broker and read the response body
+    fn request(addr: SocketAddr, body: &[u8]) -> Vec<u8> {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(&[&(body.len() as u32).to_be_bytes()[..], body].concat())
+            .unwrap();
+        read_response(&mut stream).1
+    }
+
+    // Fetch v11 of my-topic/0 from the start, returning the error and high watermark
+    fn fetch(addr: SocketAddr, current_leader_epoch: i32) -> (i16, i64) {
+        long_poll(addr, current_leader_epoch, 0, 0)
+    }
+
+    // Fetch, waiting up to max_wait milliseconds for min_bytes of records
+    fn long_poll(
+        addr: SocketAddr,
+        current_leader_epoch: i32,
+        max_wait: u32,
+        min_bytes: u32,
+    ) -> (i16, i64) {
+        let body = [
+            &[0, 1, 0, 11, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..],
+            &max_wait.to_be_bytes(),
+            &min_bytes.to_be_bytes(),
+            &[0, 1, 0, 0, 0],
+            &[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 1, 0, 8],
+            b"my-topic",
+            &[0, 0, 0, 1, 0, 0, 0, 0],
+            &current_leader_epoch.to_be_bytes(),
+            &[0; 8],
+            &[0xff; 8],
+            &[0, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+        ]
+        .concat();
+        let response = request(addr, &body);
+        // after the throttle time, error, session id, topic and partition id
+        (
+            i16::from_be_bytes(response[32..34].try_into().unwrap()),
+            i64::from_be_bytes(response[34..42].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn wake_long_polls_on_append() {
+        let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        server
+            .state()
+            .lock()
+            .unwrap()
+            .get_or_create_topic("my-topic")
+            .unwrap();
+
+        // Without records, the fetch waits for its whole max wait time
+        let started = Instant::now();
+        assert_eq!(long_poll(addr, -1, 200, 1), (0, 0));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // It's answered as soon as a record is produced
+        let started = Instant::now();
+        let poll = std::thread::spawn(move || long_poll(addr, -1, 10_000, 1));
+        std::thread::sleep(Duration::from_millis(100));
+        let produce = include_bytes!("../res/produce_request.bin");
+        request(addr, &produce[4..]);
+        assert_eq!(poll.join().unwrap(), (0, 1));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // OffsetForLeaderEpoch v3 of my-topic/0, returning the epoch and end offset found
+    fn offset_for_leader_epoch(addr: SocketAddr, current: i32, epoch: i32) -> (i16, i32, i64) {
+        let body = [
+            &[0, 23, 0, 3, 0, 0, 0, 1, 0xff, 0xff][..],
+            &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 1, 0, 8],
+            b"my-topic",
+            &[0, 0, 0, 1, 0, 0, 0, 0],
+            &current.to_be_bytes(),
+            &epoch.to_be_bytes(),
+        ]
+        .concat();
+        let response = request(addr, &body);
+        (
+            i16::from_be_bytes(response[22..24].try_into().unwrap()),
+            i32::from_be_bytes(response[28..32].try_into().unwrap()),
+            i64::from_be_bytes(response[32..40].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn fetch_and_leader_epochs() {
+        let mut state = State::default();
+        state.config.brokers = 2;
+        state.config.default_replication_factor = 2;
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let addr = |node_id| server.broker_addr(node_id, "PLAINTEXT").unwrap();
+        let produce = include_bytes!("../res/produce_request.bin");
+        let leader = {
+            let mut state = server.state().lock().unwrap();
+            state.get_or_create_topic("my-topic").unwrap();
+            state.cluster.assignment("my-topic", 0).leader.unwrap()
+        };
+        request(addr(leader), &produce[4..]);
+        assert_eq!(fetch(addr(leader), 0), (0, 1));
+
+        // A new leader starts epoch 1
+        let follower = if leader == 1003 { 1004 } else { 1003 };
+        server
+            .state()
+            .lock()
+            .unwrap()
+            .elect_leader("my-topic", 0, follower)
+            .unwrap();
+        request(addr(follower), &produce[4..]);
+        assert_eq!(fetch(addr(follower), 1), (0, 2));
+        assert_eq!(fetch(addr(follower), -1), (0, 2));
+        assert_eq!(
+            fetch(addr(follower), 0).0,
+            ErrorCode::FencedLeaderEpoch.code()
+        );
+        assert_eq!(
+            fetch(addr(follower), 2).0,
+            ErrorCode::UnknownLeaderEpoch.code()
+        );
+        assert_eq!(
+            fetch(addr(leader), 1).0,
+            ErrorCode::NotLeaderOrFollower.code()
+        );
+
+        assert_eq!(offset_for_leader_epoch(addr(follower), 1, 0), (0, 0, 1));
+        assert_eq!(offset_for_leader_epoch(addr(follower), 1, 1), (0, 1, 2));
+        assert_eq!(offset_for_leader_epoch(addr(follower), -1, 2), (0, -1, -1));
+        assert_eq!(
+            offset_for_leader_epoch(addr(follower), 0, 0).0,
+            ErrorCode::FencedLeaderEpoch.code()
+        );
+
+        // Older versions have no replica id, current leader epoch (before v2),
+        // throttle time (before v2) or leader epoch found (in v0)
+        for version in 0..3 {
+            let current = if version >= 2 { &[0, 0, 0, 1][..] } else { &[] };
+            let body = [
+                &[0, 23, 0, version, 0, 0, 0, 1, 0xff, 0xff][..],
+                &[0, 0, 0, 1, 0, 8],
+                b"my-topic",
+                &[0, 0, 0, 1, 0, 0, 0, 0],
+                current,
+                &1i32.to_be_bytes(),
+            ]
+            .concat();
+            let throttle_time = if version >= 2 { &[0; 4][..] } else { &[] };
+            let leader_epoch = if version >= 1 { &[0, 0, 0, 1][..] } else { &[] };
+            let expected = [
+                throttle_time,
+                &[0, 0, 0, 1, 0, 8],
+                b"my-topic",
+                &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0],
+                leader_epoch,
+                &2i64.to_be_bytes(),
+            ]
+            .concat();
+            assert_eq!(request(addr(follower), &body), expected, "v{}", version);
+
+            // Truncated, answered in the same layout
+            let response = request(addr(follower), &body[..body.len() - 2]);
+            let at = throttle_time.len() + 18;
+            assert_eq!(response[..at], expected[..at], "v{}", version);
+            assert_eq!(
+                response[at..at + 2],
+                ErrorCode::CorruptMessage.code().to_be_bytes()
+            );
+            assert_eq!(response.len(), expected.len());
+        }
+    }
+
+    // Send an HTTP request to the admin endpoint, returning the status and body
+    fn http_request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head[9..12].parse().unwrap(), body.to_string())
+    }
+
+    #[test]
+    fn inject_faults() {
+        let mut state = State::default();
+        state.config.admin_listener = Some("127.0.0.1:0".to_string());
+        state.get_or_create_topic("my-topic").unwrap();
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        let admin_addr = server.admin_addr().unwrap();
+        let produce = include_bytes!("../res/produce_request.bin");
+        request(addr, &produce[4..]);
+
+        let fault =
+            r#"{"api_key": 1, "topic": "my-topic", "times": 1, "error": "NOT_LEADER_OR_FOLLOWER"}"#;
+        assert_eq!(
+            http_request(admin_addr, "POST", "/faults", fault),
+            (201, r#"{"id":0}"#.to_string())
+        );
+        let (status, faults) = http_request(admin_addr, "GET", "/faults", "");
+        assert_eq!(status, 200);
+        assert!(faults.contains(r#""error":"NOT_LEADER_OR_FOLLOWER""#));
+        assert_eq!(fetch(addr, -1).0, ErrorCode::NotLeaderOrFollower.code());
+        // Used up
+        assert_eq!(fetch(addr, -1), (0, 1));
+        assert_eq!(
+            http_request(admin_addr, "GET", "/faults", ""),
+            (200, "[]".to_string())
+        );
+        assert_eq!(
+            http_request(admin_addr, "POST", "/faults", "{\"bad\": 1}").0,
+            422
+        );
+        assert_eq!(http_request(admin_addr, "DELETE", "/faults/0", "").0, 404);
+
+        // Truncated responses keep their frame
+        let id = server.state().lock().unwrap().faults.add(Fault {
+            api_key: Some(3),
+            truncate: Some(6),
+            ..Fault::default()
+        });
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        send_metadata_request(&mut stream, 7);
+        let (correlation_id, response) = read_response(&mut stream);
+        assert_eq!((correlation_id, response.len()), (7, 2));
+        assert_eq!(
+            http_request(admin_addr, "DELETE", &format!("/faults/{}", id), "").0,
+            204
+        );
+
+        // The connection is closed before or in the middle of the response
+        for disconnect in &["before_response", "mid_response"] {
+            let fault = format!(r#"{{"api_key": 3, "disconnect": "{}"}}"#, disconnect);
+            assert_eq!(http_request(admin_addr, "POST", "/faults", &fault).0, 201);
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            send_metadata_request(&mut stream, 8);
+            let mut response = vec![];
+            stream.read_to_end(&mut response).unwrap();
+            if *disconnect == "before_response" {
+                assert!(response.is_empty());
+            } else {
+                let size = u32::from_be_bytes(response[..4].try_into().unwrap()) as usize;
+                assert!(response.len() < 4 + size);
+            }
+            assert_eq!(http_request(admin_addr, "DELETE", "/faults", "").0, 204);
+        }
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let mut state = State::default();
+        state.config.metrics_listener = Some("127.0.0.1:0".to_string());
+        state.get_or_create_topic("my-topic").unwrap();
+        state
+            .offsets
+            .entry("my-group".to_string())
+            .or_default()
+            .insert(("my-topic".to_string(), 0), 0);
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        let produce = include_bytes!("../res/produce_request.bin");
+        request(addr, &produce[4..]);
+        fetch(addr, -1);
+        assert_eq!(fetch(addr, 5).0, ErrorCode::UnknownLeaderEpoch.code());
+
+        // Requests are counted once their response is written, after the client reads it
+        let metrics_addr = server.metrics_addr().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let metrics = loop {
+            let (status, metrics) = http_request(metrics_addr, "GET", "/metrics", "");
+            assert_eq!(status, 200);
+            if metrics.contains(r#"{api="Fetch",version="11"} 2"#) || Instant::now() > deadline {
+                break metrics;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let lines = metrics.lines().collect::<Vec<_>>();
+        for line in &[
+            r#"pseudokafka_request_duration_seconds_count{api="Produce",version="8"} 1"#,
+            r#"pseudokafka_request_duration_seconds_count{api="Fetch",version="11"} 2"#,
+            r#"pseudokafka_request_duration_seconds_bucket{api="Fetch",version="11",le="+Inf"} 2"#,
+            r#"pseudokafka_errors_total{error="UNKNOWN_LEADER_EPOCH"} 1"#,
+            r#"pseudokafka_bytes_in_total{topic="my-topic"} 69"#,
+            r#"pseudokafka_bytes_out_total{topic="my-topic"} 69"#,
+            r#"pseudokafka_partition_messages{topic="my-topic",partition="0"} 1"#,
+            r#"pseudokafka_partition_log_size_bytes{topic="my-topic",partition="0"} 69"#,
+            r#"pseudokafka_consumer_group_lag{group="my-group",topic="my-topic",partition="0"} 1"#,
+        ] {
+            assert!(lines.contains(line), "{} not in\n{}", line, metrics);
+        }
+        assert!(lines
+            .iter()
+            .any(|l| l.starts_with("pseudokafka_connections ")));
+    }
+
+    #[test]
+    fn rest_proxy() {
+        let mut state = State::default();
+        state.config.rest_listener = Some("127.0.0.1:0".to_string());
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let (addr, rest_addr) = (server.local_addr(), server.rest_addr().unwrap());
+        let records = r#"{"records": [{"value": "aGk="}, {"key": "aw==", "value": "aGk="}]}"#;
+        let (status, produced) = http_request(rest_addr, "POST", "/topics/my-topic", records);
+        assert_eq!(status, 200);
+        assert!(produced.contains(r#""offset":1"#), "{}", produced);
+        assert_eq!(fetch(addr, -1), (0, 2));
+
+        // Records produced by Kafka clients are fetched by consumer instances
+        let produce = include_bytes!("../res/produce_request.bin");
+        request(addr, &produce[4..]);
+        let consumer = r#"{"name": "c", "auto.offset.reset": "earliest"}"#;
+        assert_eq!(
+            http_request(rest_addr, "POST", "/consumers/g", consumer).0,
+            200
+        );
+        let base = "/consumers/g/instances/c";
+        let subscription = r#"{"topics": ["my-topic"]}"#;
+        let path = format!("{}/subscription", base);
+        assert_eq!(http_request(rest_addr, "POST", &path, subscription).0, 204);
+        let (status, records) = http_request(rest_addr, "GET", &format!("{}/records", base), "");
+        assert_eq!(status, 200);
+        let records = serde_json::from_str::<serde_json::Value>(&records).unwrap();
+        assert_eq!(records.as_array().map(Vec::len), Some(3));
+        assert_eq!(records[1]["key"], "aw==");
+        assert_eq!(
+            server.state().lock().unwrap().offsets["g"][&("my-topic".to_string(), 0)],
+            3
+        );
+    }
+
+    #[test]
+    fn schema_registry() {
+        let mut state = State::default();
+        state.config.schema_registry_listener = Some("127.0.0.1:0".to_string());
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let registry_addr = server.schema_registry_addr().unwrap();
+        let schema = r#"{"schema": "{\"type\": \"string\"}"}"#;
+        let (status, id) = http_request(registry_addr, "POST", "/subjects/s/versions", schema);
+        assert_eq!((status, id.as_str()), (200, r#"{"id":1}"#));
+        let (status, schema) = http_request(registry_addr, "GET", "/schemas/ids/1/schema", "");
+        assert_eq!((status, schema.as_str()), (200, r#"{"type":"string"}"#));
+
+        let state = server.state().lock().unwrap();
+        assert_eq!(state.topics["_schemas"].partitions[0].log_end_offset(), 1);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("pseudokafka-record-{}", std::process::id()));
+        let mut state = State::default();
+        state.config.record_file = Some(path.clone());
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        send_metadata_request(&mut stream, 1);
+        let (_, metadata) = read_response(&mut stream);
+        let produce = include_bytes!("../res/produce_request.bin");
+        request(addr, &produce[4..]);
+
+        let recording = recorder::read(&path).unwrap();
+        assert_eq!(recording.len(), 2);
+        assert_eq!(
+            (
+                recording[0].connection,
+                recording[0].api_key,
+                recording[0].api_version
+            ),
+            (0, 3, 9)
+        );
+        assert_eq!(
+            recording[0].response.as_deref(),
+            Some(&[&[0, 0, 0, 1], &metadata[..]].concat()[..])
+        );
+        assert_eq!(recording[1].connection, 1);
+        assert_eq!(recording[1].request, &produce[4..]);
+
+        // Metadata is answered the same, but the records are appended again
+        let differences = recorder::replay(&recording, |_| addr).unwrap();
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].exchange, recording[1]);
+        // Another broker, on another port, answers the same
+        let other = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        assert!(recorder::replay(&recording, |_| other.local_addr())
+            .unwrap()
+            .is_empty());
+
+        // Requests dropped by an offline broker are recorded without a response
+        server
+            .state()
+            .lock()
+            .unwrap()
+            .set_broker_online(1003, false)
+            .unwrap();
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        send_metadata_request(&mut stream, 2);
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+        let recording = recorder::read(&path).unwrap();
+        let dropped = recording.last().unwrap();
+        assert_eq!((dropped.correlation_id, dropped.closed), (2, true));
+        assert_eq!(dropped.response, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn many_concurrent_clients() {
+        let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        let clients = (0..200)
+            .map(|_| std::thread::spawn(move || advertised_port(addr)))
+            .collect::<Vec<_>>();
+        for client in clients {
+            assert_eq!(client.join().unwrap(), addr.port() as u32);
+        }
+    }
+
+    #[test]
+    fn pipelined_responses_in_order() {
+        let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let mut stream = net::TcpStream::connect(server.local_addr()).unwrap();
+        for correlation_id in 0..100 {
+            send_metadata_request(&mut stream, correlation_id);
+        }
+        for correlation_id in 0..100 {
+            assert_eq!(read_response(&mut stream).0, correlation_id);
+        }
+    }
+
+    #[test]
+    fn produce_without_acks() {
+        let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let mut stream = net::TcpStream::connect(server.local_addr()).unwrap();
+        // Required acks, after the transactional id
+        let mut body = include_bytes!("../res/produce_request.bin")[4..].to_vec();
+        body[28..30].copy_from_slice(&0i16.to_be_bytes());
+        send_metadata_request(&mut stream, 0);
+        stream
+            .write_all(&[&(body.len() as u32).to_be_bytes()[..], &body].concat())
+            .unwrap();
+        send_metadata_request(&mut stream, 1);
+        assert_eq!(read_response(&mut stream).0, 0);
+        assert_eq!(read_response(&mut stream).0, 1);
+        let state = server.state().lock().unwrap();
+        assert_eq!(state.topics["my-topic"].partitions[0].log_end_offset(), 1);
+    }
+
+    #[test]
+    fn close_on_oversized_requests() {
+        let mut state = State::default();
+        state.config.socket_request_max_bytes = 16;
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let mut stream = net::TcpStream::connect(server.local_addr()).unwrap();
+        send_metadata_request(&mut stream, 0);
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn answer_invalid_requests() {
+        let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let mut stream = net::TcpStream::connect(server.local_addr()).unwrap();
+        // Produce with a truncated batch, answered for its partition
+        let produce = include_bytes!("../res/produce_request.bin");
+        let body = &produce[4..100];
+        stream
+            .write_all(&[&(body.len() as u32).to_be_bytes()[..], body].concat())
+            .unwrap();
+        let (correlation_id, response) = read_response(&mut stream);
+        assert_eq!(correlation_id, 4);
+        // One topic, its name, one partition, its id, then the error code
+        let mut partition = vec![0, 0, 0, 1, 0, 8];
+        partition.extend_from_slice(b"my-topic");
+        partition.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(response[..22], partition[..]);
+        assert_eq!(
+            response[22..24],
+            ErrorCode::CorruptMessage.code().to_be_bytes()
+        );
+        // Unknown APIs and unsupported versions get the error alone
+        for (api_key, api_version, error) in [
+            (99u16, 0u16, ErrorCode::InvalidRequest),
+            (0, 3, ErrorCode::UnsupportedVersion),
+        ] {
+            let body = [
+                &api_key.to_be_bytes()[..],
+                &api_version.to_be_bytes(),
+                &6u32.to_be_bytes(),
+                &[0xff, 0xff],
+            ]
+            .concat();
+            stream
+                .write_all(&[&(body.len() as u32).to_be_bytes()[..], &body].concat())
+                .unwrap();
+            assert_eq!(
+                read_response(&mut stream),
+                (6, error.code().to_be_bytes().to_vec())
+            );
+        }
+        // The connection is still usable
+        send_metadata_request(&mut stream, 5);
+        assert_eq!(read_response(&mut stream).0, 5);
+    }
+
+    #[test]
+    fn reject_unreadable_batches() {
+        let server = Server::start(State::default(), "127.0.0.1:0").unwrap();
+        let produce = include_bytes!("../res/produce_request.bin");
+        // Attributes of the batch: gzip, then transactional
+        for (attributes, error) in [
+            (1, ErrorCode::UnsupportedCompressionType),
+            (0x10, ErrorCode::InvalidRecord),
+        ] {
+            let mut body = produce[4..].to_vec();
+            body[82] = attributes;
+            let response = request(server.local_addr(), &body);
+            assert_eq!(response[22..24], error.code().to_be_bytes());
+        }
+        // Offset deltas of the batch, then of its record, that skip an offset
+        for (i, delta) in [(86, 1), (124, 4)] {
+            let mut body = produce[4..].to_vec();
+            body[i] = delta;
+            let response = request(server.local_addr(), &body);
+            assert_eq!(
+                response[22..24],
+                ErrorCode::InvalidRecord.code().to_be_bytes()
+            );
+        }
+        // Null records, and two batches
+        let batch = &produce[64..];
+        for batches in [None, Some([batch, batch].concat())] {
+            let mut body = produce[4..60].to_vec();
+            let num_bytes = batches.as_ref().map_or(-1, |b| b.len() as i32);
+            body.extend_from_slice(&num_bytes.to_be_bytes());
+            body.extend_from_slice(batches.as_deref().unwrap_or_default());
+            let response = request(server.local_addr(), &body);
+            assert_eq!(
+                response[22..24],
+                ErrorCode::InvalidRecord.code().to_be_bytes()
+            );
+        }
+        let state = server.state().lock().unwrap();
+        assert_eq!(state.topics["my-topic"].partitions[0].log_end_offset(), 0);
+    }
+
+    #[test]
+    fn queue_requests_within_memory_budget() {
+        let mut state = State::default();
+        // Smaller than a single request, so they are processed one at a time
+        state.config.queued_max_request_bytes = Some(8);
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let mut stream = net::TcpStream::connect(server.local_addr()).unwrap();
+        for correlation_id in 0..10 {
+            send_metadata_request(&mut stream, correlation_id);
+        }
+        for correlation_id in 0..10 {
+            assert_eq!(read_response(&mut stream).0, correlation_id);
+        }
+    }
+
+    #[test]
+    fn close_idle_connections() {
+        let mut state = State::default();
+        state.config.connections_max_idle = Duration::from_millis(50);
+        let server = Server::start(state, "127.0.0.1:0").unwrap();
+        let mut stream = net::TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+}
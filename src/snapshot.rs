@@ -0,0 +1,267 @@
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::config::TopicConfig;
+use crate::de;
+use crate::log::{Batch, PartitionLog, ProducerState};
+use crate::ser::Serialize;
+use crate::state::{GroupOffsets, State, Topic};
+use crate::storage::{invalid_data, read_string, write_string, Recovered};
+
+const MAGIC: &[u8] = b"PKSNAP";
+const VERSION: u16 = 1;
+
+/// Write a snapshot of the whole broker state
+///
+/// The snapshot holds the topics with their configuration, the partition logs
+/// (as record batches, offsets included), producer state and the committed
+/// offsets of every consumer group.
+///
+/// * `state` - broker state
+/// * `w` - output
+pub fn write(state: &State, w: &mut impl Write) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_u16::<NetworkEndian>(VERSION)?;
+
+    w.write_u32::<NetworkEndian>(state.topics.len() as u32)?;
+    for (name, topic) in &state.topics {
+        write_string(w, name)?;
+        let props = topic.config.to_properties();
+        w.write_u32::<NetworkEndian>(props.len() as u32)?;
+        for (key, value) in props {
+            write_string(w, &key)?;
+            write_string(w, &value)?;
+        }
+        w.write_u32::<NetworkEndian>(topic.partitions.len() as u32)?;
+        for log in &topic.partitions {
+            write_partition(log, w)?;
+        }
+    }
+
+    w.write_u32::<NetworkEndian>(state.offsets.len() as u32)?;
+    for (group, offsets) in &state.offsets {
+        write_string(w, group)?;
+        w.write_u32::<NetworkEndian>(offsets.len() as u32)?;
+        for ((topic, partition), offset) in offsets {
+            write_string(w, topic)?;
+            w.write_u32::<NetworkEndian>(*partition)?;
+            w.write_i64::<NetworkEndian>(*offset)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_partition(log: &PartitionLog, w: &mut impl Write) -> io::Result<()> {
+    w.write_i64::<NetworkEndian>(log.log_start_offset())?;
+    w.write_i64::<NetworkEndian>(log.log_end_offset())?;
+    w.write_u32::<NetworkEndian>(log.producers().len() as u32)?;
+    for (id, p) in log.producers() {
+        w.write_i64::<NetworkEndian>(*id)?;
+        w.write_i16::<NetworkEndian>(p.epoch)?;
+        w.write_i32::<NetworkEndian>(p.last_sequence)?;
+    }
+    w.write_u32::<NetworkEndian>(log.batches().count() as u32)?;
+    for batch in log.batches() {
+        let bytes = batch
+            .to_bytes()
+            .map_err(|_| invalid_data("batch".to_string()))?;
+        w.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Read a snapshot written by `write`
+///
+/// * `r` - input
+pub fn read(r: &mut impl Read) -> io::Result<Recovered> {
+    let mut magic = [0u8; 6];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("not a pseudokafka snapshot".to_string()));
+    }
+    let version = r.read_u16::<NetworkEndian>()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported snapshot version {}",
+            version
+        )));
+    }
+
+    let mut topics = BTreeMap::new();
+    for _ in 0..r.read_u32::<NetworkEndian>()? {
+        let name = read_string(r)?;
+        let mut props = HashMap::new();
+        for _ in 0..r.read_u32::<NetworkEndian>()? {
+            props.insert(read_string(r)?, read_string(r)?);
+        }
+        let partitions = (0..r.read_u32::<NetworkEndian>()?)
+            .map(|_| read_partition(r))
+            .collect::<io::Result<_>>()?;
+        topics.insert(
+            name,
+            Topic {
+                config: TopicConfig::from_properties(&props),
+                partitions,
+            },
+        );
+    }
+
+    let mut offsets = BTreeMap::new();
+    for _ in 0..r.read_u32::<NetworkEndian>()? {
+        let group = read_string(r)?;
+        let mut group_offsets = GroupOffsets::new();
+        for _ in 0..r.read_u32::<NetworkEndian>()? {
+            let topic = read_string(r)?;
+            let partition = r.read_u32::<NetworkEndian>()?;
+            group_offsets.insert((topic, partition), r.read_i64::<NetworkEndian>()?);
+        }
+        offsets.insert(group, group_offsets);
+    }
+
+    Ok(Recovered { topics, offsets })
+}
+
+fn read_partition(r: &mut impl Read) -> io::Result<PartitionLog> {
+    let log_start_offset = r.read_i64::<NetworkEndian>()?;
+    let log_end_offset = r.read_i64::<NetworkEndian>()?;
+    let mut producers = BTreeMap::new();
+    for _ in 0..r.read_u32::<NetworkEndian>()? {
+        let id = r.read_i64::<NetworkEndian>()?;
+        let epoch = r.read_i16::<NetworkEndian>()?;
+        let last_sequence = r.read_i32::<NetworkEndian>()?;
+        producers.insert(
+            id,
+            ProducerState {
+                epoch,
+                last_sequence,
+            },
+        );
+    }
+    let mut batches = Vec::new();
+    for _ in 0..r.read_u32::<NetworkEndian>()? {
+        // Batches are self-delimited: base offset, then the length of the rest
+        let mut bytes = vec![0u8; 12];
+        r.read_exact(&mut bytes)?;
+        // The buffer grows as the batch is read, not as large as a corrupt
+        // length would make it
+        let length = (&bytes[8..]).read_u32::<NetworkEndian>()? as usize;
+        if r.take(length as u64).read_to_end(&mut bytes)? < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (_, batch) =
+            de::record_batch(&bytes).map_err(|_| invalid_data("corrupt batch".to_string()))?;
+        batches.push(Batch::from_request(batch.offset as i64, &batch));
+    }
+    Ok(PartitionLog::restore(
+        log_start_offset,
+        log_end_offset,
+        batches,
+        producers,
+    ))
+}
+
+impl State {
+    /// Save a snapshot of the whole state to a file
+    ///
+    /// * `path` - snapshot file
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        write(self, &mut w)?;
+        w.flush()
+    }
+
+    /// Take a snapshot of the whole state, as saved to a file
+    pub fn snapshot(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        write(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Replace the whole state with a snapshot loaded from a file
+    ///
+    /// * `path` - snapshot file
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let recovered = read(&mut BufReader::new(File::open(path)?))?;
+        self.replace(recovered)
+    }
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::ProduceRecordBatchRequest;
+    use crate::testing;
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let batch = |value| ProduceRecordBatchRequest {
+            producer_id: 3,
+            producer_epoch: 1,
+            base_sequence: 5,
+            ..testing::batch(1_000, &[(Some("k"), Some(value))])
+        };
+        let mut state = State::default();
+        let config = TopicConfig {
+            retention_ms: 1_234,
+            ..TopicConfig::default()
+        };
+        state.create_topic("a", 1, config.clone()).unwrap();
+        state.create_topic("b", 3, TopicConfig::default()).unwrap();
+        state.append("a", 0, &batch("1")).unwrap();
+        state.append("a", 0, &batch("2")).unwrap();
+        state.topics.get_mut("a").unwrap().partitions[0].pop_head();
+        state.append("b", 2, &batch("3")).unwrap();
+        state.commit_offset("g", "a", 0, 2).unwrap();
+
+        let mut bytes = Vec::new();
+        write(&state, &mut bytes).unwrap();
+        let mut restored = State::default();
+        restored
+            .create_topic("old", 1, TopicConfig::default())
+            .unwrap();
+        restored.replace(read(&mut &bytes[..]).unwrap()).unwrap();
+
+        assert_eq!(restored.topics.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(restored.topics["a"].config, config);
+        assert_eq!(restored.topics["b"].partitions.len(), 3);
+        for (name, topic) in &state.topics {
+            for (log, restored_log) in topic
+                .partitions
+                .iter()
+                .zip(&restored.topics[name].partitions)
+            {
+                assert_eq!(log.log_start_offset(), restored_log.log_start_offset());
+                assert_eq!(log.log_end_offset(), restored_log.log_end_offset());
+                assert_eq!(log.producers(), restored_log.producers());
+                assert!(log
+                    .batches()
+                    .map(|b| (b.base_offset, &b.records))
+                    .eq(restored_log.batches().map(|b| (b.base_offset, &b.records))));
+            }
+        }
+        assert_eq!(restored.offsets, state.offsets);
+    }
+
+    #[test]
+    fn reject_other_files() {
+        assert!(read(&mut &b"PKSNOP\0\x01"[..]).is_err());
+        // A topic with a partition of a single batch, truncated after a huge length
+        let truncated = [
+            &b"PKSNAP\0\x01\0\0\0\x01\0\x01t\0\0\0\0\0\0\0\x01"[..],
+            &[0; 20],
+            &[0, 0, 0, 1],
+            &[0; 8],
+            &[0xff; 4],
+            b"batch",
+        ]
+        .concat();
+        let err = read(&mut &truncated[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,304 @@
+use tokio::sync::Notify;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::cluster::Cluster;
+use crate::config::{BrokerConfig, TopicConfig};
+use crate::error::ErrorCode;
+use crate::faults::Faults;
+use crate::log::PartitionLog;
+use crate::messages::ProduceRecordBatchRequest;
+use crate::storage::{DiskStorage, MemoryStorage, Recovered, Storage};
+
+pub type SharedState = Arc<Mutex<State>>;
+
+/// Committed offsets of a consumer group, by topic and partition
+pub type GroupOffsets = BTreeMap<(String, u32), i64>;
+
+#[derive(Debug)]
+pub struct Topic {
+    pub config: TopicConfig,
+    pub partitions: Vec<PartitionLog>,
+}
+
+/// Longest topic name, as in Kafka
+pub const MAX_TOPIC_NAME_LENGTH: usize = 249;
+
+impl Topic {
+    pub fn new(num_partitions: usize, config: TopicConfig) -> Self {
+        Self {
+            config,
+            partitions: (0..num_partitions).map(|_| PartitionLog::new()).collect(),
+        }
+    }
+
+    /// Check a topic name follows Kafka's rules: 1 to 249 ASCII letters,
+    /// digits, '.', '_' or '-', other than "." and ".."
+    /// Names are used in file paths, so this also keeps topics inside the
+    /// data directory.
+    ///
+    /// * `name` - topic name
+    pub fn validate_name(name: &str) -> io::Result<()> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_TOPIC_NAME_LENGTH
+            && name != "."
+            && name != ".."
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-');
+        if valid {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid topic name {:?}", name),
+            ))
+        }
+    }
+}
+
+/// Everything the broker keeps in memory
+#[derive(Debug)]
+pub struct State {
+    pub config: BrokerConfig,
+    /// Brokers presented to clients
+    pub cluster: Cluster,
+    pub topics: BTreeMap<String, Topic>,
+    /// Committed offsets, by consumer group
+    pub offsets: BTreeMap<String, GroupOffsets>,
+    pub storage: Box<dyn Storage>,
+    /// Failures injected into requests
+    pub faults: Faults,
+    /// Woken up on every append, for the fetches waiting for records
+    pub appended: Arc<Notify>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(BrokerConfig::default()).expect("the default configuration is valid")
+    }
+}
+
+impl State {
+    /// Create an empty, in-memory only state
+    /// Fails if the configuration doesn't make a valid cluster.
+    pub fn new(config: BrokerConfig) -> io::Result<Self> {
+        Ok(Self {
+            cluster: Cluster::new(&config)?,
+            config,
+            topics: BTreeMap::new(),
+            offsets: BTreeMap::new(),
+            storage: Box::new(MemoryStorage),
+            faults: Faults::default(),
+            appended: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Create the state, recovering it from the data directory if one is configured
+    ///
+    /// * `config` - broker configuration
+    pub fn open(config: BrokerConfig) -> io::Result<Self> {
+        let mut storage: Box<dyn Storage> = match &config.data_dir {
+            Some(dir) => Box::new(DiskStorage::open(dir, config.fsync)?),
+            None => Box::new(MemoryStorage),
+        };
+        let recovered = storage.load()?;
+        let mut state = Self {
+            cluster: Cluster::new(&config)?,
+            config,
+            topics: recovered.topics,
+            offsets: recovered.offsets,
+            storage,
+            faults: Faults::default(),
+            appended: Arc::new(Notify::new()),
+        };
+        state.resume_leader_epochs();
+        Ok(state)
+    }
+
+    /// Replace all topics and offsets, e.g. with the contents of a snapshot
+    ///
+    /// * `recovered` - new topics and committed offsets
+    pub fn replace(&mut self, recovered: Recovered) -> io::Result<()> {
+        for name in recovered.topics.keys() {
+            Topic::validate_name(name)?;
+        }
+        for (name, topic) in std::mem::take(&mut self.topics) {
+            self.storage.delete_topic(&name, &topic)?;
+        }
+        for (name, topic) in &recovered.topics {
+            self.storage.create_topic(name, topic)?;
+            for (p, log) in topic.partitions.iter().enumerate() {
+                self.storage.rewrite(name, p as u32, log)?;
+            }
+        }
+        self.topics = recovered.topics;
+        self.offsets = recovered.offsets;
+        self.resume_leader_epochs();
+        self.storage.commit_offsets(&self.offsets)
+    }
+
+    pub fn into_shared(self) -> SharedState {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Create a topic unless it already exists
+    /// Returns false if the topic was already there, and an error of kind
+    /// InvalidInput if its name isn't valid.
+    ///
+    /// * `name` - topic name
+    /// * `num_partitions` - number of partitions
+    /// * `config` - topic configuration
+    pub fn create_topic(
+        &mut self,
+        name: &str,
+        num_partitions: usize,
+        config: TopicConfig,
+    ) -> io::Result<bool> {
+        if self.topics.contains_key(name) {
+            return Ok(false);
+        }
+        Topic::validate_name(name)?;
+        let topic = Topic::new(num_partitions, config);
+        self.storage.create_topic(name, &topic)?;
+        self.topics.insert(name.to_string(), topic);
+        Ok(true)
+    }
+
+    /// Delete a topic, along with the offsets committed on it
+    /// Returns false if there was no such topic.
+    ///
+    /// * `name` - topic name
+    pub fn delete_topic(&mut self, name: &str) -> io::Result<bool> {
+        let topic = match self.topics.remove(name) {
+            Some(topic) => topic,
+            None => return Ok(false),
+        };
+        self.storage.delete_topic(name, &topic)?;
+        self.cluster.remove_topic(name);
+        for offsets in self.offsets.values_mut() {
+            offsets.retain(|(t, _), _| t != name);
+        }
+        self.offsets.retain(|_, offsets| !offsets.is_empty());
+        self.storage.commit_offsets(&self.offsets)?;
+        Ok(true)
+    }
+
+    /// Remove every topic, committed offset and fault, and bring the cluster
+    /// back to its initial assignments, as for a new broker
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.replace(Recovered::default())?;
+        self.cluster.reset();
+        self.faults.clear();
+        Ok(())
+    }
+
+    /// Get a topic, creating it with the default configuration and number of
+    /// partitions if needed
+    ///
+    /// * `name` - topic name
+    pub fn get_or_create_topic(&mut self, name: &str) -> io::Result<&mut Topic> {
+        if !self.topics.contains_key(name) {
+            let config = self.config.default_topic_config.clone();
+            self.create_topic(name, self.config.num_partitions, config)?;
+        }
+        Ok(self.topics.get_mut(name).unwrap())
+    }
+
+    /// Check that a produced batch can be appended to a partition, as the
+    /// broker does before appending it
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    /// * `batch` - batch to append
+    /// * `node_id` - broker the batch was sent to, or None for any online leader
+    pub fn check_append(
+        &self,
+        topic: &str,
+        partition: u32,
+        batch: &ProduceRecordBatchRequest,
+        node_id: Option<u32>,
+    ) -> Result<(), ErrorCode> {
+        let config = match self.topics.get(topic) {
+            Some(t) if (partition as usize) < t.partitions.len() => &t.config,
+            _ => return Err(ErrorCode::UnknownTopicOrPartition),
+        };
+        match (self.cluster.assignment(topic, partition).leader, node_id) {
+            (None, None) => return Err(ErrorCode::LeaderNotAvailable),
+            (leader, Some(node_id)) if leader != Some(node_id) => {
+                return Err(ErrorCode::NotLeaderOrFollower)
+            }
+            _ => {}
+        }
+        if batch.size as usize > config.max_message_bytes {
+            return Err(ErrorCode::MessageTooLarge);
+        }
+        if batch.compression() != 0 {
+            // The records weren't read
+            return Err(ErrorCode::UnsupportedCompressionType);
+        }
+        if batch.is_transactional() || batch.is_control() {
+            // Transactions aren't supported, and clients can't write control records
+            return Err(ErrorCode::InvalidRecord);
+        }
+        let mut deltas = batch.records.iter().enumerate();
+        if batch.records.is_empty()
+            || batch.last_offset_delta as usize != batch.records.len() - 1
+            || deltas.any(|(i, r)| r.offset_delta as usize != i)
+        {
+            // Offsets are assigned from the deltas, which must follow each other
+            return Err(ErrorCode::InvalidRecord);
+        }
+        if config.cleanup_policy.compact && batch.records.iter().any(|r| r.key.is_none()) {
+            // Compacted topics can't accept records without a key
+            return Err(ErrorCode::InvalidRecord);
+        }
+        Ok(())
+    }
+
+    /// Append a batch to a partition log
+    /// Returns the base offset of the appended batch.
+    ///
+    /// * `topic` - topic name
+    /// * `partition` - partition id, which must exist
+    /// * `batch` - batch as received in a Produce request
+    pub fn append(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        batch: &ProduceRecordBatchRequest,
+    ) -> io::Result<i64> {
+        let leader_epoch = self.cluster.assignment(topic, partition).leader_epoch as i32;
+        let log = self
+            .topics
+            .get_mut(topic)
+            .and_then(|t| t.partitions.get_mut(partition as usize))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let base_offset = log.append(batch, leader_epoch);
+        self.storage.append(topic, partition, log)?;
+        self.appended.notify_waiters();
+        Ok(base_offset)
+    }
+
+    /// Commit the offset of a consumer group on a partition
+    ///
+    /// * `group` - consumer group id
+    /// * `topic` - topic name
+    /// * `partition` - partition id
+    /// * `offset` - next offset the group will consume
+    pub fn commit_offset(
+        &mut self,
+        group: &str,
+        topic: &str,
+        partition: u32,
+        offset: i64,
+    ) -> io::Result<()> {
+        self.offsets
+            .entry(group.to_string())
+            .or_default()
+            .insert((topic.to_string(), partition), offset);
+        self.storage.commit_offsets(&self.offsets)
+    }
+}
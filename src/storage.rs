@@ -0,0 +1,569 @@
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use tracing::warn;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::config::{FsyncPolicy, TopicConfig};
+use crate::de;
+use crate::log::{Batch, PartitionLog, ProducerState};
+use crate::ser::Serialize;
+use crate::state::{GroupOffsets, Topic};
+
+/// State recovered from a storage backend on startup
+#[derive(Debug, Default)]
+pub struct Recovered {
+    pub topics: BTreeMap<String, Topic>,
+    pub offsets: BTreeMap<String, GroupOffsets>,
+}
+
+/// Storage backend trait
+///
+/// The broker always works on its in-memory state, and reports every change
+/// to the storage so it can be persisted and recovered later.
+pub trait Storage: Debug + Send {
+    /// Load everything persisted so far
+    fn load(&mut self) -> io::Result<Recovered>;
+    fn create_topic(&mut self, name: &str, topic: &Topic) -> io::Result<()>;
+    fn delete_topic(&mut self, name: &str, topic: &Topic) -> io::Result<()>;
+    /// A batch was appended to the end of a partition log
+    fn append(&mut self, topic: &str, partition: u32, log: &PartitionLog) -> io::Result<()>;
+    /// A partition log changed as a whole, e.g. after retention or compaction
+    fn rewrite(&mut self, topic: &str, partition: u32, log: &PartitionLog) -> io::Result<()>;
+    fn commit_offsets(&mut self, offsets: &BTreeMap<String, GroupOffsets>) -> io::Result<()>;
+    /// Flush the writes left unsynced by an fsync interval, once it's over
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Default storage, which keeps nothing besides the in-memory state
+#[derive(Debug, Default)]
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> io::Result<Recovered> {
+        Ok(Recovered::default())
+    }
+
+    fn create_topic(&mut self, _: &str, _: &Topic) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete_topic(&mut self, _: &str, _: &Topic) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn append(&mut self, _: &str, _: u32, _: &PartitionLog) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rewrite(&mut self, _: &str, _: u32, _: &PartitionLog) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn commit_offsets(&mut self, _: &BTreeMap<String, GroupOffsets>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Storage in a data directory, laid out similarly to Kafka's:
+///
+/// ```text
+/// <dir>/topics/<topic>.properties      partition count and topic configuration
+/// <dir>/<topic>-<partition>/<offset>.log  record batches, named after the log start offset
+/// <dir>/<topic>-<partition>/checkpoint    log start and end offsets
+/// <dir>/<topic>-<partition>/producer-state
+/// <dir>/consumer-offsets
+/// ```
+#[derive(Debug)]
+pub struct DiskStorage {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    last_sync: Instant,
+    /// Files written since the last sync, with an fsync interval
+    unsynced: BTreeSet<PathBuf>,
+}
+
+impl DiskStorage {
+    /// Open a data directory, creating it if needed
+    ///
+    /// * `dir` - data directory
+    /// * `fsync` - when to flush the files to the device
+    pub fn open(dir: impl Into<PathBuf>, fsync: FsyncPolicy) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("topics"))?;
+        Ok(Self {
+            dir,
+            fsync,
+            last_sync: Instant::now(),
+            unsynced: BTreeSet::new(),
+        })
+    }
+
+    fn topic_path(&self, name: &str) -> PathBuf {
+        self.dir.join("topics").join(format!("{}.properties", name))
+    }
+
+    fn partition_dir(&self, topic: &str, partition: u32) -> PathBuf {
+        self.dir.join(format!("{}-{}", topic, partition))
+    }
+
+    // Sync a file just written, or leave it to a later write or flush if the
+    // fsync interval isn't over
+    fn sync(&mut self, file: &File, path: &Path) -> io::Result<()> {
+        match self.fsync {
+            FsyncPolicy::Never => Ok(()),
+            FsyncPolicy::Always => file.sync_data(),
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() < interval => {
+                self.unsynced.insert(path.to_path_buf());
+                Ok(())
+            }
+            FsyncPolicy::Interval(_) => {
+                file.sync_data()?;
+                self.unsynced.remove(path);
+                self.sync_unsynced()
+            }
+        }
+    }
+
+    fn sync_unsynced(&mut self) -> io::Result<()> {
+        while let Some(path) = self.unsynced.pop_first() {
+            match File::open(&path) {
+                Ok(file) => file.sync_data()?,
+                // Replaced or deleted since
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Replace a whole file, going through a temporary one so it's never left half-written
+    fn write_file(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents)?;
+        self.sync(&file, path)?;
+        fs::rename(&tmp, path)
+    }
+
+    fn write_checkpoint(&mut self, dir: &Path, log: &PartitionLog) -> io::Result<()> {
+        let contents = format!("{} {}\n", log.log_start_offset(), log.log_end_offset());
+        self.write_file(&dir.join("checkpoint"), contents.as_bytes())
+    }
+
+    fn write_producer_state(&mut self, dir: &Path, log: &PartitionLog) -> io::Result<()> {
+        let mut contents = String::new();
+        for (id, p) in log.producers() {
+            contents.push_str(&format!("{} {} {}\n", id, p.epoch, p.last_sequence));
+        }
+        self.write_file(&dir.join("producer-state"), contents.as_bytes())
+    }
+
+    fn load_topic(&self, name: &str, path: &Path) -> io::Result<Topic> {
+        let mut props = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if let Some((key, value)) = line.split_once('=') {
+                props.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        let num_partitions = props
+            .get("partitions")
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(|| invalid_data(format!("{}: missing partition count", name)))?;
+        let partitions = (0..num_partitions)
+            .map(|p| self.load_partition(name, p))
+            .collect::<io::Result<_>>()?;
+        Ok(Topic {
+            config: TopicConfig::from_properties(&props),
+            partitions,
+        })
+    }
+
+    fn load_partition(&self, topic: &str, partition: u32) -> io::Result<PartitionLog> {
+        let dir = self.partition_dir(topic, partition);
+        if !dir.exists() {
+            return Ok(PartitionLog::new());
+        }
+
+        let (mut log_start_offset, mut log_end_offset) = (0, 0);
+        if let Ok(checkpoint) = fs::read_to_string(dir.join("checkpoint")) {
+            let mut fields = checkpoint.split_whitespace().map(str::parse::<i64>);
+            if let (Some(Ok(start)), Some(Ok(end))) = (fields.next(), fields.next()) {
+                log_start_offset = start;
+                log_end_offset = end;
+            }
+        }
+
+        let mut producers = BTreeMap::new();
+        if let Ok(state) = fs::read_to_string(dir.join("producer-state")) {
+            for line in state.lines() {
+                let fields = line
+                    .split_whitespace()
+                    .map(str::parse::<i64>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| invalid_data(e.to_string()))?;
+                if let [id, epoch, last_sequence] = fields[..] {
+                    producers.insert(
+                        id,
+                        ProducerState {
+                            epoch: epoch as i16,
+                            last_sequence: last_sequence as i32,
+                        },
+                    );
+                }
+            }
+        }
+
+        let mut batches = Vec::new();
+        if let Some(segment) = find_segment(&dir)? {
+            let contents = fs::read(&segment)?;
+            let mut rest = &contents[..];
+            while !rest.is_empty() {
+                match de::record_batch(rest) {
+                    Ok((r, batch)) => {
+                        batches.push(Batch::from_request(batch.offset as i64, &batch));
+                        rest = r;
+                    }
+                    Err(_) => {
+                        // Most likely a write interrupted by a crash, keep what we could read
+                        warn!(
+                            "Ignoring {} trailing bytes in {}",
+                            rest.len(),
+                            segment.display()
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(PartitionLog::restore(
+            log_start_offset,
+            log_end_offset,
+            batches,
+            producers,
+        ))
+    }
+
+    fn load_offsets(&self) -> io::Result<BTreeMap<String, GroupOffsets>> {
+        let mut offsets = BTreeMap::<String, GroupOffsets>::new();
+        let contents = match fs::read(self.dir.join("consumer-offsets")) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(offsets),
+            Err(e) => return Err(e),
+        };
+        let cursor = &mut Cursor::new(contents);
+        while (cursor.position() as usize) < cursor.get_ref().len() {
+            let group = read_string(cursor)?;
+            let topic = read_string(cursor)?;
+            let partition = cursor.read_u32::<NetworkEndian>()?;
+            let offset = cursor.read_i64::<NetworkEndian>()?;
+            offsets
+                .entry(group)
+                .or_default()
+                .insert((topic, partition), offset);
+        }
+        Ok(offsets)
+    }
+}
+
+impl Storage for DiskStorage {
+    fn load(&mut self) -> io::Result<Recovered> {
+        let mut topics = BTreeMap::new();
+        for entry in fs::read_dir(self.dir.join("topics"))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "properties") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|n| n.to_str()) {
+                topics.insert(name.to_string(), self.load_topic(name, &path)?);
+            }
+        }
+        Ok(Recovered {
+            topics,
+            offsets: self.load_offsets()?,
+        })
+    }
+
+    fn create_topic(&mut self, name: &str, topic: &Topic) -> io::Result<()> {
+        let mut contents = format!("partitions={}\n", topic.partitions.len());
+        for (key, value) in topic.config.to_properties() {
+            contents.push_str(&format!("{}={}\n", key, value));
+        }
+        for p in 0..topic.partitions.len() as u32 {
+            fs::create_dir_all(self.partition_dir(name, p))?;
+        }
+        let path = self.topic_path(name);
+        self.write_file(&path, contents.as_bytes())
+    }
+
+    fn delete_topic(&mut self, name: &str, topic: &Topic) -> io::Result<()> {
+        fs::remove_file(self.topic_path(name))?;
+        for p in 0..topic.partitions.len() as u32 {
+            let dir = self.partition_dir(name, p);
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, topic: &str, partition: u32, log: &PartitionLog) -> io::Result<()> {
+        let dir = self.partition_dir(topic, partition);
+        fs::create_dir_all(&dir)?;
+        let batch = match log.last() {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        let segment = match find_segment(&dir)? {
+            Some(segment) => segment,
+            None => dir.join(segment_name(log.log_start_offset())),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&segment)?;
+        file.write_all(
+            &batch
+                .to_bytes()
+                .map_err(|_| invalid_data("batch".to_string()))?,
+        )?;
+        self.sync(&file, &segment)?;
+        if batch.producer_id >= 0 {
+            self.write_producer_state(&dir, log)?;
+        }
+        Ok(())
+    }
+
+    fn rewrite(&mut self, topic: &str, partition: u32, log: &PartitionLog) -> io::Result<()> {
+        let dir = self.partition_dir(topic, partition);
+        fs::create_dir_all(&dir)?;
+        let mut contents = Vec::new();
+        for batch in log.batches() {
+            contents.extend(
+                batch
+                    .to_bytes()
+                    .map_err(|_| invalid_data("batch".to_string()))?,
+            );
+        }
+        let old = find_segment(&dir)?;
+        let segment = dir.join(segment_name(log.log_start_offset()));
+        self.write_file(&segment, &contents)?;
+        if let Some(old) = old.filter(|old| *old != segment) {
+            fs::remove_file(old)?;
+        }
+        self.write_checkpoint(&dir, log)?;
+        self.write_producer_state(&dir, log)
+    }
+
+    fn commit_offsets(&mut self, offsets: &BTreeMap<String, GroupOffsets>) -> io::Result<()> {
+        let cursor = &mut Cursor::new(Vec::<u8>::new());
+        for (group, partitions) in offsets {
+            for ((topic, partition), offset) in partitions {
+                write_string(cursor, group)?;
+                write_string(cursor, topic)?;
+                cursor.write_u32::<NetworkEndian>(*partition)?;
+                cursor.write_i64::<NetworkEndian>(*offset)?;
+            }
+        }
+        let path = self.dir.join("consumer-offsets");
+        self.write_file(&path, cursor.get_ref())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.fsync {
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => {
+                self.sync_unsynced()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for DiskStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync_unsynced() {
+            warn!("Error flushing {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+fn segment_name(log_start_offset: i64) -> String {
+    format!("{:020}.log", log_start_offset)
+}
+
+// A partition has a single segment file, whatever its name
+fn find_segment(dir: &Path) -> io::Result<Option<PathBuf>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "log") {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Length-prefixed string, as in the Kafka protocol
+pub(crate) fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    if s.len() > u16::MAX as usize {
+        let msg = format!("string of {} bytes too long to write", s.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    w.write_u16::<NetworkEndian>(s.len() as u16)?;
+    w.write_all(s.as_bytes())
+}
+
+pub(crate) fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![0u8; r.read_u16::<NetworkEndian>()? as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| invalid_data(e.to_string()))
+}
+
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BrokerConfig, CleanupPolicy};
+    use crate::messages::ProduceRecordBatchRequest;
+    use crate::state::State;
+    use crate::testing::batch;
+    use std::time::Duration;
+
+    // Records of an idempotent producer
+    fn produced(records: &[(Option<&str>, Option<&str>)]) -> ProduceRecordBatchRequest {
+        ProduceRecordBatchRequest {
+            producer_id: 7,
+            producer_epoch: 0,
+            base_sequence: 0,
+            ..batch(1_000, records)
+        }
+    }
+
+    fn open(dir: &Path) -> State {
+        let config = BrokerConfig {
+            data_dir: Some(dir.to_path_buf()),
+            ..BrokerConfig::default()
+        };
+        State::open(config).unwrap()
+    }
+
+    #[test]
+    fn recover_state_from_disk() {
+        let dir = std::env::temp_dir().join(format!("pseudokafka-storage-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        {
+            let mut state = open(&dir);
+            let config = TopicConfig {
+                cleanup_policy: CleanupPolicy {
+                    delete: true,
+                    compact: true,
+                },
+                ..TopicConfig::default()
+            };
+            state.create_topic("t", 2, config).unwrap();
+            state
+                .append(
+                    "t",
+                    1,
+                    &produced(&[(Some("a"), Some("1")), (Some("b"), Some("2"))]),
+                )
+                .unwrap();
+            state
+                .append("t", 1, &produced(&[(Some("a"), Some("3"))]))
+                .unwrap();
+            state.commit_offset("group", "t", 1, 2).unwrap();
+            // Drop the first batch, as retention would
+            let log = &mut state.topics.get_mut("t").unwrap().partitions[1];
+            log.pop_head();
+            state.storage.rewrite("t", 1, log).unwrap();
+            state
+                .append("t", 1, &produced(&[(Some("c"), Some("4"))]))
+                .unwrap();
+        }
+
+        let state = open(&dir);
+        let topic = &state.topics["t"];
+        assert!(topic.config.cleanup_policy.compact);
+        assert_eq!(topic.partitions.len(), 2);
+        let log = &topic.partitions[1];
+        assert_eq!(log.log_start_offset(), 2);
+        assert_eq!(log.log_end_offset(), 4);
+        let records = log
+            .batches()
+            .flat_map(|b| b.records.iter())
+            .map(|r| (r.offset, r.value.clone().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![(2, b"3".to_vec()), (3, b"4".to_vec())]);
+        assert_eq!(log.producers()[&7].last_sequence, 0);
+        assert_eq!(state.offsets["group"][&("t".to_string(), 1)], 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_topic_names() {
+        let dir = std::env::temp_dir().join(format!("pseudokafka-names-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut state = open(&dir);
+        let long = "a".repeat(250);
+        for name in &["", ".", "..", "../t", "a/b", "t ", long.as_str()] {
+            let err = state
+                .create_topic(name, 1, TopicConfig::default())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+        assert!(!dir.join("t").exists());
+        assert!(state
+            .create_topic("a.b_c-1", 1, TopicConfig::default())
+            .unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_writes_once_the_interval_is_over() {
+        let dir = std::env::temp_dir().join(format!("pseudokafka-fsync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let interval = Duration::from_millis(50);
+        let mut storage = DiskStorage::open(&dir, FsyncPolicy::Interval(interval)).unwrap();
+        let mut log = PartitionLog::new();
+        log.append(&batch(1_000, &[(None, Some("a"))]), 0);
+        storage.append("t", 0, &log).unwrap();
+        storage.commit_offsets(&BTreeMap::new()).unwrap();
+        assert_eq!(storage.unsynced.len(), 2);
+        storage.flush().unwrap();
+        assert_eq!(storage.unsynced.len(), 2);
+
+        std::thread::sleep(interval);
+        storage.flush().unwrap();
+        assert!(storage.unsynced.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_strings_too_long_to_write() {
+        let mut buf = vec![];
+        write_string(&mut buf, &"a".repeat(u16::MAX as usize)).unwrap();
+        assert_eq!(read_string(&mut &buf[..]).unwrap().len(), u16::MAX as usize);
+        let err = write_string(&mut vec![], &"a".repeat(u16::MAX as usize + 1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
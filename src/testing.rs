@@ -0,0 +1,36 @@
+use crate::messages::{ProduceRecordBatchRequest, ProduceRecordRequest};
+
+/// A batch as produced without idempotence, its records all at the same time
+/// and with consecutive offsets. Fields can be changed with struct update
+/// syntax, e.g. for an idempotent producer.
+///
+/// * `timestamp` - timestamp of the records
+/// * `records` - key and value of each record
+pub fn batch(
+    timestamp: u64,
+    records: &[(Option<&str>, Option<&str>)],
+) -> ProduceRecordBatchRequest {
+    ProduceRecordBatchRequest {
+        offset: 0,
+        leader_epoch: -1,
+        options: 0,
+        last_offset_delta: records.len() as u32 - 1,
+        first_timestamp: timestamp,
+        last_timestamp: timestamp,
+        producer_id: -1,
+        producer_epoch: -1,
+        base_sequence: -1,
+        size: 100,
+        records: records
+            .iter()
+            .enumerate()
+            .map(|(i, (k, v))| ProduceRecordRequest {
+                timestamp_delta: 0,
+                offset_delta: i as i32,
+                key: k.map(|k| k.as_bytes().to_vec()),
+                value: v.map(|v| v.as_bytes().to_vec()),
+                headers: vec![],
+            })
+            .collect(),
+    }
+}